@@ -1,6 +1,30 @@
 //! Git subcommand implementations.
 pub(crate) mod cat_file;
+pub(crate) mod cherry_pick;
+pub(crate) mod commit;
+pub(crate) mod commit_graph;
 pub(crate) mod commit_tree;
+pub(crate) mod config;
+pub(crate) mod for_each_ref;
+pub(crate) mod fsck;
+pub(crate) mod gc;
 pub(crate) mod hash_object;
+pub(crate) mod init;
+pub(crate) mod log;
 pub(crate) mod ls_tree;
+pub(crate) mod merge;
+pub(crate) mod merge_base;
+pub(crate) mod multi_pack_index;
+pub(crate) mod pack_refs;
+pub(crate) mod prune;
+pub(crate) mod rebase;
+pub(crate) mod reflog;
+pub(crate) mod repack;
+pub(crate) mod rev_list;
+pub(crate) mod revert;
+pub(crate) mod show_ref;
+pub(crate) mod stash;
+pub(crate) mod symbolic_ref;
+pub(crate) mod update_ref;
+pub(crate) mod upload_pack;
 pub(crate) mod write_tree;
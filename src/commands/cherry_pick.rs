@@ -0,0 +1,24 @@
+//! The `cherry-pick` command.
+//!
+//! See: <https://git-scm.com/docs/git-cherry-pick>
+use crate::sequencer::{self, Action, Options};
+
+/// Invoke the `cherry-pick` command.
+/// See: <https://git-scm.com/docs/git-cherry-pick>
+pub(crate) fn invoke(
+    commits: &[String],
+    options: Options,
+    resume: bool,
+    skip: bool,
+    abort: bool,
+) -> anyhow::Result<()> {
+    if resume {
+        sequencer::resume()
+    } else if skip {
+        sequencer::skip()
+    } else if abort {
+        sequencer::abort()
+    } else {
+        sequencer::start(Action::Pick, commits, options)
+    }
+}
@@ -0,0 +1,110 @@
+//! The `commit` command.
+//!
+//! See: <https://git-scm.com/docs/git-commit>
+use anyhow::Context;
+
+use crate::commands::{commit_tree, gc, merge, write_tree};
+use crate::index::Index;
+use crate::objects::Tree;
+use crate::repository;
+use crate::{hash, refs};
+
+/// Strip `#` comment lines and surrounding blank lines from a commit message template.
+pub(crate) fn cleanup_message(message: &str) -> String {
+    message
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// Whether the file at `path` still contains conflict markers.
+fn has_conflict_markers(path: &str) -> bool {
+    let Ok(data) = std::fs::read(path) else {
+        return false;
+    };
+    data.split(|&b| b == b'\n')
+        .any(|line| line.starts_with(b"<<<<<<<") || line.starts_with(b">>>>>>>"))
+}
+
+/// Unmerged paths of the index that still contain conflict markers in the working tree.
+pub(crate) fn unresolved_paths() -> anyhow::Result<Vec<String>> {
+    Ok(Index::read()?
+        .unmerged_paths()
+        .into_iter()
+        .filter(|path| has_conflict_markers(path))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Invoke the `commit` command.
+/// See: <https://git-scm.com/docs/git-commit>
+pub(crate) fn invoke(message: Option<String>) -> anyhow::Result<()> {
+    // The branch `HEAD` points to, or `HEAD` itself if it is detached. An unborn branch has no
+    // commit yet, so the new commit is a root commit.
+    let head_ref = refs::head_target()?.unwrap_or_else(|| "HEAD".to_string());
+    let parent_hash = refs::resolve(&head_ref)?;
+
+    let merge_heads = merge::merge_heads()?;
+    let unresolved = unresolved_paths()?;
+    if !unresolved.is_empty() {
+        anyhow::bail!(
+            "Committing is not possible because you have unmerged files:\n\t{}",
+            unresolved.join("\n\t")
+        );
+    }
+
+    let message = match message {
+        Some(message) => message,
+        None => merge::read_state_message()?
+            .map(|m| cleanup_message(&m))
+            .context("no commit message given (use -m)")?,
+    };
+
+    let Some((tree_hash, cache_tree)) =
+        write_tree::write_tree_with_cache(repository::work_tree()?).context("write tree")?
+    else {
+        eprintln!("not committing empty tree");
+        return Ok(());
+    };
+    let tree_hash = hex::encode(tree_hash);
+    let parents: Vec<&str> = parent_hash
+        .iter()
+        .chain(&merge_heads)
+        .map(String::as_str)
+        .collect();
+
+    let commit_hash = commit_tree::write_commit(message.trim_end(), &tree_hash, &parents)
+        .context("create commit")?;
+    let commit_hash = hex::encode(commit_hash);
+
+    let kind = if parent_hash.is_none() {
+        " (initial)"
+    } else if merge_heads.is_empty() {
+        ""
+    } else {
+        " (merge)"
+    };
+    let subject = message.lines().next().unwrap_or_default();
+    // Fail rather than lose a commit if the branch moved while this one was being created.
+    let mut transaction = refs::Transaction::default();
+    transaction.update(
+        &head_ref,
+        &commit_hash,
+        Some(parent_hash.as_deref().unwrap_or(hash::null_hex())),
+        Some(&format!("commit{kind}: {subject}")),
+    );
+    transaction
+        .commit()
+        .with_context(|| format!("update HEAD reference target {head_ref}"))?;
+    merge::clear_state()?;
+    // The index now matches the tree, so the next commit can reuse its blobs and subtrees.
+    let mut index = Index::from_tree(&Tree::read_flat(&tree_hash)?);
+    index.cache_tree = Some(cache_tree);
+    index.write().context("update index")?;
+
+    println!("HEAD is now at {commit_hash}");
+    gc::auto()
+}
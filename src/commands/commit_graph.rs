@@ -0,0 +1,90 @@
+//! The `commit-graph` command.
+//!
+//! See: <https://git-scm.com/docs/git-commit-graph>
+use anyhow::Context;
+use clap::Subcommand;
+use std::io::BufRead;
+
+use crate::commit_graph::{self, Split, WriteOptions};
+use crate::revision;
+
+/// `commit-graph` subcommands.
+#[derive(Subcommand, Debug)]
+pub(crate) enum Action {
+    /// Write a commit-graph file with the commits in packs and every commit they reach.
+    Write {
+        /// Start from the commits references point to instead.
+        #[clap(long, conflicts_with = "stdin_commits")]
+        reachable: bool,
+        /// Start from the commits listed on standard input instead, one per line.
+        #[clap(long)]
+        stdin_commits: bool,
+        /// Add a layer to a chain of commit-graph files instead of writing a single file;
+        /// `no-merge` never merges the new layer with existing ones, `replace` merges all.
+        #[clap(long, value_name = "STRATEGY", require_equals = true)]
+        split: Option<Option<Split>>,
+        /// Compute changed-path Bloom filters, which speed up path-limited history.
+        #[clap(long, overrides_with = "no_changed_paths")]
+        changed_paths: bool,
+        /// Do not compute changed-path Bloom filters, even if the existing graph has them.
+        #[clap(long)]
+        no_changed_paths: bool,
+        /// Merge layers into the new one while they have at most this many times its commits.
+        #[clap(long, default_value_t = 2)]
+        size_multiple: usize,
+    },
+    /// Check the commit-graph file against the object database.
+    Verify,
+}
+
+/// Invoke the `commit-graph` command.
+/// See: <https://git-scm.com/docs/git-commit-graph>
+pub(crate) fn invoke(action: Action) -> anyhow::Result<()> {
+    match action {
+        Action::Write {
+            reachable,
+            stdin_commits,
+            split,
+            changed_paths,
+            no_changed_paths,
+            size_multiple,
+        } => {
+            let commits = if reachable {
+                commit_graph::reachable()?
+            } else if stdin_commits {
+                let mut commits = Vec::new();
+                for line in std::io::stdin().lock().lines() {
+                    let line = line.context("read standard input")?;
+                    let line = line.trim();
+                    if !line.is_empty() {
+                        commits.push(
+                            revision::resolve(line)
+                                .with_context(|| format!("invalid commit object id: {line}"))?,
+                        );
+                    }
+                }
+                commits
+            } else {
+                commit_graph::packed()?
+            };
+            let options = WriteOptions {
+                split: split.map(|strategy| strategy.unwrap_or(Split::Merge)),
+                changed_paths: match (changed_paths, no_changed_paths) {
+                    (true, _) => Some(true),
+                    (_, true) => Some(false),
+                    _ => None,
+                },
+                size_multiple,
+            };
+            commit_graph::write(&commits, options)
+        }
+        Action::Verify => {
+            if let Some(graph) = commit_graph::graph()? {
+                if !graph.verify()? {
+                    std::process::exit(1);
+                }
+            }
+            Ok(())
+        }
+    }
+}
@@ -7,53 +7,61 @@ use std::fmt::Write;
 use std::io::Cursor;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::config::Config;
+use crate::hash::ObjectId;
 use crate::objects::{Kind, Object};
 
-/// Get the value of a Git config key, e.g. `user.name`.
-fn get_git_config_value(key: &str) -> anyhow::Result<String> {
-    let output = std::process::Command::new("git")
-        .args(["config", "--get", key])
-        .output()
-        .context("get git config value")?;
-    if !output.status.success() {
-        anyhow::bail!("git config failed for key: {key}");
-    }
-    Ok(String::from_utf8(output.stdout)
-        .context("Invalid UTF-8 from git config")?
-        .trim()
-        .to_string())
+/// The current user's identity with the current time, e.g. `Name <email> 1700000000 +0000`, as
+/// recorded in commits and reference logs.
+pub(crate) fn identity() -> anyhow::Result<String> {
+    let config = Config::load()?;
+    let (Some(name), Some(email)) = (
+        config.get_string("user.name"),
+        config.get_string("user.email"),
+    ) else {
+        anyhow::bail!(
+            "Author identity unknown\n\n\
+             *** Please tell me who you are.\n\n\
+             Run\n\n  \
+             git config --global user.email \"you@example.com\"\n  \
+             git config --global user.name \"Your Name\"\n\n\
+             to set your account's default identity."
+        );
+    };
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let utc_offset = {
+        let now = Local::now();
+        now.format("%z").to_string()
+    };
+    Ok(format!("{name} <{email}> {timestamp} {utc_offset}"))
 }
 
 /// Write a commit object to the `.git/objects` directory.
 pub(crate) fn write_commit(
     message: &str,
     tree_hash: &str,
-    parent_hash: Option<&str>,
-) -> anyhow::Result<[u8; 20]> {
+    parent_hashes: &[&str],
+) -> anyhow::Result<ObjectId> {
+    write_commit_with_author(message, tree_hash, parent_hashes, None)
+}
+
+/// Write a commit object to the `.git/objects` directory, keeping the given author line (e.g.
+/// `Name <email> 1700000000 +0000`) instead of using the committer identity.
+pub(crate) fn write_commit_with_author(
+    message: &str,
+    tree_hash: &str,
+    parent_hashes: &[&str],
+    author: Option<&str>,
+) -> anyhow::Result<ObjectId> {
     let mut commit = String::new();
     writeln!(commit, "tree {tree_hash}")?;
-    if let Some(parent_hash) = parent_hash {
+    for parent_hash in parent_hashes {
         writeln!(commit, "parent {parent_hash}")?;
     }
 
-    let name = get_git_config_value("user.name")?;
-    let email = get_git_config_value("user.email")?;
-    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    let utc_offset = {
-        let now = Local::now();
-        now.format("%z").to_string()
-    };
-
-    writeln!(
-        commit,
-        "author {} <{}> {} {}",
-        name, email, timestamp, utc_offset
-    )?;
-    writeln!(
-        commit,
-        "committer {} <{}> {} {}",
-        name, email, timestamp, utc_offset
-    )?;
+    let committer = identity()?;
+    writeln!(commit, "author {}", author.unwrap_or(&committer))?;
+    writeln!(commit, "committer {committer}")?;
     writeln!(commit)?;
     writeln!(commit, "{message}")?;
 
@@ -71,10 +79,10 @@ pub(crate) fn write_commit(
 pub(crate) fn invoke(
     message: String,
     tree_hash: String,
-    parent_hash: Option<String>,
+    parent_hashes: Vec<String>,
 ) -> anyhow::Result<()> {
-    let hash =
-        write_commit(&message, &tree_hash, parent_hash.as_deref()).context("write commit")?;
+    let parent_hashes: Vec<&str> = parent_hashes.iter().map(String::as_str).collect();
+    let hash = write_commit(&message, &tree_hash, &parent_hashes).context("write commit")?;
     println!("{}", hex::encode(hash));
     Ok(())
 }
@@ -0,0 +1,318 @@
+//! The `config` command.
+//!
+//! See: <https://git-scm.com/docs/git-config>
+use anyhow::Context;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, Config, Entry, Scope};
+use crate::repository;
+
+/// How values are interpreted when read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Type {
+    /// `true` or `false`.
+    Bool,
+    /// A decimal number with an optional `k`, `m` or `g` suffix.
+    Int,
+    /// A boolean, or an integer if it is not one.
+    BoolOrInt,
+    /// A path, where a leading `~/` is the home directory.
+    Path,
+}
+
+/// Arguments of the `config` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Only use the system configuration.
+    #[clap(long, group = "location")]
+    system: bool,
+    /// Only use the configuration of the current user.
+    #[clap(long, group = "location")]
+    global: bool,
+    /// Only use the configuration of the repository.
+    #[clap(long, group = "location")]
+    local: bool,
+    /// Only use the given configuration file.
+    #[clap(short = 'f', long, group = "location")]
+    file: Option<PathBuf>,
+
+    /// Get the last value of a variable.
+    #[clap(long, group = "action")]
+    get: bool,
+    /// Get all the values of a multi-valued variable.
+    #[clap(long, group = "action")]
+    get_all: bool,
+    /// Get the variables whose names match a regular expression, and their values.
+    #[clap(long, group = "action")]
+    get_regexp: bool,
+    /// Add a value to a variable without replacing its current values.
+    #[clap(long, group = "action")]
+    add: bool,
+    /// Remove a variable.
+    #[clap(long, group = "action")]
+    unset: bool,
+    /// Remove all the values of a multi-valued variable.
+    #[clap(long, group = "action")]
+    unset_all: bool,
+    /// List all variables and their values.
+    #[clap(short = 'l', long, group = "action")]
+    list: bool,
+
+    /// Interpret values as this type.
+    #[clap(long = "type", value_enum)]
+    kind: Option<Type>,
+    /// Same as `--type=bool`.
+    #[clap(long)]
+    bool: bool,
+    /// Same as `--type=int`.
+    #[clap(long)]
+    int: bool,
+    /// Show the file each value comes from.
+    #[clap(long)]
+    show_origin: bool,
+    /// Read the files named by `include.path` even when only one file or scope is used.
+    #[clap(long, overrides_with = "no_includes")]
+    includes: bool,
+    /// Do not read the files named by `include.path`, the default when only one file or scope is
+    /// used.
+    #[clap(long, overrides_with = "includes")]
+    no_includes: bool,
+
+    /// The name of the variable, e.g. `user.name`, or a regular expression for `--get-regexp`.
+    name: Option<String>,
+    /// The new value, or a regular expression that values must match when getting.
+    value: Option<String>,
+}
+
+impl Args {
+    /// The scope selected by `--system`, `--global` or `--local`.
+    fn scope(&self) -> Option<Scope> {
+        if self.system {
+            Some(Scope::System)
+        } else if self.global {
+            Some(Scope::Global)
+        } else if self.local {
+            Some(Scope::Local)
+        } else {
+            None
+        }
+    }
+
+    /// The type selected by `--type`, `--bool` or `--int`.
+    fn kind(&self) -> Option<Type> {
+        if self.bool {
+            Some(Type::Bool)
+        } else if self.int {
+            Some(Type::Int)
+        } else {
+            self.kind
+        }
+    }
+
+    /// The configuration to read.
+    ///
+    /// Like in Git, includes are only followed by default when reading all scopes.
+    fn read(&self) -> anyhow::Result<Config> {
+        let path = match (&self.file, self.scope()) {
+            (Some(file), _) => file.clone(),
+            (None, Some(scope)) => scope.path()?,
+            (None, None) if self.no_includes => return Config::load_without_includes(),
+            (None, None) => return Config::load(),
+        };
+        if self.includes {
+            Config::from_file(&path)
+        } else {
+            Config::from_file_without_includes(&path)
+        }
+    }
+
+    /// The configuration file to write.
+    fn write_path(&self) -> anyhow::Result<PathBuf> {
+        if let Some(file) = &self.file {
+            return Ok(file.clone());
+        }
+        self.scope().unwrap_or(Scope::Local).path()
+    }
+
+    /// The name argument, which all actions but `--list` require.
+    fn name(&self) -> anyhow::Result<&str> {
+        self.name
+            .as_deref()
+            .context("wrong number of arguments, should be 1")
+    }
+}
+
+/// Format `entry`'s value as `kind`.
+fn format_value(entry: &Entry, kind: Option<Type>) -> anyhow::Result<String> {
+    let value = entry.value.as_deref();
+    Ok(match kind {
+        None => value.unwrap_or_default().to_string(),
+        Some(Type::Bool) => config::parse_bool(&entry.key, value)?.to_string(),
+        Some(Type::Int) => config::parse_int(&entry.key, value)?.to_string(),
+        Some(Type::BoolOrInt) => match config::parse_int(&entry.key, value) {
+            Ok(number) if value.is_some() => number.to_string(),
+            _ => config::parse_bool(&entry.key, value)?.to_string(),
+        },
+        Some(Type::Path) => {
+            let value = value.with_context(|| format!("missing value for '{}'", entry.key))?;
+            match (value.strip_prefix("~/"), std::env::var("HOME")) {
+                (Some(rest), Ok(home)) => format!("{home}/{rest}"),
+                _ => value.to_string(),
+            }
+        }
+    })
+}
+
+/// Normalize a value given on the command line as `kind` before writing it.
+fn normalize_value(key: &str, value: &str, kind: Option<Type>) -> anyhow::Result<String> {
+    let entry = Entry {
+        key: key.to_string(),
+        value: Some(value.to_string()),
+        origin: PathBuf::new(),
+    };
+    match kind {
+        Some(Type::Path) | None => Ok(value.to_string()),
+        Some(kind) => format_value(&entry, Some(kind)),
+    }
+}
+
+/// A regular expression that values must match, negated by a leading `!`.
+struct ValuePattern {
+    regex: Regex,
+    negated: bool,
+}
+
+impl ValuePattern {
+    fn new(pattern: &str) -> anyhow::Result<ValuePattern> {
+        let (pattern, negated) = match pattern.strip_prefix('!') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        Ok(ValuePattern {
+            regex: Regex::new(pattern).with_context(|| format!("invalid pattern: {pattern}"))?,
+            negated,
+        })
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.regex
+            .is_match(entry.value.as_deref().unwrap_or_default())
+            != self.negated
+    }
+}
+
+/// Lower-case the section and variable name of a `--get-regexp` pattern, like the keys it is
+/// matched against.
+fn canonical_pattern(pattern: &str) -> String {
+    let (Some(first), Some(last)) = (pattern.find('.'), pattern.rfind('.')) else {
+        return pattern.to_ascii_lowercase();
+    };
+    format!(
+        "{}{}{}",
+        pattern[..first].to_ascii_lowercase(),
+        &pattern[first..=last],
+        pattern[last + 1..].to_ascii_lowercase()
+    )
+}
+
+/// Print the values of `entries`, as `key value` if `with_key`.
+fn print(entries: &[&Entry], args: &Args, with_key: bool) -> anyhow::Result<()> {
+    for entry in entries {
+        if args.show_origin {
+            print!("file:{}\t", entry.origin.display());
+        }
+        match (with_key, &entry.value) {
+            (true, None) if args.kind().is_none() => println!("{}", entry.key),
+            (true, _) => println!("{} {}", entry.key, format_value(entry, args.kind())?),
+            (false, _) => println!("{}", format_value(entry, args.kind())?),
+        }
+    }
+    Ok(())
+}
+
+/// Exit with status 5, like Git, if `key` has several values in the file at `path`, since they
+/// cannot be replaced or removed with a single one.
+fn ensure_single_value(path: &Path, key: &str) -> anyhow::Result<()> {
+    if Config::from_file_without_includes(path)?.get_all(key).len() > 1 {
+        eprintln!("warning: {key} has multiple values");
+        std::process::exit(5);
+    }
+    Ok(())
+}
+
+/// Invoke the `config` command.
+/// See: <https://git-scm.com/docs/git-config>
+pub(crate) fn invoke(mut args: Args) -> anyhow::Result<()> {
+    // The process has changed to the top of the working tree, so resolve the file from where the
+    // command was run.
+    args.file = args.file.map(|file| repository::prefixed(&file));
+    if args.list {
+        for entry in &args.read()?.entries {
+            if args.show_origin {
+                print!("file:{}\t", entry.origin.display());
+            }
+            match &entry.value {
+                Some(value) => println!("{}={value}", entry.key),
+                None => println!("{}", entry.key),
+            }
+        }
+        return Ok(());
+    }
+
+    let name = args.name()?;
+    let found = if args.get_regexp {
+        let regex = Regex::new(&canonical_pattern(name))
+            .with_context(|| format!("invalid key pattern: {name}"))?;
+        let values = args.value.as_deref().map(ValuePattern::new).transpose()?;
+        let config = args.read()?;
+        let entries: Vec<&Entry> = config
+            .entries
+            .iter()
+            .filter(|entry| regex.is_match(&entry.key))
+            .filter(|entry| values.as_ref().map_or(true, |values| values.matches(entry)))
+            .collect();
+        print(&entries, &args, true)?;
+        !entries.is_empty()
+    } else if args.unset || args.unset_all {
+        anyhow::ensure!(
+            args.value.is_none(),
+            "wrong number of arguments, should be 1"
+        );
+        let path = args.write_path()?;
+        if !args.unset_all {
+            ensure_single_value(&path, name)?;
+        }
+        // Git exits with 5 when there is nothing to remove.
+        if config::unset(&path, name, args.unset_all)? == 0 {
+            std::process::exit(5);
+        }
+        true
+    } else if let (Some(value), false, false) = (&args.value, args.get, args.get_all) {
+        let value = normalize_value(name, value, args.kind())?;
+        let path = args.write_path()?;
+        if !args.add {
+            ensure_single_value(&path, name)?;
+        }
+        config::set(&path, name, &value, args.add)?;
+        true
+    } else {
+        anyhow::ensure!(!args.add, "wrong number of arguments, should be 2");
+        let values = args.value.as_deref().map(ValuePattern::new).transpose()?;
+        let config = args.read()?;
+        let mut entries: Vec<&Entry> = config
+            .get_all(config::canonical_key(name)?.as_str())
+            .into_iter()
+            .filter(|entry| values.as_ref().map_or(true, |values| values.matches(entry)))
+            .collect();
+        if !args.get_all {
+            entries = entries.split_off(entries.len().saturating_sub(1));
+        }
+        print(&entries, &args, false)?;
+        !entries.is_empty()
+    };
+    if !found {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,667 @@
+//! The `for-each-ref` command.
+//!
+//! See: <https://git-scm.com/docs/git-for-each-ref>
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use crate::commands::merge::short;
+use crate::commands::merge_base::History;
+use crate::config::{self, Config};
+use crate::objects::{signature_time, Commit, Kind, Object, Tag};
+use crate::{refs, revision};
+
+/// The format used without `--format`.
+const DEFAULT_FORMAT: &str = "%(objectname) %(objecttype)\t%(refname)";
+
+/// Arguments of the `for-each-ref` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// How to show each reference, with `%(<field>)` replaced by its value, e.g.
+    /// `%(refname:short)`, `%(objectname)` or `%(*objectname)` for the object a tag points to.
+    #[clap(long)]
+    format: Option<String>,
+    /// Sort by a field, e.g. `-committerdate` or `version:refname`; later keys take precedence.
+    #[clap(long, value_name = "KEY")]
+    sort: Vec<String>,
+    /// Show at most this many references.
+    #[clap(long)]
+    count: Option<usize>,
+    /// Only show references pointing at this object, directly or through a tag.
+    #[clap(long, value_name = "OBJECT")]
+    points_at: Vec<String>,
+    /// Only show references whose commit is reachable from this commit, `HEAD` by default.
+    #[clap(long, value_name = "COMMIT", num_args = 0..=1, default_missing_value = "HEAD")]
+    merged: Vec<String>,
+    /// Only show references whose commit reaches this commit, `HEAD` by default.
+    #[clap(long, value_name = "COMMIT", num_args = 0..=1, default_missing_value = "HEAD")]
+    contains: Vec<String>,
+    /// Only show references matching a glob or starting with a pattern up to a `/`.
+    patterns: Vec<String>,
+}
+
+/// How to show a reference name.
+#[derive(Debug, Clone, Copy)]
+enum NameFormat {
+    /// The full name, e.g. `refs/heads/main`.
+    Full,
+    /// The shortest unambiguous name, e.g. `main`.
+    Short,
+    /// The name without its first `n` components.
+    Lstrip(usize),
+}
+
+/// How to show the upstream of a branch.
+#[derive(Debug, Clone, Copy)]
+enum UpstreamFormat {
+    /// The name of the upstream reference.
+    Name(NameFormat),
+    /// How far ahead and behind the upstream the branch is, e.g. `[ahead 1, behind 2]`.
+    Track,
+    /// `>`, `<`, `<>` or `=` for ahead, behind, diverged or up to date.
+    TrackShort,
+}
+
+/// Whose date a date field shows.
+#[derive(Debug, Clone, Copy)]
+enum Person {
+    Author,
+    Committer,
+    Tagger,
+    /// The committer of a commit or the tagger of a tag.
+    Creator,
+}
+
+/// How to show a date.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DateFormat {
+    /// E.g. `Tue Nov 14 22:13:20 2023 +0000`.
+    Default,
+    /// E.g. `2023-11-14 22:13:20 +0000`.
+    Iso,
+    /// E.g. `2023-11-14T22:13:20+00:00`.
+    IsoStrict,
+    /// E.g. `Tue, 14 Nov 2023 22:13:20 +0000`.
+    Rfc,
+    /// E.g. `2023-11-14`.
+    Short,
+    /// Seconds since the Unix epoch.
+    Unix,
+    /// Seconds since the Unix epoch and the time zone, e.g. `1700000000 +0000`.
+    Raw,
+}
+
+/// A field of a reference or its object.
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    RefName(NameFormat),
+    ObjectName {
+        short: bool,
+    },
+    ObjectType,
+    ObjectSize,
+    /// The first paragraph of the message, on one line.
+    Subject,
+    /// The message after the subject.
+    Body,
+    /// The whole message.
+    Contents,
+    Date(Person, DateFormat),
+    Upstream(UpstreamFormat),
+    /// `*` if `HEAD` points to the reference, a space otherwise.
+    Head,
+}
+
+/// A field to show or sort by, e.g. `refname:short` or `*objectname`.
+#[derive(Debug, Clone, Copy)]
+struct Atom {
+    /// Whether the field is taken from the object a tag points to rather than the tag.
+    deref: bool,
+    field: Field,
+}
+
+impl Atom {
+    /// Parse an atom such as `*committerdate:iso`, without the surrounding `%(` and `)`.
+    fn parse(atom: &str) -> anyhow::Result<Atom> {
+        let (deref, spec) = match atom.strip_prefix('*') {
+            Some(spec) => (true, spec),
+            None => (false, atom),
+        };
+        let (name, modifier) = match spec.split_once(':') {
+            Some((name, modifier)) => (name, Some(modifier)),
+            None => (spec, None),
+        };
+        let unknown_modifier = || {
+            format!(
+                "unrecognized %({spec}) argument: {}",
+                modifier.unwrap_or("")
+            )
+        };
+        let field = match (name, modifier) {
+            ("refname", modifier) => {
+                Field::RefName(parse_name_format(modifier).with_context(unknown_modifier)?)
+            }
+            ("objectname", None) => Field::ObjectName { short: false },
+            ("objectname", Some("short")) => Field::ObjectName { short: true },
+            ("objecttype", None) => Field::ObjectType,
+            ("objectsize", None) => Field::ObjectSize,
+            ("subject", None) | ("contents", Some("subject")) => Field::Subject,
+            ("body", None) | ("contents", Some("body")) => Field::Body,
+            ("contents", None) => Field::Contents,
+            ("upstream", Some("track")) => Field::Upstream(UpstreamFormat::Track),
+            ("upstream", Some("trackshort")) => Field::Upstream(UpstreamFormat::TrackShort),
+            ("upstream", modifier) => Field::Upstream(UpstreamFormat::Name(
+                parse_name_format(modifier).with_context(unknown_modifier)?,
+            )),
+            ("HEAD", None) => Field::Head,
+            (name, modifier) if name.ends_with("date") => {
+                let person = match name {
+                    "authordate" => Person::Author,
+                    "committerdate" => Person::Committer,
+                    "taggerdate" => Person::Tagger,
+                    "creatordate" => Person::Creator,
+                    _ => anyhow::bail!("unknown field name: {name}"),
+                };
+                let format = match modifier.unwrap_or("default") {
+                    "default" => DateFormat::Default,
+                    "iso" | "iso8601" => DateFormat::Iso,
+                    "iso-strict" | "iso8601-strict" => DateFormat::IsoStrict,
+                    "rfc" | "rfc2822" => DateFormat::Rfc,
+                    "short" => DateFormat::Short,
+                    "unix" => DateFormat::Unix,
+                    "raw" => DateFormat::Raw,
+                    format => anyhow::bail!("unknown date format {format}"),
+                };
+                Field::Date(person, format)
+            }
+            (
+                "objectname" | "objecttype" | "objectsize" | "subject" | "body" | "contents"
+                | "HEAD",
+                Some(_),
+            ) => {
+                anyhow::bail!(unknown_modifier())
+            }
+            _ => anyhow::bail!("unknown field name: {name}"),
+        };
+        Ok(Atom { deref, field })
+    }
+}
+
+/// Parse the modifier of a reference name field, `None` if it is not one.
+fn parse_name_format(modifier: Option<&str>) -> Option<NameFormat> {
+    match modifier {
+        None => Some(NameFormat::Full),
+        Some("short") => Some(NameFormat::Short),
+        Some(modifier) => {
+            let n = modifier
+                .strip_prefix("lstrip=")
+                .or_else(|| modifier.strip_prefix("strip="))?;
+            n.parse().ok().map(NameFormat::Lstrip)
+        }
+    }
+}
+
+/// A part of a format string.
+#[derive(Debug)]
+enum Piece {
+    Literal(String),
+    Atom(Atom),
+}
+
+/// Parse a format string into literal text and atoms, expanding `%%` and `%xx` hex escapes.
+fn parse_format(format: &str) -> anyhow::Result<Vec<Piece>> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut rest = format;
+    while let Some(i) = rest.find('%') {
+        literal.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("%(") {
+            let end = after
+                .find(')')
+                .with_context(|| format!("malformed format string {rest}"))?;
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+            }
+            pieces.push(Piece::Atom(Atom::parse(&after[..end])?));
+            rest = &after[end + 1..];
+        } else if let Some(after) = rest.strip_prefix("%%") {
+            literal.push('%');
+            rest = after;
+        } else if let Some(byte) = rest
+            .get(1..3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            literal.push(char::from(byte));
+            rest = &rest[3..];
+        } else {
+            literal.push('%');
+            rest = &rest[1..];
+        }
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Ok(pieces)
+}
+
+/// A sort key given with `--sort`, e.g. `-version:refname`.
+#[derive(Debug)]
+struct SortKey {
+    atom: Atom,
+    /// Sort in descending order.
+    reverse: bool,
+    /// Compare numbers within the values by their value, so that `v1.10` comes after `v1.9`.
+    version: bool,
+}
+
+impl SortKey {
+    fn parse(key: &str) -> anyhow::Result<SortKey> {
+        let (reverse, key) = match key.strip_prefix('-') {
+            Some(key) => (true, key),
+            None => (false, key),
+        };
+        let (version, key) = match key
+            .strip_prefix("version:")
+            .or_else(|| key.strip_prefix("v:"))
+        {
+            Some(key) => (true, key),
+            None => (false, key),
+        };
+        Ok(SortKey {
+            atom: Atom::parse(key)?,
+            reverse,
+            version,
+        })
+    }
+}
+
+/// An object with its contents.
+struct Loaded {
+    hash: String,
+    kind: Kind,
+    data: Vec<u8>,
+}
+
+impl Loaded {
+    fn read(hash: &str) -> anyhow::Result<Loaded> {
+        let object = Object::read(hash).with_context(|| format!("read object {hash}"))?;
+        Ok(Loaded {
+            hash: hash.to_string(),
+            kind: object.kind,
+            data: object.into_bytes()?,
+        })
+    }
+
+    /// The message of a commit or tag.
+    fn message(&self) -> anyhow::Result<Option<String>> {
+        Ok(match self.kind {
+            Kind::Commit => Some(Commit::parse(&self.data)?.message),
+            Kind::Tag => Some(Tag::parse(&self.data)?.message),
+            Kind::Blob | Kind::Tree => None,
+        })
+    }
+
+    /// The identity line of `person`, e.g. `Name <email> 1700000000 +0000`, if the object has one.
+    fn signature(&self, person: Person) -> anyhow::Result<Option<String>> {
+        Ok(match (&self.kind, person) {
+            (Kind::Commit, Person::Author) => Some(Commit::parse(&self.data)?.author),
+            (Kind::Commit, Person::Committer | Person::Creator) => {
+                Some(Commit::parse(&self.data)?.committer)
+            }
+            (Kind::Tag, Person::Tagger | Person::Creator) => Tag::parse(&self.data)?.tagger,
+            _ => None,
+        })
+    }
+}
+
+/// A reference with its object and, for an annotated tag, the object the tag points to.
+struct Entry {
+    name: String,
+    object: Loaded,
+    tagged: Option<Loaded>,
+}
+
+/// The value of an atom for a reference.
+#[derive(Debug, Default)]
+struct Value {
+    text: String,
+    /// The value to sort by if the field is numeric, e.g. a timestamp.
+    number: Option<i64>,
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Value {
+        Value { text, number: None }
+    }
+}
+
+/// The first paragraph of `message` on one line, and the rest of it.
+pub(crate) fn split_subject(message: &str) -> (String, &str) {
+    let mut subject = Vec::new();
+    let mut rest = message;
+    while let Some(line) = rest.lines().next() {
+        if line.trim().is_empty() {
+            break;
+        }
+        subject.push(line.trim());
+        rest = rest[line.len()..].strip_prefix('\n').unwrap_or("");
+    }
+    (subject.join(" "), rest.trim_start_matches('\n'))
+}
+
+/// Show the date of the identity line `signature` in its own time zone.
+pub(crate) fn format_date(signature: &str, format: DateFormat) -> String {
+    let time = signature_time(signature);
+    let zone = signature.rsplit(' ').next().unwrap_or("+0000");
+    let offset = zone
+        .get(1..3)
+        .zip(zone.get(3..5))
+        .and_then(|(hours, minutes)| {
+            Some(hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60)
+        })
+        .map(|seconds| {
+            if zone.starts_with('-') {
+                -seconds
+            } else {
+                seconds
+            }
+        })
+        .and_then(FixedOffset::east_opt)
+        .unwrap_or(FixedOffset::east_opt(0).expect("zero offset is valid"));
+    let Some(date) = DateTime::from_timestamp(time, 0) else {
+        return String::new();
+    };
+    let date = date.with_timezone(&offset);
+    match format {
+        DateFormat::Default => date.format("%a %b %-d %H:%M:%S %Y %z").to_string(),
+        DateFormat::Iso => date.format("%Y-%m-%d %H:%M:%S %z").to_string(),
+        DateFormat::IsoStrict => date.format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+        DateFormat::Rfc => date.format("%a, %-d %b %Y %H:%M:%S %z").to_string(),
+        DateFormat::Short => date.format("%Y-%m-%d").to_string(),
+        DateFormat::Unix => time.to_string(),
+        DateFormat::Raw => format!("{time} {zone}"),
+    }
+}
+
+/// Compare strings such that runs of digits compare by their numeric value.
+fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let x_end = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+            let y_end = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+            let (x, y) = (
+                a[..x_end].trim_start_matches('0'),
+                b[..y_end].trim_start_matches('0'),
+            );
+            match x.len().cmp(&y.len()).then_with(|| x.cmp(y)) {
+                Ordering::Equal => {}
+                ordering => return ordering,
+            }
+            a = &a[x_end..];
+            b = &b[y_end..];
+        } else {
+            match x.cmp(&y) {
+                Ordering::Equal => {}
+                ordering => return ordering,
+            }
+            a = &a[x.len_utf8()..];
+            b = &b[y.len_utf8()..];
+        }
+    }
+}
+
+/// What fields are computed from, shared between references.
+struct Formatter {
+    config: Config,
+    history: History,
+    /// Whether short reference names must not be ambiguous with any other reference, see
+    /// `core.warnAmbiguousRefs`.
+    strict: bool,
+    /// The branch `HEAD` points to, if any.
+    head: Option<String>,
+}
+
+impl Formatter {
+    /// The value of `atom` for `entry`.
+    fn evaluate(&mut self, atom: &Atom, entry: &Entry) -> anyhow::Result<Value> {
+        let object = match (atom.deref, &entry.tagged) {
+            (false, _) => &entry.object,
+            (true, Some(tagged)) => tagged,
+            (true, None) => return Ok(Value::default()),
+        };
+        Ok(match atom.field {
+            Field::RefName(format) => self.format_name(&entry.name, format)?.into(),
+            Field::ObjectName { short: false } => object.hash.clone().into(),
+            Field::ObjectName { short: true } => short(&object.hash).to_string().into(),
+            Field::ObjectType => object.kind.to_string().into(),
+            Field::ObjectSize => Value {
+                text: object.data.len().to_string(),
+                number: Some(i64::try_from(object.data.len())?),
+            },
+            Field::Subject | Field::Body | Field::Contents => {
+                let message = object.message()?.unwrap_or_default();
+                let (subject, body) = split_subject(&message);
+                match atom.field {
+                    Field::Subject => subject.into(),
+                    Field::Body => body.to_string().into(),
+                    _ => message.into(),
+                }
+            }
+            Field::Date(person, format) => match object.signature(person)? {
+                Some(signature) => Value {
+                    text: format_date(&signature, format),
+                    number: Some(signature_time(&signature)),
+                },
+                None => Value::default(),
+            },
+            Field::Upstream(format) => self.upstream(entry, format)?.into(),
+            Field::Head => {
+                let current = self.head.as_deref() == Some(entry.name.as_str());
+                (if current { "*" } else { " " }).to_string().into()
+            }
+        })
+    }
+
+    /// Show the reference name `name` in `format`.
+    fn format_name(&self, name: &str, format: NameFormat) -> anyhow::Result<String> {
+        Ok(match format {
+            NameFormat::Full => name.to_string(),
+            NameFormat::Short => revision::shorten_ref(name, self.strict)?,
+            NameFormat::Lstrip(n) => name.splitn(n + 1, '/').nth(n).unwrap_or("").to_string(),
+        })
+    }
+
+    /// The upstream reference of the branch `name`: where the fetch refspecs of
+    /// `branch.<name>.remote` store the branch `branch.<name>.merge`.
+    fn upstream_name(&self, name: &str) -> Option<String> {
+        let branch = name.strip_prefix("refs/heads/")?;
+        let remote = self.config.get_string(&format!("branch.{branch}.remote"))?;
+        let merge = self.config.get_string(&format!("branch.{branch}.merge"))?;
+        if remote == "." {
+            return Some(merge.to_string());
+        }
+        let refspecs = self.config.get_all(&format!("remote.{remote}.fetch"));
+        for refspec in refspecs.iter().filter_map(|entry| entry.value.as_deref()) {
+            let Some((source, destination)) = refspec.trim_start_matches('+').split_once(':')
+            else {
+                continue;
+            };
+            if source == merge {
+                return Some(destination.to_string());
+            }
+            let matched = source
+                .strip_suffix('*')
+                .and_then(|prefix| merge.strip_prefix(prefix));
+            if let (Some(matched), Some((before, after))) = (matched, destination.split_once('*')) {
+                return Some(format!("{before}{matched}{after}"));
+            }
+        }
+        None
+    }
+
+    /// The upstream of the branch `entry` in `format`, empty if it has none.
+    fn upstream(&mut self, entry: &Entry, format: UpstreamFormat) -> anyhow::Result<String> {
+        let Some(upstream) = self.upstream_name(&entry.name) else {
+            return Ok(String::new());
+        };
+        let format = match format {
+            UpstreamFormat::Name(format) => return self.format_name(&upstream, format),
+            format => format,
+        };
+        let Some(upstream_hash) = refs::resolve(&upstream)? else {
+            return Ok(match format {
+                UpstreamFormat::Track => "[gone]".to_string(),
+                _ => String::new(),
+            });
+        };
+        let branch = std::slice::from_ref(&entry.object.hash);
+        let upstream = std::slice::from_ref(&upstream_hash);
+        let ahead = self.history.walk(branch, upstream)?.len();
+        let behind = self.history.walk(upstream, branch)?.len();
+        Ok(match (format, ahead, behind) {
+            (UpstreamFormat::Track, 0, 0) => String::new(),
+            (UpstreamFormat::Track, ahead, 0) => format!("[ahead {ahead}]"),
+            (UpstreamFormat::Track, 0, behind) => format!("[behind {behind}]"),
+            (UpstreamFormat::Track, ahead, behind) => format!("[ahead {ahead}, behind {behind}]"),
+            (_, 0, 0) => "=".to_string(),
+            (_, _, 0) => ">".to_string(),
+            (_, 0, _) => "<".to_string(),
+            _ => "<>".to_string(),
+        })
+    }
+}
+
+/// Whether the reference `name` matches one of `patterns`, as a glob or as a prefix up to a `/`.
+fn matches(name: &str, patterns: &[String]) -> bool {
+    patterns.is_empty()
+        || patterns.iter().any(|pattern| {
+            name.strip_prefix(pattern.as_str()).is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with('/') || pattern.ends_with('/')
+            }) || config::wildmatch(pattern.as_bytes(), name.as_bytes())
+        })
+}
+
+/// Resolve the revisions given to an option into object hashes.
+fn resolve_all(specs: &[String]) -> anyhow::Result<Vec<String>> {
+    specs
+        .iter()
+        .map(|spec| {
+            revision::resolve(spec).with_context(|| format!("malformed object name {spec}"))
+        })
+        .collect()
+}
+
+/// The commit `hash` ultimately points to, `None` if it is not a commit.
+fn commit_of(hash: &str) -> anyhow::Result<Option<String>> {
+    let hash = refs::peel(hash)?.unwrap_or_else(|| hash.to_string());
+    let object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+    Ok((object.kind == Kind::Commit).then_some(hash))
+}
+
+/// Invoke the `for-each-ref` command.
+/// See: <https://git-scm.com/docs/git-for-each-ref>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let pieces = parse_format(args.format.as_deref().unwrap_or(DEFAULT_FORMAT))?;
+    let sort_keys = args
+        .sort
+        .iter()
+        .map(|key| SortKey::parse(key))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let points_at = resolve_all(&args.points_at)?;
+    let merged = resolve_all(&args.merged)?;
+    let contains = resolve_all(&args.contains)?;
+
+    let config = Config::load()?;
+    let mut formatter = Formatter {
+        strict: config.get_bool("core.warnAmbiguousRefs")?.unwrap_or(true),
+        config,
+        history: History::default(),
+        head: refs::head_target()?,
+    };
+    let merged_into: HashSet<String> = formatter.history.walk(&merged, &[])?.into_iter().collect();
+
+    let mut entries = Vec::new();
+    for (name, hash) in refs::list()? {
+        if !matches(&name, &args.patterns) {
+            continue;
+        }
+        let object = Loaded::read(&hash)?;
+        let tagged = match object.kind {
+            Kind::Tag => Some(Loaded::read(&Tag::parse(&object.data)?.object)?),
+            _ => None,
+        };
+        let entry = Entry {
+            name,
+            object,
+            tagged,
+        };
+        if !points_at.is_empty()
+            && !points_at.iter().any(|target| {
+                *target == entry.object.hash
+                    || entry
+                        .tagged
+                        .as_ref()
+                        .is_some_and(|tagged| *target == tagged.hash)
+            })
+        {
+            continue;
+        }
+        if !merged.is_empty() || !contains.is_empty() {
+            let Some(commit) = commit_of(&hash)? else {
+                continue;
+            };
+            if !merged.is_empty() && !merged_into.contains(&commit) {
+                continue;
+            }
+            let mut reaches = contains.is_empty();
+            for target in &contains {
+                reaches = reaches || formatter.history.is_ancestor(target, &commit)?;
+            }
+            if !reaches {
+                continue;
+            }
+        }
+        entries.push(entry);
+    }
+
+    // References are listed by name, so applying each key with a stable sort, the last one
+    // given last, makes it take precedence and breaks ties by name.
+    for key in &sort_keys {
+        let mut keyed = Vec::with_capacity(entries.len());
+        for entry in entries.drain(..) {
+            keyed.push((formatter.evaluate(&key.atom, &entry)?, entry));
+        }
+        keyed.sort_by(|(a, _), (b, _)| {
+            let ordering = match (a.number, b.number) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                _ if key.version => version_cmp(&a.text, &b.text),
+                _ => a.text.cmp(&b.text),
+            };
+            if key.reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        entries = keyed.into_iter().map(|(_, entry)| entry).collect();
+    }
+
+    for entry in entries.iter().take(args.count.unwrap_or(usize::MAX)) {
+        let mut line = String::new();
+        for piece in &pieces {
+            match piece {
+                Piece::Literal(text) => line.push_str(text),
+                Piece::Atom(atom) => line.push_str(&formatter.evaluate(atom, entry)?.text),
+            }
+        }
+        println!("{line}");
+    }
+    Ok(())
+}
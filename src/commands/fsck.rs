@@ -0,0 +1,683 @@
+//! The `fsck` command.
+//!
+//! See: <https://git-scm.com/docs/git-fsck>
+use flate2::read::ZlibDecoder;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::commit_graph;
+use crate::hash::{self, Hasher, ObjectId};
+use crate::index::Index;
+use crate::objects::{self, tree_entry_cmp, Kind};
+use crate::objects::{MODE_EXECUTABLE, MODE_FILE, MODE_GITLINK, MODE_SYMLINK, MODE_TREE};
+use crate::pack::{self, midx, Pack};
+use crate::{reflog, refs, revision};
+
+/// Exit status bit for corrupt or malformed objects.
+const ERROR_OBJECT: i32 = 1;
+/// Exit status bit for missing objects and references to them.
+const ERROR_REACHABLE: i32 = 2;
+/// Exit status bit for corrupt packs.
+const ERROR_PACK: i32 = 4;
+/// Exit status bit for a commit-graph that does not match the objects.
+const ERROR_COMMIT_GRAPH: i32 = 16;
+/// Exit status bit for a multi-pack index that does not match the packs.
+const ERROR_MULTI_PACK_INDEX: i32 = 32;
+
+/// The problems a tree can have, in the order they are reported.
+const TREE_PROBLEMS: [(&str, Severity, &str); 10] = [
+    (
+        "nullSha1",
+        Severity::Warning,
+        "contains entries pointing to null sha1",
+    ),
+    ("fullPathname", Severity::Warning, "contains full pathnames"),
+    ("emptyName", Severity::Warning, "contains empty pathname"),
+    ("hasDot", Severity::Warning, "contains '.'"),
+    ("hasDotdot", Severity::Warning, "contains '..'"),
+    ("hasDotgit", Severity::Warning, "contains '.git'"),
+    (
+        "zeroPaddedFilemode",
+        Severity::Warning,
+        "contains zero-padded file modes",
+    ),
+    ("badFilemode", Severity::Info, "contains bad file modes"),
+    (
+        "duplicateEntries",
+        Severity::Error,
+        "contains duplicate file entries",
+    ),
+    ("treeNotSorted", Severity::Error, "not properly sorted"),
+];
+
+/// Arguments of the `fsck` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Print objects that exist but are not reachable from any reference.
+    #[clap(long)]
+    unreachable: bool,
+    /// Do not print unreachable objects no other object points to.
+    #[clap(long)]
+    no_dangling: bool,
+    /// Print root commits.
+    #[clap(long)]
+    root: bool,
+    /// Treat warnings as errors, and the `100664` file mode of old Git versions as bad.
+    #[clap(long)]
+    strict: bool,
+    /// Do not consider commits only referenced by reference logs reachable.
+    #[clap(long)]
+    no_reflogs: bool,
+    /// Only check that reachable objects exist, not their contents.
+    #[clap(long)]
+    connectivity_only: bool,
+    /// Check reachability from these objects instead of the references, index and logs.
+    objects: Vec<String>,
+}
+
+/// How serious a problem found in an object is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    /// Always an error.
+    Error,
+    /// A warning, or an error with `--strict`.
+    Warning,
+    /// Always only a warning.
+    Info,
+}
+
+/// An object found in the object store.
+struct Found {
+    /// The type of the object.
+    kind: Kind,
+    /// The objects it points to, with the type they are expected to have.
+    links: Vec<(Kind, String)>,
+}
+
+/// The state of a check: the objects found so far and the problems reported.
+struct Fsck<'a> {
+    /// The command line arguments.
+    args: &'a Args,
+    /// The readable objects, by hash.
+    objects: BTreeMap<String, Found>,
+    /// The exit status bits of the problems found.
+    errors: i32,
+}
+
+impl Fsck<'_> {
+    /// Report a problem with the contents of the object `hash` of type `kind`.
+    fn report(&mut self, kind: Kind, hash: &str, severity: Severity, id: &str, message: &str) {
+        let strict = self.args.strict && severity == Severity::Warning;
+        if severity == Severity::Error || strict {
+            eprintln!("error in {kind} {hash}: {id}: {message}");
+            self.errors |= ERROR_OBJECT;
+        } else {
+            eprintln!("warning in {kind} {hash}: {id}: {message}");
+        }
+    }
+
+    /// Read, hash and check the loose object `hash`, remembering it if it can be read.
+    ///
+    /// Blobs are hashed as they are inflated, so that big ones are never held in memory.
+    fn check_loose(&mut self, hash: &str) {
+        let path = objects::path(hash);
+        let shown = display_path(&path);
+        let corrupt = |fsck: &mut Self| {
+            eprintln!("error: {hash}: object corrupt or missing: {shown}");
+            fsck.errors |= ERROR_OBJECT;
+        };
+        let mut header = Vec::new();
+        let opened = std::fs::File::open(&path).and_then(|file| {
+            let mut reader = BufReader::new(ZlibDecoder::new(file));
+            reader.read_until(0, &mut header)?;
+            Ok(reader)
+        });
+        let mut reader = match opened {
+            Ok(reader) => reader,
+            Err(e) => {
+                eprintln!("error: inflate: {e}");
+                eprintln!("error: unable to unpack header of {shown}");
+                return corrupt(self);
+            }
+        };
+        let Some((kind, size)) = parse_header(&header) else {
+            eprintln!("error: unable to parse header of {shown}");
+            return corrupt(self);
+        };
+        let mut hasher = Hasher::for_repository();
+        hasher.update(&header);
+        let mut body = Vec::new();
+        let read = if kind == Kind::Blob {
+            std::io::copy(&mut reader, &mut hasher)
+        } else {
+            reader.read_to_end(&mut body).map(|read| {
+                hasher.update(&body);
+                read as u64
+            })
+        };
+        match read {
+            Ok(read) if read == size => {}
+            Ok(_) => {
+                eprintln!("error: unable to parse header of {shown}");
+                return corrupt(self);
+            }
+            Err(e) => {
+                eprintln!("error: inflate: {e}");
+                eprintln!("error: unable to unpack contents of {shown}");
+                return corrupt(self);
+            }
+        }
+        if !self.args.connectivity_only {
+            match hasher.finalize() {
+                Ok(actual) if actual.to_string() != hash => {
+                    eprintln!("error: {actual}: hash-path mismatch, found at: {shown}");
+                    self.errors |= ERROR_OBJECT;
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("error: {e}");
+                    return corrupt(self);
+                }
+            }
+        }
+        self.check_contents(hash, kind, &body);
+    }
+
+    /// Verify the checksums of `pack`, then read, hash and check the objects in it that are not
+    /// also loose.
+    ///
+    /// Blobs are hashed as they are read from the pack, so that big ones are never held in
+    /// memory.
+    fn check_pack(&mut self, pack: &Pack) {
+        let shown = display_path(&pack.path);
+        if !self.args.connectivity_only {
+            if let Err(e) = pack.verify() {
+                eprintln!("error: {e:#}");
+                self.errors |= ERROR_PACK;
+            }
+        }
+        for object in pack.objects() {
+            let (hash, object) = match object {
+                Ok(object) => object,
+                Err(e) => {
+                    eprintln!("error: {e:#}");
+                    self.errors |= ERROR_PACK;
+                    continue;
+                }
+            };
+            if self.objects.contains_key(&hash) {
+                continue;
+            }
+            let kind = object.kind;
+            let (actual, data) = match kind {
+                Kind::Blob if self.args.connectivity_only => (None, Vec::new()),
+                Kind::Blob => (Some(object.hash()), Vec::new()),
+                _ => match object.into_bytes() {
+                    Ok(data) => {
+                        let actual = (!self.args.connectivity_only).then(|| rehash(kind, &data));
+                        (actual, data)
+                    }
+                    Err(e) => (Some(Err(e)), Vec::new()),
+                },
+            };
+            match actual {
+                Some(Ok(actual)) if actual.to_string() != hash => {
+                    eprintln!("error: packed {hash} from {shown} is corrupt");
+                    self.errors |= ERROR_PACK;
+                    continue;
+                }
+                Some(Err(e)) => {
+                    eprintln!("error: {e}");
+                    self.errors |= ERROR_PACK;
+                    continue;
+                }
+                _ => {}
+            }
+            self.check_contents(&hash, kind, &data);
+        }
+    }
+
+    /// Check the contents of the object `hash`, remembering it and what it points to.
+    fn check_contents(&mut self, hash: &str, kind: Kind, body: &[u8]) {
+        let links = match kind {
+            Kind::Blob => Vec::new(),
+            Kind::Tree => self.check_tree(hash, body),
+            Kind::Commit => self.check_commit(hash, body),
+            Kind::Tag => self.check_tag(hash, body),
+        };
+        self.objects.insert(hash.to_string(), Found { kind, links });
+    }
+
+    /// Check the entries of the tree `hash`, returning the objects they point to.
+    fn check_tree(&mut self, hash: &str, mut data: &[u8]) -> Vec<(Kind, String)> {
+        let size = hash::algorithm().size();
+        let mut links = Vec::new();
+        let mut entries: Vec<(&[u8], bool)> = Vec::new();
+        let mut found = HashSet::new();
+        while !data.is_empty() {
+            let entry = data.iter().position(|&b| b == 0).and_then(|nul| {
+                let space = data[..nul].iter().position(|&b| b == b' ')?;
+                let id = data.get(nul + 1..nul + 1 + size)?;
+                Some((&data[..space], &data[space + 1..nul], id, nul + 1 + size))
+            });
+            let Some((mode, name, id, len)) = entry else {
+                let message = "cannot be parsed as a tree";
+                self.report(Kind::Tree, hash, Severity::Error, "badTree", message);
+                return links;
+            };
+            data = &data[len..];
+            if mode.starts_with(b"0") {
+                found.insert("zeroPaddedFilemode");
+            }
+            let mode = std::str::from_utf8(mode)
+                .ok()
+                .and_then(|mode| u32::from_str_radix(mode, 8).ok());
+            let valid = matches!(
+                mode,
+                Some(MODE_FILE | MODE_EXECUTABLE | MODE_SYMLINK | MODE_TREE | MODE_GITLINK)
+            );
+            // Old Git versions wrote group-writable files as such.
+            if !valid && (self.args.strict || mode != Some(0o100664)) {
+                found.insert("badFilemode");
+            }
+            let name_problem = match name {
+                b"" => Some("emptyName"),
+                b"." => Some("hasDot"),
+                b".." => Some("hasDotdot"),
+                _ if name.eq_ignore_ascii_case(b".git") => Some("hasDotgit"),
+                _ if name.contains(&b'/') => Some("fullPathname"),
+                _ => None,
+            };
+            found.extend(name_problem);
+            if id.iter().all(|&b| b == 0) {
+                found.insert("nullSha1");
+            }
+            match mode {
+                Some(MODE_TREE) => links.push((Kind::Tree, hex::encode(id))),
+                Some(MODE_GITLINK) => {}
+                _ => links.push((Kind::Blob, hex::encode(id))),
+            }
+            let is_tree = mode == Some(MODE_TREE);
+            if let Some(&(previous, previous_is_tree)) = entries.last() {
+                if previous == name {
+                    found.insert("duplicateEntries");
+                } else if tree_entry_cmp(previous, previous_is_tree, name, is_tree).is_gt() {
+                    found.insert("treeNotSorted");
+                }
+            }
+            entries.push((name, is_tree));
+        }
+        if found.contains("duplicateEntries") {
+            found.remove("treeNotSorted");
+        }
+        for (id, severity, message) in TREE_PROBLEMS {
+            if found.contains(id) {
+                self.report(Kind::Tree, hash, severity, id, message);
+            }
+        }
+        links
+    }
+
+    /// Check the headers of the commit `hash`, returning the objects it points to.
+    fn check_commit(&mut self, hash: &str, data: &[u8]) -> Vec<(Kind, String)> {
+        let mut links = Vec::new();
+        let result = check_headers(data).and_then(|mut lines| {
+            let tree = lines
+                .next_if(|line| line.starts_with("tree "))
+                .ok_or(("missingTree", "invalid format - expected 'tree' line"))?;
+            let tree = parse_id(&tree["tree ".len()..])
+                .ok_or(("badTreeSha1", "invalid 'tree' line format - bad sha1"))?;
+            links.push((Kind::Tree, tree));
+            while let Some(parent) = lines.next_if(|line| line.starts_with("parent ")) {
+                let parent = parse_id(&parent["parent ".len()..])
+                    .ok_or(("badParentSha1", "invalid 'parent' line format - bad sha1"))?;
+                links.push((Kind::Commit, parent));
+            }
+            let author = lines
+                .next_if(|line| line.starts_with("author "))
+                .ok_or(("missingAuthor", "invalid format - expected 'author' line"))?;
+            check_ident(&author["author ".len()..])?;
+            let committer = lines
+                .next_if(|line| line.starts_with("committer "))
+                .ok_or((
+                    "missingCommitter",
+                    "invalid format - expected 'committer' line",
+                ))?;
+            check_ident(&committer["committer ".len()..])
+        });
+        if let Err((id, message)) = result {
+            self.report(Kind::Commit, hash, Severity::Error, id, message);
+        }
+        if self.args.root && links.len() == 1 {
+            println!("root {hash}");
+        }
+        links
+    }
+
+    /// Check the headers of the annotated tag `hash`, returning the object it points to.
+    fn check_tag(&mut self, hash: &str, data: &[u8]) -> Vec<(Kind, String)> {
+        let mut links = Vec::new();
+        let mut missing_tagger = false;
+        let result = check_headers(data).and_then(|mut lines| {
+            let object = lines
+                .next_if(|line| line.starts_with("object "))
+                .ok_or(("missingObject", "invalid format - expected 'object' line"))?;
+            let object = parse_id(&object["object ".len()..])
+                .ok_or(("badObjectSha1", "invalid 'object' line format - bad sha1"))?;
+            let kind = lines
+                .next_if(|line| line.starts_with("type "))
+                .ok_or(("missingTypeEntry", "invalid format - expected 'type' line"))?;
+            let kind =
+                parse_kind(&kind["type ".len()..]).ok_or(("badType", "invalid 'type' value"))?;
+            links.push((kind, object));
+            lines
+                .next_if(|line| line.starts_with("tag "))
+                .ok_or(("missingTagEntry", "invalid format - expected 'tag' line"))?;
+            match lines.next_if(|line| line.starts_with("tagger ")) {
+                Some(tagger) => check_ident(&tagger["tagger ".len()..]),
+                None => {
+                    missing_tagger = true;
+                    Ok(())
+                }
+            }
+        });
+        if missing_tagger {
+            let message = "invalid format - expected 'tagger' line";
+            self.report(
+                Kind::Tag,
+                hash,
+                Severity::Info,
+                "missingTaggerEntry",
+                message,
+            );
+        }
+        if let Err((id, message)) = result {
+            self.report(Kind::Tag, hash, Severity::Error, id, message);
+        }
+        links
+    }
+
+    /// The objects the references, their logs, `HEAD` and the index point to, checking that
+    /// they exist.
+    fn roots(&mut self) -> anyhow::Result<Vec<(Kind, String)>> {
+        let mut roots = Vec::new();
+        if !self.args.objects.is_empty() {
+            for spec in &self.args.objects {
+                let hash = revision::resolve(spec)?;
+                match self.objects.get(&hash) {
+                    Some(found) => roots.push((found.kind, hash)),
+                    None => {
+                        eprintln!("error: {spec}: object missing");
+                        self.errors |= ERROR_REACHABLE;
+                    }
+                }
+            }
+            return Ok(roots);
+        }
+        let mut names = refs::list()?;
+        match refs::resolve("HEAD")? {
+            Some(hash) => names.push(("HEAD".to_string(), hash)),
+            None => {
+                if let Some(branch) = refs::head_target()? {
+                    let branch = branch.strip_prefix("refs/heads/").unwrap_or(&branch);
+                    eprintln!("notice: HEAD points to an unborn branch ({branch})");
+                }
+            }
+        }
+        if names.is_empty() {
+            eprintln!("notice: No default references");
+        }
+        for (name, hash) in names {
+            match self.objects.get(&hash) {
+                Some(found) => roots.push((found.kind, hash)),
+                None => {
+                    eprintln!("error: {name}: invalid sha1 pointer {hash}");
+                    self.errors |= ERROR_REACHABLE;
+                }
+            }
+        }
+        if !self.args.no_reflogs {
+            for name in reflog::names()? {
+                for entry in reflog::read(&name)? {
+                    for hash in [entry.old, entry.new] {
+                        if hash::is_null(&hash) {
+                            continue;
+                        }
+                        match self.objects.get(&hash) {
+                            Some(found) => roots.push((found.kind, hash)),
+                            None => {
+                                eprintln!("error: {name}: invalid reflog entry {hash}");
+                                self.errors |= ERROR_REACHABLE;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for entry in Index::read()?.entries {
+            if entry.entry.mode != MODE_GITLINK {
+                roots.push((Kind::Blob, entry.entry.hash));
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Walk the objects reachable from the roots, then report the missing, unreachable and
+    /// dangling ones.
+    fn check_connectivity(&mut self) -> anyhow::Result<()> {
+        let mut pending = self.roots()?;
+        let mut reachable = HashSet::new();
+        let mut missing = BTreeMap::new();
+        while let Some((kind, hash)) = pending.pop() {
+            if !reachable.insert(hash.clone()) {
+                continue;
+            }
+            match self.objects.get(&hash) {
+                Some(found) => pending.extend(found.links.iter().cloned()),
+                None => {
+                    missing.insert(hash, kind);
+                }
+            }
+        }
+        let used: HashSet<&str> = self
+            .objects
+            .values()
+            .flat_map(|found| found.links.iter().map(|(_, hash)| hash.as_str()))
+            .collect();
+        let present = self.objects.iter().map(|(hash, found)| (hash, found.kind));
+        let all: BTreeSet<(&String, Kind, bool)> = present
+            .map(|(hash, kind)| (hash, kind, false))
+            .chain(missing.iter().map(|(hash, kind)| (hash, *kind, true)))
+            .collect();
+        for (hash, kind, is_missing) in all {
+            if is_missing {
+                println!("missing {kind} {hash}");
+                self.errors |= ERROR_REACHABLE;
+            } else if reachable.contains(hash) {
+            } else if self.args.unreachable {
+                println!("unreachable {kind} {hash}");
+            } else if !self.args.no_dangling && !used.contains(hash.as_str()) {
+                println!("dangling {kind} {hash}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Invoke the `fsck` command.
+/// See: <https://git-scm.com/docs/git-fsck>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let mut fsck = Fsck {
+        args: &args,
+        objects: BTreeMap::new(),
+        errors: 0,
+    };
+    for hash in objects::loose_objects()? {
+        fsck.check_loose(&hash);
+    }
+    for pack in pack::packs()?.iter() {
+        fsck.check_pack(pack);
+    }
+    fsck.check_connectivity()?;
+    if let Some(graph) = commit_graph::graph()? {
+        if !graph.verify()? {
+            fsck.errors |= ERROR_COMMIT_GRAPH;
+        }
+    }
+    if let Some(midx) = midx::load()? {
+        if !midx.verify()? {
+            fsck.errors |= ERROR_MULTI_PACK_INDEX;
+        }
+    }
+    if fsck.errors != 0 {
+        std::process::exit(fsck.errors);
+    }
+    Ok(())
+}
+
+/// The id of an object of type `kind` with contents `body`.
+fn rehash(kind: Kind, body: &[u8]) -> anyhow::Result<ObjectId> {
+    let mut hasher = Hasher::for_repository();
+    hasher.update(format!("{kind} {}\0", body.len()).as_bytes());
+    hasher.update(body);
+    hasher.finalize()
+}
+
+/// The path of an object file as shown in messages, relative to the working tree if inside it.
+fn display_path(path: &Path) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    path.strip_prefix(&cwd)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+/// Parse the header of an inflated object, up to and including its NUL byte, into its type and
+/// size.
+fn parse_header(header: &[u8]) -> Option<(Kind, u64)> {
+    let header = std::str::from_utf8(header.strip_suffix(&[0])?).ok()?;
+    let (kind, size) = header.split_once(' ')?;
+    Some((parse_kind(kind)?, size.parse().ok()?))
+}
+
+/// The object type called `name`.
+fn parse_kind(name: &str) -> Option<Kind> {
+    match name {
+        "blob" => Some(Kind::Blob),
+        "tree" => Some(Kind::Tree),
+        "commit" => Some(Kind::Commit),
+        "tag" => Some(Kind::Tag),
+        _ => None,
+    }
+}
+
+/// A full lowercase hex object id of the repository's algorithm.
+fn parse_id(hex: &str) -> Option<String> {
+    let valid = hex.len() == hash::algorithm().hex_len()
+        && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    valid.then(|| hex.to_string())
+}
+
+/// A problem found in an object: its message id and message.
+type Problem = (&'static str, &'static str);
+
+/// Check that the headers of a commit or tag are terminated and free of NULs, returning its
+/// header lines.
+fn check_headers(data: &[u8]) -> Result<std::iter::Peekable<std::str::Lines<'_>>, Problem> {
+    let end = data
+        .windows(2)
+        .position(|pair| pair == b"\n\n")
+        .map(|end| end + 1)
+        .or_else(|| data.ends_with(b"\n").then_some(data.len()))
+        .ok_or(("unterminatedHeader", "unterminated header"))?;
+    let headers = &data[..end];
+    if headers.contains(&0) {
+        return Err(("nulInHeader", "unterminated header: NUL in header"));
+    }
+    let headers =
+        std::str::from_utf8(headers).map_err(|_| ("badHeader", "invalid UTF-8 in header"))?;
+    Ok(headers.lines().peekable())
+}
+
+/// Check an identity such as `Name <email> 1700000000 +0000` the way Git does.
+fn check_ident(ident: &str) -> Result<(), Problem> {
+    let ident = ident.as_bytes();
+    let at = |i: usize| ident.get(i).copied().unwrap_or(b'\n');
+    let skip = |mut i: usize| {
+        while !matches!(at(i), b'<' | b'>' | b'\n') {
+            i += 1;
+        }
+        i
+    };
+    if at(0) == b'<' {
+        return Err((
+            "missingNameBeforeEmail",
+            "invalid author/committer line - missing space before email",
+        ));
+    }
+    let mut i = skip(0);
+    if at(i) == b'>' {
+        return Err(("badName", "invalid author/committer line - bad name"));
+    }
+    if at(i) != b'<' {
+        return Err((
+            "missingEmail",
+            "invalid author/committer line - missing email",
+        ));
+    }
+    if at(i - 1) != b' ' {
+        return Err((
+            "missingSpaceBeforeEmail",
+            "invalid author/committer line - missing space before email",
+        ));
+    }
+    i = skip(i + 1);
+    if at(i) != b'>' {
+        return Err(("badEmail", "invalid author/committer line - bad email"));
+    }
+    i += 1;
+    if at(i) != b' ' {
+        return Err((
+            "missingSpaceBeforeDate",
+            "invalid author/committer line - missing space before date",
+        ));
+    }
+    i += 1;
+    if at(i) == b'0' && at(i + 1) != b' ' {
+        return Err((
+            "zeroPaddedDate",
+            "invalid author/committer line - zero-padded date",
+        ));
+    }
+    let digits = ident[i.min(ident.len())..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    if digits == 0 || at(i + digits) != b' ' {
+        return Err(("badDate", "invalid author/committer line - bad date"));
+    }
+    if std::str::from_utf8(&ident[i..i + digits])
+        .ok()
+        .and_then(|date| date.parse::<i64>().ok())
+        .is_none()
+    {
+        return Err((
+            "badDateOverflow",
+            "invalid author/committer line - date causes integer overflow",
+        ));
+    }
+    i += digits + 1;
+    let zone = (i..i + 5).map(at).collect::<Vec<_>>();
+    if !matches!(zone[0], b'+' | b'-')
+        || !zone[1..].iter().all(u8::is_ascii_digit)
+        || at(i + 5) != b'\n'
+    {
+        return Err((
+            "badTimezone",
+            "invalid author/committer line - bad time zone",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! The `gc` command.
+//!
+//! See: <https://git-scm.com/docs/git-gc>
+use crate::commands::prune::{self, now};
+use crate::commands::reflog::{self, expiry, Action};
+use crate::commands::repack::{self, Mode};
+use crate::commit_graph::{self, WriteOptions};
+use crate::config::Config;
+use crate::repository::git_path;
+use crate::{hash, pack, reachable, refs};
+
+/// Arguments of the `gc` command.
+#[derive(clap::Args, Debug, Default)]
+pub(crate) struct Args {
+    /// Only clean up if there are too many loose objects or packs, see `gc.auto` and
+    /// `gc.autoPackLimit`.
+    #[clap(long)]
+    auto: bool,
+    /// Prune unreachable loose objects older than this, see `gc.pruneExpire`.
+    #[clap(
+        long,
+        value_name = "DATE",
+        require_equals = true,
+        conflicts_with = "no_prune"
+    )]
+    prune: Option<String>,
+    /// Do not prune unreachable loose objects.
+    #[clap(long)]
+    no_prune: bool,
+    /// Look harder for deltas, see `gc.aggressiveWindow` and `gc.aggressiveDepth`.
+    #[clap(long)]
+    aggressive: bool,
+    /// Print nothing.
+    #[clap(short = 'q', long)]
+    quiet: bool,
+}
+
+/// Whether there are more loose objects than `gc.auto` allows.
+///
+/// Like Git, this estimates the count from the `objects/17` fan-out directory alone.
+fn too_many_loose_objects(config: &Config) -> anyhow::Result<bool> {
+    let limit = config.get_int("gc.auto")?.unwrap_or(6700);
+    if limit <= 0 {
+        return Ok(false);
+    }
+    let per_directory = (limit + 255) / 256;
+    let Ok(dir) = std::fs::read_dir(git_path("objects/17")) else {
+        return Ok(false);
+    };
+    let rest_len = hash::algorithm().hex_len() - 2;
+    let count = dir
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.len() == rest_len && name.bytes().all(|b| b.is_ascii_hexdigit())
+        })
+        .count();
+    Ok(count as i64 > per_directory)
+}
+
+/// Whether there are more packs than `gc.autoPackLimit` allows, not counting kept packs.
+fn too_many_packs(config: &Config) -> anyhow::Result<bool> {
+    let limit = config.get_int("gc.autoPackLimit")?.unwrap_or(50);
+    if limit <= 0 {
+        return Ok(false);
+    }
+    let count = pack::packs()?
+        .iter()
+        .filter(|pack| !pack.path.with_extension("keep").exists())
+        .count();
+    Ok(count as i64 > limit)
+}
+
+/// Run `gc --auto`, as commands that create objects do when they are done.
+pub(crate) fn auto() -> anyhow::Result<()> {
+    invoke(Args {
+        auto: true,
+        ..Args::default()
+    })
+}
+
+/// Invoke the `gc` command.
+/// See: <https://git-scm.com/docs/git-gc>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let config = Config::load()?;
+    let mut mode = Mode::AllLoosen;
+    if args.auto {
+        if config.get_int("gc.auto")?.unwrap_or(6700) <= 0 {
+            return Ok(());
+        }
+        // Too many loose objects alone only need them packed, not everything repacked.
+        if !too_many_packs(&config)? {
+            if !too_many_loose_objects(&config)? {
+                return Ok(());
+            }
+            mode = Mode::Loose;
+        }
+        if !args.quiet {
+            eprintln!("Auto packing the repository for optimum performance.");
+            eprintln!("See \"git help gc\" for manual housekeeping.");
+        }
+    }
+
+    refs::pack(true, true)?;
+    reflog::invoke(Some(Action::Expire {
+        expire: None,
+        expire_unreachable: None,
+        all: true,
+        dry_run: false,
+        references: Vec::new(),
+    }))?;
+    let (window, depth) = if args.aggressive {
+        let window = config.get_int("gc.aggressiveWindow")?.unwrap_or(250);
+        let depth = config.get_int("gc.aggressiveDepth")?.unwrap_or(50);
+        (usize::try_from(window)?, usize::try_from(depth)?)
+    } else {
+        repack::configured_window()?
+    };
+    // Packing only loose objects cannot keep bitmaps whatever the configuration says.
+    let write_bitmap = (mode == Mode::Loose).then_some(false);
+    repack::repack(mode, true, window, depth, true, write_bitmap)?;
+    if !args.no_prune {
+        let expire = expiry(
+            args.prune.as_deref(),
+            "gc.pruneExpire",
+            "2.weeks.ago",
+            now()?,
+        )?;
+        prune::prune(&reachable::roots(true)?, expire, false, false)?;
+    }
+    if config.get_bool("gc.writeCommitGraph")?.unwrap_or(true) {
+        commit_graph::write(&commit_graph::reachable()?, WriteOptions::default())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! The `init` command.
+//!
+//! See: <https://git-scm.com/docs/git-init>
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, Config};
+use crate::hash::Algorithm;
+use crate::refs;
+use crate::repository::{self, Repository};
+use crate::shared::{self, Shared};
+
+/// The branch `HEAD` of a new repository points to without `--initial-branch` or
+/// `init.defaultBranch`.
+const DEFAULT_BRANCH: &str = "main";
+
+/// Arguments of the `init` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Print only errors and warnings.
+    #[clap(short = 'q', long)]
+    quiet: bool,
+    /// Create a bare repository, without a working tree.
+    #[clap(long)]
+    bare: bool,
+    /// The branch `HEAD` points to, instead of `init.defaultBranch`.
+    #[clap(short = 'b', long, value_name = "BRANCH")]
+    initial_branch: Option<String>,
+    /// Copy the files of this directory into the Git directory, instead of `init.templateDir`.
+    #[clap(long, value_name = "DIR")]
+    template: Option<String>,
+    /// Share the repository among several users: `umask`, `group`, `all` or an octal mode.
+    #[clap(long, value_name = "PERMISSIONS", num_args = 0..=1, require_equals = true, default_missing_value = "group")]
+    shared: Option<String>,
+    /// Put the Git directory here and point to it from a `.git` file in the working tree.
+    #[clap(long, value_name = "GIT_DIR", conflicts_with = "bare")]
+    separate_git_dir: Option<PathBuf>,
+    /// The hash algorithm of the objects: `sha1` or `sha256`.
+    #[clap(long, value_name = "FORMAT")]
+    object_format: Option<String>,
+    /// How to store references: as loose files and `packed-refs`, or in reftables.
+    #[clap(long, value_enum)]
+    ref_format: Option<refs::Format>,
+    /// The directory to create the repository in, instead of the current one.
+    directory: Option<PathBuf>,
+}
+
+/// Copy the files of the template directory `template` into `git_dir`, keeping existing files.
+fn copy_template(template: &Path, git_dir: &Path) -> anyhow::Result<()> {
+    let Ok(entries) = std::fs::read_dir(template) else {
+        eprintln!("warning: templates not found in {}", template.display());
+        return Ok(());
+    };
+    for entry in entries {
+        let entry = entry?;
+        let target = git_dir.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("create {}", target.display()))?;
+            copy_template(&entry.path(), &target)?;
+        } else if !target.exists() {
+            if file_type.is_symlink() {
+                let link = std::fs::read_link(entry.path())?;
+                std::os::unix::fs::symlink(link, &target)
+            } else {
+                std::fs::copy(entry.path(), &target).map(|_| ())
+            }
+            .with_context(|| format!("copy template {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// The template directory: `--template`, `GIT_TEMPLATE_DIR` or `init.templateDir`, relative to
+/// `cwd`. An empty path means no template.
+fn template_dir(args: &Args, config: &Config, cwd: &Path) -> Option<PathBuf> {
+    args.template
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("GIT_TEMPLATE_DIR").map(PathBuf::from))
+        .or_else(|| {
+            config
+                .get_string("init.templateDir")
+                .map(config::expand_home)
+        })
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| cwd.join(dir))
+}
+
+/// Invoke the `init` command.
+/// See: <https://git-scm.com/docs/git-init>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let algorithm = match &args.object_format {
+        Some(name) => Some(Algorithm::from_name(name)?),
+        None => match std::env::var("GIT_DEFAULT_HASH") {
+            Ok(name) => Some(Algorithm::from_name(&name)?),
+            Err(_) => None,
+        },
+    };
+    let config = Config::load()?;
+    let shared = match &args.shared {
+        Some(value) => Some(Shared::parse(value)?),
+        None => None,
+    };
+
+    let cwd = std::env::current_dir().context("get current directory")?;
+    let dir = match &args.directory {
+        Some(directory) => {
+            let dir = cwd.join(directory);
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("cannot mkdir {}", directory.display()))?;
+            dir
+        }
+        None => cwd.clone(),
+    };
+    let work_tree = match std::env::var_os("GIT_WORK_TREE") {
+        Some(work_tree) => Some(cwd.join(work_tree)),
+        None if args.bare => None,
+        None => Some(dir.clone()),
+    };
+    let git_dir = match (&args.separate_git_dir, std::env::var_os("GIT_DIR")) {
+        (Some(separate), _) => cwd.join(separate),
+        (None, Some(git_dir)) => cwd.join(git_dir),
+        (None, None) if args.bare => dir.clone(),
+        (None, None) => dir.join(".git"),
+    };
+
+    if let Some(separate) = &args.separate_git_dir {
+        // Move an existing Git directory out of the working tree when reinitializing.
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() && !git_dir.exists() {
+            std::fs::rename(&dot_git, &git_dir).with_context(|| {
+                format!(
+                    "unable to move {} to {}",
+                    dot_git.display(),
+                    separate.display()
+                )
+            })?;
+        }
+    }
+    std::fs::create_dir_all(&git_dir)
+        .with_context(|| format!("cannot mkdir {}", git_dir.display()))?;
+    let git_dir = std::fs::canonicalize(&git_dir)
+        .with_context(|| format!("resolve {}", git_dir.display()))?;
+    if args.separate_git_dir.is_some() {
+        let dot_git = dir.join(".git");
+        std::fs::write(&dot_git, format!("gitdir: {}\n", git_dir.display()))
+            .with_context(|| format!("write {}", dot_git.display()))?;
+    }
+    let reinit = git_dir.join("HEAD").exists();
+    if let Some(template) = template_dir(&args, &config, &cwd) {
+        copy_template(&template, &git_dir)?;
+    }
+
+    // The object format is fixed when the repository is created, before anything is hashed.
+    let path = git_dir.join("config");
+    let algorithm = if reinit {
+        let existing = Algorithm::of_repository(&git_dir)?;
+        anyhow::ensure!(
+            args.object_format.is_none() || algorithm == Some(existing),
+            "attempt to reinitialize repository with different hash"
+        );
+        existing
+    } else {
+        let algorithm = algorithm.unwrap_or(Algorithm::Sha1);
+        if algorithm != Algorithm::Sha1 {
+            config::set(&path, "extensions.objectFormat", algorithm.name(), false)?;
+        }
+        algorithm
+    };
+    Repository::new(git_dir.clone(), work_tree, &cwd).enter()?;
+
+    for dir in ["objects", "objects/info", "objects/pack", "refs"] {
+        let path = repository::git_path(dir);
+        std::fs::create_dir_all(&path).with_context(|| format!("create {}", path.display()))?;
+    }
+
+    let format = if reinit {
+        let existing = refs::Format::of_repository()?;
+        anyhow::ensure!(
+            args.ref_format.unwrap_or(existing) == existing,
+            "attempt to reinitialize repository with different reference storage format"
+        );
+        if let Some(branch) = &args.initial_branch {
+            eprintln!("warning: re-init: ignored --initial-branch={branch}");
+        }
+        existing
+    } else {
+        args.ref_format.unwrap_or(refs::Format::Files)
+    };
+    // Extensions other than the defaults need version 1, so that older Git refuses the repository.
+    let version = if format == refs::Format::Files && algorithm == Algorithm::Sha1 {
+        "0"
+    } else {
+        "1"
+    };
+    config::set(&path, "core.repositoryformatversion", version, false)?;
+    config::set(&path, "core.filemode", "true", false)?;
+    config::set(
+        &path,
+        "core.bare",
+        if args.bare { "true" } else { "false" },
+        false,
+    )?;
+    if !args.bare
+        && Config::from_file(&path)?
+            .get("core.logallrefupdates")
+            .is_none()
+    {
+        config::set(&path, "core.logallrefupdates", "true", false)?;
+    }
+    if let Some(value) = shared.and_then(Shared::config_value) {
+        config::set(&path, "core.sharedrepository", &value, false)?;
+        if !reinit {
+            config::set(&path, "receive.denyNonFastforwards", "true", false)?;
+        }
+    }
+    if !reinit {
+        let branch = match &args.initial_branch {
+            Some(branch) => branch.clone(),
+            None => config
+                .get_string("init.defaultBranch")
+                .unwrap_or(DEFAULT_BRANCH)
+                .to_string(),
+        };
+        anyhow::ensure!(
+            refs::check_name(&format!("refs/heads/{branch}")).is_ok(),
+            "invalid initial branch name: '{branch}'"
+        );
+        format.init(&format!("refs/heads/{branch}"))?;
+    }
+    if let Some(shared) = shared {
+        shared::adjust_recursively(&git_dir, shared)?;
+    }
+
+    if !args.quiet {
+        let verb = if reinit {
+            "Reinitialized existing"
+        } else {
+            "Initialized empty"
+        };
+        let shared = if shared.is_some_and(|shared| shared != Shared::Umask) {
+            "shared "
+        } else {
+            ""
+        };
+        println!("{verb} {shared}Git repository in {}/", git_dir.display());
+    }
+    Ok(())
+}
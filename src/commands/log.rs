@@ -0,0 +1,221 @@
+//! The `log` command.
+//!
+//! See: <https://git-scm.com/docs/git-log>
+use anyhow::Context;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::io::Write;
+
+use crate::commands::for_each_ref::{format_date, split_subject, DateFormat};
+use crate::commands::merge::short;
+use crate::commands::merge_base::History;
+use crate::commit_graph::{bloom, CommitGraph};
+use crate::objects::{Commit, Tree, TreeEntry, MODE_TREE};
+use crate::revision::{self, Selection};
+use crate::{commit_graph, repository};
+
+/// Arguments of the `log` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Show each commit on one line, as its abbreviated hash and subject.
+    #[clap(long)]
+    oneline: bool,
+    /// Show at most this many commits.
+    #[clap(short = 'n', long)]
+    max_count: Option<usize>,
+    /// The commits whose history is shown, e.g. `main` or `main..topic`; `HEAD` by default.
+    revisions: Vec<String>,
+    /// Only show commits that change these paths.
+    #[clap(last = true)]
+    paths: Vec<String>,
+}
+
+/// The entry at `path` of the tree `tree`, the tree itself for the empty path.
+fn entry_at(tree: &str, path: &str) -> anyhow::Result<Option<TreeEntry>> {
+    let mut entry = TreeEntry {
+        mode: MODE_TREE,
+        hash: tree.to_string(),
+    };
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if !entry.is_tree() {
+            return Ok(None);
+        }
+        let found = Tree::read(&entry.hash)?
+            .entries
+            .into_iter()
+            .find(|(entry_name, _)| entry_name == name);
+        let Some((_, found)) = found else {
+            return Ok(None);
+        };
+        entry = found;
+    }
+    Ok(Some(entry))
+}
+
+/// Whether the trees `old`, `None` for the empty tree, and `new` have the same `paths`.
+fn treesame(old: Option<&str>, new: &str, paths: &[String]) -> anyhow::Result<bool> {
+    for path in paths {
+        let old = old.map(|old| entry_at(old, path)).transpose()?.flatten();
+        if old != entry_at(new, path)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether the commit `hash` may change `paths` compared to its first parent: false if its
+/// changed-path Bloom filter rules out every path and its leading directories.
+fn maybe_changed(graph: Option<&CommitGraph>, hash: &str, paths: &[String]) -> bool {
+    let Some((settings, filter)) = graph.and_then(|graph| graph.filter(hash)) else {
+        return true;
+    };
+    paths.iter().any(|path| {
+        let mut path = path.as_str();
+        if path.is_empty() {
+            return true;
+        }
+        loop {
+            if !bloom::contains(filter, &settings.key(path)) {
+                return false;
+            }
+            match path.rsplit_once('/') {
+                Some((dir, _)) => path = dir,
+                None => return true,
+            }
+        }
+    })
+}
+
+/// Whether the commit `hash` is shown when limiting history to `paths`, and the parents the walk
+/// continues with.
+///
+/// Like Git by default, a commit that has the same `paths` as one of its parents is not shown and
+/// only that parent's history is followed.
+fn simplify(
+    history: &mut History,
+    graph: Option<&CommitGraph>,
+    hash: &str,
+    paths: &[String],
+) -> anyhow::Result<(bool, Vec<String>)> {
+    let tree = history.tree(hash)?;
+    let parents = history.parents(hash)?;
+    if parents.is_empty() {
+        return Ok((!treesame(None, &tree, paths)?, parents));
+    }
+    for (i, parent) in parents.iter().enumerate() {
+        let same = (i == 0 && !maybe_changed(graph, hash, paths))
+            || treesame(Some(&history.tree(parent)?), &tree, paths)?;
+        if same {
+            return Ok((false, vec![parent.clone()]));
+        }
+    }
+    Ok((true, parents))
+}
+
+/// Write the commit `hash` in the default format, or on one line.
+fn show(out: &mut impl Write, hash: &str, oneline: bool, first: bool) -> anyhow::Result<()> {
+    let commit = Commit::read(hash)?;
+    let (subject, _) = split_subject(&commit.message);
+    if oneline {
+        writeln!(out, "{} {subject}", short(hash))?;
+        return Ok(());
+    }
+    if !first {
+        writeln!(out)?;
+    }
+    writeln!(out, "commit {hash}")?;
+    if commit.parents.len() > 1 {
+        let parents: Vec<&str> = commit.parents.iter().map(|p| short(p)).collect();
+        writeln!(out, "Merge: {}", parents.join(" "))?;
+    }
+    let author = commit.author.rsplit_once('>').map_or("", |(name, _)| name);
+    writeln!(out, "Author: {author}>")?;
+    writeln!(
+        out,
+        "Date:   {}",
+        format_date(&commit.author, DateFormat::Default)
+    )?;
+    writeln!(out)?;
+    for line in commit
+        .message
+        .trim_end()
+        .lines()
+        .skip_while(|line| line.trim().is_empty())
+    {
+        writeln!(out, "    {line}")?;
+    }
+    Ok(())
+}
+
+/// Walk the history of `selection` newest first, in the order commits were reached among equal
+/// dates, calling `visit` on at most `max_count` commits that are shown when limiting history to
+/// `paths`.
+pub(crate) fn walk(
+    selection: &Selection,
+    paths: &[String],
+    max_count: Option<usize>,
+    mut visit: impl FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let graph = commit_graph::graph()?;
+    let mut history = History::default();
+    let excluded: HashSet<String> = history.walk(&selection.exclude, &[])?.into_iter().collect();
+    let mut queue = BinaryHeap::new();
+    let mut seen = HashSet::new();
+    let mut reached = 0;
+    for hash in &selection.include {
+        if seen.insert(hash.clone()) {
+            queue.push((history.priority(hash)?, Reverse(reached), hash.clone()));
+            reached += 1;
+        }
+    }
+
+    let mut shown = 0;
+    while let Some((_, _, hash)) = queue.pop() {
+        if max_count.is_some_and(|max| shown >= max) {
+            break;
+        }
+        if excluded.contains(&hash) {
+            continue;
+        }
+        let (visible, parents) = if paths.is_empty() {
+            (true, history.parents(&hash)?)
+        } else {
+            simplify(&mut history, graph.as_deref(), &hash, paths)?
+        };
+        for parent in parents {
+            if seen.insert(parent.clone()) {
+                queue.push((history.priority(&parent)?, Reverse(reached), parent));
+                reached += 1;
+            }
+        }
+        if visible {
+            visit(&hash)?;
+            shown += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Invoke the `log` command.
+/// See: <https://git-scm.com/docs/git-log>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let revisions = if args.revisions.is_empty() {
+        vec!["HEAD".to_string()]
+    } else {
+        args.revisions
+    };
+    let selection = revision::resolve_selection(&revisions)?;
+    let paths = args
+        .paths
+        .iter()
+        .map(|path| repository::pathspec(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut out = std::io::stdout().lock();
+    let mut first = true;
+    walk(&selection, &paths, args.max_count, |hash| {
+        show(&mut out, hash, args.oneline, first).with_context(|| format!("show commit {hash}"))?;
+        first = false;
+        Ok(())
+    })
+}
@@ -1,6 +1,7 @@
 //! The `ls-tree` command.
 //!
 //! See <https://git-scm.com/docs/git-ls-tree>
+use crate::hash;
 use crate::objects::{Kind, Object};
 use anyhow::Context;
 use std::{
@@ -14,7 +15,7 @@ pub(crate) fn invoke(name_only: bool, tree_sha: &str) -> anyhow::Result<()> {
     match object.kind {
         Kind::Tree => {
             let mut buf = Vec::new();
-            let mut hash_buf = [0; 20];
+            let mut hash_buf = vec![0; hash::algorithm().size()];
             let mut stdout = std::io::stdout().lock();
             loop {
                 buf.clear();
@@ -45,7 +46,7 @@ pub(crate) fn invoke(name_only: bool, tree_sha: &str) -> anyhow::Result<()> {
                     writeln!(stdout).context("write newline to stdout")?;
                 } else {
                     let mode = std::str::from_utf8(mode).context("mode is always valid UTF-8")?;
-                    let hash = hex::encode(hash_buf);
+                    let hash = hex::encode(&hash_buf);
                     let object = Object::read(&hash)
                         .with_context(|| format!("read object for tree entry {hash}"))?;
                     write!(stdout, "{mode:0>6} {} {hash}\t", object.kind)
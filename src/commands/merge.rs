@@ -0,0 +1,509 @@
+//! The `merge` command.
+//!
+//! See: <https://git-scm.com/docs/git-merge>
+use anyhow::Context;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::commands::commit_tree;
+use crate::commands::merge_base::History;
+use crate::index::Index;
+use crate::merge::{self, Labels, MergeOptions, TreeMerge};
+use crate::objects::{Commit, FlatTree, Tree};
+use crate::repository::git_path;
+use crate::{refs, revision, sequencer, worktree};
+
+/// The commits being merged into `HEAD` by an in-progress merge, one per line.
+pub(crate) const MERGE_HEAD: &str = "MERGE_HEAD";
+/// The message prepared for the commit concluding an in-progress merge.
+pub(crate) const MERGE_MSG: &str = "MERGE_MSG";
+/// Options of an in-progress merge (`no-ff`).
+const MERGE_MODE: &str = "MERGE_MODE";
+/// The message prepared for the commit concluding a `--squash` merge.
+const SQUASH_MSG: &str = "SQUASH_MSG";
+
+/// The commits recorded in `MERGE_HEAD`, if a merge is in progress.
+pub(crate) fn merge_heads() -> anyhow::Result<Vec<String>> {
+    if !git_path(MERGE_HEAD).exists() {
+        return Ok(Vec::new());
+    }
+    let contents = std::fs::read_to_string(git_path(MERGE_HEAD)).context("read .git/MERGE_HEAD")?;
+    Ok(contents.lines().map(|l| l.trim().to_string()).collect())
+}
+
+/// The message prepared by an in-progress (or squashed) merge, if any.
+pub(crate) fn read_state_message() -> anyhow::Result<Option<String>> {
+    for path in [git_path(MERGE_MSG), git_path(SQUASH_MSG)] {
+        if path.exists() {
+            return Ok(Some(
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("read {}", path.display()))?,
+            ));
+        }
+    }
+    Ok(None)
+}
+
+/// Remove the state files of an in-progress merge, including the merges done by `cherry-pick`
+/// and `revert`.
+pub(crate) fn clear_state() -> anyhow::Result<()> {
+    for name in [
+        MERGE_HEAD,
+        MERGE_MSG,
+        MERGE_MODE,
+        SQUASH_MSG,
+        sequencer::CHERRY_PICK_HEAD,
+        sequencer::REVERT_HEAD,
+    ] {
+        let path = git_path(name);
+        if path.exists() {
+            std::fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Abbreviate an object hash for display.
+pub(crate) fn short(hash: &str) -> &str {
+    &hash[..7.min(hash.len())]
+}
+
+/// Compute the merge base tree of `bases`, merging multiple (criss-cross) bases into a virtual
+/// common ancestor first.
+fn virtual_base(
+    history: &mut History,
+    bases: &[String],
+    options: &MergeOptions,
+) -> anyhow::Result<FlatTree> {
+    let Some((first, rest)) = bases.split_first() else {
+        return Ok(FlatTree::new());
+    };
+    let mut merged = Tree::read_flat(&Commit::read(first)?.tree)?;
+    let mut merged_commits = vec![first.clone()];
+    for next in rest {
+        let inner_bases = history.merge_bases(next, &merged_commits)?;
+        let inner = virtual_base(history, &inner_bases, options)?;
+        let labels = Labels {
+            base: "merged common ancestors".to_string(),
+            ours: "Temporary merge branch 1".to_string(),
+            theirs: "Temporary merge branch 2".to_string(),
+        };
+        let next_tree = Tree::read_flat(&Commit::read(next)?.tree)?;
+        merged = merge::merge_trees(&inner, &merged, &next_tree, &labels, options)?.result;
+        merged_commits.push(next.clone());
+    }
+    Ok(merged)
+}
+
+/// Merge strategies, see `git merge -s <strategy>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Strategy {
+    /// Three-way merge of two heads, merging multiple common ancestors into a virtual one.
+    #[value(alias = "ort")]
+    Recursive,
+    /// Merge more than two heads, refusing merges that need manual resolution.
+    Octopus,
+    /// Keep the tree of the current branch, ignoring all changes from the other heads.
+    Ours,
+    /// Recursive merge with their tree shifted to match a subdirectory of ours.
+    Subtree,
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strategy::Recursive => write!(f, "recursive"),
+            Strategy::Octopus => write!(f, "octopus"),
+            Strategy::Ours => write!(f, "ours"),
+            Strategy::Subtree => write!(f, "subtree"),
+        }
+    }
+}
+
+/// Prefix every path of `tree` with the directory `prefix`.
+fn shift_tree(tree: FlatTree, prefix: &str) -> FlatTree {
+    if prefix.is_empty() {
+        return tree;
+    }
+    tree.into_iter()
+        .map(|(path, entry)| (format!("{prefix}/{path}"), entry))
+        .collect()
+}
+
+/// How well `tree` matches the subdirectory `prefix` of `ours`.
+fn subtree_score(ours: &FlatTree, tree: &FlatTree, prefix: &str) -> i64 {
+    let mut score = 0;
+    for (path, entry) in tree {
+        let shifted = if prefix.is_empty() {
+            path.clone()
+        } else {
+            format!("{prefix}/{path}")
+        };
+        score += match ours.get(&shifted) {
+            Some(ours) if ours == entry => 3,
+            Some(_) => 1,
+            None => -1,
+        };
+    }
+    let dir = format!("{prefix}/");
+    score -= ours
+        .keys()
+        .filter(|path| prefix.is_empty() || path.starts_with(&dir))
+        .filter(|path| {
+            let relative = if prefix.is_empty() {
+                path.as_str()
+            } else {
+                &path[dir.len()..]
+            };
+            !tree.contains_key(relative)
+        })
+        .count() as i64;
+    score
+}
+
+/// Find the directory of `ours` that best matches `tree`, or the empty string if `tree` is best
+/// merged without shifting it.
+fn detect_subtree(ours: &FlatTree, tree: &FlatTree) -> String {
+    let mut dirs: Vec<&str> = ours
+        .keys()
+        .flat_map(|path| path.match_indices('/').map(|(i, _)| &path[..i]))
+        .collect();
+    dirs.sort_unstable();
+    dirs.dedup();
+    let mut best = (subtree_score(ours, tree, ""), String::new());
+    for dir in dirs {
+        let score = subtree_score(ours, tree, dir);
+        if score > best.0 {
+            best = (score, dir.to_string());
+        }
+    }
+    best.1
+}
+
+/// Merge the trees of the commits `ours` and `theirs`.
+pub(crate) fn merge_commits(
+    history: &mut History,
+    ours: &str,
+    theirs: &str,
+    ours_label: &str,
+    theirs_label: &str,
+    options: &MergeOptions,
+) -> anyhow::Result<TreeMerge> {
+    let bases = history.merge_bases(ours, &[theirs.to_string()])?;
+    let base_tree = virtual_base(history, &bases, options)?;
+    let labels = Labels {
+        base: match bases.as_slice() {
+            [base] => short(base).to_string(),
+            _ => "merged common ancestors".to_string(),
+        },
+        ours: ours_label.to_string(),
+        theirs: theirs_label.to_string(),
+    };
+    let ours_tree = Tree::read_flat(&Commit::read(ours)?.tree)?;
+    let mut theirs_tree = Tree::read_flat(&Commit::read(theirs)?.tree)?;
+    let mut base_tree = base_tree;
+    if let Some(prefix) = &options.subtree {
+        let prefix = if prefix.is_empty() {
+            detect_subtree(&ours_tree, &theirs_tree)
+        } else {
+            prefix.clone()
+        };
+        theirs_tree = shift_tree(theirs_tree, &prefix);
+        // The merge base may already be shaped like our tree, e.g. after earlier subtree merges
+        // with unrelated histories.
+        if subtree_score(&ours_tree, &base_tree, &prefix)
+            > subtree_score(&ours_tree, &base_tree, "")
+        {
+            base_tree = shift_tree(base_tree, &prefix);
+        }
+    }
+    merge::merge_trees(&base_tree, &ours_tree, &theirs_tree, &labels, options)
+}
+
+/// Merge several heads into `HEAD` one after another, failing if any of them conflicts.
+fn octopus(
+    history: &mut History,
+    head: &str,
+    head_tree: &FlatTree,
+    heads: &[(String, String)],
+    options: &MergeOptions,
+) -> anyhow::Result<TreeMerge> {
+    let mut result = head_tree.clone();
+    let mut merged = vec![head.to_string()];
+    let mut auto_merged = Vec::new();
+    for (spec, hash) in heads {
+        println!("Trying simple merge with {spec}");
+        let bases = history.merge_bases(hash, &merged)?;
+        let base_tree = virtual_base(history, &bases, options)?;
+        let labels = Labels {
+            base: "merged common ancestors".to_string(),
+            ours: "HEAD".to_string(),
+            theirs: spec.clone(),
+        };
+        let theirs_tree = Tree::read_flat(&Commit::read(hash)?.tree)?;
+        let merge = merge::merge_trees(&base_tree, &result, &theirs_tree, &labels, options)?;
+        if !merge.conflicts.is_empty() {
+            for conflict in &merge.conflicts {
+                println!("{}", conflict.message);
+            }
+            anyhow::bail!(
+                "Automated merge did not work.\n\
+                 Should not be doing an octopus.\n\
+                 Merge with strategy octopus failed."
+            );
+        }
+        auto_merged.extend(merge.auto_merged);
+        result = merge.result;
+        merged.push(hash.clone());
+    }
+    Ok(TreeMerge {
+        result,
+        conflicts: Vec::new(),
+        auto_merged,
+    })
+}
+
+/// Join names as `'a'`, `'a' and 'b'` or `'a', 'b' and 'c'`.
+fn join_names(names: &[&str]) -> String {
+    let quoted: Vec<String> = names.iter().map(|name| format!("'{name}'")).collect();
+    match quoted.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {last}", rest.join(", ")),
+        _ => quoted.concat(),
+    }
+}
+
+/// The default message of a merge commit merging `heads`, e.g.
+/// `Merge branches 'a' and 'b'; commit 'c'`.
+fn merge_message(heads: &[(String, String)]) -> anyhow::Result<String> {
+    let mut branches = Vec::new();
+    let mut commits = Vec::new();
+    for (spec, _) in heads {
+        if refs::resolve(&format!("refs/heads/{spec}"))?.is_some() {
+            branches.push(spec.as_str());
+        } else {
+            commits.push(spec.as_str());
+        }
+    }
+    let mut groups = Vec::new();
+    for (names, singular, plural) in [
+        (&branches, "branch", "branches"),
+        (&commits, "commit", "commits"),
+    ] {
+        if !names.is_empty() {
+            let kind = if names.len() == 1 { singular } else { plural };
+            groups.push(format!("{kind} {}", join_names(names)));
+        }
+    }
+    let mut message = format!("Merge {}", groups.join("; "));
+    if let Some(branch) = refs::head_target()? {
+        let branch = branch.strip_prefix("refs/heads/").unwrap_or(&branch);
+        if branch != "main" && branch != "master" {
+            write!(message, " into {branch}")?;
+        }
+    }
+    Ok(message)
+}
+
+/// The commit message template of a squashed merge, listing the squashed commits.
+fn squash_message(history: &mut History, head: &str, theirs: &str) -> anyhow::Result<String> {
+    let mut excluded = HashSet::new();
+    let mut stack = vec![head.to_string()];
+    while let Some(hash) = stack.pop() {
+        if excluded.insert(hash.clone()) {
+            stack.extend(history.parents(&hash)?);
+        }
+    }
+
+    let mut message = String::from("Squashed commit of the following:\n");
+    let mut seen = HashSet::new();
+    let mut stack = vec![theirs.to_string()];
+    while let Some(hash) = stack.pop() {
+        if excluded.contains(&hash) || !seen.insert(hash.clone()) {
+            continue;
+        }
+        let commit = Commit::read(&hash)?;
+        let author = commit
+            .author
+            .rsplit_once('>')
+            .map_or(commit.author.as_str(), |(who, _)| who);
+        write!(message, "\ncommit {hash}\nAuthor: {author}>\n\n")?;
+        for line in commit.message.lines() {
+            writeln!(message, "    {line}")?;
+        }
+        stack.extend(commit.parents.iter().rev().cloned());
+    }
+    Ok(message)
+}
+
+/// Print the conflicts of a merge and exit with a failure status.
+fn report_conflicts(merge: &TreeMerge) -> ! {
+    for conflict in &merge.conflicts {
+        println!("{}", conflict.message);
+    }
+    println!("Automatic merge failed; fix conflicts and then commit the result.");
+    std::process::exit(1);
+}
+
+/// Abort an in-progress merge, restoring the working tree and index to `HEAD`.
+fn abort() -> anyhow::Result<()> {
+    anyhow::ensure!(
+        git_path(MERGE_HEAD).exists(),
+        "There is no merge to abort (MERGE_HEAD missing)."
+    );
+    let head = refs::resolve("HEAD")?.context("HEAD does not point to a commit")?;
+    worktree::reset_hard(&Tree::read_flat(&Commit::read(&head)?.tree)?)?;
+    clear_state()
+}
+
+/// Invoke the `merge` command.
+/// See: <https://git-scm.com/docs/git-merge>
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn invoke(
+    commits: &[String],
+    message: Option<String>,
+    strategy: Option<Strategy>,
+    strategy_options: &[String],
+    no_ff: bool,
+    ff_only: bool,
+    squash: bool,
+    abort_merge: bool,
+) -> anyhow::Result<()> {
+    if abort_merge {
+        return abort();
+    }
+    if git_path(MERGE_HEAD).exists() {
+        anyhow::bail!(
+            "You have not concluded your merge (MERGE_HEAD exists).\n\
+             Please, commit your changes before you merge."
+        );
+    }
+
+    let head = refs::resolve("HEAD")?.context("cannot merge into an unborn branch")?;
+    let mut heads = Vec::with_capacity(commits.len());
+    for spec in commits {
+        let hash = revision::resolve(spec)
+            .with_context(|| format!("{spec} - not something we can merge"))?;
+        heads.push((spec.clone(), hash));
+    }
+    let strategy = strategy.unwrap_or(if heads.len() > 1 {
+        Strategy::Octopus
+    } else {
+        Strategy::Recursive
+    });
+    if heads.len() > 1 && matches!(strategy, Strategy::Recursive | Strategy::Subtree) {
+        anyhow::bail!("the '{strategy}' strategy can only merge a single head");
+    }
+
+    let mut history = History::default();
+    let mut remaining = Vec::with_capacity(heads.len());
+    for (spec, hash) in heads {
+        if !history.is_ancestor(&hash, &head)? {
+            remaining.push((spec, hash));
+        }
+    }
+    let heads = remaining;
+    if heads.is_empty() {
+        println!("Already up to date.");
+        return Ok(());
+    }
+    let head_tree = Tree::read_flat(&Commit::read(&head)?.tree)?;
+
+    if let [(_, theirs)] = heads.as_slice() {
+        if !no_ff && !squash && history.is_ancestor(&head, theirs)? {
+            let target = Tree::read_flat(&Commit::read(theirs)?.tree)?;
+            let overwritten = worktree::overwritten_paths(&head_tree, &target)?;
+            if !overwritten.is_empty() {
+                anyhow::bail!(
+                    "Your local changes to the following files would be overwritten by merge:\n\t{}",
+                    overwritten.join("\n\t")
+                );
+            }
+            println!("Updating {}..{}", short(&head), short(theirs));
+            println!("Fast-forward");
+            worktree::checkout(&head_tree, &target)?;
+            Index::from_tree(&target).write()?;
+            refs::write("ORIG_HEAD", &head)?;
+            refs::update_head(
+                theirs,
+                &format!("merge {}: Fast-forward", commits.join(" ")),
+            )?;
+            return Ok(());
+        }
+    }
+    if ff_only {
+        anyhow::bail!("Not possible to fast-forward, aborting.");
+    }
+
+    let mut options = MergeOptions::new(strategy_options)?;
+    if strategy == Strategy::Subtree && options.subtree.is_none() {
+        options.subtree = Some(String::new());
+    }
+    let merge = match strategy {
+        Strategy::Ours => TreeMerge {
+            result: head_tree.clone(),
+            conflicts: Vec::new(),
+            auto_merged: Vec::new(),
+        },
+        Strategy::Octopus => octopus(&mut history, &head, &head_tree, &heads, &options)?,
+        Strategy::Recursive | Strategy::Subtree => {
+            let (spec, theirs) = &heads[0];
+            merge_commits(&mut history, &head, theirs, "HEAD", spec, &options)?
+        }
+    };
+    let overwritten = worktree::overwritten_paths(&head_tree, &merge.result)?;
+    if !overwritten.is_empty() {
+        anyhow::bail!(
+            "Your local changes to the following files would be overwritten by merge:\n\t{}",
+            overwritten.join("\n\t")
+        );
+    }
+    for path in &merge.auto_merged {
+        println!("Auto-merging {path}");
+    }
+    worktree::checkout(&head_tree, &merge.result)?;
+    Index::from_merge(&merge.result, &merge.conflicts).write()?;
+    refs::write("ORIG_HEAD", &head)?;
+
+    if squash {
+        let mut squash_msg = String::new();
+        for (_, theirs) in &heads {
+            squash_msg.push_str(&squash_message(&mut history, &head, theirs)?);
+        }
+        std::fs::write(git_path(SQUASH_MSG), squash_msg).context("write .git/SQUASH_MSG")?;
+        println!("Squash commit -- not updating HEAD");
+        if !merge.conflicts.is_empty() {
+            report_conflicts(&merge);
+        }
+        return Ok(());
+    }
+
+    let message = match message {
+        Some(message) => message,
+        None => merge_message(&heads)?,
+    };
+    if !merge.conflicts.is_empty() {
+        let mut merge_msg = format!("{message}\n\n# Conflicts:\n");
+        for conflict in &merge.conflicts {
+            writeln!(merge_msg, "#\t{}", conflict.path)?;
+        }
+        let merge_head: String = heads.iter().map(|(_, hash)| format!("{hash}\n")).collect();
+        std::fs::write(git_path(MERGE_HEAD), merge_head).context("write .git/MERGE_HEAD")?;
+        std::fs::write(git_path(MERGE_MSG), merge_msg).context("write .git/MERGE_MSG")?;
+        std::fs::write(git_path(MERGE_MODE), if no_ff { "no-ff" } else { "" })
+            .context("write .git/MERGE_MODE")?;
+        report_conflicts(&merge);
+    }
+
+    let tree_hash = hex::encode(Tree::write_flat(&merge.result)?);
+    let mut parents = vec![head.as_str()];
+    parents.extend(heads.iter().map(|(_, hash)| hash.as_str()));
+    let commit_hash =
+        commit_tree::write_commit(&message, &tree_hash, &parents).context("create merge commit")?;
+    let made_by = format!("Merge made by the '{strategy}' strategy.");
+    refs::update_head(
+        &hex::encode(commit_hash),
+        &format!("merge {}: {made_by}", commits.join(" ")),
+    )?;
+    println!("{made_by}");
+    Ok(())
+}
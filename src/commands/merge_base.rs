@@ -0,0 +1,311 @@
+//! The `merge-base` command.
+//!
+//! See: <https://git-scm.com/docs/git-merge-base>
+use anyhow::Context;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::commit_graph::{self, GENERATION_INFINITY};
+use crate::objects::Commit;
+use crate::revision;
+
+/// The commit is reachable from the first commit.
+const PARENT1: u8 = 1 << 0;
+/// The commit is reachable from one of the other commits.
+const PARENT2: u8 = 1 << 1;
+/// The commit is reachable from a common ancestor that was already found.
+const STALE: u8 = 1 << 2;
+/// The commit has been recorded as a merge base.
+const RESULT: u8 = 1 << 3;
+
+/// Cached commit metadata needed to walk the commit graph.
+struct Node {
+    tree: String,
+    parents: Vec<String>,
+    date: i64,
+    /// The generation from the commit-graph file, [`GENERATION_INFINITY`] if it is not there.
+    generation: u64,
+}
+
+/// A lazily loaded view of the commit graph, read from the commit-graph file where possible.
+#[derive(Default)]
+pub(crate) struct History {
+    nodes: HashMap<String, Node>,
+}
+
+impl History {
+    /// Load (and cache) the commit with the given hash.
+    fn node(&mut self, hash: &str) -> anyhow::Result<&Node> {
+        if !self.nodes.contains_key(hash) {
+            let graph = commit_graph::graph()?;
+            let node = match graph.map(|graph| graph.lookup(hash)).transpose()?.flatten() {
+                Some(commit) => Node {
+                    tree: commit.tree,
+                    parents: commit.parents,
+                    date: commit.date,
+                    generation: commit.generation,
+                },
+                None => {
+                    let commit = Commit::read(hash)?;
+                    Node {
+                        date: commit.committer_time(),
+                        tree: commit.tree,
+                        parents: commit.parents,
+                        generation: GENERATION_INFINITY,
+                    }
+                }
+            };
+            self.nodes.insert(hash.to_string(), node);
+        }
+        Ok(&self.nodes[hash])
+    }
+
+    /// The root tree of a commit.
+    pub(crate) fn tree(&mut self, hash: &str) -> anyhow::Result<String> {
+        Ok(self.node(hash)?.tree.clone())
+    }
+
+    /// The parents of a commit.
+    pub(crate) fn parents(&mut self, hash: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self.node(hash)?.parents.clone())
+    }
+
+    /// The walk priority of a commit: newer commits are visited first.
+    pub(crate) fn priority(&mut self, hash: &str) -> anyhow::Result<i64> {
+        Ok(self.node(hash)?.date)
+    }
+
+    /// The generation of a commit: commits can only reach commits of lower generations.
+    fn generation(&mut self, hash: &str) -> anyhow::Result<u64> {
+        Ok(self.node(hash)?.generation)
+    }
+
+    /// Walk down from `one` and `twos` at the same time, painting every commit with the side(s)
+    /// it is reachable from, and collect the commits reachable from both sides that are not
+    /// reachable from another such commit.
+    ///
+    /// The result may contain redundant commits (ancestors of other results) in criss-cross
+    /// histories; see [`History::remove_redundant`].
+    ///
+    /// See: <https://github.com/git/git/blob/v2.50.0/commit-reach.c#L52>
+    fn paint_down_to_common(&mut self, one: &str, twos: &[String]) -> anyhow::Result<Vec<String>> {
+        let mut flags: HashMap<String, u8> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let mut result = Vec::new();
+
+        *flags.entry(one.to_string()).or_default() |= PARENT1;
+        queue.push((self.generation(one)?, self.priority(one)?, one.to_string()));
+        for two in twos {
+            if two == one {
+                return Ok(vec![one.to_string()]);
+            }
+            *flags.entry(two.clone()).or_default() |= PARENT2;
+            queue.push((self.generation(two)?, self.priority(two)?, two.clone()));
+        }
+
+        while queue.iter().any(|(_, _, hash)| flags[hash] & STALE == 0) {
+            let (_, _, hash) = queue.pop().expect("queue is not empty");
+            let mut paint = flags[&hash] & (PARENT1 | PARENT2 | STALE);
+            if paint == PARENT1 | PARENT2 {
+                let f = flags.get_mut(&hash).expect("queued commits have flags");
+                if *f & RESULT == 0 {
+                    *f |= RESULT;
+                    result.push(hash.clone());
+                }
+                // Everything below a merge base is not interesting anymore.
+                paint |= STALE;
+            }
+            for parent in self.parents(&hash)? {
+                let f = flags.entry(parent.clone()).or_default();
+                if *f & paint == paint {
+                    continue;
+                }
+                *f |= paint;
+                queue.push((self.generation(&parent)?, self.priority(&parent)?, parent));
+            }
+        }
+
+        // A result may have been reached later from another result; drop those.
+        result.retain(|hash| flags[hash] & STALE == 0);
+        Ok(result)
+    }
+
+    /// Whether `ancestor` is reachable from `descendant` (every commit is its own ancestor).
+    pub(crate) fn is_ancestor(&mut self, ancestor: &str, descendant: &str) -> anyhow::Result<bool> {
+        if ancestor == descendant {
+            return Ok(true);
+        }
+        // Commits of a lower generation than the ancestor cannot reach it.
+        let min_generation = self.generation(ancestor)?;
+        let mut seen = HashSet::new();
+        let mut stack = vec![descendant.to_string()];
+        while let Some(hash) = stack.pop() {
+            if hash == ancestor {
+                return Ok(true);
+            }
+            if !seen.insert(hash.clone()) {
+                continue;
+            }
+            for parent in self.parents(&hash)? {
+                if self.generation(&parent)? >= min_generation {
+                    stack.push(parent);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Remove every commit from `commits` that is an ancestor of another commit in the list.
+    pub(crate) fn remove_redundant(&mut self, commits: &[String]) -> anyhow::Result<Vec<String>> {
+        let mut unique: Vec<String> = Vec::new();
+        for commit in commits {
+            if !unique.contains(commit) {
+                unique.push(commit.clone());
+            }
+        }
+        let mut result = Vec::new();
+        for (i, candidate) in unique.iter().enumerate() {
+            let mut redundant = false;
+            for (j, other) in unique.iter().enumerate() {
+                if i != j && self.is_ancestor(candidate, other)? {
+                    redundant = true;
+                    break;
+                }
+            }
+            if !redundant {
+                result.push(candidate.clone());
+            }
+        }
+        Ok(result)
+    }
+
+    /// Compute the best common ancestors of `one` and all of `twos`, as if `twos` were merged
+    /// into a single commit first.
+    ///
+    /// The result is sorted with the most recent commit first.
+    pub(crate) fn merge_bases(
+        &mut self,
+        one: &str,
+        twos: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        let result = self.paint_down_to_common(one, twos)?;
+        let mut result = if result.len() > 1 {
+            self.remove_redundant(&result)?
+        } else {
+            result
+        };
+        let mut dated = Vec::with_capacity(result.len());
+        for hash in result.drain(..) {
+            dated.push((self.priority(&hash)?, hash));
+        }
+        dated.sort_by(|a, b| b.cmp(a));
+        Ok(dated.into_iter().map(|(_, hash)| hash).collect())
+    }
+
+    /// List the commits reachable from `include` but not from `exclude`, most recent first.
+    pub(crate) fn walk(
+        &mut self,
+        include: &[String],
+        exclude: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        let mut excluded = HashSet::new();
+        let mut stack = exclude.to_vec();
+        while let Some(hash) = stack.pop() {
+            if excluded.insert(hash.clone()) {
+                stack.extend(self.parents(&hash)?);
+            }
+        }
+
+        let mut result = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = BinaryHeap::new();
+        for hash in include {
+            if seen.insert(hash.clone()) {
+                queue.push((self.priority(hash)?, hash.clone()));
+            }
+        }
+        while let Some((_, hash)) = queue.pop() {
+            if excluded.contains(&hash) {
+                continue;
+            }
+            for parent in self.parents(&hash)? {
+                if seen.insert(parent.clone()) {
+                    queue.push((self.priority(&parent)?, parent));
+                }
+            }
+            result.push(hash);
+        }
+        Ok(result)
+    }
+
+    /// Compute the merge bases needed for an n-way (octopus) merge of `commits`.
+    pub(crate) fn octopus_merge_bases(
+        &mut self,
+        commits: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        let mut result: Vec<String> = Vec::new();
+        for commit in commits {
+            if result.is_empty() {
+                result.push(commit.clone());
+                continue;
+            }
+            let mut next = Vec::new();
+            for base in &result {
+                for found in self.merge_bases(base, std::slice::from_ref(commit))? {
+                    if !next.contains(&found) {
+                        next.push(found);
+                    }
+                }
+            }
+            result = next;
+        }
+        Ok(result)
+    }
+}
+
+/// Invoke the `merge-base` command.
+/// See: <https://git-scm.com/docs/git-merge-base>
+pub(crate) fn invoke(
+    all: bool,
+    octopus: bool,
+    independent: bool,
+    is_ancestor: bool,
+    commits: &[String],
+) -> anyhow::Result<()> {
+    let commits = commits
+        .iter()
+        .map(|spec| {
+            revision::resolve(spec).with_context(|| format!("not a valid object name {spec}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut history = History::default();
+
+    if is_ancestor {
+        anyhow::ensure!(
+            commits.len() == 2,
+            "--is-ancestor takes exactly two commits"
+        );
+        if !history.is_ancestor(&commits[0], &commits[1])? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let bases = if independent {
+        history.remove_redundant(&commits)?
+    } else if octopus {
+        let bases = history.octopus_merge_bases(&commits)?;
+        history.remove_redundant(&bases)?
+    } else {
+        anyhow::ensure!(commits.len() >= 2, "merge-base needs at least two commits");
+        history.merge_bases(&commits[0], &commits[1..])?
+    };
+
+    if bases.is_empty() {
+        std::process::exit(1);
+    }
+    let show_all = all || independent;
+    for base in bases.iter().take(if show_all { bases.len() } else { 1 }) {
+        println!("{base}");
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! The `multi-pack-index` command.
+//!
+//! See: <https://git-scm.com/docs/git-multi-pack-index>
+use clap::Subcommand;
+
+use crate::commands::repack::configured_window;
+use crate::pack::midx;
+
+/// `multi-pack-index` subcommands.
+#[derive(Subcommand, Debug)]
+pub(crate) enum Action {
+    /// Write a multi-pack index covering every pack.
+    Write {
+        /// List objects in several packs for this pack.
+        #[clap(long, value_name = "PACK")]
+        preferred_pack: Option<String>,
+    },
+    /// Check the multi-pack index against the packs.
+    Verify,
+    /// Delete the packs none of whose objects the multi-pack index lists for them.
+    Expire,
+    /// Write the objects of the oldest packs to a new pack, leaving the old ones for `expire`.
+    Repack {
+        /// Only repack packs adding up to about this many bytes, e.g. `2g`; all packs if 0.
+        #[clap(long, value_name = "SIZE", value_parser = parse_size, default_value = "0")]
+        batch_size: u64,
+    },
+}
+
+/// Parse a size in bytes, scaled by 1024, 1024² or 1024³ with a `k`, `m` or `g` suffix.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (number, factor) = match size.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('g') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(factor))
+        .ok_or_else(|| {
+            "expects a non-negative integer value with an optional k/m/g suffix".to_string()
+        })
+}
+
+/// Invoke the `multi-pack-index` command.
+/// See: <https://git-scm.com/docs/git-multi-pack-index>
+pub(crate) fn invoke(action: Action) -> anyhow::Result<()> {
+    match action {
+        Action::Write { preferred_pack } => midx::write(preferred_pack.as_deref()),
+        Action::Verify => {
+            if let Some(midx) = midx::load()? {
+                if !midx.verify()? {
+                    std::process::exit(1);
+                }
+            }
+            Ok(())
+        }
+        Action::Expire => midx::expire(),
+        Action::Repack { batch_size } => {
+            let (window, depth) = configured_window()?;
+            midx::repack(batch_size, window, depth)
+        }
+    }
+}
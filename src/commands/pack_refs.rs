@@ -0,0 +1,10 @@
+//! The `pack-refs` command.
+//!
+//! See: <https://git-scm.com/docs/git-pack-refs>
+use crate::refs;
+
+/// Invoke the `pack-refs` command.
+/// See: <https://git-scm.com/docs/git-pack-refs>
+pub(crate) fn invoke(all: bool, no_prune: bool) -> anyhow::Result<()> {
+    refs::pack(all, !no_prune)
+}
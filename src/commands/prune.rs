@@ -0,0 +1,123 @@
+//! The `prune` command.
+//!
+//! See: <https://git-scm.com/docs/git-prune>
+use anyhow::Context;
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::commands::reflog::parse_expiry;
+use crate::objects::{self, Object};
+use crate::{pack, reachable, revision};
+
+/// Arguments of the `prune` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Only print the objects that would be removed.
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+    /// Print the removed objects.
+    #[clap(short = 'v', long)]
+    verbose: bool,
+    /// Only remove objects older than this, e.g. `2.weeks.ago`.
+    #[clap(long)]
+    expire: Option<String>,
+    /// Also keep the objects reachable from these.
+    heads: Vec<String>,
+}
+
+/// The current time as a timestamp.
+pub(crate) fn now() -> anyhow::Result<i64> {
+    Ok(i64::try_from(
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    )?)
+}
+
+/// Whether the loose object `hash` was last modified before `expire`.
+fn expired(hash: &str, expire: i64) -> anyhow::Result<bool> {
+    let modified = objects::path(hash)
+        .metadata()
+        .and_then(|meta| meta.modified())
+        .with_context(|| format!("read the modification time of {hash}"))?;
+    let modified = modified.duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(i64::try_from(modified)? < expire)
+}
+
+/// Remove the loose objects that are neither reachable from `roots` nor modified since `expire`,
+/// `None` for never, and then the loose objects that are also in a pack.
+///
+/// Unreachable objects that are kept because they are recent also keep what they point to.
+pub(crate) fn prune(
+    roots: &[String],
+    expire: Option<i64>,
+    dry_run: bool,
+    verbose: bool,
+) -> anyhow::Result<()> {
+    if let Some(expire) = expire {
+        let mut keep: HashSet<String> = reachable::walk(roots, false)?
+            .into_iter()
+            .map(|reached| reached.hash)
+            .collect();
+        let loose = objects::loose_objects()?;
+        let mut recent = Vec::new();
+        for hash in &loose {
+            if !keep.contains(hash) && !expired(hash, expire)? {
+                recent.push(hash.clone());
+            }
+        }
+        keep.extend(
+            reachable::walk(&recent, true)?
+                .into_iter()
+                .map(|reached| reached.hash),
+        );
+        for hash in loose.iter().filter(|hash| !keep.contains(*hash)) {
+            if dry_run || verbose {
+                let kind = Object::read(hash).map_or("unknown".to_string(), |o| o.kind.to_string());
+                println!("{hash} {kind}");
+            }
+            if !dry_run {
+                remove(hash)?;
+            }
+        }
+    }
+    prune_packed(dry_run)
+}
+
+/// Remove the loose objects that are also in a pack.
+pub(crate) fn prune_packed(dry_run: bool) -> anyhow::Result<()> {
+    for hash in objects::loose_objects()? {
+        if !pack::contains(&hash)? {
+            continue;
+        }
+        if dry_run {
+            println!("rm -f {}", objects::path(&hash).display());
+        } else {
+            remove(&hash)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the loose object `hash`, and its fan-out directory once it is empty.
+fn remove(hash: &str) -> anyhow::Result<()> {
+    let path = objects::path(hash);
+    std::fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+    if let Some(dir) = path.parent() {
+        // This fails while other objects remain, which is fine.
+        let _ = std::fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+/// Invoke the `prune` command.
+/// See: <https://git-scm.com/docs/git-prune>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let expire = match &args.expire {
+        Some(expire) => parse_expiry(expire, now()?)?,
+        None => Some(i64::MAX),
+    };
+    let mut roots = reachable::roots(true)?;
+    for head in &args.heads {
+        roots.push(revision::resolve(head)?);
+    }
+    prune(&roots, expire, args.dry_run, args.verbose)
+}
@@ -0,0 +1,354 @@
+//! The `rebase` command.
+//!
+//! See: <https://git-scm.com/docs/git-rebase>
+use anyhow::Context;
+use std::collections::{HashMap, HashSet};
+
+use crate::commands::merge::short;
+use crate::commands::merge_base::History;
+use crate::index::Index;
+use crate::objects::{Commit, FlatTree, Tree, TreeEntry};
+use crate::rebase::{self, Command, Instruction};
+use crate::repository::git_path;
+use crate::{refs, revision, worktree};
+
+/// The changes a commit makes to its first parent, per path.
+type Changes = Vec<(String, Option<TreeEntry>, Option<TreeEntry>)>;
+
+/// The changes `commit` makes to its first parent, used to recognize commits that are already
+/// upstream.
+fn changes(commit: &Commit) -> anyhow::Result<Changes> {
+    let old = match commit.parents.first() {
+        Some(parent) => Tree::read_flat(&Commit::read(parent)?.tree)?,
+        None => FlatTree::new(),
+    };
+    let new = Tree::read_flat(&commit.tree)?;
+    let mut changes = Vec::new();
+    for path in old
+        .keys()
+        .chain(new.keys().filter(|p| !old.contains_key(*p)))
+    {
+        let (a, b) = (old.get(path), new.get(path));
+        if a != b {
+            changes.push((path.clone(), a.cloned(), b.cloned()));
+        }
+    }
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(changes)
+}
+
+/// Pick the commits of `head` that are not in `upstream`, oldest first, leaving out merges and
+/// commits whose changes are already upstream.
+fn linear_todo(
+    history: &mut History,
+    head: &str,
+    upstream: &str,
+) -> anyhow::Result<Vec<Instruction>> {
+    let mut upstream_changes = HashSet::new();
+    for hash in history.walk(&[upstream.to_string()], &[head.to_string()])? {
+        let commit = Commit::read(&hash)?;
+        if commit.parents.len() <= 1 {
+            upstream_changes.insert(changes(&commit)?);
+        }
+    }
+
+    let mut todo = Vec::new();
+    for hash in history
+        .walk(&[head.to_string()], &[upstream.to_string()])?
+        .into_iter()
+        .rev()
+    {
+        let commit = Commit::read(&hash)?;
+        if commit.parents.len() > 1 {
+            continue;
+        }
+        if upstream_changes.contains(&changes(&commit)?) {
+            eprintln!(
+                "warning: skipped previously applied commit {}",
+                short(&hash)
+            );
+            continue;
+        }
+        todo.push(Instruction::new(Command::Pick, &hash));
+    }
+    Ok(todo)
+}
+
+/// The branch name in the subject of a merge commit such as `Merge branch 'topic' into main`.
+fn merged_branch(subject: &str) -> Option<&str> {
+    let rest = subject
+        .strip_prefix("Merge branch '")
+        .or_else(|| subject.strip_prefix("Merge remote-tracking branch '"))?;
+    rest.split_once('\'').map(|(name, _)| name)
+}
+
+/// Recreate the topology of the commits of `head` that are not in `upstream`, using `label`,
+/// `reset` and `merge` instructions for the branches and merges between them.
+fn merges_todo(
+    history: &mut History,
+    head: &str,
+    upstream: &str,
+) -> anyhow::Result<Vec<Instruction>> {
+    let in_range: HashSet<String> = history
+        .walk(&[head.to_string()], &[upstream.to_string()])?
+        .into_iter()
+        .collect();
+
+    // Parents before children; second parents are visited first, so that side branches come
+    // before the merges into the first-parent line.
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![(head.to_string(), false)];
+    while let Some((hash, expanded)) = stack.pop() {
+        if expanded {
+            order.push(hash);
+            continue;
+        }
+        if !in_range.contains(&hash) || !visited.insert(hash.clone()) {
+            continue;
+        }
+        stack.push((hash.clone(), true));
+        for parent in history.parents(&hash)? {
+            stack.push((parent, false));
+        }
+    }
+
+    // Name the commits that later instructions need to refer back to.
+    let mut labels: HashMap<String, String> = HashMap::new();
+    let mut used = HashSet::from(["onto".to_string()]);
+    let mut name = |labels: &mut HashMap<String, String>, hash: &str, base: &str| {
+        if labels.contains_key(hash) {
+            return;
+        }
+        let base: String = base
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        let mut label = base.clone();
+        let mut n = 2;
+        while !used.insert(label.clone()) {
+            label = format!("{base}-{n}");
+            n += 1;
+        }
+        labels.insert(hash.to_string(), label);
+    };
+    let mut current: Option<&String> = None;
+    for hash in &order {
+        let commit = Commit::read(hash)?;
+        if let Some(second) = commit.parents.get(1).filter(|p| in_range.contains(*p)) {
+            let branch = merged_branch(commit.summary()).unwrap_or("branch");
+            name(&mut labels, second, branch);
+        }
+        let first = commit.parents.first().filter(|p| in_range.contains(*p));
+        if let Some(first) = first.filter(|_| first != current) {
+            name(&mut labels, first, "branch-point");
+        }
+        current = Some(hash);
+    }
+
+    let mut todo = vec![Instruction {
+        command: Command::Label,
+        commit: None,
+        arg: "onto".to_string(),
+    }];
+    let mut current: Option<&String> = None;
+    for hash in &order {
+        let commit = Commit::read(hash)?;
+        let first = commit.parents.first().filter(|p| in_range.contains(*p));
+        if first != current {
+            todo.push(Instruction {
+                command: Command::Reset,
+                commit: None,
+                arg: match first {
+                    Some(first) => labels[first].clone(),
+                    None => "onto".to_string(),
+                },
+            });
+        }
+        match commit.parents.get(1) {
+            Some(second) => todo.push(Instruction {
+                command: Command::Merge,
+                commit: Some(hash.clone()),
+                arg: match labels.get(second) {
+                    Some(label) => label.clone(),
+                    None => short(second).to_string(),
+                },
+            }),
+            None => todo.push(Instruction::new(Command::Pick, hash)),
+        }
+        if let Some(label) = labels.get(hash) {
+            todo.push(Instruction {
+                command: Command::Label,
+                commit: None,
+                arg: label.clone(),
+            });
+        }
+        current = Some(hash);
+    }
+    Ok(todo)
+}
+
+/// Move `fixup!`, `squash!` and `amend!` commits right after the commits they refer to, turning
+/// them into `fixup` and `squash` instructions.
+fn autosquash(todo: Vec<Instruction>) -> anyhow::Result<Vec<Instruction>> {
+    let mut subjects: Vec<(usize, String, String)> = Vec::new();
+    let mut attached: Vec<Vec<usize>> = vec![Vec::new(); todo.len()];
+    let mut moved = vec![false; todo.len()];
+    let mut commands = vec![None; todo.len()];
+
+    for (i, instruction) in todo.iter().enumerate() {
+        let (Command::Pick, Some(hash)) = (instruction.command, &instruction.commit) else {
+            continue;
+        };
+        let subject = Commit::read(hash)?.summary().to_string();
+        let mut target = subject.as_str();
+        let mut command = None;
+        loop {
+            if let Some(rest) = target.strip_prefix("fixup! ") {
+                command = command.or(Some(Command::Fixup));
+                target = rest;
+            } else if let Some(rest) = target.strip_prefix("amend! ") {
+                command = command.or(Some(Command::Fixup));
+                target = rest;
+            } else if let Some(rest) = target.strip_prefix("squash! ") {
+                command = command.or(Some(Command::Squash));
+                target = rest;
+            } else {
+                break;
+            }
+        }
+        if let Some(command) = command {
+            let found = subjects
+                .iter()
+                .find(|(_, s, _)| s == target)
+                .or_else(|| {
+                    subjects.iter().find(|(_, _, h)| {
+                        target.len() >= 4
+                            && target.bytes().all(|b| b.is_ascii_hexdigit())
+                            && h.starts_with(target)
+                    })
+                })
+                .or_else(|| subjects.iter().find(|(_, s, _)| s.starts_with(target)));
+            if let Some(&(j, _, _)) = found {
+                attached[j].push(i);
+                moved[i] = true;
+                commands[i] = Some(command);
+                continue;
+            }
+        }
+        subjects.push((i, subject, hash.clone()));
+    }
+
+    let mut result = Vec::with_capacity(todo.len());
+    for (i, instruction) in todo.iter().enumerate() {
+        if moved[i] {
+            continue;
+        }
+        result.push(instruction.clone());
+        for &j in &attached[i] {
+            let mut fixup = todo[j].clone();
+            fixup.command = commands[j].context("fixup without command")?;
+            result.push(fixup);
+        }
+    }
+    Ok(result)
+}
+
+/// Switch to `branch` before rebasing it.
+fn switch_branch(branch: &str) -> anyhow::Result<()> {
+    let name = format!("refs/heads/{branch}");
+    let target = refs::resolve(&name)?.with_context(|| format!("no such branch: {branch}"))?;
+    let head = refs::resolve("HEAD")?.context("HEAD does not point to a commit")?;
+    let from = Tree::read_flat(&Commit::read(&head)?.tree)?;
+    let to = Tree::read_flat(&Commit::read(&target)?.tree)?;
+    let overwritten = worktree::overwritten_paths(&from, &to)?;
+    if !overwritten.is_empty() {
+        anyhow::bail!(
+            "Your local changes to the following files would be overwritten by checkout:\n\t{}",
+            overwritten.join("\n\t")
+        );
+    }
+    worktree::checkout(&from, &to)?;
+    Index::from_tree(&to).write()?;
+    let from = refs::head_target()?.unwrap_or(head);
+    let from = from.strip_prefix("refs/heads/").unwrap_or(&from);
+    refs::set_head(&name, &format!("checkout: moving from {from} to {branch}"))
+}
+
+/// Invoke the `rebase` command.
+/// See: <https://git-scm.com/docs/git-rebase>
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn invoke(
+    upstream: Option<&str>,
+    branch: Option<&str>,
+    onto: Option<&str>,
+    interactive: bool,
+    autosquash_fixups: bool,
+    rebase_merges: bool,
+    resume: bool,
+    skip: bool,
+    abort: bool,
+) -> anyhow::Result<()> {
+    if resume {
+        return rebase::resume();
+    }
+    if skip {
+        return rebase::skip();
+    }
+    if abort {
+        return rebase::abort();
+    }
+    if git_path(rebase::STATE_DIR).exists() {
+        anyhow::bail!(
+            "It seems that there is already a rebase-merge directory.\n\
+             Use \"git rebase (--continue | --abort | --skip)\" to go on."
+        );
+    }
+
+    let upstream = upstream.context("no upstream configured; please specify the upstream")?;
+    let onto_name = onto.unwrap_or(upstream);
+    let upstream =
+        revision::resolve(upstream).with_context(|| format!("invalid upstream '{upstream}'"))?;
+    let onto = match onto {
+        Some(onto) => revision::resolve(onto)
+            .with_context(|| format!("Does not point to a valid commit '{onto}'"))?,
+        None => upstream.clone(),
+    };
+    if let Some(branch) = branch {
+        switch_branch(branch)?;
+    }
+
+    let head = refs::resolve("HEAD")?.context("cannot rebase an unborn branch")?;
+    let head_name = refs::head_target()?.unwrap_or_else(|| "detached HEAD".to_string());
+    let modified = worktree::modified_paths(&Tree::read_flat(&Commit::read(&head)?.tree)?)?;
+    if !modified.is_empty() {
+        anyhow::bail!("cannot rebase: You have unstaged changes.\nPlease commit or stash them.");
+    }
+
+    let mut history = History::default();
+    let fork_point = history.merge_bases(&head, std::slice::from_ref(&upstream))?;
+    if !interactive
+        && history.is_ancestor(&onto, &head)?
+        && (upstream == onto || fork_point.first() == Some(&onto))
+    {
+        let name = head_name.strip_prefix("refs/heads/").unwrap_or(&head_name);
+        println!("Current branch {name} is up to date.");
+        return Ok(());
+    }
+
+    let mut todo = if rebase_merges {
+        merges_todo(&mut history, &head, &upstream)?
+    } else {
+        linear_todo(&mut history, &head, &upstream)?
+    };
+    if autosquash_fixups {
+        todo = autosquash(todo)?;
+    }
+    rebase::start(&head_name, &head, &onto, onto_name, &todo, interactive)
+}
@@ -0,0 +1,243 @@
+//! The `reflog` command.
+//!
+//! See: <https://git-scm.com/docs/git-reflog>
+use anyhow::Context;
+use chrono::NaiveDate;
+use clap::Subcommand;
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::commands::merge::short;
+use crate::commands::merge_base::History;
+use crate::{config, hash, reflog, refs, revision};
+
+/// `reflog` subcommands.
+#[derive(Subcommand, Debug)]
+pub(crate) enum Action {
+    /// Show the log of a reference, most recent update first.
+    Show {
+        /// The reference, defaults to `HEAD`.
+        reference: Option<String>,
+    },
+    /// Remove old entries from reference logs.
+    Expire {
+        /// Remove entries older than this, e.g. `90.days.ago`, `now` or `never`.
+        #[clap(long)]
+        expire: Option<String>,
+        /// Remove entries older than this that are not reachable from the current tip.
+        #[clap(long)]
+        expire_unreachable: Option<String>,
+        /// Process the logs of all references.
+        #[clap(long)]
+        all: bool,
+        /// Only show which entries would be removed.
+        #[clap(short = 'n', long)]
+        dry_run: bool,
+        /// The references whose logs are processed.
+        references: Vec<String>,
+    },
+    /// Remove single entries from reference logs, e.g. `main@{2}`.
+    Delete {
+        /// Point the reference at the value of the most recent remaining entry.
+        #[clap(long)]
+        updateref: bool,
+        /// Make each remaining entry start where the previous one ends.
+        #[clap(long)]
+        rewrite: bool,
+        /// Only show which entries would be removed.
+        #[clap(short = 'n', long)]
+        dry_run: bool,
+        /// The entries to remove.
+        #[clap(required = true)]
+        entries: Vec<String>,
+    },
+    /// Check whether a reference has a log.
+    Exists {
+        /// The reference.
+        reference: String,
+    },
+}
+
+/// The full name of the reference `name` names, which must have a log.
+fn log_name(name: &str) -> anyhow::Result<String> {
+    revision::expand_ref(name)?
+        .filter(|full| reflog::exists(full))
+        .with_context(|| format!("reflog could not be found: '{name}'"))
+}
+
+/// Parse an expiry date such as `90.days.ago`, `2.weeks`, `2024-01-31`, `now` or `never` into a
+/// timestamp before which entries expire, `None` for never.
+pub(crate) fn parse_expiry(value: &str, now: i64) -> anyhow::Result<Option<i64>> {
+    let invalid = || format!("'{value}' is not a valid expiry date");
+    match value {
+        "never" | "false" => return Ok(None),
+        "now" | "all" => return Ok(Some(i64::MAX)),
+        _ => {}
+    }
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Ok(Some(timestamp));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let time = date.and_hms_opt(0, 0, 0).with_context(invalid)?;
+        return Ok(Some(time.and_utc().timestamp()));
+    }
+
+    let mut words = value.split(['.', ' ']).filter(|word| !word.is_empty());
+    let count: i64 = words
+        .next()
+        .and_then(|n| n.parse().ok())
+        .with_context(invalid)?;
+    let unit = words.next().with_context(invalid)?;
+    anyhow::ensure!(matches!(words.next(), None | Some("ago")), invalid());
+    let seconds = match unit.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => anyhow::bail!(invalid()),
+    };
+    Ok(Some(now - count * seconds))
+}
+
+/// The expiry date from the command line, else the configuration `key`, else `default`.
+pub(crate) fn expiry(option: Option<&str>, key: &str, default: &str, now: i64) -> anyhow::Result<Option<i64>> {
+    let configured = config::get(key)?;
+    parse_expiry(option.or(configured.as_deref()).unwrap_or(default), now)
+}
+
+/// Remove the entries of the log of `name` that have expired.
+fn expire_log(
+    history: &mut History,
+    name: &str,
+    expire: Option<i64>,
+    expire_unreachable: Option<i64>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    // Like Git, entries of `HEAD` are reachable if any reference reaches them.
+    let tips: Vec<String> = if name == "HEAD" {
+        refs::list()?.into_iter().map(|(_, hash)| hash).collect()
+    } else {
+        refs::resolve(name)?.into_iter().collect()
+    };
+    let reachable: HashSet<String> = history.walk(&tips, &[])?.into_iter().collect();
+    let mut kept = Vec::new();
+    for entry in reflog::read(name)? {
+        let timestamp = entry.timestamp();
+        let expired = expire.is_some_and(|expire| timestamp < expire)
+            || (expire_unreachable.is_some_and(|expire| timestamp < expire)
+                && [&entry.old, &entry.new].iter().any(|hash| {
+                    !hash::is_null(hash) && !reachable.contains(*hash)
+                }));
+        if !expired {
+            kept.push(entry);
+        } else if dry_run {
+            println!("would prune {}", entry.message);
+        }
+    }
+    if !dry_run {
+        reflog::write(name, &kept)?;
+    }
+    Ok(())
+}
+
+/// Remove the entry `spec`, e.g. `main@{2}`, from its log.
+fn delete(spec: &str, updateref: bool, rewrite: bool, dry_run: bool) -> anyhow::Result<()> {
+    let (name, n) = spec
+        .strip_suffix('}')
+        .and_then(|spec| spec.rsplit_once("@{"))
+        .with_context(|| format!("not a reflog: {spec}"))?;
+    let n: usize = n
+        .parse()
+        .with_context(|| format!("invalid reflog entry: {spec}"))?;
+    let name = log_name(if name.is_empty() { "HEAD" } else { name })?;
+
+    let mut entries = reflog::read(&name)?;
+    // Entries are numbered from the most recent one.
+    let index = entries
+        .len()
+        .checked_sub(n + 1)
+        .with_context(|| format!("reflog entry {spec} not found"))?;
+    let removed = entries.remove(index);
+    if dry_run {
+        println!("would prune {}", removed.message);
+        return Ok(());
+    }
+    if rewrite && index > 0 && index < entries.len() {
+        entries[index].old = entries[index - 1].new.clone();
+    }
+    reflog::write(&name, &entries)?;
+    if updateref {
+        if let Some(latest) = entries.last() {
+            refs::write(&name, &latest.new)?;
+        }
+    }
+    Ok(())
+}
+
+/// Invoke the `reflog` command.
+/// See: <https://git-scm.com/docs/git-reflog>
+pub(crate) fn invoke(action: Option<Action>) -> anyhow::Result<()> {
+    match action.unwrap_or(Action::Show { reference: None }) {
+        Action::Show { reference } => {
+            let name = reference.as_deref().unwrap_or("HEAD");
+            let full = match revision::expand_ref(name)? {
+                Some(full) => full,
+                None => anyhow::bail!(
+                    "ambiguous argument '{name}': unknown revision or path not in the working tree."
+                ),
+            };
+            for (n, entry) in reflog::read(&full)?.iter().rev().enumerate() {
+                println!("{} {name}@{{{n}}}: {}", short(&entry.new), entry.message);
+            }
+        }
+        Action::Expire {
+            expire,
+            expire_unreachable,
+            all,
+            dry_run,
+            references,
+        } => {
+            let now = i64::try_from(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())?;
+            let expire = expiry(expire.as_deref(), "gc.reflogExpire", "90.days.ago", now)?;
+            let expire_unreachable = expiry(
+                expire_unreachable.as_deref(),
+                "gc.reflogExpireUnreachable",
+                "30.days.ago",
+                now,
+            )?;
+            let names = if all {
+                reflog::names()?
+            } else {
+                references
+                    .iter()
+                    .map(|name| log_name(name))
+                    .collect::<anyhow::Result<_>>()?
+            };
+            anyhow::ensure!(all || !names.is_empty(), "no reflog specified to expire");
+            let mut history = History::default();
+            for name in names {
+                expire_log(&mut history, &name, expire, expire_unreachable, dry_run)?;
+            }
+        }
+        Action::Delete {
+            updateref,
+            rewrite,
+            dry_run,
+            entries,
+        } => {
+            // Like Git, later entries are numbered after earlier ones are removed.
+            for spec in &entries {
+                delete(spec, updateref, rewrite, dry_run)?;
+            }
+        }
+        Action::Exists { reference } => {
+            if !reflog::exists(&reference) {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+//! The `repack` command.
+//!
+//! See: <https://git-scm.com/docs/git-repack>
+use anyhow::Context;
+use std::collections::HashSet;
+
+use crate::commands::prune::prune_packed;
+use crate::config::Config;
+use crate::objects::{self, Object};
+use crate::pack::{self, bitmap, midx, Pack, PackObject};
+use crate::{reachable, repository};
+
+/// Arguments of the `repack` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Pack every reachable object into a single pack, not only the loose ones.
+    #[clap(short = 'a')]
+    all: bool,
+    /// Like `-a`, but unreachable objects in the packs `-d` removes are made loose instead of lost.
+    #[clap(short = 'A', conflicts_with = "all")]
+    all_loosen: bool,
+    /// Remove the packs and loose objects made redundant by the new pack.
+    #[clap(short = 'd')]
+    delete: bool,
+    /// Print nothing.
+    #[clap(short = 'q', long)]
+    quiet: bool,
+    /// How many objects each object is tried as a delta against, see `pack.window`.
+    #[clap(long)]
+    window: Option<usize>,
+    /// The longest chain of deltas, see `pack.depth`.
+    #[clap(long)]
+    depth: Option<usize>,
+    /// Write a multi-pack index covering the packs left afterwards.
+    #[clap(short = 'm', long)]
+    write_midx: bool,
+    /// Write reachability bitmaps for the new pack, which requires `-a` or `-A`; by default only
+    /// done for those in a bare repository, see `repack.writeBitmaps`.
+    #[clap(short = 'b', long, overrides_with = "no_write_bitmap_index")]
+    write_bitmap_index: bool,
+    /// Do not write reachability bitmaps, even if `repack.writeBitmaps` is set.
+    #[clap(long)]
+    no_write_bitmap_index: bool,
+}
+
+/// Which objects a repack writes to the new pack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    /// Only reachable loose objects.
+    Loose,
+    /// All reachable objects.
+    All,
+    /// All reachable objects, and unreachable objects in deleted packs are made loose.
+    AllLoosen,
+}
+
+/// The delta window and depth from the configuration `pack.window` and `pack.depth`.
+pub(crate) fn configured_window() -> anyhow::Result<(usize, usize)> {
+    let config = Config::load()?;
+    let window = config.get_int("pack.window")?.unwrap_or(10);
+    let depth = config.get_int("pack.depth")?.unwrap_or(50);
+    Ok((usize::try_from(window)?, usize::try_from(depth)?))
+}
+
+/// Write the objects selected by `mode` to a new pack, and with `delete` remove what it makes
+/// redundant: loose objects it contains, and the older packs unless `mode` is [`Mode::Loose`].
+///
+/// `write_bitmap` tells whether to write reachability bitmaps for the new pack, which only a pack
+/// of all reachable objects can have; `None` follows `repack.writeBitmaps`, which defaults to
+/// writing them when repacking everything in a bare repository.
+///
+/// Removing a pack the multi-pack index covers removes the index too.
+pub(crate) fn repack(
+    mode: Mode,
+    delete: bool,
+    window: usize,
+    depth: usize,
+    quiet: bool,
+    write_bitmap: Option<bool>,
+) -> anyhow::Result<()> {
+    let write_bitmap = match write_bitmap {
+        Some(write) => write,
+        None => Config::load()?
+            .get_bool("repack.writeBitmaps")?
+            .unwrap_or_else(|| {
+                mode != Mode::Loose && repository::get().is_some_and(|r| r.work_tree.is_none())
+            }),
+    };
+    anyhow::ensure!(
+        !write_bitmap || mode != Mode::Loose,
+        "Incremental repacks are incompatible with bitmap indexes.  Use\n\
+         --no-write-bitmap-index or disable the pack.writeBitmaps configuration."
+    );
+    let reached = reachable::walk(&reachable::roots(true)?, false)?;
+    let old = pack::packs()?;
+    let mut objects = Vec::new();
+    for reached in &reached {
+        if mode == Mode::Loose && pack::contains(&reached.hash)? {
+            continue;
+        }
+        objects.push(PackObject::read(
+            reached.hash.clone(),
+            Object::read(&reached.hash)?,
+            pack::name_hash(&reached.name),
+        )?);
+    }
+    let new = if objects.is_empty() {
+        if !quiet {
+            println!("Nothing new to pack.");
+        }
+        None
+    } else {
+        let path = pack::write(&objects, window, depth)?;
+        if write_bitmap {
+            bitmap::write(&Pack::open(&path.with_extension("idx"))?, &objects)?;
+        }
+        Some(path)
+    };
+    if !delete {
+        return Ok(());
+    }
+
+    if mode != Mode::Loose {
+        let reachable: HashSet<&str> = reached.iter().map(|r| r.hash.as_str()).collect();
+        let midx = midx::load()?;
+        for old in old.iter() {
+            if Some(&old.path) == new.as_ref() || old.path.with_extension("keep").exists() {
+                continue;
+            }
+            if mode == Mode::AllLoosen {
+                loosen(old, &reachable)?;
+            }
+            if midx
+                .as_ref()
+                .is_some_and(|midx| midx.covers(&old.idx_name()))
+            {
+                midx::remove()?;
+            }
+            pack::remove(&old.path)?;
+        }
+    }
+    prune_packed(false)
+}
+
+/// Write the objects of `pack` that are not `reachable` as loose objects, dated like the pack so
+/// that pruning them waits as long as if they had never been packed.
+fn loosen(pack: &Pack, reachable: &HashSet<&str>) -> anyhow::Result<()> {
+    let modified = pack
+        .path
+        .metadata()
+        .and_then(|meta| meta.modified())
+        .with_context(|| format!("read the modification time of {}", pack.path.display()))?;
+    for hash in pack.hashes() {
+        if reachable.contains(hash.as_str()) || objects::path(&hash).exists() {
+            continue;
+        }
+        pack.read(&hash)?
+            .with_context(|| format!("read object {hash} from {}", pack.path.display()))?
+            .write_loose()?;
+        std::fs::File::options()
+            .write(true)
+            .open(objects::path(&hash))
+            .and_then(|file| file.set_modified(modified))
+            .with_context(|| format!("set the modification time of {hash}"))?;
+    }
+    Ok(())
+}
+
+/// Invoke the `repack` command.
+/// See: <https://git-scm.com/docs/git-repack>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let mode = match (args.all, args.all_loosen) {
+        (_, true) => Mode::AllLoosen,
+        (true, _) => Mode::All,
+        _ => Mode::Loose,
+    };
+    let (window, depth) = configured_window()?;
+    repack(
+        mode,
+        args.delete,
+        args.window.unwrap_or(window),
+        args.depth.unwrap_or(depth),
+        args.quiet,
+        match (args.write_bitmap_index, args.no_write_bitmap_index) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+    )?;
+    if args.write_midx && !pack::packs()?.is_empty() {
+        midx::write(None)?;
+    }
+    Ok(())
+}
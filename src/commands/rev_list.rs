@@ -0,0 +1,187 @@
+//! The `rev-list` command.
+//!
+//! See: <https://git-scm.com/docs/git-rev-list>
+use anyhow::Context;
+use std::collections::HashSet;
+use std::io::Write;
+
+use crate::commands::log;
+use crate::objects::{Commit, Kind, Object, Tag, Tree, MODE_GITLINK};
+use crate::pack::bitmap::{self, PackBitmap};
+use crate::revision::{self, Selection};
+use crate::{reachable, refs};
+
+/// Arguments of the `rev-list` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Also list the trees, blobs and tags the commits reach, each with the path or tag name it
+    /// was reached at.
+    #[clap(long)]
+    objects: bool,
+    /// Print how many commits, or objects with `--objects`, would be listed instead.
+    #[clap(long)]
+    count: bool,
+    /// Use the reachability bitmaps of a pack if it has some, which lists objects by type in pack
+    /// order and without names.
+    #[clap(long)]
+    use_bitmap_index: bool,
+    /// Start from `HEAD` and every reference, in addition to the revisions given.
+    #[clap(long)]
+    all: bool,
+    /// List at most this many commits.
+    #[clap(short = 'n', long)]
+    max_count: Option<usize>,
+    /// The commits whose history is listed, e.g. `main` or `main..topic`.
+    #[clap(required_unless_present = "all")]
+    revisions: Vec<String>,
+}
+
+/// The commit `hash` points to, peeling tags, and the tags peeled with their names.
+fn peel(hash: &str) -> anyhow::Result<(String, Vec<(String, String)>)> {
+    let mut hash = hash.to_string();
+    let mut tags = Vec::new();
+    loop {
+        let object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+        match object.kind {
+            Kind::Commit => return Ok((hash, tags)),
+            Kind::Tag => {
+                let tag = Tag::parse(&object.into_bytes()?)
+                    .with_context(|| format!("parse tag {hash}"))?;
+                tags.push((hash, tag.name));
+                hash = tag.object;
+            }
+            kind => anyhow::bail!("object {hash} is a {kind}, not a commit"),
+        }
+    }
+}
+
+/// Add the tree `hash` reached at `path` and the objects it contains, depth first, to `listed`
+/// unless they are `seen`.
+fn list_tree(
+    hash: &str,
+    path: &str,
+    seen: &mut HashSet<String>,
+    listed: &mut Vec<(String, String)>,
+) -> anyhow::Result<()> {
+    if !seen.insert(hash.to_string()) {
+        return Ok(());
+    }
+    listed.push((hash.to_string(), path.to_string()));
+    for (name, entry) in Tree::read(hash)?.entries {
+        let path = if path.is_empty() {
+            name
+        } else {
+            format!("{path}/{name}")
+        };
+        if entry.is_tree() {
+            list_tree(&entry.hash, &path, seen, listed)?;
+        } else if entry.mode != MODE_GITLINK && seen.insert(entry.hash.clone()) {
+            listed.push((entry.hash, path));
+        }
+    }
+    Ok(())
+}
+
+/// List or count the objects `selection` selects using `bitmap`, or return false if some of them
+/// are not in its pack.
+fn list_with_bitmap(
+    bitmap: &PackBitmap,
+    selection: &Selection,
+    objects: bool,
+    count: bool,
+) -> anyhow::Result<bool> {
+    let Some(mut wanted) = bitmap.reach(&selection.include)? else {
+        return Ok(false);
+    };
+    let Some(excluded) = bitmap.reach(&selection.exclude)? else {
+        return Ok(false);
+    };
+    wanted.and_not(&excluded);
+    let kinds: &[Kind] = if objects {
+        &[Kind::Commit, Kind::Tree, Kind::Blob, Kind::Tag]
+    } else {
+        &[Kind::Commit]
+    };
+    let mut out = std::io::stdout().lock();
+    if count {
+        let total: usize = kinds.iter().map(|&kind| bitmap.count(&wanted, kind)).sum();
+        writeln!(out, "{total}")?;
+        return Ok(true);
+    }
+    for &kind in kinds {
+        for hash in bitmap.hashes(&wanted, kind) {
+            writeln!(out, "{hash}")?;
+        }
+    }
+    Ok(true)
+}
+
+/// Invoke the `rev-list` command.
+/// See: <https://git-scm.com/docs/git-rev-list>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let mut revisions = Vec::new();
+    if args.all {
+        revisions.extend(refs::list()?.into_iter().map(|(_, hash)| hash));
+        revisions.extend(refs::resolve("HEAD")?);
+    }
+    revisions.extend(args.revisions);
+    let selection = revision::resolve_selection(&revisions)?;
+
+    // Like Git, bitmaps are only used to list everything at once, or to count.
+    if args.use_bitmap_index && args.max_count.is_none() && (args.objects || args.count) {
+        if let Some(bitmap) = bitmap::load()? {
+            if list_with_bitmap(&bitmap, &selection, args.objects, args.count)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // The history of the commits tags point to is walked, and the tags listed after the commits.
+    let mut tags = Vec::new();
+    let mut peeled = Selection {
+        walk: selection.walk,
+        ..Selection::default()
+    };
+    for hash in &selection.include {
+        let (commit, peeled_tags) = peel(hash)?;
+        peeled.include.push(commit);
+        tags.extend(peeled_tags);
+    }
+    for hash in &selection.exclude {
+        peeled.exclude.push(peel(hash)?.0);
+    }
+
+    let mut out = std::io::stdout().lock();
+    let mut commits = Vec::new();
+    log::walk(&peeled, &[], args.max_count, |hash| {
+        if !args.count {
+            writeln!(out, "{hash}")?;
+        }
+        commits.push(hash.to_string());
+        Ok(())
+    })?;
+    let mut listed = Vec::new();
+    if args.objects {
+        let mut seen: HashSet<String> = reachable::walk(&peeled.exclude, false)?
+            .into_iter()
+            .map(|reached| reached.hash)
+            .collect();
+        for (hash, name) in tags {
+            if seen.insert(hash.clone()) {
+                listed.push((hash, name));
+            }
+        }
+        for commit in &commits {
+            let tree = Commit::read(commit)?.tree;
+            list_tree(&tree, "", &mut seen, &mut listed)?;
+        }
+    }
+    if args.count {
+        writeln!(out, "{}", commits.len() + listed.len())?;
+        return Ok(());
+    }
+    for (hash, name) in listed {
+        writeln!(out, "{hash} {name}")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,24 @@
+//! The `revert` command.
+//!
+//! See: <https://git-scm.com/docs/git-revert>
+use crate::sequencer::{self, Action, Options};
+
+/// Invoke the `revert` command.
+/// See: <https://git-scm.com/docs/git-revert>
+pub(crate) fn invoke(
+    commits: &[String],
+    options: Options,
+    resume: bool,
+    skip: bool,
+    abort: bool,
+) -> anyhow::Result<()> {
+    if resume {
+        sequencer::resume()
+    } else if skip {
+        sequencer::skip()
+    } else if abort {
+        sequencer::abort()
+    } else {
+        sequencer::start(Action::Revert, commits, options)
+    }
+}
@@ -0,0 +1,108 @@
+//! The `show-ref` command.
+//!
+//! See: <https://git-scm.com/docs/git-show-ref>
+use crate::refs;
+
+/// Arguments of the `show-ref` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Also show `HEAD`.
+    #[clap(long)]
+    head: bool,
+    /// Only show branches.
+    #[clap(long)]
+    heads: bool,
+    /// Only show tags.
+    #[clap(long)]
+    tags: bool,
+    /// Also show the objects annotated tags point to, as `<tag>^{}`.
+    #[clap(short = 'd', long)]
+    dereference: bool,
+    /// Only show object hashes, not reference names.
+    #[clap(short = 's', long)]
+    hash: bool,
+    /// Require each pattern to be the exact name of an existing reference.
+    #[clap(long)]
+    verify: bool,
+    /// Print nothing, only set the exit status.
+    #[clap(short = 'q', long)]
+    quiet: bool,
+    /// Only show references whose name is a pattern or ends in `/` and a pattern.
+    patterns: Vec<String>,
+}
+
+impl Args {
+    /// Print the reference `name` pointing to `hash`.
+    fn show(&self, name: &str, hash: &str) -> anyhow::Result<()> {
+        if self.quiet {
+            return Ok(());
+        }
+        if self.hash {
+            println!("{hash}");
+        } else {
+            println!("{hash} {name}");
+        }
+        if self.dereference {
+            if let Some(peeled) = refs::peel(hash)? {
+                if self.hash {
+                    println!("{peeled}");
+                } else {
+                    println!("{peeled} {name}^{{}}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the reference `name` is selected by `--heads`, `--tags` and the patterns.
+    fn selects(&self, name: &str) -> bool {
+        let kind = (!self.heads && !self.tags)
+            || (self.heads && name.starts_with("refs/heads/"))
+            || (self.tags && name.starts_with("refs/tags/"));
+        kind && (self.patterns.is_empty()
+            || self.patterns.iter().any(|pattern| {
+                name == pattern
+                    || name
+                        .strip_suffix(pattern.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('/'))
+            }))
+    }
+}
+
+/// Invoke the `show-ref` command.
+/// See: <https://git-scm.com/docs/git-show-ref>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    if args.verify {
+        anyhow::ensure!(!args.patterns.is_empty(), "--verify requires a reference");
+        for name in &args.patterns {
+            let hash = (name == "HEAD" || name.starts_with("refs/"))
+                .then(|| refs::resolve(name))
+                .transpose()?
+                .flatten();
+            match hash {
+                Some(hash) => args.show(name, &hash)?,
+                None if args.quiet => std::process::exit(1),
+                None => anyhow::bail!("'{name}' - not a valid ref"),
+            }
+        }
+        return Ok(());
+    }
+
+    let mut found = false;
+    if args.head {
+        if let Some(hash) = refs::resolve("HEAD")? {
+            args.show("HEAD", &hash)?;
+            found = true;
+        }
+    }
+    for (name, hash) in refs::list()? {
+        if args.selects(&name) {
+            args.show(&name, &hash)?;
+            found = true;
+        }
+    }
+    if !found {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,475 @@
+//! The `stash` command.
+//!
+//! A stash entry is a merge commit `W` recording the working tree, whose parents are the commit
+//! `HEAD` pointed to, a commit `I` recording the index and, optionally, a commit `U` recording
+//! untracked files. `refs/stash` points to the latest entry and its reference log to the others.
+//!
+//! See: <https://git-scm.com/docs/git-stash>
+use anyhow::Context;
+use clap::Subcommand;
+use std::io::Write;
+
+use crate::commands::commit_tree;
+use crate::commands::merge::short;
+use crate::index::Index;
+use crate::merge::{self, Labels, MergeOptions};
+use crate::objects::{Commit, FlatTree, Tree};
+use crate::{diff, reflog, refs, repository, revision, worktree};
+
+/// The reference pointing to the latest stash entry.
+const STASH_REF: &str = "refs/stash";
+
+/// `stash` subcommands.
+#[derive(Subcommand, Debug)]
+pub(crate) enum Action {
+    /// Save local modifications to a new stash entry and roll them back to HEAD.
+    Push {
+        /// The description of the stash entry.
+        #[clap(short = 'm', long)]
+        message: Option<String>,
+        /// Keep the changes already added to the index intact.
+        #[clap(short = 'k', long)]
+        keep_index: bool,
+        /// Also stash untracked files, and remove them from the working tree.
+        #[clap(short = 'u', long)]
+        include_untracked: bool,
+        /// Only stash the changes to these paths.
+        #[clap(last = true)]
+        pathspecs: Vec<String>,
+    },
+    /// List the stash entries.
+    List,
+    /// Show the changes recorded in a stash entry.
+    Show {
+        /// Show the changes as a patch instead of a diffstat.
+        #[clap(short = 'p', long)]
+        patch: bool,
+        /// The stash entry, defaults to `stash@{0}`.
+        stash: Option<String>,
+    },
+    /// Remove a single stash entry.
+    Drop {
+        /// The stash entry, defaults to `stash@{0}`.
+        stash: Option<String>,
+    },
+    /// Apply a stash entry and remove it from the stash list.
+    Pop {
+        /// Also reinstate the changes to the index.
+        #[clap(long)]
+        index: bool,
+        /// The stash entry, defaults to `stash@{0}`.
+        stash: Option<String>,
+    },
+    /// Apply a stash entry on top of the current working tree.
+    Apply {
+        /// Also reinstate the changes to the index.
+        #[clap(long)]
+        index: bool,
+        /// The stash entry, defaults to `stash@{0}`.
+        stash: Option<String>,
+    },
+    /// Remove all the stash entries.
+    Clear,
+    /// Create a branch at the commit a stash entry was created from and apply it there.
+    Branch {
+        /// The name of the new branch.
+        branch: String,
+        /// The stash entry, defaults to `stash@{0}`.
+        stash: Option<String>,
+    },
+}
+
+/// The stash entries, newest first.
+fn entries() -> anyhow::Result<Vec<reflog::Entry>> {
+    let mut entries = reflog::read(STASH_REF)?;
+    entries.reverse();
+    Ok(entries)
+}
+
+/// The position in the stash list named by `stash@{n}` or `n`, or `None` for another revision.
+fn stash_index(spec: &str) -> Option<usize> {
+    let n = spec
+        .strip_prefix("stash@{")
+        .or_else(|| spec.strip_prefix("refs/stash@{"))
+        .and_then(|rest| rest.strip_suffix('}'))
+        .unwrap_or(spec);
+    n.parse().ok()
+}
+
+/// Resolve a stash entry, defaulting to the latest one.
+fn resolve(spec: Option<&str>) -> anyhow::Result<String> {
+    let entries = entries()?;
+    anyhow::ensure!(
+        !entries.is_empty() || spec.is_some(),
+        "No stash entries found."
+    );
+    let spec = spec.unwrap_or("stash@{0}");
+    match stash_index(spec) {
+        Some(n) => Ok(entries
+            .get(n)
+            .with_context(|| format!("{spec} is not a valid reference"))?
+            .new
+            .clone()),
+        None => revision::resolve(spec),
+    }
+}
+
+/// The tree recorded by the index, or the tree of `HEAD` if there is no index.
+fn index_tree(head_tree: &FlatTree) -> anyhow::Result<FlatTree> {
+    let index = Index::read()?;
+    if index.entries.is_empty() {
+        return Ok(head_tree.clone());
+    }
+    index.tree()
+}
+
+/// The name of the current branch, or `(no branch)` if `HEAD` is detached.
+fn current_branch() -> anyhow::Result<String> {
+    Ok(match refs::head_target()? {
+        Some(target) => target
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&target)
+            .to_string(),
+        None => "(no branch)".to_string(),
+    })
+}
+
+/// Store files of the working tree as a tree.
+fn store_files(paths: &[String]) -> anyhow::Result<FlatTree> {
+    let mut tree = FlatTree::new();
+    for path in paths {
+        if let Some(entry) = worktree::store_file(path)? {
+            tree.insert(path.clone(), entry);
+        }
+    }
+    Ok(tree)
+}
+
+/// Save local modifications to a new stash entry and roll them back.
+fn push(
+    message: Option<String>,
+    keep_index: bool,
+    include_untracked: bool,
+    pathspecs: &[String],
+) -> anyhow::Result<()> {
+    let head = refs::resolve("HEAD")?.context("You do not have the initial commit yet")?;
+    let head_commit = Commit::read(&head)?;
+    let head_tree = Tree::read_flat(&head_commit.tree)?;
+    let index_tree = index_tree(&head_tree).context("Cannot save the current index state")?;
+
+    // The working tree state of tracked files, limited to `pathspecs`.
+    let mut work_tree = index_tree.clone();
+    let tracked: Vec<String> = index_tree
+        .keys()
+        .chain(head_tree.keys())
+        .filter(|path| worktree::matches_pathspec(path, pathspecs))
+        .cloned()
+        .collect();
+    for path in &tracked {
+        match worktree::store_file(path)? {
+            Some(entry) => work_tree.insert(path.clone(), entry),
+            None => work_tree.remove(path),
+        };
+    }
+    let untracked: Vec<String> = if include_untracked {
+        let mut known = index_tree.clone();
+        known.extend(head_tree.clone());
+        worktree::untracked_paths(&known)?
+            .into_iter()
+            .filter(|path| worktree::matches_pathspec(path, pathspecs))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    // Only changes to paths in `pathspecs` count.
+    let changed = |tree: &FlatTree| {
+        tree.keys()
+            .chain(head_tree.keys())
+            .filter(|path| worktree::matches_pathspec(path, pathspecs))
+            .any(|path| tree.get(path) != head_tree.get(path))
+    };
+    if !changed(&work_tree) && !changed(&index_tree) && untracked.is_empty() {
+        println!("No local changes to save");
+        return Ok(());
+    }
+
+    let branch = current_branch()?;
+    let description = format!("{}: {} {}", branch, short(&head), head_commit.summary());
+    let index_commit = hex::encode(commit_tree::write_commit(
+        &format!("index on {description}"),
+        &hex::encode(Tree::write_flat(&index_tree)?),
+        &[&head],
+    )?);
+    let mut parents = vec![head.clone(), index_commit];
+    if !untracked.is_empty() {
+        let untracked_tree = store_files(&untracked)?;
+        parents.push(hex::encode(commit_tree::write_commit(
+            &format!("untracked files on {description}"),
+            &hex::encode(Tree::write_flat(&untracked_tree)?),
+            &[],
+        )?));
+    }
+    let message = match message {
+        Some(message) => format!("On {branch}: {message}"),
+        None => format!("WIP on {description}"),
+    };
+    let parents: Vec<&str> = parents.iter().map(String::as_str).collect();
+    let stash = hex::encode(commit_tree::write_commit(
+        &message,
+        &hex::encode(Tree::write_flat(&work_tree)?),
+        &parents,
+    )?);
+    let old = refs::resolve(STASH_REF)?;
+    refs::write(STASH_REF, &stash)?;
+    reflog::append(STASH_REF, old.as_deref(), &stash, &message)?;
+    println!("Saved working directory and index state {message}");
+
+    // Roll back the stashed changes.
+    let target = if keep_index { &index_tree } else { &head_tree };
+    if pathspecs.is_empty() {
+        worktree::reset_hard(target)?;
+        if keep_index {
+            Index::from_tree(&index_tree).write()?;
+        }
+    } else {
+        let mut index = if keep_index {
+            index_tree.clone()
+        } else {
+            index_tree
+                .iter()
+                .filter(|(path, _)| !worktree::matches_pathspec(path, pathspecs))
+                .map(|(path, entry)| (path.clone(), entry.clone()))
+                .collect()
+        };
+        for path in &tracked {
+            match target.get(path) {
+                Some(entry) => {
+                    if !worktree::matches(path, Some(entry))? {
+                        worktree::write_entry(path, entry)?;
+                    }
+                    index.insert(path.clone(), entry.clone());
+                }
+                None => worktree::remove_entry(path)?,
+            }
+        }
+        Index::from_tree(&index).write()?;
+    }
+    for path in &untracked {
+        worktree::remove_entry(path)?;
+    }
+    Ok(())
+}
+
+/// Apply the stash entry `stash` to the working tree (and the index if `restore_index`),
+/// returning whether it applied without conflicts.
+fn apply(stash: &str, restore_index: bool) -> anyhow::Result<bool> {
+    let commit = Commit::read(stash)?;
+    anyhow::ensure!(
+        commit.parents.len() >= 2,
+        "'{}' is not a stash-like commit",
+        short(stash)
+    );
+    let head = refs::resolve("HEAD")?.context("HEAD does not point to a commit")?;
+    let head_tree = Tree::read_flat(&Commit::read(&head)?.tree)?;
+    let current =
+        index_tree(&head_tree).context("Cannot apply a stash in the middle of a merge")?;
+    let base = Tree::read_flat(&Commit::read(&commit.parents[0])?.tree)?;
+    let stashed_index = Tree::read_flat(&Commit::read(&commit.parents[1])?.tree)?;
+    let stashed_work = Tree::read_flat(&commit.tree)?;
+    let options = MergeOptions::new(&[])?;
+    let labels = Labels {
+        base: "Stash base".to_string(),
+        ours: "Updated upstream".to_string(),
+        theirs: "Stashed changes".to_string(),
+    };
+
+    let mut index_result = None;
+    if restore_index && stashed_index != base {
+        let merge = merge::merge_trees(&base, &current, &stashed_index, &labels, &options)?;
+        anyhow::ensure!(
+            merge.conflicts.is_empty(),
+            "Conflicts in index. Try without --index."
+        );
+        index_result = Some(merge.result);
+    }
+
+    let untracked = match commit.parents.get(2) {
+        Some(untracked) => Tree::read_flat(&Commit::read(untracked)?.tree)?,
+        None => FlatTree::new(),
+    };
+    for path in untracked.keys() {
+        if std::fs::symlink_metadata(path).is_ok() {
+            anyhow::bail!(
+                "{path} already exists, no checkout\n\
+                 error: could not restore untracked files from stash"
+            );
+        }
+    }
+
+    // Like Git, the working tree changes are merged into the current index, whatever the stashed
+    // index merged into; that is only written to the index once the working tree is updated.
+    let merge = merge::merge_trees(&base, &current, &stashed_work, &labels, &options)?;
+    let overwritten = worktree::overwritten_paths(&current, &merge.result)?;
+    if !overwritten.is_empty() {
+        anyhow::bail!(
+            "Your local changes to the following files would be overwritten by merge:\n\t{}\n\
+             Please commit your changes or stash them before you merge.",
+            overwritten.join("\n\t")
+        );
+    }
+    for path in &merge.auto_merged {
+        println!("Auto-merging {path}");
+    }
+    worktree::checkout(&current, &merge.result)?;
+    for (path, entry) in &untracked {
+        worktree::write_entry(path, entry)?;
+    }
+
+    if !merge.conflicts.is_empty() {
+        Index::from_merge(&merge.result, &merge.conflicts).write()?;
+        for conflict in &merge.conflicts {
+            println!("{}", conflict.message);
+        }
+        return Ok(false);
+    }
+    let index = match index_result {
+        Some(index) => index,
+        None => {
+            // Changes are left unstaged, except that new files are added to the index.
+            let mut index = current.clone();
+            for (path, entry) in &merge.result {
+                if !current.contains_key(path) {
+                    index.insert(path.clone(), entry.clone());
+                }
+            }
+            index
+        }
+    };
+    Index::from_tree(&index).write()?;
+    Ok(true)
+}
+
+/// Remove a stash entry.
+fn drop(spec: Option<&str>) -> anyhow::Result<()> {
+    let mut entries = entries()?;
+    anyhow::ensure!(!entries.is_empty(), "No stash entries found.");
+    let name = spec.unwrap_or("refs/stash@{0}");
+    let n = stash_index(name).with_context(|| format!("'{name}' is not a stash reference"))?;
+    anyhow::ensure!(n < entries.len(), "{name} is not a valid reference");
+    let dropped = entries.remove(n);
+    entries.reverse();
+    match entries.last() {
+        Some(latest) => {
+            reflog::write(STASH_REF, &entries)?;
+            refs::write(STASH_REF, &latest.new)?;
+        }
+        None => {
+            reflog::delete(STASH_REF)?;
+            refs::delete(STASH_REF)?;
+        }
+    }
+    println!("Dropped {name} ({})", dropped.new);
+    Ok(())
+}
+
+/// Invoke the `stash` command.
+/// See: <https://git-scm.com/docs/git-stash>
+pub(crate) fn invoke(action: Option<Action>) -> anyhow::Result<()> {
+    let action = action.unwrap_or(Action::Push {
+        message: None,
+        keep_index: false,
+        include_untracked: false,
+        pathspecs: Vec::new(),
+    });
+    match action {
+        Action::Push {
+            message,
+            keep_index,
+            include_untracked,
+            pathspecs,
+        } => {
+            let pathspecs = pathspecs
+                .iter()
+                .map(|spec| repository::pathspec(spec))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            push(message, keep_index, include_untracked, &pathspecs)?
+        }
+        Action::List => {
+            for (n, entry) in entries()?.iter().enumerate() {
+                println!("stash@{{{n}}}: {}", entry.message);
+            }
+        }
+        Action::Show { patch, stash } => {
+            let stash = Commit::read(&resolve(stash.as_deref())?)?;
+            let base = Commit::read(stash.parents.first().context("not a stash-like commit")?)?;
+            let (old, new) = (Tree::read_flat(&base.tree)?, Tree::read_flat(&stash.tree)?);
+            let mut out = Vec::new();
+            if patch {
+                diff::write_tree_patch(&mut out, &old, &new)?;
+            } else {
+                diff::write_tree_stat(&mut out, &old, &new)?;
+            }
+            std::io::stdout().write_all(&out)?;
+        }
+        Action::Drop { stash } => drop(stash.as_deref())?,
+        Action::Pop { index, stash } => {
+            if !apply(&resolve(stash.as_deref())?, index)? {
+                println!("The stash entry is kept in case you need it again.");
+                std::process::exit(1);
+            }
+            drop(stash.as_deref())?;
+        }
+        Action::Apply { index, stash } => {
+            if !apply(&resolve(stash.as_deref())?, index)? {
+                std::process::exit(1);
+            }
+        }
+        Action::Clear => {
+            refs::delete(STASH_REF)?;
+            reflog::delete(STASH_REF)?;
+        }
+        Action::Branch { branch, stash } => {
+            let hash = resolve(stash.as_deref())?;
+            let name = format!("refs/heads/{branch}");
+            anyhow::ensure!(
+                refs::resolve(&name)?.is_none(),
+                "a branch named '{branch}' already exists"
+            );
+            let base = Commit::read(&hash)?
+                .parents
+                .first()
+                .context("not a stash-like commit")?
+                .clone();
+            let head = refs::resolve("HEAD")?.context("HEAD does not point to a commit")?;
+            let from = Tree::read_flat(&Commit::read(&head)?.tree)?;
+            let to = Tree::read_flat(&Commit::read(&base)?.tree)?;
+            let overwritten = worktree::overwritten_paths(&from, &to)?;
+            if !overwritten.is_empty() {
+                anyhow::bail!(
+                    "Your local changes to the following files would be overwritten by checkout:\n\t{}",
+                    overwritten.join("\n\t")
+                );
+            }
+            worktree::checkout(&from, &to)?;
+            Index::from_tree(&to).write()?;
+            refs::update(
+                &name,
+                &base,
+                &format!("branch: Created from {}", short(&base)),
+            )?;
+            refs::set_head(
+                &name,
+                &format!("checkout: moving from {} to {branch}", current_branch()?),
+            )?;
+            println!("Switched to a new branch '{branch}'");
+            if !apply(&hash, true)? {
+                println!("The stash entry is kept in case you need it again.");
+                std::process::exit(1);
+            }
+            if stash.as_deref().map_or(true, |s| stash_index(s).is_some()) {
+                drop(stash.as_deref())?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! The `symbolic-ref` command.
+//!
+//! See: <https://git-scm.com/docs/git-symbolic-ref>
+use crate::{refs, revision};
+
+/// Arguments of the `symbolic-ref` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Why the reference is updated, recorded in its log.
+    #[clap(short = 'm', value_name = "REASON")]
+    message: Option<String>,
+    /// Print nothing if the reference is not symbolic, only set the exit status.
+    #[clap(short = 'q', long)]
+    quiet: bool,
+    /// Delete the symbolic reference.
+    #[clap(short = 'd', long, conflicts_with = "target")]
+    delete: bool,
+    /// Show the target as the shortest unambiguous name, e.g. `main`.
+    #[clap(long)]
+    short: bool,
+    /// Show only the direct target, not the one a chain of symbolic references ends in.
+    #[clap(long)]
+    no_recurse: bool,
+    /// The symbolic reference, e.g. `HEAD`.
+    name: String,
+    /// The reference to point it to, e.g. `refs/heads/main`.
+    target: Option<String>,
+}
+
+/// The reference the symbolic reference `name` points to directly, or `None` if `name` is not a
+/// symbolic reference.
+fn symbolic_target(name: &str) -> anyhow::Result<Option<String>> {
+    Ok(refs::read_raw(name)?
+        .as_deref()
+        .and_then(|contents| contents.strip_prefix("ref: "))
+        .map(|target| target.trim().to_string()))
+}
+
+/// Invoke the `symbolic-ref` command.
+/// See: <https://git-scm.com/docs/git-symbolic-ref>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let name = args.name.as_str();
+    if let Some(target) = &args.target {
+        anyhow::ensure!(
+            name != "HEAD" || target.starts_with("refs/"),
+            "Refusing to point HEAD outside of refs/"
+        );
+        anyhow::ensure!(
+            refs::check_name(target).is_ok(),
+            "Refusing to set '{name}' to invalid ref '{target}'"
+        );
+        // Like Git, the update is logged even without a reason if the target exists.
+        return refs::write_symbolic(
+            name,
+            target,
+            Some(args.message.as_deref().unwrap_or_default()),
+        );
+    }
+
+    let Some(direct) = symbolic_target(name)? else {
+        if args.quiet {
+            std::process::exit(1);
+        }
+        if args.delete {
+            anyhow::bail!("Cannot delete {name}, not a symbolic ref");
+        }
+        anyhow::bail!("ref {name} is not a symbolic ref");
+    };
+    if args.delete {
+        anyhow::ensure!(name != "HEAD", "deleting '{name}' is not allowed");
+        return refs::delete(name);
+    }
+
+    let target = if args.no_recurse {
+        direct
+    } else {
+        refs::target_name(&direct)?
+    };
+    if args.short {
+        println!("{}", revision::shorten_ref(&target, false)?);
+    } else {
+        println!("{target}");
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! The `update-ref` command.
+//!
+//! See: <https://git-scm.com/docs/git-update-ref>
+use anyhow::Context;
+use std::io::BufRead;
+
+use crate::{hash, refs, revision};
+
+/// Arguments of the `update-ref` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// Why the reference is updated, recorded in the reference logs.
+    #[clap(short = 'm')]
+    message: Option<String>,
+    /// Delete the reference instead of updating it.
+    #[clap(short = 'd', conflicts_with = "stdin")]
+    delete: bool,
+    /// Update a symbolic reference itself rather than the reference it points to.
+    #[clap(long)]
+    no_deref: bool,
+    /// Read updates from standard input and apply them in a single transaction.
+    #[clap(long)]
+    stdin: bool,
+    /// The reference to update, e.g. `refs/heads/main`.
+    #[clap(required_unless_present = "stdin", conflicts_with = "stdin")]
+    reference: Option<String>,
+    /// The new value, or the expected old value with `-d`.
+    value: Option<String>,
+    /// The value the reference must have beforehand; empty or all zeros if it must not exist.
+    old_value: Option<String>,
+}
+
+/// Resolve a value given on the command line to an object hash, or the null hash for an empty
+/// value or the null hash itself.
+fn resolve_value(value: &str) -> anyhow::Result<String> {
+    if value.is_empty() || hash::is_null(value) {
+        return Ok(hash::null_hex().to_string());
+    }
+    revision::resolve(value)
+}
+
+/// Resolve the expected old value of a reference given on the command line. A full object id is
+/// taken as it is, even if there is no such object, so that a reference at another one fails the
+/// comparison rather than the lookup.
+fn resolve_old_value(value: &str) -> anyhow::Result<String> {
+    if value.len() == hash::algorithm().hex_len() && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(value.to_ascii_lowercase());
+    }
+    resolve_value(value)
+}
+
+/// The reference updated for `name`: the one it points to, unless `no_deref`.
+fn target(name: &str, no_deref: bool) -> anyhow::Result<String> {
+    if no_deref {
+        Ok(name.to_string())
+    } else {
+        refs::target_name(name)
+    }
+}
+
+/// Add the command on `line` of standard input, e.g. `update refs/heads/main <new> <old>`, to
+/// `transaction`.
+fn parse_line(line: &str, transaction: &mut refs::Transaction, args: &Args) -> anyhow::Result<()> {
+    let mut words = line.split(' ');
+    let command = words.next().unwrap_or_default();
+    let invalid = || format!("{command}: missing arguments");
+    let name = target(words.next().with_context(invalid)?, args.no_deref)?;
+    let mut value =
+        |resolve: fn(&str) -> anyhow::Result<String>| words.next().map(resolve).transpose();
+    let message = Some(args.message.as_deref().unwrap_or_default());
+    match command {
+        "update" => {
+            let new = value(resolve_value)?.with_context(invalid)?;
+            let old = value(resolve_old_value)?;
+            // Updating to the null hash deletes the reference.
+            if hash::is_null(&new) {
+                transaction.delete(&name, old.as_deref());
+            } else {
+                transaction.update(&name, &new, old.as_deref(), message);
+            }
+        }
+        "create" => {
+            let new = value(resolve_value)?.with_context(invalid)?;
+            anyhow::ensure!(!hash::is_null(&new), "create {name}: zero <new-oid>");
+            transaction.update(&name, &new, Some(hash::null_hex()), message);
+        }
+        "delete" => {
+            let old = value(resolve_old_value)?;
+            anyhow::ensure!(
+                !old.as_deref().is_some_and(hash::is_null),
+                "delete {name}: zero <old-oid>"
+            );
+            transaction.delete(&name, old.as_deref());
+        }
+        "verify" => {
+            let old = value(resolve_old_value)?.unwrap_or_else(|| hash::null_hex().to_string());
+            transaction.verify(&name, &old);
+        }
+        _ => anyhow::bail!("unknown command: {line}"),
+    }
+    anyhow::ensure!(
+        value(resolve_value)?.is_none(),
+        "{command} {name}: extra input"
+    );
+    Ok(())
+}
+
+/// Invoke the `update-ref` command.
+/// See: <https://git-scm.com/docs/git-update-ref>
+pub(crate) fn invoke(args: Args) -> anyhow::Result<()> {
+    let mut transaction = refs::Transaction::default();
+    if args.stdin {
+        for line in std::io::stdin().lock().lines() {
+            let line = line.context("read standard input")?;
+            if !line.is_empty() {
+                parse_line(&line, &mut transaction, &args)?;
+            }
+        }
+        return transaction.commit();
+    }
+
+    let name = target(args.reference.as_deref().unwrap_or_default(), args.no_deref)?;
+    if args.delete {
+        anyhow::ensure!(
+            args.old_value.is_none(),
+            "usage: update-ref -d <refname> [<old-val>]"
+        );
+        let old = args.value.as_deref().map(resolve_old_value).transpose()?;
+        transaction.delete(&name, old.as_deref());
+    } else {
+        let new = args
+            .value
+            .as_deref()
+            .context("usage: update-ref <refname> <new-val> [<old-val>]")?;
+        anyhow::ensure!(
+            !new.is_empty() && !hash::is_null(new),
+            "{new}: not a valid SHA1"
+        );
+        let new = resolve_value(new)?;
+        let old = args
+            .old_value
+            .as_deref()
+            .map(resolve_old_value)
+            .transpose()?;
+        let message = args.message.as_deref().unwrap_or_default();
+        transaction.update(&name, &new, old.as_deref(), Some(message));
+    }
+    transaction.commit()
+}
@@ -0,0 +1,205 @@
+//! The `upload-pack` command, which `git clone` and `git fetch` run in the repository they fetch
+//! from, e.g. with `--upload-pack`.
+//!
+//! Only version 0 of the protocol is spoken, without multi-ack, side-band or shallow
+//! capabilities: the references are advertised, the client names the commits it wants and then
+//! the ones it has, and the objects the wanted commits reach but the common ones do not are sent
+//! as a pack. They are counted with the reachability bitmaps when the bitmaps cover both sides,
+//! and by walking the history otherwise.
+//!
+//! See: <https://git-scm.com/docs/pack-protocol>
+use anyhow::Context;
+use std::collections::HashSet;
+use std::io::{BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use crate::commands::repack::configured_window;
+use crate::objects::{self, Object};
+use crate::pack::{self, bitmap, PackObject};
+use crate::{hash, reachable, refs};
+
+/// Arguments of the `upload-pack` command.
+#[derive(clap::Args, Debug)]
+pub(crate) struct Args {
+    /// The repository to send objects from.
+    pub(crate) directory: PathBuf,
+}
+
+/// A packet of the protocol.
+enum Packet {
+    /// A line of data, without its line feed.
+    Data(String),
+    /// A flush packet, `0000`, ending a section.
+    Flush,
+    /// The end of the input.
+    End,
+}
+
+/// Read a packet from `input`: a 4-digit hex length including itself, then the data.
+fn read_packet(input: &mut impl Read) -> anyhow::Result<Packet> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(Packet::End),
+        Err(e) => return Err(e).context("read packet"),
+    }
+    let len = std::str::from_utf8(&len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .with_context(|| format!("protocol error: bad line length character: {len:?}"))?;
+    if len == 0 {
+        return Ok(Packet::Flush);
+    }
+    anyhow::ensure!(len > 4, "protocol error: bad line length {len}");
+    let mut data = vec![0; len - 4];
+    input.read_exact(&mut data).context("read packet")?;
+    let line = String::from_utf8(data).context("protocol error: packet is not UTF-8")?;
+    Ok(Packet::Data(
+        line.strip_suffix('\n').unwrap_or(&line).to_string(),
+    ))
+}
+
+/// Write `line` as a packet.
+fn write_packet(out: &mut impl Write, line: &str) -> anyhow::Result<()> {
+    write!(out, "{:04x}{line}", line.len() + 4)?;
+    Ok(())
+}
+
+/// Write the references and what annotated tags point to, `HEAD` first, each with its object,
+/// and the capabilities after the first one.
+///
+/// Returns the objects advertised, which are the only ones clients may ask for.
+fn advertise(out: &mut impl Write) -> anyhow::Result<HashSet<String>> {
+    let mut capabilities = String::from("ofs-delta");
+    if let Some(target) = refs::head_target()? {
+        capabilities.push_str(&format!(" symref=HEAD:{target}"));
+    }
+    capabilities.push_str(concat!(" agent=mini-git/", env!("CARGO_PKG_VERSION")));
+
+    let mut lines = Vec::new();
+    if let Some(head) = refs::resolve("HEAD")? {
+        lines.push((head, "HEAD".to_string()));
+    }
+    for (name, hash) in refs::list()? {
+        let peeled = refs::peel(&hash)?;
+        lines.push((hash, name.clone()));
+        if let Some(peeled) = peeled {
+            lines.push((peeled, format!("{name}^{{}}")));
+        }
+    }
+    // An empty repository still advertises its capabilities.
+    if lines.is_empty() {
+        lines.push((hash::null_hex().to_string(), "capabilities^{}".to_string()));
+    }
+    for (i, (hash, name)) in lines.iter().enumerate() {
+        if i == 0 {
+            write_packet(out, &format!("{hash} {name}\0{capabilities}\n"))?;
+        } else {
+            write_packet(out, &format!("{hash} {name}\n"))?;
+        }
+    }
+    out.write_all(b"0000")?;
+    out.flush()?;
+    Ok(lines.into_iter().map(|(hash, _)| hash).collect())
+}
+
+/// The objects reachable from `wants` but not from `common`, read to be packed.
+fn objects_to_send(wants: &[String], common: &[String]) -> anyhow::Result<Vec<PackObject>> {
+    if let Some(bitmap) = bitmap::load()? {
+        if let (Some(mut wanted), Some(excluded)) = (bitmap.reach(wants)?, bitmap.reach(common)?) {
+            wanted.and_not(&excluded);
+            return bitmap
+                .named_hashes(&wanted)
+                .into_iter()
+                .map(|(hash, name_hash)| {
+                    let object = Object::read(&hash)?;
+                    PackObject::read(hash, object, name_hash)
+                })
+                .collect();
+        }
+    }
+    let excluded: HashSet<String> = reachable::walk(common, false)?
+        .into_iter()
+        .map(|reached| reached.hash)
+        .collect();
+    reachable::walk(wants, false)?
+        .into_iter()
+        .filter(|reached| !excluded.contains(&reached.hash))
+        .map(|reached| {
+            let object = Object::read(&reached.hash)?;
+            PackObject::read(reached.hash, object, pack::name_hash(&reached.name))
+        })
+        .collect()
+}
+
+/// Invoke the `upload-pack` command.
+/// See: <https://git-scm.com/docs/git-upload-pack>
+pub(crate) fn invoke() -> anyhow::Result<()> {
+    let mut input = std::io::stdin().lock();
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let advertised = advertise(&mut out)?;
+
+    // The objects the client wants, ending with a flush; none if it is up to date.
+    let mut wants = Vec::new();
+    loop {
+        match read_packet(&mut input)? {
+            Packet::Data(line) => {
+                let hash = line
+                    .strip_prefix("want ")
+                    .and_then(|rest| rest.split(' ').next())
+                    .with_context(|| {
+                        format!("git upload-pack: protocol error, expected a want, not '{line}'")
+                    })?;
+                anyhow::ensure!(
+                    advertised.contains(hash),
+                    "git upload-pack: not our ref {hash}"
+                );
+                wants.push(hash.to_string());
+            }
+            Packet::Flush => break,
+            Packet::End => return Ok(()),
+        }
+    }
+    if wants.is_empty() {
+        return Ok(());
+    }
+
+    // The commits the client has, in batches ending with a flush, until it is done. Without
+    // multi-ack, only the first common commit is acknowledged, and each batch without one is
+    // answered with a NAK.
+    let mut common = Vec::new();
+    loop {
+        match read_packet(&mut input)? {
+            Packet::Data(line) if line == "done" => {
+                if common.is_empty() {
+                    write_packet(&mut out, "NAK\n")?;
+                }
+                break;
+            }
+            Packet::Data(line) => {
+                let hash = line.strip_prefix("have ").with_context(|| {
+                    format!("git upload-pack: expected SHA1 list, got '{line}'")
+                })?;
+                if objects::exists(hash)? {
+                    common.push(hash.to_string());
+                    if common.len() == 1 {
+                        write_packet(&mut out, &format!("ACK {hash}\n"))?;
+                    }
+                }
+            }
+            Packet::Flush => {
+                if common.is_empty() {
+                    write_packet(&mut out, "NAK\n")?;
+                }
+                out.flush()?;
+            }
+            Packet::End => anyhow::bail!("the remote end hung up unexpectedly"),
+        }
+    }
+
+    let objects = objects_to_send(&wants, &common)?;
+    let (window, depth) = configured_window()?;
+    pack::write_to(&mut out, &objects, window, depth).context("write pack")?;
+    out.flush()?;
+    Ok(())
+}
@@ -2,18 +2,119 @@
 //!
 //! See: <https://git-scm.com/docs/git-write-tree>
 use anyhow::Context;
-use std::cmp::Ordering;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::{fs, io::Cursor};
 
-use crate::objects::{Kind, Object};
+use crate::hash::ObjectId;
+use crate::index::{CacheTree, Index, IndexEntry, Stat, INDEX_PATH};
+use crate::objects::{self, tree_entry_cmp, Kind, Object, MODE_EXECUTABLE, MODE_FILE};
+use crate::objects::{MODE_SYMLINK, MODE_TREE};
+use crate::repository::{self, git_path};
+use crate::worktree;
 
-/// Recursively write a tree object for a directory. Returns the hash of the tree object if it was
-/// created, or `None` if the directory is empty.
+/// What the index knows about the working tree: the blob of each file whose stat data has not
+/// changed, and the trees of directories whose files have not changed.
+#[derive(Default)]
+struct Known {
+    /// Stage 0 entries by path.
+    entries: HashMap<String, IndexEntry>,
+    /// The modification time of the index file, as seconds and nanoseconds. Files modified in the
+    /// same instant may have changed after their stat data was recorded, so they are rehashed.
+    index_mtime: (u32, u32),
+    /// The cached trees of the index.
+    cache_tree: Option<CacheTree>,
+}
+
+impl Known {
+    /// Read what the index knows, nothing if there is no index.
+    fn read() -> anyhow::Result<Known> {
+        let Ok(meta) = fs::metadata(git_path(INDEX_PATH)) else {
+            return Ok(Known::default());
+        };
+        let mtime = Stat::from_metadata(&meta);
+        let index = Index::read()?;
+        Ok(Known {
+            entries: index
+                .entries
+                .into_iter()
+                .filter(|entry| entry.stage == 0)
+                .map(|entry| (entry.path.clone(), entry))
+                .collect(),
+            index_mtime: (mtime.mtime, mtime.mtime_nsec),
+            cache_tree: index.cache_tree,
+        })
+    }
+
+    /// The blob hash of the file at `path` with metadata `meta` and mode `mode`, if the index
+    /// has it and its stat data shows it has not changed since.
+    fn blob(&self, path: &str, meta: &fs::Metadata, mode: u32) -> anyhow::Result<Option<ObjectId>> {
+        let Some(entry) = self.entries.get(path) else {
+            return Ok(None);
+        };
+        let stat = Stat::from_metadata(meta);
+        if entry.entry.mode != mode
+            || entry.stat != stat
+            || (stat.mtime, stat.mtime_nsec) >= self.index_mtime
+        {
+            return Ok(None);
+        }
+        ObjectId::from_hex(&entry.entry.hash).map(Some)
+    }
+}
+
+/// A tree written for a directory.
+struct Written {
+    /// The hash of the tree object.
+    hash: ObjectId,
+    /// The tree as cached by an index matching the directory.
+    cache: CacheTree,
+    /// Whether the index has every file of the directory, unchanged.
+    unchanged: bool,
+}
+
+/// An entry of a directory, written.
+struct Item {
+    /// The mode of the entry.
+    mode: u32,
+    /// The file name of the entry.
+    file_name: OsString,
+    /// The hash of the blob or tree.
+    hash: ObjectId,
+    /// Whether the index has the entry, unchanged.
+    unchanged: bool,
+    /// The name and cached tree of a subdirectory.
+    subtree: Option<(String, CacheTree)>,
+}
+
+/// Store the object unless the object store already has it. Hashing is much cheaper than
+/// compressing, so existing objects are not written again.
+fn store<R: std::io::Read>(
+    object: impl Fn() -> anyhow::Result<Object<R>>,
+) -> anyhow::Result<ObjectId> {
+    let hash = object()?.hash()?;
+    if objects::exists(&hash.to_string())? {
+        return Ok(hash);
+    }
+    let written = object()?.write_to_objects()?;
+    anyhow::ensure!(written == hash, "object changed while it was written");
+    Ok(hash)
+}
+
+/// Recursively write a tree object for the directory `path`, found at `prefix` in the working
+/// tree, unless it has no files.
 ///
-/// NOTE: this uses Unix permissions to determine file mode, so it may not work on Windows.
-pub(crate) fn write_tree_for(path: &Path) -> anyhow::Result<Option<[u8; 20]>> {
+/// Files whose stat data the index shows unchanged are not read, and a directory whose files
+/// all are reuses the tree `cached` for it.
+fn write_dir(
+    path: &Path,
+    prefix: &str,
+    known: &Known,
+    cached: Option<&CacheTree>,
+) -> anyhow::Result<Option<Written>> {
     let dir = fs::read_dir(path).with_context(|| format!("open directory {}", path.display()))?;
     let mut tree_object = Vec::new();
 
@@ -24,106 +125,141 @@ pub(crate) fn write_tree_for(path: &Path) -> anyhow::Result<Option<[u8; 20]>> {
         let meta = entry.metadata().context("metadata for directory entry")?;
         entries.push((entry, name, meta))
     }
-    // Sort file contents following Git's conventions:
-    // See <https://github.com/git/git/blob/64cbe5e2e8a7b0f92c780b210e602496bd5cad0f/tree.c#L101>
+    // Sort file contents following Git's conventions.
     entries.sort_unstable_by(|a, b| {
-        let afn = a.1.as_encoded_bytes();
-        let bfn = b.1.as_encoded_bytes();
-        let common_len = std::cmp::min(afn.len(), bfn.len());
-        match afn[..common_len].cmp(&bfn[..common_len]) {
-            Ordering::Equal => {}
-            o => return o,
-        }
-        if afn.len() == bfn.len() {
-            return Ordering::Equal;
-        }
-        let c1 = if let Some(c) = afn.get(common_len).copied() {
-            Some(c)
-        } else if a.2.is_dir() {
-            Some(b'/')
-        } else {
-            None
-        };
-
-        let c2 = if let Some(c) = bfn.get(common_len).copied() {
-            Some(c)
-        } else if b.2.is_dir() {
-            Some(b'/')
-        } else {
-            None
-        };
-
-        c1.cmp(&c2)
+        tree_entry_cmp(
+            a.1.as_encoded_bytes(),
+            a.2.is_dir(),
+            b.1.as_encoded_bytes(),
+            b.2.is_dir(),
+        )
     });
 
-    for (entry, file_name, meta) in entries {
+    // Files are hashed and compressed, and subdirectories written, in parallel. The entries keep
+    // their order, so the tree is the same as if they were written one by one.
+    let items = entries
+        .into_par_iter()
         // Skip `.git/` entries
-        if file_name == ".git" {
-            continue;
-        }
-
-        // NOTE: these permissions are for UNIX systems
-        let mode = if meta.is_dir() {
-            "40000"
-        } else if meta.is_symlink() {
-            "120000"
-        } else if (meta.permissions().mode() & 0o111) != 0 {
-            // Has at least one executable bit set
-            "100755"
-        } else {
-            // Regular file
-            "100644"
-        };
-        let path = entry.path();
-        let hash = if meta.is_dir() {
-            let Some(hash) = write_tree_for(&path)? else {
-                // Empty directory
-                continue;
+        .filter(|(_, file_name, _)| file_name != ".git")
+        .map(|(entry, file_name, meta)| -> anyhow::Result<Option<Item>> {
+            // NOTE: these permissions are for UNIX systems
+            let mode = if meta.is_dir() {
+                MODE_TREE
+            } else if meta.is_symlink() {
+                MODE_SYMLINK
+            } else if (meta.permissions().mode() & 0o111) != 0 {
+                // Has at least one executable bit set
+                MODE_EXECUTABLE
+            } else {
+                // Regular file
+                MODE_FILE
             };
-            hash
-        } else {
-            let tmp = "tmp";
-            let hash = Object::blob_from_file(&path)
-                .context("open blob input file")?
-                .write(std::fs::File::create(tmp).context("construct temporary file for blob")?)
-                .context("stream file into blob")?;
-            let hash_hex = hex::encode(hash);
-            std::fs::create_dir_all(format!(".git/objects/{}/", &hash_hex[..2]))
-                .context("create subdirectory of `.git/objects/`")?;
-            std::fs::rename(
-                tmp,
-                format!(".git/objects/{}/{}", &hash_hex[..2], &hash_hex[2..]),
-            )
-            .context("move tmp blob file into `.git/objects`")?;
-            hash
-        };
-        tree_object.extend(mode.as_bytes());
+            let path = entry.path();
+            let name = file_name.to_string_lossy().into_owned();
+            let file_path = format!("{prefix}{name}");
+            if meta.is_dir() {
+                let cached = cached.and_then(|cached| cached.subtrees.get(&name));
+                let written = write_dir(&path, &format!("{file_path}/"), known, cached)?;
+                // Empty directories are left out.
+                return Ok(written.map(|written| Item {
+                    mode,
+                    file_name,
+                    hash: written.hash,
+                    unchanged: written.unchanged,
+                    subtree: Some((name, written.cache)),
+                }));
+            }
+            let (hash, unchanged) = match known.blob(&file_path, &meta, mode)? {
+                Some(hash) => (hash, true),
+                // A link is stored as the path it points to, which may not even exist.
+                None if mode == MODE_SYMLINK => (store(|| worktree::symlink_blob(&path))?, false),
+                None => {
+                    let hash =
+                        store(|| Object::blob_from_file(&path).context("open blob input file"))?;
+                    (hash, false)
+                }
+            };
+            Ok(Some(Item {
+                mode,
+                file_name,
+                hash,
+                unchanged,
+                subtree: None,
+            }))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut unchanged = true;
+    let mut count = 0;
+    let mut subtrees = BTreeMap::new();
+    for item in items.into_iter().flatten() {
+        unchanged &= item.unchanged;
+        match item.subtree {
+            Some((name, subtree)) => {
+                count += subtree.entries;
+                subtrees.insert(name, subtree);
+            }
+            None => count += 1,
+        }
+        tree_object.extend(format!("{:o}", item.mode).as_bytes());
         tree_object.push(b' ');
-        tree_object.extend(file_name.as_encoded_bytes());
+        tree_object.extend(item.file_name.as_encoded_bytes());
         tree_object.push(0);
-        tree_object.extend(hash);
+        tree_object.extend_from_slice(item.hash.as_bytes());
     }
 
     if tree_object.is_empty() {
-        Ok(None)
-    } else {
-        // Return created hash
-        Ok(Some(
-            Object {
+        return Ok(None);
+    }
+    // The index has exactly the files of the directory if it has as many, all unchanged.
+    let reused = cached
+        .filter(|cached| unchanged && cached.entries == count)
+        .and_then(|cached| cached.hash.as_deref());
+    let hash = match reused {
+        Some(hash) => ObjectId::from_hex(hash)?,
+        None => store(|| {
+            Ok(Object {
                 kind: Kind::Tree,
                 expected_size: tree_object.len() as u64,
-                reader: Cursor::new(tree_object),
-            }
-            .write_to_objects()
-            .context("write tree object")?,
-        ))
-    }
+                reader: Cursor::new(&tree_object),
+            })
+        })
+        .context("write tree object")?,
+    };
+    Ok(Some(Written {
+        cache: CacheTree {
+            hash: Some(hash.to_string()),
+            entries: count,
+            subtrees,
+        },
+        hash,
+        unchanged,
+    }))
+}
+
+/// Write a tree object for the directory `path`, the top of the working tree, like
+/// [`write_tree_for`], and also return the trees an index matching it caches.
+pub(crate) fn write_tree_with_cache(path: &Path) -> anyhow::Result<Option<(ObjectId, CacheTree)>> {
+    let known = Known::read()?;
+    let written = write_dir(path, "", &known, known.cache_tree.as_ref())?;
+    Ok(written.map(|written| (written.hash, written.cache)))
+}
+
+/// Recursively write a tree object for a directory, the top of the working tree. Returns the hash
+/// of the tree object if it was created, or `None` if the directory is empty.
+///
+/// Only objects missing from the object store are written, and files and directories the index
+/// shows unchanged reuse the blobs and trees it records instead of being read again.
+///
+/// NOTE: this uses Unix permissions to determine file mode, so it may not work on Windows.
+pub(crate) fn write_tree_for(path: &Path) -> anyhow::Result<Option<ObjectId>> {
+    Ok(write_tree_with_cache(path)?.map(|(hash, _)| hash))
 }
 
 /// Invoke the `write-tree` command.
 /// See: <https://git-scm.com/docs/git-write-tree>
 pub(crate) fn invoke() -> anyhow::Result<()> {
-    let Some(hash) = write_tree_for(&std::env::current_dir()?)? else {
+    let Some(hash) = write_tree_for(repository::work_tree()?)? else {
         anyhow::bail!("empty tree, no files to write");
     };
 
@@ -0,0 +1,962 @@
+//! Commit-graph files, which store the root tree, parents, commit date and generation number of
+//! commits, so that walking history does not need to inflate and parse commit objects.
+//!
+//! The graph is either the single file `objects/info/commit-graph`, or a chain of layers
+//! `objects/info/commit-graphs/graph-<checksum>.graph` listed, base first, in
+//! `objects/info/commit-graphs/commit-graph-chain`. A layer only has the commits the layers below
+//! it do not have, and its positions continue after theirs. Every parent of a commit in the graph
+//! is in the graph too.
+//!
+//! A commit can only reach commits with a smaller generation, which lets walks stop early. Layers
+//! store the topological level of each commit and, in the `GDA2` chunk, its corrected commit date,
+//! which is the generation when every layer has it.
+//!
+//! See: <https://git-scm.com/docs/gitformat-commit-graph>
+use anyhow::Context;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+use crate::hash::{self, Hasher, ObjectId};
+use crate::lockfile::LockFile;
+use crate::objects::{Commit, FlatTree, Kind, Object, Tag, Tree};
+use crate::repository::git_path;
+use crate::{pack, refs, shared};
+
+pub(crate) mod bloom;
+
+/// The magic bytes commit-graph files start with.
+const SIGNATURE: &[u8; 4] = b"CGPH";
+
+/// The version of the file format.
+const VERSION: u8 = 1;
+
+/// The size of the header: signature, version, hash version, chunk count and base layer count.
+const HEADER_SIZE: usize = 8;
+
+/// The size of an entry of the table of contents: a chunk id and its offset.
+const TOC_ENTRY_SIZE: usize = 12;
+
+/// The fan-out table of object ids by first byte.
+const OID_FANOUT: [u8; 4] = *b"OIDF";
+/// The sorted object ids.
+const OID_LOOKUP: [u8; 4] = *b"OIDL";
+/// Root tree, parent positions, topological level and date of each commit.
+const COMMIT_DATA: [u8; 4] = *b"CDAT";
+/// Corrected commit date offsets.
+const GENERATION_DATA: [u8; 4] = *b"GDA2";
+/// Corrected commit date offsets too large for `GDA2`.
+const GENERATION_OVERFLOW: [u8; 4] = *b"GDO2";
+/// Parents after the first of commits with more than two.
+const EXTRA_EDGES: [u8; 4] = *b"EDGE";
+/// The end of each commit's changed-path Bloom filter in `BDAT`.
+const BLOOM_INDEXES: [u8; 4] = *b"BIDX";
+/// Changed-path Bloom filter settings, then the filters.
+const BLOOM_DATA: [u8; 4] = *b"BDAT";
+/// The checksums of the layers below a layer of a chain.
+const BASE_GRAPHS: [u8; 4] = *b"BASE";
+
+/// The parent position of a commit without (another) parent.
+const PARENT_NONE: u32 = 0x7000_0000;
+/// Set on the second parent position when it is an index into `EDGE` instead, and there on the
+/// last parent of a commit.
+const EDGE_FLAG: u32 = 0x8000_0000;
+/// The largest topological level, which higher levels are capped at.
+const MAX_LEVEL: u32 = 0x3fff_ffff;
+/// Set on a corrected commit date offset that is an index into `GDO2` instead.
+const OFFSET_OVERFLOW: u32 = 0x8000_0000;
+
+/// The generation of commits that are not in the graph, which may reach any commit in it.
+pub(crate) const GENERATION_INFINITY: u64 = u64::MAX;
+
+/// The path of the single-file commit-graph.
+fn single_path() -> PathBuf {
+    git_path("objects/info/commit-graph")
+}
+
+/// The directory of the layers of a split commit-graph.
+fn chain_dir() -> PathBuf {
+    git_path("objects/info/commit-graphs")
+}
+
+/// A commit as stored in the commit-graph.
+#[derive(Debug, Clone)]
+pub(crate) struct GraphCommit {
+    /// Hex hash of the root tree.
+    pub(crate) tree: String,
+    /// Hex hashes of the parents, in order.
+    pub(crate) parents: Vec<String>,
+    /// The committer timestamp.
+    pub(crate) date: i64,
+    /// One more than the highest level of the parents, 1 for root commits.
+    pub(crate) level: u32,
+    /// The corrected commit date if the graph has them, else the topological level.
+    pub(crate) generation: u64,
+}
+
+/// One file of the commit-graph.
+struct Layer {
+    /// The path of the file.
+    path: PathBuf,
+    /// The contents of the file.
+    data: Vec<u8>,
+    /// The checksum at the end of the file, which names the layers of a chain.
+    checksum: String,
+    /// The number of commits in the layer.
+    count: usize,
+    /// The number of commits in the layers below.
+    base_count: usize,
+    /// The start and end of each chunk.
+    chunks: HashMap<[u8; 4], (usize, usize)>,
+    /// The settings of the changed-path Bloom filters, if the layer has them.
+    bloom: Option<bloom::Settings>,
+}
+
+impl Layer {
+    /// Open the layer at `path`, whose positions start at `base_count`.
+    fn open(path: &Path, base_count: usize) -> anyhow::Result<Layer> {
+        let data =
+            std::fs::read(path).with_context(|| format!("read commit-graph {}", path.display()))?;
+        let size = hash::algorithm().size();
+        anyhow::ensure!(
+            data.len() >= HEADER_SIZE + TOC_ENTRY_SIZE + size && &data[..4] == SIGNATURE,
+            "{} is not a commit-graph file",
+            path.display()
+        );
+        anyhow::ensure!(
+            data[4] == VERSION,
+            "commit-graph version {} does not match version {VERSION}",
+            data[4]
+        );
+        anyhow::ensure!(
+            data[5] == hash::algorithm().version(),
+            "commit-graph hash version {} does not match version {}",
+            data[5],
+            hash::algorithm().version()
+        );
+        let end = data.len() - size;
+        let mut chunks = HashMap::new();
+        for i in 0..usize::from(data[6]) {
+            let entry = HEADER_SIZE + i * TOC_ENTRY_SIZE;
+            let next = entry + TOC_ENTRY_SIZE;
+            anyhow::ensure!(
+                next + TOC_ENTRY_SIZE <= end,
+                "commit-graph chunk lookup table is truncated"
+            );
+            let id: [u8; 4] = data[entry..entry + 4].try_into().expect("4 bytes");
+            let start = usize::try_from(be64(&data[entry + 4..]))?;
+            let stop = usize::try_from(be64(&data[next + 4..]))?;
+            anyhow::ensure!(
+                start <= stop && stop <= end,
+                "commit-graph chunk {} is out of bounds",
+                String::from_utf8_lossy(&id)
+            );
+            chunks.insert(id, (start, stop));
+        }
+        for id in [OID_FANOUT, OID_LOOKUP, COMMIT_DATA] {
+            anyhow::ensure!(
+                chunks.contains_key(&id),
+                "commit-graph is missing the {} chunk",
+                String::from_utf8_lossy(&id)
+            );
+        }
+        let (start, stop) = chunks[&OID_LOOKUP];
+        let count = (stop - start) / size;
+        let chunk_len = |id| chunks[&id].1 - chunks[&id].0;
+        anyhow::ensure!(
+            chunk_len(OID_FANOUT) == 256 * 4 && chunk_len(COMMIT_DATA) == count * (size + 16),
+            "commit-graph {} has chunks of the wrong size",
+            path.display()
+        );
+        let mut layer = Layer {
+            path: path.to_path_buf(),
+            checksum: hex::encode(&data[end..]),
+            data,
+            count,
+            base_count,
+            chunks,
+            bloom: None,
+        };
+        layer.bloom = match (layer.chunk(BLOOM_INDEXES), layer.chunk(BLOOM_DATA)) {
+            (Some(indexes), Some(filters)) if indexes.len() == count * 4 && filters.len() >= 12 => {
+                Some(bloom::Settings {
+                    hash_version: be32(filters),
+                    num_hashes: be32(&filters[4..]),
+                    bits_per_entry: be32(&filters[8..]),
+                })
+            }
+            _ => None,
+        };
+        Ok(layer)
+    }
+
+    /// The contents of the chunk `id`, if the layer has it.
+    fn chunk(&self, id: [u8; 4]) -> Option<&[u8]> {
+        let &(start, stop) = self.chunks.get(&id)?;
+        Some(&self.data[start..stop])
+    }
+
+    /// A chunk that every layer has.
+    fn required(&self, id: [u8; 4]) -> &[u8] {
+        self.chunk(id).expect("required chunks are checked on open")
+    }
+
+    /// The id of the `i`th commit of the layer.
+    fn id(&self, i: usize) -> &[u8] {
+        let size = hash::algorithm().size();
+        &self.required(OID_LOOKUP)[i * size..(i + 1) * size]
+    }
+
+    /// The hex ids of the commits of the layer, sorted.
+    fn hashes(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.count).map(|i| hex::encode(self.id(i)))
+    }
+
+    /// The position of the commit `id` within the layer, if it has it.
+    fn find(&self, id: &[u8]) -> Option<usize> {
+        let fan_out = self.required(OID_FANOUT);
+        let first = usize::from(id[0]);
+        let mut low = if first == 0 {
+            0
+        } else {
+            be32(&fan_out[(first - 1) * 4..]) as usize
+        };
+        let mut high = (be32(&fan_out[first * 4..]) as usize).min(self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.id(mid).cmp(id) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    /// The changed-path Bloom filter of the `i`th commit of the layer.
+    fn filter(&self, i: usize) -> Option<(bloom::Settings, &[u8])> {
+        let settings = self.bloom?;
+        let indexes = self.chunk(BLOOM_INDEXES)?;
+        let filters = &self.chunk(BLOOM_DATA)?[12..];
+        let start = if i == 0 {
+            0
+        } else {
+            be32(&indexes[(i - 1) * 4..]) as usize
+        };
+        let end = be32(&indexes[i * 4..]) as usize;
+        Some((settings, filters.get(start..end)?))
+    }
+}
+
+/// The commit-graph of the repository.
+pub(crate) struct CommitGraph {
+    /// The layers, base first; a single-file graph is one layer.
+    layers: Vec<Layer>,
+    /// Whether every layer has corrected commit dates, which are then the generations.
+    generation_v2: bool,
+}
+
+impl CommitGraph {
+    /// Load the single-file commit-graph, or else the chain of layers, if there is either.
+    fn load() -> anyhow::Result<Option<CommitGraph>> {
+        let single = single_path();
+        let layers = if single.exists() {
+            vec![Layer::open(&single, 0)?]
+        } else {
+            let chain = match std::fs::read_to_string(chain_dir().join("commit-graph-chain")) {
+                Ok(chain) => chain,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e).context("read commit-graph chain"),
+            };
+            let mut layers: Vec<Layer> = Vec::new();
+            for checksum in chain.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                let base_count = layers.last().map_or(0, |l| l.base_count + l.count);
+                let layer = Layer::open(
+                    &chain_dir().join(format!("graph-{checksum}.graph")),
+                    base_count,
+                )?;
+                let bases: Vec<u8> = layers
+                    .iter()
+                    .map(|l| hex::decode(&l.checksum))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .concat();
+                anyhow::ensure!(
+                    layer.checksum == checksum
+                        && usize::from(layer.data[7]) == layers.len()
+                        && layer.chunk(BASE_GRAPHS).unwrap_or_default() == bases,
+                    "commit-graph chain does not match"
+                );
+                layers.push(layer);
+            }
+            layers
+        };
+        if layers.is_empty() {
+            return Ok(None);
+        }
+        let generation_v2 = layers
+            .iter()
+            .all(|layer| layer.chunk(GENERATION_DATA).is_some());
+        Ok(Some(CommitGraph {
+            layers,
+            generation_v2,
+        }))
+    }
+
+    /// The number of commits in the graph.
+    pub(crate) fn len(&self) -> usize {
+        self.layers.last().map_or(0, |l| l.base_count + l.count)
+    }
+
+    /// The layer with the commit at `position`, and its position within the layer.
+    fn layer(&self, position: usize) -> anyhow::Result<(&Layer, usize)> {
+        let layer = self
+            .layers
+            .iter()
+            .find(|l| position < l.base_count + l.count)
+            .with_context(|| format!("commit-graph position {position} is out of bounds"))?;
+        Ok((layer, position - layer.base_count))
+    }
+
+    /// The position of the commit `hash` in the bottom `layers` layers.
+    fn position_in(&self, hash: &str, layers: usize) -> Option<usize> {
+        let id = ObjectId::from_hex(hash).ok()?;
+        self.layers[..layers]
+            .iter()
+            .find_map(|layer| Some(layer.base_count + layer.find(id.as_bytes())?))
+    }
+
+    /// The position of the commit `hash`, if the graph has it.
+    pub(crate) fn position(&self, hash: &str) -> Option<usize> {
+        self.position_in(hash, self.layers.len())
+    }
+
+    /// The hex id of the commit at `position`.
+    pub(crate) fn hash(&self, position: usize) -> anyhow::Result<String> {
+        let (layer, i) = self.layer(position)?;
+        Ok(hex::encode(layer.id(i)))
+    }
+
+    /// The commit at `position`.
+    pub(crate) fn commit(&self, position: usize) -> anyhow::Result<GraphCommit> {
+        let (layer, i) = self.layer(position)?;
+        let size = hash::algorithm().size();
+        let entry = &layer.required(COMMIT_DATA)[i * (size + 16)..(i + 1) * (size + 16)];
+        let mut parents = Vec::new();
+        let first = be32(&entry[size..]);
+        if first != PARENT_NONE {
+            parents.push(self.hash(first as usize)?);
+        }
+        let second = be32(&entry[size + 4..]);
+        if second != PARENT_NONE && second & EDGE_FLAG == 0 {
+            parents.push(self.hash(second as usize)?);
+        } else if second != PARENT_NONE {
+            let edges = layer
+                .chunk(EXTRA_EDGES)
+                .context("commit-graph has no EDGE chunk")?;
+            let mut k = (second & !EDGE_FLAG) as usize;
+            loop {
+                let edge = be32(
+                    edges
+                        .get(k * 4..k * 4 + 4)
+                        .context("commit-graph EDGE chunk is truncated")?,
+                );
+                parents.push(self.hash((edge & !EDGE_FLAG) as usize)?);
+                if edge & EDGE_FLAG != 0 {
+                    break;
+                }
+                k += 1;
+            }
+        }
+        let high = be32(&entry[size + 8..]);
+        let date = i64::from(high & 3) << 32 | i64::from(be32(&entry[size + 12..]));
+        let level = high >> 2;
+        let generation = if self.generation_v2 {
+            let offsets = layer.required(GENERATION_DATA);
+            let offset = be32(&offsets[i * 4..]);
+            let offset = if offset & OFFSET_OVERFLOW == 0 {
+                u64::from(offset)
+            } else {
+                let k = (offset & !OFFSET_OVERFLOW) as usize * 8;
+                layer
+                    .chunk(GENERATION_OVERFLOW)
+                    .and_then(|overflow| overflow.get(k..k + 8))
+                    .map(be64)
+                    .context("commit-graph GDO2 chunk is missing or truncated")?
+            };
+            date as u64 + offset
+        } else {
+            u64::from(level)
+        };
+        Ok(GraphCommit {
+            tree: hex::encode(&entry[..size]),
+            parents,
+            date,
+            level,
+            generation,
+        })
+    }
+
+    /// The commit `hash`, if the graph has it.
+    pub(crate) fn lookup(&self, hash: &str) -> anyhow::Result<Option<GraphCommit>> {
+        self.position(hash)
+            .map(|position| self.commit(position))
+            .transpose()
+    }
+
+    /// The changed-path Bloom filter of the commit `hash`, and how it was built, if the graph has
+    /// one for it.
+    pub(crate) fn filter(&self, hash: &str) -> Option<(bloom::Settings, &[u8])> {
+        let (layer, i) = self.layer(self.position(hash)?).ok()?;
+        layer.filter(i)
+    }
+
+    /// Check the graph against the object database, printing every problem found. Returns
+    /// whether there were none.
+    pub(crate) fn verify(&self) -> anyhow::Result<bool> {
+        let mut ok = true;
+        let mut report = |message: String| {
+            eprintln!("{message}");
+            ok = false;
+        };
+        let size = hash::algorithm().size();
+        for layer in &self.layers {
+            let (content, checksum) = layer.data.split_at(layer.data.len() - size);
+            if Hasher::digest(content)?.as_bytes() != checksum {
+                report(
+                    "the commit-graph file has incorrect checksum and is likely corrupt"
+                        .to_string(),
+                );
+            }
+            for i in 1..layer.count {
+                if layer.id(i - 1) >= layer.id(i) {
+                    report(format!(
+                        "commit-graph has incorrect OID order: {} then {}",
+                        hex::encode(layer.id(i - 1)),
+                        hex::encode(layer.id(i))
+                    ));
+                }
+            }
+            let fan_out = layer.required(OID_FANOUT);
+            for byte in 0..256 {
+                let expected = (0..layer.count)
+                    .filter(|&i| usize::from(layer.id(i)[0]) <= byte)
+                    .count();
+                let actual = be32(&fan_out[byte * 4..]) as usize;
+                if actual != expected {
+                    report(format!(
+                        "commit-graph has incorrect fanout value: fanout[{byte}] = {actual} != {expected}"
+                    ));
+                }
+            }
+        }
+        for position in 0..self.len() {
+            let hash = self.hash(position)?;
+            let Ok(graph) = self.commit(position) else {
+                report(format!("failed to parse commit {hash} from commit-graph"));
+                continue;
+            };
+            let Ok(commit) = Commit::read(&hash) else {
+                report(format!(
+                    "failed to parse commit {hash} from object database for commit-graph"
+                ));
+                continue;
+            };
+            if graph.tree != commit.tree {
+                report(format!(
+                    "root tree OID for commit {hash} in commit-graph is {} != {}",
+                    graph.tree, commit.tree
+                ));
+            }
+            for (i, parent) in graph.parents.iter().enumerate() {
+                match commit.parents.get(i) {
+                    None => {
+                        report(format!(
+                            "commit-graph parent list for commit {hash} is too long"
+                        ));
+                        break;
+                    }
+                    Some(expected) if expected != parent => report(format!(
+                        "commit-graph parent for {hash} is {parent} != {expected}"
+                    )),
+                    _ => {}
+                }
+            }
+            if commit.parents.len() > graph.parents.len() {
+                report(format!(
+                    "commit-graph parent list for commit {hash} terminates early"
+                ));
+            }
+            let mut min_generation = 0;
+            for parent in &graph.parents {
+                if let Some(parent) = self.lookup(parent)? {
+                    min_generation = min_generation.max(parent.generation.saturating_add(1));
+                }
+            }
+            // Levels stop growing at their maximum.
+            if !self.generation_v2 {
+                min_generation = min_generation.min(u64::from(MAX_LEVEL));
+            }
+            if graph.generation < min_generation {
+                report(format!(
+                    "commit-graph generation for commit {hash} is {} < {min_generation}",
+                    graph.generation
+                ));
+            }
+            if graph.date != commit.committer_time() {
+                report(format!(
+                    "commit date for commit {hash} in commit-graph is {} != {}",
+                    graph.date,
+                    commit.committer_time()
+                ));
+            }
+        }
+        Ok(ok)
+    }
+}
+
+/// The commit-graph of the repository, loaded once and shared until [`reload`].
+static GRAPH: Mutex<Option<Option<Arc<CommitGraph>>>> = Mutex::new(None);
+
+/// The commit-graph of the repository, if it has one and `core.commitGraph` is not false.
+pub(crate) fn graph() -> anyhow::Result<Option<Arc<CommitGraph>>> {
+    let mut cached = GRAPH.lock().expect("commit-graph lock is not poisoned");
+    if let Some(graph) = &*cached {
+        return Ok(graph.clone());
+    }
+    let graph = if Config::load()?
+        .get_bool("core.commitGraph")?
+        .unwrap_or(true)
+    {
+        CommitGraph::load()?.map(Arc::new)
+    } else {
+        None
+    };
+    *cached = Some(graph.clone());
+    Ok(graph)
+}
+
+/// Forget the loaded commit-graph, so that one written since is seen.
+pub(crate) fn reload() {
+    *GRAPH.lock().expect("commit-graph lock is not poisoned") = None;
+}
+
+/// The commits the references point to, peeling annotated tags.
+pub(crate) fn reachable() -> anyhow::Result<Vec<String>> {
+    let mut commits = Vec::new();
+    for (_, mut hash) in refs::list()? {
+        loop {
+            let object = Object::read(&hash)?;
+            match object.kind {
+                Kind::Commit => commits.push(hash),
+                Kind::Tag => {
+                    hash = Tag::parse(&object.into_bytes()?)?.object;
+                    continue;
+                }
+                Kind::Blob | Kind::Tree => {}
+            }
+            break;
+        }
+    }
+    Ok(commits)
+}
+
+/// The commits in the packs of the repository.
+pub(crate) fn packed() -> anyhow::Result<Vec<String>> {
+    let mut commits = Vec::new();
+    for pack in pack::packs()?.iter() {
+        for (hash, kind) in pack.kinds()? {
+            if kind == Kind::Commit {
+                commits.push(hash);
+            }
+        }
+    }
+    Ok(commits)
+}
+
+/// How a split commit-graph gets its new layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Split {
+    /// Merge the top layers into the new one while they are not much larger than it.
+    #[value(skip)]
+    Merge,
+    /// Never merge layers.
+    NoMerge,
+    /// Merge all layers into the new one.
+    Replace,
+}
+
+/// How [`write`] writes the commit-graph.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WriteOptions {
+    /// Add a layer to a chain instead of writing a single file.
+    pub(crate) split: Option<Split>,
+    /// Whether to compute changed-path Bloom filters, by default only if the graph has them.
+    pub(crate) changed_paths: Option<bool>,
+    /// Layers of a chain are merged into the new layer while they have at most this many times
+    /// as many commits.
+    pub(crate) size_multiple: usize,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            split: None,
+            changed_paths: None,
+            size_multiple: 2,
+        }
+    }
+}
+
+/// The settings for new changed-path Bloom filters: the version from
+/// `commitGraph.changedPathsVersion`, else that of the existing filters, else version 1.
+fn bloom_settings(existing: Option<&CommitGraph>) -> anyhow::Result<bloom::Settings> {
+    let existing = existing.and_then(|graph| graph.layers.iter().find_map(|l| l.bloom));
+    let mut settings = existing.unwrap_or_default();
+    match Config::load()?.get_int("commitGraph.changedPathsVersion")? {
+        Some(version @ (1 | 2)) => settings.hash_version = u32::try_from(version)?,
+        None | Some(-1) => {}
+        Some(version) => anyhow::bail!("unsupported changed-path filter version {version}"),
+    }
+    Ok(settings)
+}
+
+/// Write a commit-graph with `commits` and every commit they reach.
+pub(crate) fn write(commits: &[String], options: WriteOptions) -> anyhow::Result<()> {
+    let existing = graph()?;
+    let existing = existing.as_deref();
+    let changed_paths = options.changed_paths.unwrap_or_else(|| {
+        existing.is_some_and(|graph| graph.layers.iter().any(|l| l.bloom.is_some()))
+    });
+    let settings = changed_paths
+        .then(|| bloom_settings(existing))
+        .transpose()?;
+
+    // The layers kept below the new one, and the commits the new one gets.
+    let mut kept = 0;
+    let mut new: BTreeSet<String> = commits.iter().cloned().collect();
+    if let (Some(graph), Some(split)) = (existing, options.split) {
+        kept = graph.layers.len();
+        new.retain(|hash| graph.position_in(hash, kept).is_none());
+        while kept > 0 {
+            let top = &graph.layers[kept - 1];
+            let merge = match split {
+                Split::Merge => top.count <= options.size_multiple * new.len(),
+                Split::NoMerge => false,
+                Split::Replace => true,
+            };
+            if !merge {
+                break;
+            }
+            new.extend(top.hashes());
+            kept -= 1;
+        }
+        if new.is_empty() {
+            return Ok(());
+        }
+    }
+    let below = |hash: &str| existing.and_then(|graph| graph.position_in(hash, kept));
+
+    // Every parent of a commit in the graph must be in the graph too.
+    let mut info: HashMap<String, (String, Vec<String>, i64)> = HashMap::new();
+    let mut pending: Vec<String> = new.iter().cloned().collect();
+    while let Some(hash) = pending.pop() {
+        let commit = match existing
+            .map(|graph| graph.lookup(&hash))
+            .transpose()?
+            .flatten()
+        {
+            Some(commit) => (commit.tree, commit.parents, commit.date),
+            None => {
+                let commit = Commit::read(&hash)
+                    .with_context(|| format!("invalid commit object id: {hash}"))?;
+                let date = commit.committer_time();
+                (commit.tree, commit.parents, date)
+            }
+        };
+        for parent in &commit.1 {
+            if below(parent).is_none() && new.insert(parent.clone()) {
+                pending.push(parent.clone());
+            }
+        }
+        info.insert(hash, commit);
+    }
+    if new.is_empty() {
+        return Ok(());
+    }
+
+    let base_count = existing.map_or(0, |graph| {
+        graph.layers[..kept]
+            .last()
+            .map_or(0, |l| l.base_count + l.count)
+    });
+    let sorted: Vec<&String> = new.iter().collect();
+    let local: HashMap<&str, usize> = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, hash)| (hash.as_str(), i))
+        .collect();
+    let position = |hash: &str| -> anyhow::Result<u32> {
+        let position = match local.get(hash) {
+            Some(&i) => base_count + i,
+            None => below(hash).with_context(|| format!("commit {hash} is not in the graph"))?,
+        };
+        Ok(u32::try_from(position)?)
+    };
+
+    // Generations of parents before their children.
+    let mut generations: HashMap<&str, (u32, u64)> = HashMap::new();
+    for &start in &sorted {
+        let mut stack = vec![start.as_str()];
+        while let Some(&hash) = stack.last() {
+            if generations.contains_key(hash) {
+                stack.pop();
+                continue;
+            }
+            let (_, parents, date) = &info[hash];
+            let waiting: Vec<&str> = parents
+                .iter()
+                .map(String::as_str)
+                .filter(|p| local.contains_key(p) && !generations.contains_key(p))
+                .collect();
+            if !waiting.is_empty() {
+                stack.extend(waiting);
+                continue;
+            }
+            let (mut level, mut corrected) = (0, 0);
+            for parent in parents {
+                let (parent_level, parent_corrected) = match generations.get(parent.as_str()) {
+                    Some(&generation) => generation,
+                    None => {
+                        let graph = existing.context("parent is in the graph")?;
+                        let commit = graph.commit(position(parent)? as usize)?;
+                        (commit.level, commit.generation)
+                    }
+                };
+                level = level.max(parent_level);
+                corrected = corrected.max(parent_corrected);
+            }
+            let level = (level + 1).min(MAX_LEVEL);
+            let corrected = (corrected + 1).max(u64::try_from((*date).max(0))?);
+            generations.insert(hash, (level, corrected));
+            stack.pop();
+        }
+    }
+
+    let size = hash::algorithm().size();
+    let mut fan_out = Vec::with_capacity(256 * 4);
+    for byte in 0..=255u8 {
+        let prefix = format!("{byte:02x}");
+        let count = sorted.partition_point(|hash| hash[..2] <= *prefix);
+        fan_out.extend(u32::try_from(count)?.to_be_bytes());
+    }
+    let mut lookup = Vec::with_capacity(sorted.len() * size);
+    let mut data = Vec::with_capacity(sorted.len() * (size + 16));
+    let mut edges: Vec<u8> = Vec::new();
+    let mut offsets = Vec::with_capacity(sorted.len() * 4);
+    let mut overflow: Vec<u8> = Vec::new();
+    for &hash in &sorted {
+        lookup.extend_from_slice(ObjectId::from_hex(hash)?.as_bytes());
+        let (tree, parents, date) = &info[hash.as_str()];
+        data.extend_from_slice(ObjectId::from_hex(tree)?.as_bytes());
+        let first = parents.first().map_or(Ok(PARENT_NONE), |p| position(p))?;
+        let second = match parents.len() {
+            0 | 1 => PARENT_NONE,
+            2 => position(&parents[1])?,
+            _ => {
+                let index = u32::try_from(edges.len() / 4)? | EDGE_FLAG;
+                for (i, parent) in parents.iter().enumerate().skip(1) {
+                    let last = if i == parents.len() - 1 { EDGE_FLAG } else { 0 };
+                    edges.extend((position(parent)? | last).to_be_bytes());
+                }
+                index
+            }
+        };
+        data.extend(first.to_be_bytes());
+        data.extend(second.to_be_bytes());
+        let date = u64::try_from((*date).max(0))?;
+        let (level, corrected) = generations[hash.as_str()];
+        data.extend((level << 2 | (date >> 32) as u32 & 3).to_be_bytes());
+        data.extend((date as u32).to_be_bytes());
+        let offset = corrected - date;
+        match u32::try_from(offset) {
+            Ok(small) if small & OFFSET_OVERFLOW == 0 => offsets.extend(small.to_be_bytes()),
+            _ => {
+                let index = u32::try_from(overflow.len() / 8)? | OFFSET_OVERFLOW;
+                offsets.extend(index.to_be_bytes());
+                overflow.extend(offset.to_be_bytes());
+            }
+        }
+    }
+
+    let mut chunks: Vec<([u8; 4], Vec<u8>)> = vec![
+        (OID_FANOUT, fan_out),
+        (OID_LOOKUP, lookup),
+        (COMMIT_DATA, data),
+    ];
+    // Corrected commit dates are only used if every layer has them.
+    let layers_below = existing.map_or(&[][..], |graph| &graph.layers[..kept]);
+    if layers_below
+        .iter()
+        .all(|layer| layer.chunk(GENERATION_DATA).is_some())
+    {
+        chunks.push((GENERATION_DATA, offsets));
+        if !overflow.is_empty() {
+            chunks.push((GENERATION_OVERFLOW, overflow));
+        }
+    }
+    if !edges.is_empty() {
+        chunks.push((EXTRA_EDGES, edges));
+    }
+    if let Some(settings) = settings {
+        let (indexes, filters) = filters(&sorted, &info, settings)?;
+        chunks.push((BLOOM_INDEXES, indexes));
+        chunks.push((BLOOM_DATA, filters));
+    }
+    if !layers_below.is_empty() {
+        let mut bases = Vec::new();
+        for layer in layers_below {
+            bases.extend(hex::decode(&layer.checksum)?);
+        }
+        chunks.push((BASE_GRAPHS, bases));
+    }
+
+    let mut file = Vec::new();
+    file.extend_from_slice(SIGNATURE);
+    file.extend([
+        VERSION,
+        hash::algorithm().version(),
+        u8::try_from(chunks.len())?,
+        u8::try_from(layers_below.len())?,
+    ]);
+    let mut offset = (HEADER_SIZE + (chunks.len() + 1) * TOC_ENTRY_SIZE) as u64;
+    for (id, chunk) in &chunks {
+        file.extend_from_slice(id);
+        file.extend(offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    file.extend([0; 4]);
+    file.extend(offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+        file.extend_from_slice(chunk);
+    }
+    let checksum = Hasher::digest(&file)?;
+    file.extend_from_slice(checksum.as_bytes());
+
+    if options.split.is_some() {
+        let dir = chain_dir();
+        install(&dir.join(format!("graph-{checksum}.graph")), &file)?;
+        let mut chain = String::new();
+        for layer in layers_below {
+            // Like Git, a single-file graph becomes the base layer of the new chain.
+            if layer.path == single_path() {
+                let path = dir.join(format!("graph-{}.graph", layer.checksum));
+                std::fs::rename(&layer.path, &path).with_context(|| {
+                    format!("move {} to {}", layer.path.display(), path.display())
+                })?;
+            }
+            chain.push_str(&format!("{}\n", layer.checksum));
+        }
+        chain.push_str(&format!("{checksum}\n"));
+        let mut lock = LockFile::acquire(dir.join("commit-graph-chain"))?;
+        lock.write_all(chain.as_bytes())?;
+        lock.commit()?;
+        remove_if_exists(&single_path())?;
+        expire(&chain)?;
+    } else {
+        install(&single_path(), &file)?;
+        remove_if_exists(&chain_dir().join("commit-graph-chain"))?;
+        expire("")?;
+    }
+    reload();
+    Ok(())
+}
+
+/// The `BIDX` and `BDAT` chunks for `commits`.
+fn filters(
+    commits: &[&String],
+    info: &HashMap<String, (String, Vec<String>, i64)>,
+    settings: bloom::Settings,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let mut indexes = Vec::with_capacity(commits.len() * 4);
+    let mut filters = Vec::new();
+    for value in [
+        settings.hash_version,
+        settings.num_hashes,
+        settings.bits_per_entry,
+    ] {
+        filters.extend(value.to_be_bytes());
+    }
+    for &hash in commits {
+        let (tree, parents, _) = &info[hash.as_str()];
+        // Filters compare against the first parent, and root commits against the empty tree.
+        let old = match parents.first() {
+            Some(parent) => {
+                let parent_tree = match info.get(parent) {
+                    Some((tree, _, _)) => tree.clone(),
+                    None => Commit::read(parent)?.tree,
+                };
+                Tree::read_flat(&parent_tree)?
+            }
+            None => FlatTree::new(),
+        };
+        let new = Tree::read_flat(tree)?;
+        filters.extend(settings.filter(bloom::changed_paths(&old, &new).as_ref()));
+        indexes.extend(u32::try_from(filters.len() - 12)?.to_be_bytes());
+    }
+    Ok((indexes, filters))
+}
+
+/// Write `data` to the read-only file `path`, replacing it atomically.
+fn install(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().expect("commit-graph files have a directory");
+    shared::create_dir_all(dir)?;
+    let tmp = dir.join(format!("tmp_graph_{}", std::process::id()));
+    std::fs::write(&tmp, data).with_context(|| format!("write {}", tmp.display()))?;
+    let mut permissions = std::fs::metadata(&tmp)?.permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&tmp, permissions)?;
+    shared::adjust(&tmp)?;
+    std::fs::rename(&tmp, path).with_context(|| format!("move commit-graph to {}", path.display()))
+}
+
+/// Remove the file at `path`, if there is one.
+fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Remove the layers that `chain` does not list.
+fn expire(chain: &str) -> anyhow::Result<()> {
+    let Ok(dir) = std::fs::read_dir(chain_dir()) else {
+        return Ok(());
+    };
+    for entry in dir {
+        let path = entry.context("read commit-graph directory")?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let Some(checksum) = name
+            .strip_prefix("graph-")
+            .and_then(|name| name.strip_suffix(".graph"))
+        else {
+            continue;
+        };
+        if !chain.lines().any(|line| line == checksum) {
+            remove_if_exists(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read a big-endian 32-bit number from the start of `data`.
+fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().expect("4 bytes"))
+}
+
+/// Read a big-endian 64-bit number from the start of `data`.
+fn be64(data: &[u8]) -> u64 {
+    u64::from_be_bytes(data[..8].try_into().expect("8 bytes"))
+}
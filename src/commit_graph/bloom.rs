@@ -0,0 +1,143 @@
+//! Changed-path Bloom filters, which tell for each commit of a commit-graph which paths it
+//! definitely did not change compared to its first parent.
+//!
+//! A filter has `bits_per_entry` bits for each changed path and each of their leading
+//! directories. A path sets `num_hashes` bits, derived from two seeded murmur3 hashes of it.
+//!
+//! See: <https://git-scm.com/docs/gitformat-commit-graph#_chunk_data>
+use std::collections::BTreeSet;
+
+use crate::objects::FlatTree;
+
+/// Commits that change more paths than this get a filter that matches every path.
+pub(crate) const MAX_CHANGED_PATHS: usize = 512;
+
+/// The seeds of the two murmur3 hashes a path's bits are derived from.
+const SEEDS: [u32; 2] = [0x293a_e76f, 0x7e64_6e2c];
+
+/// How filters are built, as stored at the start of the `BDAT` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Settings {
+    /// 1 for Git's original murmur3, which sign-extends bytes above 0x7f, 2 for the fixed one.
+    pub(crate) hash_version: u32,
+    /// How many bits each path sets.
+    pub(crate) num_hashes: u32,
+    /// How many bits a filter has per path.
+    pub(crate) bits_per_entry: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            hash_version: 1,
+            num_hashes: 7,
+            bits_per_entry: 10,
+        }
+    }
+}
+
+impl Settings {
+    /// The bit positions `path` sets, before reducing them to a filter's size.
+    pub(crate) fn key(&self, path: &str) -> Vec<u32> {
+        let [a, b] = SEEDS.map(|seed| murmur3(seed, path.as_bytes(), self.hash_version));
+        (0..self.num_hashes)
+            .map(|i| a.wrapping_add(i.wrapping_mul(b)))
+            .collect()
+    }
+
+    /// The filter of a commit that changed `paths`, as computed by [`changed_paths`].
+    pub(crate) fn filter(&self, paths: Option<&BTreeSet<String>>) -> Vec<u8> {
+        let Some(paths) = paths else {
+            return vec![0xff];
+        };
+        let len = (paths.len() * self.bits_per_entry as usize)
+            .div_ceil(8)
+            .max(1);
+        let mut filter = vec![0; len];
+        for path in paths {
+            for bit in self.key(path) {
+                let bit = bit as u64 % (len as u64 * 8);
+                filter[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+}
+
+/// Whether `filter` may contain the path whose [`Settings::key`] is `key`. An empty filter was
+/// not computed and may contain anything.
+pub(crate) fn contains(filter: &[u8], key: &[u32]) -> bool {
+    if filter.is_empty() {
+        return true;
+    }
+    let bits = filter.len() as u64 * 8;
+    key.iter().all(|&bit| {
+        let bit = u64::from(bit) % bits;
+        filter[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+    })
+}
+
+/// The paths that differ between `old` and `new` together with their leading directories, or
+/// `None` if more than [`MAX_CHANGED_PATHS`] files changed.
+pub(crate) fn changed_paths(old: &FlatTree, new: &FlatTree) -> Option<BTreeSet<String>> {
+    let changed: Vec<&String> = old
+        .iter()
+        .filter(|(path, entry)| new.get(*path) != Some(entry))
+        .map(|(path, _)| path)
+        .chain(new.keys().filter(|path| !old.contains_key(*path)))
+        .collect();
+    if changed.len() > MAX_CHANGED_PATHS {
+        return None;
+    }
+    let mut paths = BTreeSet::new();
+    for path in changed {
+        let mut path = path.as_str();
+        paths.insert(path.to_string());
+        while let Some((dir, _)) = path.rsplit_once('/') {
+            paths.insert(dir.to_string());
+            path = dir;
+        }
+    }
+    Some(paths)
+}
+
+/// The 32-bit murmur3 hash of `data`.
+///
+/// Version 1 reproduces Git's original implementation, which read bytes as signed characters.
+fn murmur3(mut seed: u32, data: &[u8], version: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let byte = |b: u8| {
+        if version == 1 {
+            b as i8 as u32
+        } else {
+            u32::from(b)
+        }
+    };
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let k = byte(chunk[0]) | byte(chunk[1]) << 8 | byte(chunk[2]) << 16 | byte(chunk[3]) << 24;
+        seed ^= mix(k);
+        seed = seed
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .enumerate()
+            .fold(0, |k, (i, &b)| k ^ byte(b) << (8 * i));
+        seed ^= mix(k);
+    }
+
+    seed ^= data.len() as u32;
+    seed ^= seed >> 16;
+    seed = seed.wrapping_mul(0x85eb_ca6b);
+    seed ^= seed >> 13;
+    seed = seed.wrapping_mul(0xc2b2_ae35);
+    seed ^ (seed >> 16)
+}
@@ -0,0 +1,745 @@
+//! Configuration files, e.g. `.git/config` and `~/.gitconfig`.
+//!
+//! See: <https://git-scm.com/docs/git-config#_configuration_file>
+use anyhow::Context;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::lockfile::LockFile;
+use crate::repository;
+
+/// How deeply `include.path` and `includeIf.<condition>.path` may nest.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// A set of configuration files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope {
+    /// The configuration for all users, e.g. `/etc/gitconfig`.
+    System,
+    /// The configuration of the current user, `$XDG_CONFIG_HOME/git/config` and `~/.gitconfig`.
+    Global,
+    /// The configuration of the repository, `.git/config`.
+    Local,
+}
+
+impl Scope {
+    /// The files of the scope, in the order they are read.
+    fn paths(self) -> Vec<PathBuf> {
+        match self {
+            Scope::System => {
+                let disabled = std::env::var("GIT_CONFIG_NOSYSTEM").is_ok_and(|value| {
+                    parse_bool("GIT_CONFIG_NOSYSTEM", Some(&value)).unwrap_or(true)
+                });
+                if disabled {
+                    return Vec::new();
+                }
+                vec![self.path().unwrap_or_default()]
+            }
+            Scope::Global => {
+                if let Some(path) = std::env::var_os("GIT_CONFIG_GLOBAL") {
+                    return vec![PathBuf::from(path)];
+                }
+                let mut paths = Vec::new();
+                match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+                    Some(dir) => paths.push(Path::new(&dir).join("git/config")),
+                    None => paths.extend(home().map(|home| home.join(".config/git/config"))),
+                }
+                paths.extend(home().map(|home| home.join(".gitconfig")));
+                paths
+            }
+            Scope::Local => repository::get()
+                .map(|repository| repository.git_dir.join("config"))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// The file that variables of the scope are written to.
+    pub(crate) fn path(self) -> anyhow::Result<PathBuf> {
+        Ok(match self {
+            Scope::System => std::env::var_os("GIT_CONFIG_SYSTEM")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("/etc/gitconfig")),
+            Scope::Global => match std::env::var_os("GIT_CONFIG_GLOBAL") {
+                Some(path) => PathBuf::from(path),
+                None => {
+                    let home = home().context("$HOME not set")?;
+                    let user = home.join(".gitconfig");
+                    // Like Git, prefer the XDG file only if it exists and `~/.gitconfig` does not.
+                    match self.paths().into_iter().next() {
+                        Some(xdg) if !user.exists() && xdg.exists() => xdg,
+                        _ => user,
+                    }
+                }
+            },
+            Scope::Local => repository::get()
+                .context("not in a git directory")?
+                .git_dir
+                .join("config"),
+        })
+    }
+}
+
+/// The home directory of the current user.
+fn home() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Expand a leading `~/` of `path` to the home directory.
+pub(crate) fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// A variable set by a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    /// The canonical name of the variable: the section and variable name in lower case, and the
+    /// subsection as written, e.g. `branch.Topic.remote`.
+    pub(crate) key: String,
+    /// The value, or `None` for a variable without `=`, which counts as a boolean true.
+    pub(crate) value: Option<String>,
+    /// The file that set the variable.
+    pub(crate) origin: PathBuf,
+}
+
+/// Variables read from configuration files, in the order they were set so that later values
+/// override earlier ones.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Config {
+    pub(crate) entries: Vec<Entry>,
+}
+
+impl Config {
+    /// Read the system, global and repository configuration.
+    pub(crate) fn load() -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        for scope in [Scope::System, Scope::Global, Scope::Local] {
+            for path in scope.paths() {
+                config.read_file(&path, Some(0))?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read the system, global and repository configuration, leaving out the files they include.
+    pub(crate) fn load_without_includes() -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        for scope in [Scope::System, Scope::Global, Scope::Local] {
+            for path in scope.paths() {
+                config.read_file(&path, None)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read a single configuration file and the files it includes.
+    pub(crate) fn from_file(path: &Path) -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        config.read_file(path, Some(0))?;
+        Ok(config)
+    }
+
+    /// Read a single configuration file, leaving out the files it includes but keeping the
+    /// `include.path` variables themselves, like `git config --file` without `--includes`.
+    pub(crate) fn from_file_without_includes(path: &Path) -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        config.read_file(path, None)?;
+        Ok(config)
+    }
+
+    /// Read the configuration file at `path`, if it exists, and the files it includes.
+    ///
+    /// `depth` is how deeply the file is nested in includes, or `None` not to read the files it
+    /// includes.
+    fn read_file(&mut self, path: &Path, depth: Option<usize>) -> anyhow::Result<()> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => {
+                return Err(error).with_context(|| format!("read config file {}", path.display()))
+            }
+        };
+        for item in parse(&text, path)? {
+            let Item::Variable { key, value, .. } = item else {
+                continue;
+            };
+            let include = match depth {
+                Some(depth) => {
+                    included_path(&key, value.as_deref(), path)?.map(|include| (include, depth))
+                }
+                None => None,
+            };
+            self.entries.push(Entry {
+                key,
+                value,
+                origin: path.to_path_buf(),
+            });
+            if let Some((include, depth)) = include {
+                anyhow::ensure!(
+                    depth < MAX_INCLUDE_DEPTH,
+                    "exceeded maximum include depth ({MAX_INCLUDE_DEPTH}) while including\n\t{}\nfrom\n\t{}",
+                    include.display(),
+                    path.display()
+                );
+                self.read_file(&include, Some(depth + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All the values of `key`, in the order they were set.
+    pub(crate) fn get_all(&self, key: &str) -> Vec<&Entry> {
+        let Ok(key) = canonical_key(key) else {
+            return Vec::new();
+        };
+        self.entries
+            .iter()
+            .filter(|entry| entry.key == key)
+            .collect()
+    }
+
+    /// The value of `key`, the last one if it is set several times.
+    pub(crate) fn get(&self, key: &str) -> Option<&Entry> {
+        self.get_all(key).pop()
+    }
+
+    /// The value of `key` as a string, empty for a variable without a value.
+    pub(crate) fn get_string(&self, key: &str) -> Option<&str> {
+        self.get(key)
+            .map(|entry| entry.value.as_deref().unwrap_or_default())
+    }
+
+    /// The value of `key` as a boolean.
+    pub(crate) fn get_bool(&self, key: &str) -> anyhow::Result<Option<bool>> {
+        self.get(key)
+            .map(|entry| parse_bool(&entry.key, entry.value.as_deref()))
+            .transpose()
+    }
+
+    /// The value of `key` as an integer, which may have a `k`, `m` or `g` suffix.
+    pub(crate) fn get_int(&self, key: &str) -> anyhow::Result<Option<i64>> {
+        self.get(key)
+            .map(|entry| parse_int(&entry.key, entry.value.as_deref()))
+            .transpose()
+    }
+}
+
+/// Read the value of `key` from the system, global and repository configuration, e.g.
+/// `user.name`.
+pub(crate) fn get(key: &str) -> anyhow::Result<Option<String>> {
+    Ok(Config::load()?.get_string(key).map(str::to_string))
+}
+
+/// Parse a boolean value; a variable without a value is true.
+pub(crate) fn parse_bool(key: &str, value: Option<&str>) -> anyhow::Result<bool> {
+    let Some(value) = value else {
+        return Ok(true);
+    };
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" | "" => Ok(false),
+        _ => Ok(parse_int(key, Some(value))
+            .with_context(|| format!("bad boolean config value '{value}' for '{key}'"))?
+            != 0),
+    }
+}
+
+/// Parse an integer value, scaled by 1024, 1024² or 1024³ with a `k`, `m` or `g` suffix.
+pub(crate) fn parse_int(key: &str, value: Option<&str>) -> anyhow::Result<i64> {
+    let value = value.with_context(|| format!("missing value for '{key}'"))?;
+    let invalid = || format!("bad numeric config value '{value}' for '{key}': invalid unit");
+    let trimmed = value.trim();
+    let (number, factor) = match trimmed.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&trimmed[..trimmed.len() - 1], 1 << 10),
+        Some('m') => (&trimmed[..trimmed.len() - 1], 1 << 20),
+        Some('g') => (&trimmed[..trimmed.len() - 1], 1 << 30),
+        _ => (trimmed, 1),
+    };
+    let number: i64 = number.parse().with_context(invalid)?;
+    number
+        .checked_mul(factor)
+        .with_context(|| format!("bad numeric config value '{value}' for '{key}': out of range"))
+}
+
+/// The section, optional subsection and variable name of `key`, validated but keeping their case.
+fn split_key(key: &str) -> anyhow::Result<(&str, Option<&str>, &str)> {
+    let (section, rest) = key
+        .split_once('.')
+        .with_context(|| format!("key does not contain a section: {key}"))?;
+    let (subsection, name) = match rest.rsplit_once('.') {
+        Some((subsection, name)) => (Some(subsection), name),
+        None => (None, rest),
+    };
+    anyhow::ensure!(
+        !name.is_empty(),
+        "key does not contain variable name: {key}"
+    );
+    let valid_section = !section.is_empty()
+        && section
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    anyhow::ensure!(valid_section && valid_name, "invalid key: {key}");
+    Ok((section, subsection, name))
+}
+
+/// The canonical form of `key`, see [`Entry::key`].
+pub(crate) fn canonical_key(key: &str) -> anyhow::Result<String> {
+    let (section, subsection, name) = split_key(key)?;
+    Ok(match subsection {
+        Some(subsection) => format!(
+            "{}.{subsection}.{}",
+            section.to_ascii_lowercase(),
+            name.to_ascii_lowercase()
+        ),
+        None => format!(
+            "{}.{}",
+            section.to_ascii_lowercase(),
+            name.to_ascii_lowercase()
+        ),
+    })
+}
+
+/// The file that `key` includes, if it is `include.path` or an `includeIf.<condition>.path`
+/// whose condition holds.
+fn included_path(key: &str, value: Option<&str>, file: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let condition = match key.strip_prefix("includeif.") {
+        Some(rest) => match rest.strip_suffix(".path") {
+            Some(condition) => Some(condition),
+            None => return Ok(None),
+        },
+        None if key == "include.path" => None,
+        None => return Ok(None),
+    };
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    if let Some(condition) = condition {
+        if !condition_holds(condition, file)? {
+            return Ok(None);
+        }
+    }
+    let path = expand_home(value);
+    Ok(Some(if path.is_relative() {
+        file.parent().unwrap_or(Path::new("")).join(path)
+    } else {
+        path
+    }))
+}
+
+/// Whether the condition of an `includeIf` section holds; only `gitdir:` and `gitdir/i:` are
+/// supported.
+fn condition_holds(condition: &str, file: &Path) -> anyhow::Result<bool> {
+    let (pattern, ignore_case) = if let Some(pattern) = condition.strip_prefix("gitdir:") {
+        (pattern, false)
+    } else if let Some(pattern) = condition.strip_prefix("gitdir/i:") {
+        (pattern, true)
+    } else {
+        return Ok(false);
+    };
+    let Some(Ok(git_dir)) = repository::get().map(|repository| repository.git_dir.canonicalize())
+    else {
+        return Ok(false);
+    };
+
+    let mut pattern = match pattern.strip_prefix("./") {
+        Some(rest) => {
+            let dir = std::fs::canonicalize(file.parent().unwrap_or(Path::new(".")))
+                .context("resolve directory of config file")?;
+            dir.join(rest).to_string_lossy().into_owned()
+        }
+        None => expand_home(pattern).to_string_lossy().into_owned(),
+    };
+    if !pattern.starts_with('/') {
+        pattern.insert_str(0, "**/");
+    }
+    if pattern.ends_with('/') {
+        pattern.push_str("**");
+    }
+    let mut git_dir = git_dir.to_string_lossy().into_owned();
+    if ignore_case {
+        pattern = pattern.to_lowercase();
+        git_dir = git_dir.to_lowercase();
+    }
+    Ok(wildmatch(pattern.as_bytes(), git_dir.as_bytes()))
+}
+
+/// Match `text` against a glob `pattern` in which `*` and `?` do not match `/` but `**` does.
+pub(crate) fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` also matches no directory at all.
+            if let Some(after) = rest.strip_prefix(b"/") {
+                if wildmatch(after, text) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|i| wildmatch(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| wildmatch(rest, &text[i..])),
+        [b'?', rest @ ..] => {
+            matches!(text.first(), Some(&c) if c != b'/') && wildmatch(rest, &text[1..])
+        }
+        [b'[', class @ ..] => {
+            let negate = matches!(class.first(), Some(b'!' | b'^'));
+            let class = if negate { &class[1..] } else { class };
+            // A `]` right after the opening bracket is part of the class.
+            let Some(end) = class.iter().skip(1).position(|&c| c == b']').map(|i| i + 1) else {
+                return text.first() == Some(&b'[') && wildmatch(class, &text[1..]);
+            };
+            let Some(&c) = text.first().filter(|&&c| c != b'/') else {
+                return false;
+            };
+            let (set, rest) = (&class[..end], &class[end + 1..]);
+            let mut matched = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == b'-' {
+                    matched |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    matched |= set[i] == c;
+                    i += 1;
+                }
+            }
+            matched != negate && wildmatch(rest, &text[1..])
+        }
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => {
+            text.first() == Some(c) && wildmatch(rest, &text[1..])
+        }
+    }
+}
+
+/// A section header or variable of a configuration file.
+#[derive(Debug, Clone)]
+enum Item {
+    Section {
+        /// The canonical name of the section, e.g. `branch.Topic`.
+        name: String,
+        /// The line of the header.
+        line: usize,
+    },
+    Variable {
+        /// See [`Entry::key`].
+        key: String,
+        /// See [`Entry::value`].
+        value: Option<String>,
+        /// The lines spanned by the variable, more than one if it has continuation lines.
+        lines: Range<usize>,
+    },
+}
+
+/// Reads the characters of a configuration file, keeping track of the line.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r')) {
+            self.bump();
+        }
+    }
+
+    /// Skip the rest of the line, including the newline.
+    fn skip_line(&mut self) {
+        while let Some(c) = self.bump() {
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(c) = self.peek().filter(|&c| predicate(c)) {
+            taken.push(c);
+            self.bump();
+        }
+        taken
+    }
+
+    /// Parse a section header after its `[`, returning the canonical section name.
+    fn section_header(&mut self) -> Option<String> {
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if name.is_empty() {
+            return None;
+        }
+        match self.bump()? {
+            // The deprecated `[section.subsection]` syntax is case-insensitive.
+            ']' => Some(name.to_ascii_lowercase()),
+            ' ' | '\t' if !name.contains('.') => {
+                self.skip_whitespace();
+                if self.bump()? != '"' {
+                    return None;
+                }
+                let mut subsection = String::new();
+                loop {
+                    match self.bump()? {
+                        '"' => break,
+                        '\n' => return None,
+                        '\\' => subsection.push(self.bump().filter(|&c| c != '\n')?),
+                        c => subsection.push(c),
+                    }
+                }
+                (self.bump()? == ']').then(|| format!("{}.{subsection}", name.to_ascii_lowercase()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse a value after its `=`, up to and including the end of the line.
+    fn value(&mut self) -> Option<String> {
+        let mut value = String::new();
+        let mut quoted = false;
+        let mut comment = false;
+        let mut spaces = 0;
+        loop {
+            let c = self.bump().unwrap_or('\n');
+            if c == '\n' {
+                return (!quoted).then_some(value);
+            }
+            if comment {
+                continue;
+            }
+            if c.is_whitespace() && !quoted {
+                // Whitespace is kept between words but trimmed at both ends.
+                if !value.is_empty() {
+                    spaces += 1;
+                }
+                continue;
+            }
+            if !quoted && (c == '#' || c == ';') {
+                comment = true;
+                continue;
+            }
+            value.extend(std::iter::repeat(' ').take(spaces));
+            spaces = 0;
+            match c {
+                '\\' => match self.bump().unwrap_or('\n') {
+                    '\n' => {}
+                    't' => value.push('\t'),
+                    'b' => value.push('\u{8}'),
+                    'n' => value.push('\n'),
+                    c @ ('\\' | '"') => value.push(c),
+                    _ => return None,
+                },
+                '"' => quoted = !quoted,
+                c => value.push(c),
+            }
+        }
+    }
+}
+
+/// Parse the configuration file `text` read from `path`.
+fn parse(text: &str, path: &Path) -> anyhow::Result<Vec<Item>> {
+    let mut parser = Parser {
+        chars: text.trim_start_matches('\u{feff}').chars().collect(),
+        pos: 0,
+        line: 0,
+    };
+    let bad =
+        |line: usize| anyhow::anyhow!("bad config line {} in file {}", line + 1, path.display());
+    let mut items = Vec::new();
+    let mut section: Option<String> = None;
+    loop {
+        parser.skip_whitespace();
+        let first = parser.line;
+        match parser.peek() {
+            None => break,
+            Some('\n') => {
+                parser.bump();
+            }
+            Some('#' | ';') => parser.skip_line(),
+            Some('[') => {
+                parser.bump();
+                let name = parser.section_header().ok_or_else(|| bad(first))?;
+                items.push(Item::Section {
+                    name: name.clone(),
+                    line: first,
+                });
+                section = Some(name);
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let name = parser
+                    .take_while(|c| c.is_ascii_alphanumeric() || c == '-')
+                    .to_ascii_lowercase();
+                let section = section.as_ref().ok_or_else(|| bad(first))?;
+                parser.skip_whitespace();
+                let value = match parser.bump() {
+                    Some('=') => Some(parser.value().ok_or_else(|| bad(parser.line))?),
+                    None | Some('\n') => None,
+                    Some(_) => return Err(bad(first)),
+                };
+                items.push(Item::Variable {
+                    key: format!("{section}.{name}"),
+                    value,
+                    lines: first..parser.line.max(first + 1),
+                });
+            }
+            Some(_) => return Err(bad(first)),
+        }
+    }
+    Ok(items)
+}
+
+/// Quote and escape `value` so that it reads back unchanged.
+fn quote_value(value: &str) -> String {
+    let needs_quotes = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    let mut quoted = String::new();
+    if needs_quotes {
+        quoted.push('"');
+    }
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\u{8}' => quoted.push_str("\\b"),
+            c => quoted.push(c),
+        }
+    }
+    if needs_quotes {
+        quoted.push('"');
+    }
+    quoted
+}
+
+/// The lines of the configuration file at `path` and what they contain.
+fn read_lines(path: &Path) -> anyhow::Result<(Vec<String>, Vec<Item>)> {
+    let mut text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(error) => {
+            return Err(error).with_context(|| format!("read config file {}", path.display()))
+        }
+    };
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    let items = parse(&text, path)?;
+    Ok((
+        text.split_inclusive('\n').map(str::to_string).collect(),
+        items,
+    ))
+}
+
+/// Replace the configuration file at `path` with `lines`, through a lock file.
+fn write_lines(path: &Path, lines: &[String]) -> anyhow::Result<()> {
+    let mut lock = LockFile::acquire(path)
+        .with_context(|| format!("could not lock config file {}", path.display()))?;
+    lock.write_all(lines.concat().as_bytes())?;
+    lock.commit()
+}
+
+/// The lines of the variables named `key`.
+fn matching_lines(items: &[Item], key: &str) -> Vec<Range<usize>> {
+    items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Variable { key: k, lines, .. } if k == key => Some(lines.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Set `key` to `value` in the configuration file at `path`: replace its value, or add it if it
+/// is not set yet or `add` is true.
+pub(crate) fn set(path: &Path, key: &str, value: &str, add: bool) -> anyhow::Result<()> {
+    let (section, subsection, name) = split_key(key)?;
+    let canonical = canonical_key(key)?;
+    let (mut lines, items) = read_lines(path)?;
+    let variable = format!("\t{name} = {}\n", quote_value(value));
+
+    let existing = matching_lines(&items, &canonical);
+    if !add && existing.len() > 1 {
+        anyhow::bail!(
+            "cannot overwrite multiple values with a single value\n       \
+             Use a regexp, --add or --replace-all to change {key}."
+        );
+    }
+    match existing.first().filter(|_| !add) {
+        Some(range) => {
+            lines.splice(range.clone(), [variable]);
+        }
+        None => {
+            // Add the variable to the end of the last section with the same name.
+            let section_name = canonical
+                .rsplit_once('.')
+                .map_or("", |(section, _)| section);
+            let end = items
+                .iter()
+                .filter_map(|item| match item {
+                    Item::Section { name, line } if name == section_name => Some(line + 1),
+                    Item::Variable { key, lines, .. }
+                        if key.rsplit_once('.').map(|(section, _)| section)
+                            == Some(section_name) =>
+                    {
+                        Some(lines.end)
+                    }
+                    _ => None,
+                })
+                .max();
+            match end {
+                Some(end) => lines.insert(end.min(lines.len()), variable),
+                None => {
+                    let header = match subsection {
+                        Some(subsection) => format!(
+                            "[{section} \"{}\"]\n",
+                            subsection.replace('\\', "\\\\").replace('"', "\\\"")
+                        ),
+                        None => format!("[{section}]\n"),
+                    };
+                    lines.push(header);
+                    lines.push(variable);
+                }
+            }
+        }
+    }
+    write_lines(path, &lines)
+}
+
+/// Remove `key` from the configuration file at `path`, returning how many values were removed.
+/// Unless `all` is true, fails if it has several values.
+pub(crate) fn unset(path: &Path, key: &str, all: bool) -> anyhow::Result<usize> {
+    let canonical = canonical_key(key)?;
+    let (mut lines, items) = read_lines(path)?;
+    let existing = matching_lines(&items, &canonical);
+    if !all && existing.len() > 1 {
+        anyhow::bail!("{key} has multiple values");
+    }
+    for range in existing.iter().rev() {
+        lines.drain(range.clone());
+    }
+    if !existing.is_empty() {
+        write_lines(path, &lines)?;
+    }
+    Ok(existing.len())
+}
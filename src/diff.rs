@@ -0,0 +1,620 @@
+//! Line-based diffing.
+//!
+//! See: <http://www.xmailserver.org/diff2.pdf>
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::Write;
+use std::ops::Range;
+
+use crate::objects::{self, FlatTree, Object, TreeEntry, MODE_GITLINK};
+
+/// The algorithm used to compute line differences, see `diff.algorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Algorithm {
+    /// The basic greedy diff algorithm.
+    #[default]
+    Myers,
+    /// Anchor the diff on lines that are unique on both sides first.
+    Patience,
+}
+
+/// A run of `len` equal items starting at `a` in the old sequence and at `b` in the new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Match {
+    pub(crate) a: usize,
+    pub(crate) b: usize,
+    pub(crate) len: usize,
+}
+
+/// Split `data` into lines, keeping the line terminators.
+pub(crate) fn lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|&b| b == b'\n').collect()
+}
+
+/// Whether `data` looks like binary content, using the same heuristic as Git: a NUL byte within
+/// the first 8000 bytes.
+pub(crate) fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(8000)].contains(&0)
+}
+
+/// Compute the matching runs between `a` and `b` using Myers' O(ND) algorithm in linear space.
+///
+/// The matches are sorted and non-overlapping.
+pub(crate) fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Match> {
+    let max = a.len() + b.len() + 1;
+    let mut vf = V::new(max);
+    let mut vb = V::new(max);
+    let mut matches = Vec::new();
+    conquer(a, 0..a.len(), b, 0..b.len(), &mut vf, &mut vb, &mut matches);
+    coalesce(matches)
+}
+
+/// Compute the matching runs between `a` and `b` with the given algorithm.
+pub(crate) fn diff<T: Eq + Hash>(algorithm: Algorithm, a: &[T], b: &[T]) -> Vec<Match> {
+    match algorithm {
+        Algorithm::Myers => myers(a, b),
+        Algorithm::Patience => patience(a, b),
+    }
+}
+
+/// Compute the matching runs between `a` and `b` using the patience diff algorithm: lines that
+/// occur exactly once on both sides anchor the diff, and the gaps between anchors are diffed
+/// recursively, falling back to Myers' algorithm where there are no unique lines.
+///
+/// See: <https://bramcohen.livejournal.com/73318.html>
+pub(crate) fn patience<T: Eq + Hash>(a: &[T], b: &[T]) -> Vec<Match> {
+    let max = a.len() + b.len() + 1;
+    let mut vf = V::new(max);
+    let mut vb = V::new(max);
+    let mut matches = Vec::new();
+    patience_range(a, 0..a.len(), b, 0..b.len(), &mut vf, &mut vb, &mut matches);
+    matches.sort_by_key(|m| m.a);
+    coalesce(matches)
+}
+
+fn patience_range<T: Eq + Hash>(
+    a: &[T],
+    ar: Range<usize>,
+    b: &[T],
+    br: Range<usize>,
+    vf: &mut V,
+    vb: &mut V,
+    matches: &mut Vec<Match>,
+) {
+    if ar.is_empty() || br.is_empty() {
+        return;
+    }
+
+    // Count occurrences on both sides to find lines unique to each.
+    let mut counts: HashMap<&T, (usize, usize, usize)> = HashMap::new();
+    for i in ar.clone() {
+        let entry = counts.entry(&a[i]).or_insert((0, 0, i));
+        entry.0 += 1;
+        entry.2 = i;
+    }
+    for j in br.clone() {
+        if let Some(entry) = counts.get_mut(&b[j]) {
+            entry.1 += 1;
+        }
+    }
+    let unique: Vec<(usize, usize)> = br
+        .clone()
+        .filter_map(|j| match counts.get(&b[j]) {
+            Some(&(1, 1, i)) => Some((i, j)),
+            _ => None,
+        })
+        .collect();
+    if unique.is_empty() {
+        conquer(a, ar, b, br, vf, vb, matches);
+        return;
+    }
+
+    // Longest increasing subsequence of `a` positions, in `b` order, via patience sorting.
+    let mut piles: Vec<usize> = Vec::new();
+    let mut back: Vec<Option<usize>> = Vec::with_capacity(unique.len());
+    for (n, &(i, _)) in unique.iter().enumerate() {
+        let pile = piles.partition_point(|&top| unique[top].0 < i);
+        back.push(if pile > 0 {
+            Some(piles[pile - 1])
+        } else {
+            None
+        });
+        if pile == piles.len() {
+            piles.push(n);
+        } else {
+            piles[pile] = n;
+        }
+    }
+    let mut anchors = Vec::with_capacity(piles.len());
+    let mut next = piles.last().copied();
+    while let Some(n) = next {
+        anchors.push(unique[n]);
+        next = back[n];
+    }
+    anchors.reverse();
+
+    let (mut i, mut j) = (ar.start, br.start);
+    for (ai, bj) in anchors {
+        patience_range(a, i..ai, b, j..bj, vf, vb, matches);
+        matches.push(Match {
+            a: ai,
+            b: bj,
+            len: 1,
+        });
+        (i, j) = (ai + 1, bj + 1);
+    }
+    patience_range(a, i..ar.end, b, j..br.end, vf, vb, matches);
+}
+
+/// Merge adjacent matches into single runs.
+fn coalesce(matches: Vec<Match>) -> Vec<Match> {
+    let mut result: Vec<Match> = Vec::with_capacity(matches.len());
+    for m in matches {
+        if m.len == 0 {
+            continue;
+        }
+        if let Some(last) = result.last_mut() {
+            if last.a + last.len == m.a && last.b + last.len == m.b {
+                last.len += m.len;
+                continue;
+            }
+        }
+        result.push(m);
+    }
+    result
+}
+
+/// Lines of context shown around changes in patches.
+pub(crate) const CONTEXT: usize = 3;
+
+/// A changed region: lines `a` of the old sequence were replaced by lines `b` of the new one.
+#[derive(Debug, Clone)]
+struct Change {
+    a: Range<usize>,
+    b: Range<usize>,
+}
+
+/// The changed regions between `a` and `b`.
+fn changes<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let end = Match {
+        a: a.len(),
+        b: b.len(),
+        len: 0,
+    };
+    for m in myers(a, b).into_iter().chain([end]) {
+        if m.a > i || m.b > j {
+            changes.push(Change {
+                a: i..m.a,
+                b: j..m.b,
+            });
+        }
+        (i, j) = (m.a + m.len, m.b + m.len);
+    }
+    changes
+}
+
+/// The number of inserted and deleted lines between `a` and `b`.
+pub(crate) fn count_changes(a: &[u8], b: &[u8]) -> (usize, usize) {
+    changes(&lines(a), &lines(b))
+        .iter()
+        .fold((0, 0), |(ins, del), c| (ins + c.b.len(), del + c.a.len()))
+}
+
+/// Format a hunk range as in `@@ -1,3 +1,4 @@`.
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+/// The text shown after a hunk header: the closest preceding line that looks like the start of
+/// a function, i.e. one starting with a letter, `_` or `$`.
+fn hunk_heading<'a>(a: &[&'a [u8]], before: usize) -> Option<&'a [u8]> {
+    let line = a[..before].iter().rev().find(|line| {
+        line.first()
+            .is_some_and(|&c| c.is_ascii_alphabetic() || c == b'_' || c == b'$')
+    })?;
+    let line = line.trim_ascii_end();
+    Some(&line[..line.len().min(80)])
+}
+
+/// Write the unified diff hunks between `a` and `b`, with `context` lines of context.
+pub(crate) fn write_hunks(out: &mut Vec<u8>, a: &[u8], b: &[u8], context: usize) {
+    let (a, b) = (lines(a), lines(b));
+    let changes = changes(&a, &b);
+    let line = |out: &mut Vec<u8>, prefix: u8, text: &[u8]| {
+        out.push(prefix);
+        out.extend_from_slice(text);
+        if !text.ends_with(b"\n") {
+            out.extend_from_slice(b"\n\\ No newline at end of file\n");
+        }
+    };
+
+    let mut k = 0;
+    while k < changes.len() {
+        let first = k;
+        while k + 1 < changes.len() && changes[k + 1].a.start - changes[k].a.end <= 2 * context {
+            k += 1;
+        }
+        let (start, end) = (&changes[first], &changes[k]);
+        let a_start = start.a.start.saturating_sub(context);
+        let a_end = (end.a.end + context).min(a.len());
+        let b_start = start.b.start - (start.a.start - a_start);
+        let b_end = end.b.end + (a_end - end.a.end);
+        write!(
+            out,
+            "@@ -{} +{} @@",
+            hunk_range(a_start, a_end - a_start),
+            hunk_range(b_start, b_end - b_start)
+        )
+        .unwrap();
+        if let Some(heading) = hunk_heading(&a, a_start) {
+            out.push(b' ');
+            out.extend_from_slice(heading);
+        }
+        out.push(b'\n');
+
+        let mut pos = a_start;
+        for change in &changes[first..=k] {
+            for text in &a[pos..change.a.start] {
+                line(out, b' ', text);
+            }
+            for text in &a[change.a.clone()] {
+                line(out, b'-', text);
+            }
+            for text in &b[change.b.clone()] {
+                line(out, b'+', text);
+            }
+            pos = change.a.end;
+        }
+        for text in &a[pos..a_end] {
+            line(out, b' ', text);
+        }
+        k += 1;
+    }
+}
+
+/// One side of a file diff: either text to compare line by line, or binary data of which only
+/// the size is kept.
+enum Contents {
+    /// Text, which is diffed line by line.
+    Text(Vec<u8>),
+    /// Binary data of this size.
+    Binary(u64),
+}
+
+impl Contents {
+    /// The size of the contents.
+    fn size(&self) -> u64 {
+        match self {
+            Contents::Text(data) => data.len() as u64,
+            Contents::Binary(size) => *size,
+        }
+    }
+}
+
+/// Read the side of a diff that `entry` is, or empty text if it does not exist.
+///
+/// Like in Git, blobs larger than `core.bigFileThreshold` are binary, and are not read.
+fn entry_contents(entry: Option<&TreeEntry>) -> anyhow::Result<Contents> {
+    let data = match entry {
+        None => Vec::new(),
+        Some(entry) if entry.mode == MODE_GITLINK => {
+            format!("Subproject commit {}\n", entry.hash).into_bytes()
+        }
+        Some(entry) => {
+            let object = Object::read(&entry.hash)?;
+            if object.expected_size > objects::big_file_threshold()? {
+                return Ok(Contents::Binary(object.expected_size));
+            }
+            object.into_bytes()?
+        }
+    };
+    Ok(if is_binary(&data) {
+        Contents::Binary(data.len() as u64)
+    } else {
+        Contents::Text(data)
+    })
+}
+
+/// Paths that differ between `old` and `new`, in order, with their old and new entries.
+fn changed_paths<'a>(
+    old: &'a FlatTree,
+    new: &'a FlatTree,
+) -> Vec<(&'a String, Option<&'a TreeEntry>, Option<&'a TreeEntry>)> {
+    let mut paths: Vec<&String> = old
+        .keys()
+        .chain(new.keys().filter(|p| !old.contains_key(*p)))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| (path, old.get(path), new.get(path)))
+        .filter(|(_, a, b)| a != b)
+        .collect()
+}
+
+/// Abbreviate an object hash as in `index` lines of patches.
+fn abbrev(entry: Option<&TreeEntry>) -> &str {
+    entry.map_or("0000000", |e| &e.hash[..7])
+}
+
+/// Write the differences between the trees `old` and `new` as a patch in `git diff` format.
+pub(crate) fn write_tree_patch(
+    out: &mut Vec<u8>,
+    old: &FlatTree,
+    new: &FlatTree,
+) -> anyhow::Result<()> {
+    for (path, a, b) in changed_paths(old, new) {
+        writeln!(out, "diff --git a/{path} b/{path}")?;
+        match (a, b) {
+            (None, Some(b)) => writeln!(out, "new file mode {:06o}", b.mode)?,
+            (Some(a), None) => writeln!(out, "deleted file mode {:06o}", a.mode)?,
+            (Some(a), Some(b)) if a.mode != b.mode => {
+                writeln!(out, "old mode {:06o}", a.mode)?;
+                writeln!(out, "new mode {:06o}", b.mode)?;
+            }
+            _ => {}
+        }
+        if a.map(|e| &e.hash) == b.map(|e| &e.hash) {
+            continue;
+        }
+        write!(out, "index {}..{}", abbrev(a), abbrev(b))?;
+        match (a, b) {
+            (Some(a), Some(b)) if a.mode == b.mode => writeln!(out, " {:06o}", a.mode)?,
+            _ => writeln!(out)?,
+        }
+        let old_name = a.map_or("/dev/null".to_string(), |_| format!("a/{path}"));
+        let new_name = b.map_or("/dev/null".to_string(), |_| format!("b/{path}"));
+        let (Contents::Text(old_data), Contents::Text(new_data)) =
+            (entry_contents(a)?, entry_contents(b)?)
+        else {
+            writeln!(out, "Binary files {old_name} and {new_name} differ")?;
+            continue;
+        };
+        writeln!(out, "--- {old_name}")?;
+        writeln!(out, "+++ {new_name}")?;
+        write_hunks(out, &old_data, &new_data, CONTEXT);
+    }
+    Ok(())
+}
+
+/// Write a summary of the differences between the trees `old` and `new` as in
+/// `git diff --stat`.
+pub(crate) fn write_tree_stat(
+    out: &mut Vec<u8>,
+    old: &FlatTree,
+    new: &FlatTree,
+) -> anyhow::Result<()> {
+    /// Total width of a stat line, as for an 80 column terminal.
+    const WIDTH: usize = 80;
+
+    enum Stat {
+        Text(usize, usize),
+        Binary(u64, u64),
+    }
+    let mut stats = Vec::new();
+    for (path, a, b) in changed_paths(old, new) {
+        let stat = match (entry_contents(a)?, entry_contents(b)?) {
+            (Contents::Text(old_data), Contents::Text(new_data)) => {
+                let (ins, del) = count_changes(&old_data, &new_data);
+                Stat::Text(ins, del)
+            }
+            (old, new) => Stat::Binary(old.size(), new.size()),
+        };
+        stats.push((path, stat));
+    }
+
+    let name_width = stats.iter().map(|(p, _)| p.len()).max().unwrap_or(0);
+    let max_change = stats
+        .iter()
+        .map(|(_, s)| match s {
+            Stat::Text(ins, del) => ins + del,
+            Stat::Binary(..) => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    let number_width = if stats.iter().any(|(_, s)| matches!(s, Stat::Binary(..))) {
+        max_change.to_string().len().max(3)
+    } else {
+        max_change.to_string().len()
+    };
+    let graph_width = WIDTH.saturating_sub(name_width + number_width + 6).max(6);
+    let scale = |n: usize| {
+        if max_change <= graph_width || n == 0 {
+            n
+        } else {
+            ((n * graph_width * 2 + max_change) / (max_change * 2)).max(1)
+        }
+    };
+
+    let (mut insertions, mut deletions) = (0, 0);
+    for (path, stat) in &stats {
+        match *stat {
+            Stat::Text(ins, del) => {
+                insertions += ins;
+                deletions += del;
+                writeln!(
+                    out,
+                    " {path:<name_width$} | {:>number_width$}{}{}{}",
+                    ins + del,
+                    if ins + del > 0 { " " } else { "" },
+                    "+".repeat(scale(ins)),
+                    "-".repeat(scale(del))
+                )?;
+            }
+            Stat::Binary(old_size, new_size) => writeln!(
+                out,
+                " {path:<name_width$} | {:>number_width$} {old_size} -> {new_size} bytes",
+                "Bin"
+            )?,
+        }
+    }
+    let files = stats.len();
+    write!(
+        out,
+        " {files} file{} changed",
+        if files == 1 { "" } else { "s" }
+    )?;
+    if insertions > 0 || deletions == 0 {
+        write!(
+            out,
+            ", {insertions} insertion{}(+)",
+            if insertions == 1 { "" } else { "s" }
+        )?;
+    }
+    if deletions > 0 || insertions == 0 {
+        write!(
+            out,
+            ", {deletions} deletion{}(-)",
+            if deletions == 1 { "" } else { "s" }
+        )?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// A furthest-reaching path array indexed by diagonal `k`, which may be negative.
+struct V {
+    offset: isize,
+    v: Vec<usize>,
+}
+
+impl V {
+    fn new(max: usize) -> V {
+        V {
+            offset: max as isize,
+            v: vec![0; 2 * max + 2],
+        }
+    }
+}
+
+impl std::ops::Index<isize> for V {
+    type Output = usize;
+
+    fn index(&self, k: isize) -> &usize {
+        &self.v[(k + self.offset) as usize]
+    }
+}
+
+impl std::ops::IndexMut<isize> for V {
+    fn index_mut(&mut self, k: isize) -> &mut usize {
+        &mut self.v[(k + self.offset) as usize]
+    }
+}
+
+fn common_prefix<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn common_suffix<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+/// Find the start of the middle snake of an optimal edit path between `a[ar]` and `b[br]`.
+fn middle_snake<T: PartialEq>(
+    a: &[T],
+    ar: Range<usize>,
+    b: &[T],
+    br: Range<usize>,
+    vf: &mut V,
+    vb: &mut V,
+) -> (usize, usize) {
+    let n = ar.len();
+    let m = br.len();
+    let delta = n as isize - m as isize;
+    let odd = delta & 1 == 1;
+    vf[1] = 0;
+    vb[1] = 0;
+    let d_max = (n + m).div_ceil(2) as isize + 1;
+
+    for d in 0..d_max {
+        let mut k = d;
+        while k >= -d {
+            let mut x = if k == -d || (k != d && vf[k - 1] < vf[k + 1]) {
+                vf[k + 1]
+            } else {
+                vf[k - 1] + 1
+            };
+            let y = (x as isize - k) as usize;
+            let (x0, y0) = (x, y);
+            if x < n && y < m {
+                x += common_prefix(&a[ar.start + x..ar.end], &b[br.start + y..br.end]);
+            }
+            vf[k] = x;
+            if odd && (k - delta).abs() < d && vf[k] + vb[-(k - delta)] >= n {
+                return (ar.start + x0, br.start + y0);
+            }
+            k -= 2;
+        }
+
+        let mut k = d;
+        while k >= -d {
+            let mut x = if k == -d || (k != d && vb[k - 1] < vb[k + 1]) {
+                vb[k + 1]
+            } else {
+                vb[k - 1] + 1
+            };
+            let mut y = (x as isize - k) as usize;
+            if x < n && y < m {
+                let s = common_suffix(&a[ar.start..ar.end - x], &b[br.start..br.end - y]);
+                x += s;
+                y += s;
+            }
+            vb[k] = x;
+            if !odd && (k - delta).abs() <= d && vb[k] + vf[-(k - delta)] >= n {
+                return (ar.end - x, br.end - y);
+            }
+            k -= 2;
+        }
+    }
+
+    unreachable!("an edit path always exists")
+}
+
+fn conquer<T: PartialEq>(
+    a: &[T],
+    mut ar: Range<usize>,
+    b: &[T],
+    mut br: Range<usize>,
+    vf: &mut V,
+    vb: &mut V,
+    matches: &mut Vec<Match>,
+) {
+    let prefix = common_prefix(&a[ar.clone()], &b[br.clone()]);
+    if prefix > 0 {
+        matches.push(Match {
+            a: ar.start,
+            b: br.start,
+            len: prefix,
+        });
+    }
+    ar.start += prefix;
+    br.start += prefix;
+
+    let suffix = common_suffix(&a[ar.clone()], &b[br.clone()]);
+    ar.end -= suffix;
+    br.end -= suffix;
+
+    if !ar.is_empty() && !br.is_empty() {
+        let (x, y) = middle_snake(a, ar.clone(), b, br.clone(), vf, vb);
+        conquer(a, ar.start..x, b, br.start..y, vf, vb, matches);
+        conquer(a, x..ar.end, b, y..br.end, vf, vb, matches);
+    }
+
+    if suffix > 0 {
+        matches.push(Match {
+            a: ar.end,
+            b: br.end,
+            len: suffix,
+        });
+    }
+}
@@ -0,0 +1,45 @@
+//! Launching the user's editor on files such as commit messages and todo lists.
+//!
+//! See: <https://git-scm.com/docs/git-var#Documentation/git-var.txt-GITEDITOR>
+use anyhow::Context;
+use std::path::Path;
+
+use crate::config;
+
+/// The editor for commit messages: `GIT_EDITOR`, `core.editor`, `VISUAL`, `EDITOR` or `vi`.
+pub(crate) fn editor() -> String {
+    std::env::var("GIT_EDITOR")
+        .ok()
+        .or_else(|| config::get("core.editor").ok().flatten())
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+/// The editor for todo lists: `GIT_SEQUENCE_EDITOR`, `sequence.editor` or [`editor`].
+pub(crate) fn sequence_editor() -> String {
+    std::env::var("GIT_SEQUENCE_EDITOR")
+        .ok()
+        .or_else(|| config::get("sequence.editor").ok().flatten())
+        .unwrap_or_else(editor)
+}
+
+/// Let the user edit the file at `path` with `editor`, which is run by the shell so that it may
+/// contain arguments.
+pub(crate) fn launch(editor: &str, path: &Path) -> anyhow::Result<()> {
+    if editor == ":" {
+        return Ok(());
+    }
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(editor)
+        .arg(path)
+        .status()
+        .with_context(|| format!("run editor '{editor}'"))?;
+    anyhow::ensure!(
+        status.success(),
+        "there was a problem with the editor '{editor}'"
+    );
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! Object ids and the hash algorithms computing them.
+//!
+//! A repository uses SHA-1 unless `extensions.objectFormat` names SHA-256, in which case object
+//! ids, index entries and reference tables are 32 bytes instead of 20.
+//!
+//! SHA-1 is computed with collision detection (the sha1dc algorithm git uses), so content crafted
+//! to collide with another object, as in the SHAttered attack, is rejected instead of stored.
+//!
+//! See: <https://git-scm.com/docs/hash-function-transition>
+use sha2::Digest;
+use std::path::Path;
+use std::{fmt, io};
+
+use crate::config::Config;
+use crate::repository;
+
+/// The largest object id of any algorithm, in bytes.
+const MAX_SIZE: usize = 32;
+
+/// A hash algorithm objects can be named with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Algorithm {
+    /// SHA-1, the original format with 20-byte ids.
+    Sha1,
+    /// SHA-256, with 32-byte ids.
+    Sha256,
+}
+
+impl Algorithm {
+    /// The algorithm called `name` in `extensions.objectFormat` and `--object-format`.
+    pub(crate) fn from_name(name: &str) -> anyhow::Result<Algorithm> {
+        match name {
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            _ => anyhow::bail!("unknown hash algorithm '{name}'"),
+        }
+    }
+
+    /// The name of the algorithm, e.g. `sha256`.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// The size of object ids in bytes.
+    pub(crate) fn size(self) -> usize {
+        match self {
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 => 32,
+        }
+    }
+
+    /// The number identifying the algorithm in the headers of commit-graph and multi-pack-index
+    /// files.
+    pub(crate) fn version(self) -> u8 {
+        match self {
+            Algorithm::Sha1 => 1,
+            Algorithm::Sha256 => 2,
+        }
+    }
+
+    /// The length of object ids in hex digits.
+    pub(crate) fn hex_len(self) -> usize {
+        self.size() * 2
+    }
+
+    /// The all-zeros id that stands for no object, e.g. the old value of a created reference.
+    pub(crate) fn null_hex(self) -> &'static str {
+        const ZEROS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+        &ZEROS[..self.hex_len()]
+    }
+
+    /// The algorithm of the repository in `git_dir`, per `extensions.objectFormat`.
+    pub(crate) fn of_repository(git_dir: &Path) -> anyhow::Result<Algorithm> {
+        let config = Config::from_file(&git_dir.join("config"))?;
+        match config.get_string("extensions.objectFormat") {
+            None => Ok(Algorithm::Sha1),
+            Some(name) => Algorithm::from_name(name),
+        }
+    }
+}
+
+/// The hash algorithm of the repository, SHA-1 outside of one.
+pub(crate) fn algorithm() -> Algorithm {
+    repository::get().map_or(Algorithm::Sha1, |repository| repository.algorithm)
+}
+
+/// The all-zeros id of the repository's algorithm, see [`Algorithm::null_hex`].
+pub(crate) fn null_hex() -> &'static str {
+    algorithm().null_hex()
+}
+
+/// Whether `hash` is the all-zeros id that stands for no object.
+pub(crate) fn is_null(hash: &str) -> bool {
+    hash == null_hex()
+}
+
+/// The binary id of an object.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct ObjectId {
+    bytes: [u8; MAX_SIZE],
+    len: u8,
+}
+
+impl ObjectId {
+    /// The id made of `bytes`, which must be the size of an id of some algorithm.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> anyhow::Result<ObjectId> {
+        anyhow::ensure!(
+            bytes.len() == Algorithm::Sha1.size() || bytes.len() == Algorithm::Sha256.size(),
+            "invalid object id length {}",
+            bytes.len()
+        );
+        let mut id = ObjectId {
+            bytes: [0; MAX_SIZE],
+            len: bytes.len() as u8,
+        };
+        id.bytes[..bytes.len()].copy_from_slice(bytes);
+        Ok(id)
+    }
+
+    /// Parse the hex id `hex` of the repository's algorithm.
+    pub(crate) fn from_hex(hex: &str) -> anyhow::Result<ObjectId> {
+        let bytes = hex::decode(hex).map_err(|_| anyhow::anyhow!("invalid object id '{hex}'"))?;
+        anyhow::ensure!(
+            bytes.len() == algorithm().size(),
+            "invalid object id '{hex}'"
+        );
+        ObjectId::from_bytes(&bytes)
+    }
+
+    /// The bytes of the id.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+}
+
+impl AsRef<[u8]> for ObjectId {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.as_bytes()))
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({self})")
+    }
+}
+
+/// An in-progress hash of some algorithm.
+#[derive(Clone)]
+pub(crate) enum Hasher {
+    Sha1(Box<sha1_checked::Sha1>),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    /// A hasher for `algorithm`.
+    pub(crate) fn new(algorithm: Algorithm) -> Hasher {
+        match algorithm {
+            Algorithm::Sha1 => Hasher::Sha1(Box::new(
+                sha1_checked::Sha1::builder().safe_hash(false).build(),
+            )),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    /// A hasher for the repository's algorithm.
+    pub(crate) fn for_repository() -> Hasher {
+        Hasher::new(algorithm())
+    }
+
+    /// Hash `data` in one go with the repository's algorithm.
+    pub(crate) fn digest(data: &[u8]) -> anyhow::Result<ObjectId> {
+        let mut hasher = Hasher::for_repository();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Add `data` to the hash.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// The hash of everything added, failing if the data looks like half of a SHA-1 collision.
+    pub(crate) fn finalize(self) -> anyhow::Result<ObjectId> {
+        match self {
+            Hasher::Sha1(hasher) => {
+                let result = hasher.try_finalize();
+                let id = ObjectId::from_bytes(result.hash())?;
+                anyhow::ensure!(
+                    !result.has_collision(),
+                    "SHA-1 appears to be part of a collision attack: {id}"
+                );
+                Ok(id)
+            }
+            Hasher::Sha256(hasher) => ObjectId::from_bytes(&hasher.finalize()),
+        }
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+//! The Git index (staging area) file.
+//!
+//! See: <https://git-scm.com/docs/index-format>
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+
+use crate::hash::{self, Hasher, ObjectId};
+use crate::merge::Conflict;
+use crate::objects::{FlatTree, TreeEntry};
+use crate::repository::git_path;
+use crate::shared;
+
+/// Path of the index file.
+pub(crate) const INDEX_PATH: &str = "index";
+
+/// Signature at the start of every index file.
+const SIGNATURE: &[u8; 4] = b"DIRC";
+
+/// Signature of the extension caching the trees of the index.
+const CACHE_TREE_SIGNATURE: &[u8; 4] = b"TREE";
+
+/// Size of the fixed-length part of an index entry without the object hash: ten 32-bit stat and
+/// mode fields before it and 16-bit flags after it.
+const ENTRY_FIELDS_SIZE: usize = 42;
+
+/// File system metadata cached in the index to detect changed files without rehashing them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Stat {
+    pub(crate) ctime: u32,
+    pub(crate) ctime_nsec: u32,
+    pub(crate) mtime: u32,
+    pub(crate) mtime_nsec: u32,
+    pub(crate) dev: u32,
+    pub(crate) ino: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) size: u32,
+}
+
+impl Stat {
+    /// Collect the metadata of a file in the working tree.
+    pub(crate) fn from_metadata(meta: &std::fs::Metadata) -> Stat {
+        // Git truncates all of these to 32 bits as well.
+        Stat {
+            ctime: meta.ctime() as u32,
+            ctime_nsec: meta.ctime_nsec() as u32,
+            mtime: meta.mtime() as u32,
+            mtime_nsec: meta.mtime_nsec() as u32,
+            dev: meta.dev() as u32,
+            ino: meta.ino() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: meta.size() as u32,
+        }
+    }
+}
+
+/// A single entry of the index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    /// Cached file system metadata; all zeroes for conflict stages.
+    pub(crate) stat: Stat,
+    /// The file mode and blob hash.
+    pub(crate) entry: TreeEntry,
+    /// Merge stage: 0 for a normal entry, 1–3 for the base, ours and theirs sides of a conflict.
+    pub(crate) stage: u8,
+    /// The `/`-separated path relative to the top of the working tree.
+    pub(crate) path: String,
+}
+
+/// A directory of the index and the tree object it was last written as, cached in the `TREE`
+/// extension so that writing a tree for an unchanged directory reuses it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CacheTree {
+    /// The hash of the tree object, `None` if entries below the directory changed since.
+    pub(crate) hash: Option<String>,
+    /// The number of index entries below the directory.
+    pub(crate) entries: usize,
+    /// The subdirectories by name.
+    pub(crate) subtrees: BTreeMap<String, CacheTree>,
+}
+
+impl CacheTree {
+    /// Parse the directory at the start of `data` and its subdirectories, returning its name and
+    /// advancing `data` past them.
+    fn parse(data: &mut &[u8]) -> anyhow::Result<(String, CacheTree)> {
+        let nul = data
+            .iter()
+            .position(|&b| b == 0)
+            .context("cached tree name is not NUL-terminated")?;
+        let name = std::str::from_utf8(&data[..nul])
+            .context("cached tree name is not valid UTF-8")?
+            .to_string();
+        let newline = data[nul..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .context("cached tree counts are truncated")?
+            + nul;
+        let counts = std::str::from_utf8(&data[nul + 1..newline])?;
+        let (entries, subtrees) = counts
+            .split_once(' ')
+            .with_context(|| format!("invalid cached tree counts '{counts}'"))?;
+        let entries: i64 = entries.parse().context("invalid cached tree entry count")?;
+        let subtrees: usize = subtrees
+            .parse()
+            .context("invalid cached tree subtree count")?;
+        *data = &data[newline + 1..];
+
+        // Directories changed since their tree was written have a negative count and no hash.
+        let mut tree = CacheTree::default();
+        if entries >= 0 {
+            let size = hash::algorithm().size();
+            anyhow::ensure!(data.len() >= size, "cached tree hash is truncated");
+            tree.hash = Some(hex::encode(&data[..size]));
+            tree.entries = usize::try_from(entries)?;
+            *data = &data[size..];
+        }
+        for _ in 0..subtrees {
+            let (name, subtree) = CacheTree::parse(data)?;
+            tree.subtrees.insert(name, subtree);
+        }
+        Ok((name, tree))
+    }
+
+    /// Append the directory `name` and its subdirectories in the format of the `TREE` extension.
+    fn write(&self, name: &str, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        match &self.hash {
+            Some(hash) => {
+                out.extend(format!("{} {}\n", self.entries, self.subtrees.len()).bytes());
+                out.extend_from_slice(ObjectId::from_hex(hash)?.as_bytes());
+            }
+            None => out.extend(format!("-1 {}\n", self.subtrees.len()).bytes()),
+        }
+        for (name, subtree) in &self.subtrees {
+            subtree.write(name, out)?;
+        }
+        Ok(())
+    }
+}
+
+/// The contents of the index file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Index {
+    /// Entries sorted by path and stage.
+    pub(crate) entries: Vec<IndexEntry>,
+    /// The trees cached for the entries, if known.
+    pub(crate) cache_tree: Option<CacheTree>,
+}
+
+impl Index {
+    /// Read the index file, returning an empty index if there is none.
+    pub(crate) fn read() -> anyhow::Result<Index> {
+        if !git_path(INDEX_PATH).exists() {
+            return Ok(Index::default());
+        }
+        let data = std::fs::read(git_path(INDEX_PATH)).context("read .git/index")?;
+        Index::parse(&data).context("parse .git/index")
+    }
+
+    /// Parse the contents of an index file.
+    pub(crate) fn parse(data: &[u8]) -> anyhow::Result<Index> {
+        let hash_size = hash::algorithm().size();
+        anyhow::ensure!(data.len() >= 12 + hash_size, "index file is too short");
+        let (body, checksum) = data.split_at(data.len() - hash_size);
+        anyhow::ensure!(
+            Hasher::digest(body)?.as_bytes() == checksum,
+            "index file checksum mismatch"
+        );
+        anyhow::ensure!(&body[..4] == SIGNATURE, "bad index file signature");
+        let version = be32(&body[4..]);
+        anyhow::ensure!(version == 2, "unsupported index file version {version}");
+        let count = be32(&body[8..]) as usize;
+
+        let mut entries = Vec::with_capacity(count);
+        let mut pos = 12;
+        for _ in 0..count {
+            let header = body
+                .get(pos..pos + entry_header_size())
+                .context("index entry is truncated")?;
+            let field = |i: usize| be32(&header[i * 4..]);
+            let stat = Stat {
+                ctime: field(0),
+                ctime_nsec: field(1),
+                mtime: field(2),
+                mtime_nsec: field(3),
+                dev: field(4),
+                ino: field(5),
+                uid: field(7),
+                gid: field(8),
+                size: field(9),
+            };
+            let mode = field(6);
+            let hash = hex::encode(&header[40..40 + hash_size]);
+            let flags = u16::from_be_bytes([header[40 + hash_size], header[41 + hash_size]]);
+            let path_start = pos + entry_header_size();
+            let path_len = body[path_start..]
+                .iter()
+                .position(|&b| b == 0)
+                .context("index entry path is not NUL-terminated")?;
+            let path = std::str::from_utf8(&body[path_start..path_start + path_len])
+                .context("index entry path is not valid UTF-8")?
+                .to_string();
+            entries.push(IndexEntry {
+                stat,
+                entry: TreeEntry { mode, hash },
+                stage: ((flags >> 12) & 0b11) as u8,
+                path,
+            });
+            pos += entry_size(path_len);
+        }
+        // Extensions other than cached trees (resolve-undo, ...) are optional and ignored.
+        let mut cache_tree = None;
+        while pos + 8 <= body.len() {
+            let size = be32(&body[pos + 4..]) as usize;
+            let mut extension = body
+                .get(pos + 8..pos + 8 + size)
+                .context("index extension is truncated")?;
+            if &body[pos..pos + 4] == CACHE_TREE_SIGNATURE {
+                let (_, tree) = CacheTree::parse(&mut extension).context("parse cached trees")?;
+                cache_tree = Some(tree);
+            }
+            pos += 8 + size;
+        }
+
+        Ok(Index {
+            entries,
+            cache_tree,
+        })
+    }
+
+    /// Write the index file.
+    pub(crate) fn write(&self) -> anyhow::Result<()> {
+        let mut entries: Vec<&IndexEntry> = self.entries.iter().collect();
+        entries.sort_by(|a, b| {
+            a.path
+                .as_bytes()
+                .cmp(b.path.as_bytes())
+                .then(a.stage.cmp(&b.stage))
+        });
+
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.extend(2u32.to_be_bytes());
+        data.extend((entries.len() as u32).to_be_bytes());
+        for entry in entries {
+            let start = data.len();
+            let stat = entry.stat;
+            for field in [
+                stat.ctime,
+                stat.ctime_nsec,
+                stat.mtime,
+                stat.mtime_nsec,
+                stat.dev,
+                stat.ino,
+                entry.entry.mode,
+                stat.uid,
+                stat.gid,
+                stat.size,
+            ] {
+                data.extend(field.to_be_bytes());
+            }
+            data.extend_from_slice(
+                ObjectId::from_hex(&entry.entry.hash)
+                    .context("index entry hash is not hex")?
+                    .as_bytes(),
+            );
+            let flags = (u16::from(entry.stage) << 12) | entry.path.len().min(0xfff) as u16;
+            data.extend(flags.to_be_bytes());
+            data.extend_from_slice(entry.path.as_bytes());
+            data.resize(start + entry_size(entry.path.len()), 0);
+        }
+        if let Some(tree) = &self.cache_tree {
+            let mut extension = Vec::new();
+            tree.write("", &mut extension)?;
+            data.extend_from_slice(CACHE_TREE_SIGNATURE);
+            data.extend(u32::try_from(extension.len())?.to_be_bytes());
+            data.extend(extension);
+        }
+        let checksum = Hasher::digest(&data)?;
+        data.extend_from_slice(checksum.as_bytes());
+
+        let tmp = git_path(format!("{INDEX_PATH}.lock"));
+        std::fs::write(&tmp, data).context("write .git/index.lock")?;
+        shared::adjust(&tmp)?;
+        std::fs::rename(&tmp, git_path(INDEX_PATH)).context("move .git/index.lock into place")?;
+        Ok(())
+    }
+
+    /// Build an index matching `tree`, taking stat data from the working tree where possible.
+    pub(crate) fn from_tree(tree: &FlatTree) -> Index {
+        let entries = tree
+            .iter()
+            .map(|(path, entry)| IndexEntry {
+                stat: std::fs::symlink_metadata(path)
+                    .map(|meta| Stat::from_metadata(&meta))
+                    .unwrap_or_default(),
+                entry: entry.clone(),
+                stage: 0,
+                path: path.clone(),
+            })
+            .collect();
+        Index {
+            entries,
+            cache_tree: None,
+        }
+    }
+
+    /// Build an index for a merge result: clean paths at stage 0 and conflicted paths at
+    /// stages 1–3.
+    pub(crate) fn from_merge(result: &FlatTree, conflicts: &[Conflict]) -> Index {
+        let mut index = Index::from_tree(result);
+        index
+            .entries
+            .retain(|e| !conflicts.iter().any(|c| c.path == e.path));
+        for conflict in conflicts {
+            for (stage, entry) in [
+                (1, &conflict.base),
+                (2, &conflict.ours),
+                (3, &conflict.theirs),
+            ] {
+                if let Some(entry) = entry {
+                    index.entries.push(IndexEntry {
+                        stat: Stat::default(),
+                        entry: entry.clone(),
+                        stage,
+                        path: conflict.path.clone(),
+                    });
+                }
+            }
+        }
+        index
+    }
+
+    /// The tree recorded by the index, failing if it has unmerged entries.
+    pub(crate) fn tree(&self) -> anyhow::Result<FlatTree> {
+        let unmerged = self.unmerged_paths();
+        if !unmerged.is_empty() {
+            anyhow::bail!("{}: needs merge", unmerged.join(": needs merge\n"));
+        }
+        Ok(self
+            .entries
+            .iter()
+            .map(|e| (e.path.clone(), e.entry.clone()))
+            .collect())
+    }
+
+    /// Paths that have unmerged (stage 1–3) entries.
+    pub(crate) fn unmerged_paths(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = self
+            .entries
+            .iter()
+            .filter(|e| e.stage != 0)
+            .map(|e| e.path.as_str())
+            .collect();
+        paths.dedup();
+        paths
+    }
+}
+
+/// Size of the fixed-length part of an index entry, which includes the object hash.
+fn entry_header_size() -> usize {
+    ENTRY_FIELDS_SIZE + hash::algorithm().size()
+}
+
+/// Size of an on-disk index entry with a path of `path_len` bytes, including NUL padding.
+fn entry_size(path_len: usize) -> usize {
+    (entry_header_size() + path_len + 8) & !7
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().expect("slice is 4 bytes long"))
+}
@@ -0,0 +1,89 @@
+//! Lock files, which keep concurrent processes from updating the same file and replace it
+//! atomically.
+//!
+//! To update `path`, `path.lock` is created exclusively and written, then renamed over `path`.
+//! A lock that is dropped without being committed is removed, leaving `path` unchanged.
+use anyhow::Context;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::shared;
+
+/// An exclusive lock on a file, holding its new contents.
+#[derive(Debug)]
+pub(crate) struct LockFile {
+    /// The file being updated.
+    path: PathBuf,
+    /// The lock file, `path` with `.lock` appended.
+    lock: PathBuf,
+    /// The open lock file, `None` once committed.
+    file: Option<File>,
+}
+
+impl LockFile {
+    /// Lock `path` for an update, failing if another process holds the lock.
+    pub(crate) fn acquire(path: impl AsRef<Path>) -> anyhow::Result<LockFile> {
+        let path = path.as_ref().to_path_buf();
+        let mut lock = OsString::from(path.as_os_str());
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            shared::create_dir_all(parent)?;
+        }
+        let file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock)
+        {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => anyhow::bail!(
+                "Unable to create '{}': File exists.\n\n\
+                 Another git process seems to be running in this repository, or a process\n\
+                 crashed earlier: make sure all processes are terminated, then remove the file.",
+                lock.display()
+            ),
+            Err(error) => {
+                return Err(error).with_context(|| format!("Unable to create '{}'", lock.display()))
+            }
+        };
+        shared::adjust(&lock)?;
+        Ok(LockFile {
+            path,
+            lock,
+            file: Some(file),
+        })
+    }
+
+    /// Append `data` to the new contents.
+    pub(crate) fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.file
+            .as_mut()
+            .context("lock file is already committed")?
+            .write_all(data)
+            .with_context(|| format!("write {}", self.lock.display()))
+    }
+
+    /// Replace the locked file with the new contents and release the lock.
+    pub(crate) fn commit(mut self) -> anyhow::Result<()> {
+        self.file
+            .as_ref()
+            .context("lock file is already committed")?
+            .sync_all()
+            .with_context(|| format!("flush {}", self.lock.display()))?;
+        std::fs::rename(&self.lock, &self.path).with_context(|| {
+            format!("rename {} to {}", self.lock.display(), self.path.display())
+        })?;
+        self.file = None;
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.lock);
+        }
+    }
+}
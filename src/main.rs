@@ -1,15 +1,43 @@
 //! A (mini) Git implementation in Rust.
 use anyhow::{Context, Ok};
 use clap::{Parser, Subcommand};
-use std::fs;
 use std::path::PathBuf;
 
 pub(crate) mod commands;
+pub(crate) mod commit_graph;
+pub(crate) mod config;
+pub(crate) mod diff;
+pub(crate) mod editor;
+pub(crate) mod hash;
+pub(crate) mod index;
+pub(crate) mod lockfile;
+pub(crate) mod merge;
 pub(crate) mod objects;
+pub(crate) mod pack;
+pub(crate) mod reachable;
+pub(crate) mod rebase;
+pub(crate) mod reflog;
+pub(crate) mod refs;
+pub(crate) mod repository;
+pub(crate) mod revision;
+pub(crate) mod sequencer;
+pub(crate) mod shared;
+pub(crate) mod worktree;
+
+use repository::Repository;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Run as if started in this directory; relative paths are relative to the previous one.
+    #[clap(short = 'C', value_name = "PATH", global = true)]
+    directories: Vec<PathBuf>,
+    /// The Git directory, overriding `GIT_DIR` and discovery.
+    #[clap(long, value_name = "PATH", global = true)]
+    git_dir: Option<PathBuf>,
+    /// The top of the working tree, overriding `GIT_WORK_TREE`.
+    #[clap(long, value_name = "PATH", global = true)]
+    work_tree: Option<PathBuf>,
     #[command(subcommand)]
     command: Command,
 }
@@ -18,7 +46,10 @@ struct Args {
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Initialize a new Git repository
-    Init,
+    Init {
+        #[command(flatten)]
+        args: commands::init::Args,
+    },
     /// See the contents of a Git object
     CatFile {
         /// The name of the object to show.
@@ -53,32 +84,292 @@ enum Command {
         #[clap(short = 'm')]
         message: String,
 
-        /// Parent tree
+        /// Parent commit(s); may be given multiple times for merge commits.
         #[clap(short = 'p')]
-        parent_hash: Option<String>,
+        parent_hashes: Vec<String>,
 
         /// Hash of tree object to commit.
         tree_hash: String,
     },
     /// Record changes to the repository.
     Commit {
-        /// Commit message.
+        /// Commit message. Defaults to the prepared message of an in-progress merge.
         #[clap(short = 'm')]
-        message: String,
+        message: Option<String>,
+    },
+    /// Join two development histories together.
+    Merge {
+        /// Commit message of the merge commit.
+        #[clap(short = 'm')]
+        message: Option<String>,
+        /// Create a merge commit even when the merge resolves as a fast-forward.
+        #[clap(long, conflicts_with = "ff_only")]
+        no_ff: bool,
+        /// Refuse to merge unless the current HEAD is already up to date or the merge can be
+        /// resolved as a fast-forward.
+        #[clap(long)]
+        ff_only: bool,
+        /// Produce the working tree and index state as if a real merge happened, but do not
+        /// make a commit or move HEAD.
+        #[clap(long, conflicts_with = "no_ff")]
+        squash: bool,
+        /// Abort the current conflict resolution process, and reconstruct the pre-merge state.
+        #[clap(long, conflicts_with_all = ["no_ff", "ff_only", "squash", "message"])]
+        abort: bool,
+        /// Use the given merge strategy; defaults to `recursive` for a single head and `octopus`
+        /// otherwise.
+        #[clap(short = 's', long)]
+        strategy: Option<commands::merge::Strategy>,
+        /// Pass a merge strategy specific option through to the merge strategy, e.g. `ours`,
+        /// `theirs`, `ignore-space-change`, `patience`, `renormalize` or `subtree=<path>`.
+        #[clap(short = 'X', long = "strategy-option")]
+        strategy_options: Vec<String>,
+        /// Commits to merge into the current branch.
+        #[clap(required_unless_present = "abort")]
+        commits: Vec<String>,
+    },
+    /// Apply the changes introduced by some existing commits.
+    CherryPick {
+        /// Append a line that says "(cherry picked from commit ...)" to the original commit
+        /// message.
+        #[clap(short = 'x')]
+        record_origin: bool,
+        #[command(flatten)]
+        sequencer: SequencerArgs,
+    },
+    /// Revert some existing commits.
+    Revert {
+        #[command(flatten)]
+        sequencer: SequencerArgs,
+    },
+    /// Reapply commits on top of another base tip.
+    Rebase {
+        /// Make a list of the commits which are about to be rebased and let the user edit that
+        /// list before rebasing.
+        #[clap(short = 'i', long)]
+        interactive: bool,
+        /// Starting point at which to create the new commits; defaults to <upstream>.
+        #[clap(long)]
+        onto: Option<String>,
+        /// Move commits whose subject starts with "fixup!" or "squash!" right after the commit
+        /// they refer to.
+        #[clap(long)]
+        autosquash: bool,
+        /// Try to recreate the branch structure within the commits that are to be rebased.
+        #[clap(short = 'r', long)]
+        rebase_merges: bool,
+        /// Restart the rebasing process after having resolved a merge conflict.
+        #[clap(long = "continue", conflicts_with_all = ["skip", "abort"])]
+        resume: bool,
+        /// Restart the rebasing process by skipping the current patch.
+        #[clap(long, conflicts_with = "abort")]
+        skip: bool,
+        /// Abort the rebase operation and reset HEAD to the original branch.
+        #[clap(long)]
+        abort: bool,
+        /// Upstream branch to compare against.
+        #[clap(required_unless_present_any = ["resume", "skip", "abort"])]
+        upstream: Option<String>,
+        /// Branch to switch to before rebasing.
+        branch: Option<String>,
+    },
+    /// Manage reference logs.
+    Reflog {
+        #[command(subcommand)]
+        action: Option<commands::reflog::Action>,
+    },
+    /// Get and set repository or global options.
+    Config {
+        #[command(flatten)]
+        args: commands::config::Args,
+    },
+    /// Stash the changes in a dirty working directory away.
+    Stash {
+        #[command(subcommand)]
+        action: Option<commands::stash::Action>,
+    },
+    /// Update the object name stored in a reference safely.
+    UpdateRef {
+        #[command(flatten)]
+        args: commands::update_ref::Args,
+    },
+    /// List references in a local repository.
+    ShowRef {
+        #[command(flatten)]
+        args: commands::show_ref::Args,
+    },
+    /// Read, modify and delete symbolic references.
+    SymbolicRef {
+        #[command(flatten)]
+        args: commands::symbolic_ref::Args,
+    },
+    /// Output information on each reference.
+    ForEachRef {
+        #[command(flatten)]
+        args: commands::for_each_ref::Args,
+    },
+    /// Verify the connectivity and validity of the objects in the database.
+    Fsck {
+        #[command(flatten)]
+        args: commands::fsck::Args,
+    },
+    /// Cleanup unnecessary files and optimize the local repository.
+    Gc {
+        #[command(flatten)]
+        args: commands::gc::Args,
+    },
+    /// Pack unpacked objects in a repository.
+    Repack {
+        #[command(flatten)]
+        args: commands::repack::Args,
+    },
+    /// Write and verify commit-graph files.
+    CommitGraph {
+        #[command(subcommand)]
+        action: commands::commit_graph::Action,
+    },
+    /// Write and verify multi-pack-index files.
+    MultiPackIndex {
+        #[command(subcommand)]
+        action: commands::multi_pack_index::Action,
+    },
+    /// List commits, and the objects they reach, in reverse chronological order.
+    RevList {
+        #[command(flatten)]
+        args: commands::rev_list::Args,
+    },
+    /// Send objects packed back to `git fetch` and `git clone`.
+    UploadPack {
+        #[command(flatten)]
+        args: commands::upload_pack::Args,
+    },
+    /// Show commit logs.
+    Log {
+        #[command(flatten)]
+        args: commands::log::Args,
     },
+    /// Prune all unreachable objects from the object database.
+    Prune {
+        #[command(flatten)]
+        args: commands::prune::Args,
+    },
+    /// Pack references into a single file for efficient repository access.
+    PackRefs {
+        /// Pack all references, not only tags.
+        #[clap(long)]
+        all: bool,
+        /// Keep the loose references after packing them.
+        #[clap(long)]
+        no_prune: bool,
+    },
+    /// Find as good common ancestors as possible for a merge.
+    MergeBase {
+        /// Output all merge bases for the commits, instead of just one.
+        #[clap(short = 'a', long)]
+        all: bool,
+        /// Compute the best common ancestors of all supplied commits, in preparation for an
+        /// n-way merge.
+        #[clap(long, conflicts_with_all = ["independent", "is_ancestor"])]
+        octopus: bool,
+        /// Instead of printing merge bases, print a minimal subset of the supplied commits with
+        /// the same ancestors.
+        #[clap(long, conflicts_with = "is_ancestor")]
+        independent: bool,
+        /// Check if the first commit is an ancestor of the second commit, and exit with status 0
+        /// if true, or with status 1 if not.
+        #[clap(long)]
+        is_ancestor: bool,
+        /// The commits to compute merge bases for.
+        #[clap(required = true)]
+        commits: Vec<String>,
+    },
+}
+
+/// Arguments shared by `cherry-pick` and `revert`.
+#[derive(clap::Args, Debug)]
+struct SequencerArgs {
+    /// Apply the changes to the working tree and the index without making any commits.
+    #[clap(short = 'n', long)]
+    no_commit: bool,
+    /// The parent number (starting from 1) of merge commits to compute their changes against.
+    #[clap(short = 'm', long)]
+    mainline: Option<usize>,
+    /// Pass a merge strategy specific option through to the merge.
+    #[clap(short = 'X', long = "strategy-option")]
+    strategy_options: Vec<String>,
+    /// Continue the operation in progress after resolving a conflict.
+    #[clap(long = "continue", conflicts_with_all = ["skip", "abort"])]
+    resume: bool,
+    /// Skip the current commit and continue with the rest of the sequence.
+    #[clap(long, conflicts_with = "abort")]
+    skip: bool,
+    /// Cancel the operation and return to the pre-sequence state.
+    #[clap(long)]
+    abort: bool,
+    /// Commits to apply; ranges such as `A..B` select every commit in the range.
+    #[clap(required_unless_present_any = ["resume", "skip", "abort"])]
+    commits: Vec<String>,
+}
+
+impl SequencerArgs {
+    fn options(&self, record_origin: bool) -> sequencer::Options {
+        sequencer::Options {
+            record_origin,
+            no_commit: self.no_commit,
+            mainline: self.mainline,
+            strategy_options: self.strategy_options.clone(),
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    match args.command {
-        Command::Init => {
-            fs::create_dir(".git").unwrap();
-            fs::create_dir(".git/objects").unwrap();
-            fs::create_dir(".git/refs").unwrap();
-            fs::write(".git/HEAD", "ref: refs/heads/main\n").unwrap();
-            println!("Initialized git directory")
+    for dir in args
+        .directories
+        .iter()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        std::env::set_current_dir(dir)
+            .with_context(|| format!("cannot change to '{}'", dir.display()))?;
+    }
+    // Like Git, the options take effect through the environment, so that commands run from hooks
+    // and editors see the same repository.
+    if let Some(git_dir) = &args.git_dir {
+        std::env::set_var("GIT_DIR", git_dir);
+    }
+    if let Some(work_tree) = &args.work_tree {
+        std::env::set_var("GIT_WORK_TREE", work_tree);
+    }
+    match &args.command {
+        // A new repository is set up by the command itself.
+        Command::Init { .. } => {}
+        // Configuration outside a repository has no local scope, and an object that is not written
+        // is only hashed, with SHA-1 when there is no repository to say otherwise.
+        Command::Config { .. } | Command::HashObject { write: false, .. } => {
+            if let Some(repository) = Repository::discover()? {
+                repository.enter()?;
+            }
+        }
+        // The repository to serve is given as an argument rather than found from where the
+        // fetching client runs the command.
+        Command::UploadPack { args } => {
+            let not_a_repository = || {
+                let directory = args.directory.display();
+                format!("'{directory}' does not appear to be a git repository")
+            };
+            std::env::set_current_dir(&args.directory).with_context(not_a_repository)?;
+            Repository::discover()?
+                .with_context(not_a_repository)?
+                .enter()?
         }
+        _ => Repository::discover()?
+            .context("not a git repository (or any of the parent directories): .git")?
+            .enter()?,
+    }
+
+    match args.command {
+        Command::Init { args } => commands::init::invoke(args)?,
         Command::CatFile {
             pretty_print,
             object_hash,
@@ -92,36 +383,90 @@ fn main() -> anyhow::Result<()> {
         Command::CommitTree {
             message,
             tree_hash,
-            parent_hash,
-        } => commands::commit_tree::invoke(message, tree_hash, parent_hash)?,
-        Command::Commit { message } => {
-            let head_ref = fs::read_to_string(".git/HEAD").context("read HEAD")?;
-            let Some(head_ref) = head_ref.strip_prefix("ref: ") else {
-                anyhow::bail!("refusing to commit onto detached HEAD");
-            };
-            let head_ref = head_ref.trim();
-            let parent_hash = std::fs::read_to_string(format!(".git/{head_ref}"))
-                .with_context(|| format!("read HEAD reference target {head_ref}"))?;
-            let parent_hash = parent_hash.trim();
-            let Some(tree_hash) = commands::write_tree::write_tree_for(&std::env::current_dir()?)
-                .context("write tree")?
-            else {
-                eprintln!("not committing empty tree");
-                return Ok(());
-            };
-            let commit_hash = commands::commit_tree::write_commit(
-                &message,
-                &hex::encode(tree_hash),
-                Some(parent_hash),
-            )
-            .context("create commit")?;
-            let commit_hash = hex::encode(commit_hash);
-
-            std::fs::write(format!(".git/{head_ref}"), &commit_hash)
-                .with_context(|| format!("update HEAD reference target {head_ref}"))?;
-
-            println!("HEAD is now at {commit_hash}")
-        }
+            parent_hashes,
+        } => commands::commit_tree::invoke(message, tree_hash, parent_hashes)?,
+        Command::Commit { message } => commands::commit::invoke(message)?,
+        Command::Merge {
+            message,
+            no_ff,
+            ff_only,
+            squash,
+            abort,
+            strategy,
+            strategy_options,
+            commits,
+        } => commands::merge::invoke(
+            &commits,
+            message,
+            strategy,
+            &strategy_options,
+            no_ff,
+            ff_only,
+            squash,
+            abort,
+        )?,
+        Command::CherryPick {
+            record_origin,
+            sequencer,
+        } => commands::cherry_pick::invoke(
+            &sequencer.commits,
+            sequencer.options(record_origin),
+            sequencer.resume,
+            sequencer.skip,
+            sequencer.abort,
+        )?,
+        Command::Revert { sequencer } => commands::revert::invoke(
+            &sequencer.commits,
+            sequencer.options(false),
+            sequencer.resume,
+            sequencer.skip,
+            sequencer.abort,
+        )?,
+        Command::Rebase {
+            interactive,
+            onto,
+            autosquash,
+            rebase_merges,
+            resume,
+            skip,
+            abort,
+            upstream,
+            branch,
+        } => commands::rebase::invoke(
+            upstream.as_deref(),
+            branch.as_deref(),
+            onto.as_deref(),
+            interactive,
+            autosquash,
+            rebase_merges,
+            resume,
+            skip,
+            abort,
+        )?,
+        Command::Reflog { action } => commands::reflog::invoke(action)?,
+        Command::Config { args } => commands::config::invoke(args)?,
+        Command::Stash { action } => commands::stash::invoke(action)?,
+        Command::UpdateRef { args } => commands::update_ref::invoke(args)?,
+        Command::ShowRef { args } => commands::show_ref::invoke(args)?,
+        Command::SymbolicRef { args } => commands::symbolic_ref::invoke(args)?,
+        Command::ForEachRef { args } => commands::for_each_ref::invoke(args)?,
+        Command::Fsck { args } => commands::fsck::invoke(args)?,
+        Command::Gc { args } => commands::gc::invoke(args)?,
+        Command::Repack { args } => commands::repack::invoke(args)?,
+        Command::Prune { args } => commands::prune::invoke(args)?,
+        Command::CommitGraph { action } => commands::commit_graph::invoke(action)?,
+        Command::MultiPackIndex { action } => commands::multi_pack_index::invoke(action)?,
+        Command::RevList { args } => commands::rev_list::invoke(args)?,
+        Command::UploadPack { .. } => commands::upload_pack::invoke()?,
+        Command::Log { args } => commands::log::invoke(args)?,
+        Command::PackRefs { all, no_prune } => commands::pack_refs::invoke(all, no_prune)?,
+        Command::MergeBase {
+            all,
+            octopus,
+            independent,
+            is_ancestor,
+            commits,
+        } => commands::merge_base::invoke(all, octopus, independent, is_ancestor, &commits)?,
     }
 
     Ok(())
@@ -0,0 +1,852 @@
+//! Three-way merging of trees and file contents.
+//!
+//! See: <https://git-scm.com/docs/git-merge#_how_conflicts_are_presented>
+use anyhow::Context;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::config;
+use crate::diff::{self, Match};
+use crate::objects::{FlatTree, Kind, Object, TreeEntry, MODE_GITLINK, MODE_SYMLINK};
+
+/// Length of the `<<<<<<<`, `|||||||`, `=======` and `>>>>>>>` conflict markers.
+const MARKER_SIZE: usize = 7;
+
+/// Minimum similarity (in percent) for a deleted and an added file to be considered a rename.
+const RENAME_THRESHOLD: usize = 50;
+
+/// Maximum number of source/destination pairs compared when looking for inexact renames.
+const RENAME_LIMIT: usize = 10_000;
+
+/// How conflicted hunks are written out, see `merge.conflictStyle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum ConflictStyle {
+    /// Show our and their side of the conflict.
+    #[default]
+    Merge,
+    /// Additionally show the original text of the merge base.
+    Diff3,
+    /// Like [`ConflictStyle::Diff3`], but move lines common to both sides out of the conflict.
+    ZDiff3,
+}
+
+impl ConflictStyle {
+    /// Read the conflict style from the `merge.conflictStyle` configuration.
+    pub(crate) fn from_config() -> anyhow::Result<ConflictStyle> {
+        match config::get("merge.conflictStyle")?.as_deref() {
+            None | Some("merge") => Ok(ConflictStyle::Merge),
+            Some("diff3") => Ok(ConflictStyle::Diff3),
+            Some("zdiff3") => Ok(ConflictStyle::ZDiff3),
+            Some(other) => anyhow::bail!("unknown style '{other}' given for 'merge.conflictstyle'"),
+        }
+    }
+}
+
+/// Which side wins conflicting hunks, see `-X ours` and `-X theirs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Favor {
+    /// Record conflicts with conflict markers.
+    #[default]
+    None,
+    /// Resolve conflicting hunks in favor of our side.
+    Ours,
+    /// Resolve conflicting hunks in favor of their side.
+    Theirs,
+}
+
+/// Options controlling a merge.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MergeOptions {
+    /// How conflicts are written out.
+    pub(crate) style: ConflictStyle,
+    /// Which side, if any, wins conflicting hunks.
+    pub(crate) favor: Favor,
+    /// Treat lines that only differ in the amount of whitespace as equal.
+    pub(crate) ignore_space_change: bool,
+    /// The diff algorithm used to line up the sides of a merge.
+    pub(crate) algorithm: diff::Algorithm,
+    /// Normalize line endings of all sides before merging file contents.
+    pub(crate) renormalize: bool,
+    /// Shift their tree into this subdirectory of ours before merging; empty to detect the
+    /// subdirectory automatically.
+    pub(crate) subtree: Option<String>,
+}
+
+impl MergeOptions {
+    /// Build merge options from the configuration and `-X <option>` strategy options.
+    pub(crate) fn new(strategy_options: &[String]) -> anyhow::Result<MergeOptions> {
+        let mut options = MergeOptions {
+            style: ConflictStyle::from_config()?,
+            ..MergeOptions::default()
+        };
+        for option in strategy_options {
+            match option.as_str() {
+                "ours" => options.favor = Favor::Ours,
+                "theirs" => options.favor = Favor::Theirs,
+                "ignore-space-change" => options.ignore_space_change = true,
+                "patience" => options.algorithm = diff::Algorithm::Patience,
+                "diff-algorithm=myers" | "diff-algorithm=default" => {
+                    options.algorithm = diff::Algorithm::Myers
+                }
+                "diff-algorithm=patience" => options.algorithm = diff::Algorithm::Patience,
+                "renormalize" => options.renormalize = true,
+                "no-renormalize" => options.renormalize = false,
+                _ => match option.strip_prefix("subtree=") {
+                    Some(path) => options.subtree = Some(path.trim_matches('/').to_string()),
+                    None => anyhow::bail!("unknown strategy option: -X{option}"),
+                },
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Names of the three sides of a merge, used in conflict markers and messages.
+#[derive(Debug, Clone)]
+pub(crate) struct Labels {
+    pub(crate) base: String,
+    pub(crate) ours: String,
+    pub(crate) theirs: String,
+}
+
+/// The result of merging the contents of a file.
+pub(crate) struct ContentMerge {
+    /// The merged contents, including conflict markers.
+    pub(crate) data: Vec<u8>,
+    /// Number of conflicted hunks.
+    pub(crate) conflicts: usize,
+}
+
+/// A region of a three-way merge.
+#[derive(Debug)]
+enum Region {
+    /// Lines of our side, left alone by both sides.
+    Unchanged(usize, usize),
+    /// Lines of our side that both sides changed the same way.
+    Same(usize, usize),
+    /// Lines of our side; only we changed this region.
+    Ours(usize, usize),
+    /// Lines of their side; only they changed this region.
+    Theirs(usize, usize),
+    /// Both sides changed the region differently: base, ours and theirs ranges.
+    Conflict((usize, usize), (usize, usize), (usize, usize)),
+}
+
+/// Find the regions of `base` that are unchanged on both sides, as
+/// `(base_start, base_end, ours_start, ours_end, theirs_start, theirs_end)` tuples, terminated by
+/// an empty region at the end of all three inputs.
+fn sync_regions(
+    base_len: usize,
+    ours: &[Match],
+    ours_len: usize,
+    theirs: &[Match],
+    theirs_len: usize,
+) -> Vec<(usize, usize, usize, usize, usize, usize)> {
+    let (mut i, mut j) = (0, 0);
+    let mut regions = Vec::new();
+    while i < ours.len() && j < theirs.len() {
+        let a = ours[i];
+        let b = theirs[j];
+        let start = a.a.max(b.a);
+        let end = (a.a + a.len).min(b.a + b.len);
+        if start < end {
+            let ours_start = a.b + (start - a.a);
+            let theirs_start = b.b + (start - b.a);
+            regions.push((
+                start,
+                end,
+                ours_start,
+                ours_start + (end - start),
+                theirs_start,
+                theirs_start + (end - start),
+            ));
+        }
+        if a.a + a.len < b.a + b.len {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    regions.push((
+        base_len, base_len, ours_len, ours_len, theirs_len, theirs_len,
+    ));
+    regions
+}
+
+/// Split a three-way merge into regions.
+///
+/// See: <https://www.cis.upenn.edu/~bcpierce/papers/diff3-short.pdf>
+fn merge_regions<T: Eq + std::hash::Hash>(
+    base: &[T],
+    ours: &[T],
+    theirs: &[T],
+    algorithm: diff::Algorithm,
+) -> Vec<Region> {
+    let ours_matches = diff::diff(algorithm, base, ours);
+    let theirs_matches = diff::diff(algorithm, base, theirs);
+    let (mut iz, mut ia, mut ib) = (0, 0, 0);
+    let mut regions = Vec::new();
+    for (zmatch, zend, amatch, aend, bmatch, bend) in sync_regions(
+        base.len(),
+        &ours_matches,
+        ours.len(),
+        &theirs_matches,
+        theirs.len(),
+    ) {
+        if amatch > ia || bmatch > ib {
+            let ours_unchanged = ours[ia..amatch] == base[iz..zmatch];
+            let theirs_unchanged = theirs[ib..bmatch] == base[iz..zmatch];
+            if ours[ia..amatch] == theirs[ib..bmatch] {
+                regions.push(Region::Same(ia, amatch));
+            } else if ours_unchanged {
+                regions.push(Region::Theirs(ib, bmatch));
+            } else if theirs_unchanged {
+                regions.push(Region::Ours(ia, amatch));
+            } else {
+                regions.push(Region::Conflict((iz, zmatch), (ia, amatch), (ib, bmatch)));
+            }
+        }
+        if zend > zmatch {
+            regions.push(Region::Unchanged(amatch, aend));
+        }
+        (iz, ia, ib) = (zend, aend, bend);
+    }
+    regions
+}
+
+/// Append `lines` to `out`, making sure the result ends with a newline so that a following
+/// conflict marker starts on its own line.
+fn push_lines_terminated(out: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        out.extend_from_slice(line);
+    }
+    if !lines.is_empty() && !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+}
+
+fn push_marker(out: &mut Vec<u8>, marker: u8, label: &str) {
+    out.extend(std::iter::repeat(marker).take(MARKER_SIZE));
+    if !label.is_empty() {
+        out.push(b' ');
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(b'\n');
+}
+
+/// Collapse runs of whitespace into a single space and drop trailing whitespace, so that lines
+/// only differing in the amount of whitespace compare equal.
+fn normalize_space(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    let mut in_space = false;
+    for &b in line {
+        if b.is_ascii_whitespace() {
+            in_space = true;
+            continue;
+        }
+        if in_space && !out.is_empty() {
+            out.push(b' ');
+        }
+        in_space = false;
+        out.push(b);
+    }
+    out
+}
+
+/// Convert CRLF line endings to LF, as `core.autocrlf` would when checking contents in.
+pub(crate) fn renormalize(data: Vec<u8>) -> Vec<u8> {
+    if diff::is_binary(&data) || !data.windows(2).any(|w| w == b"\r\n") {
+        return data;
+    }
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\r' && data.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        out.push(b);
+    }
+    out
+}
+
+/// Merge the contents of a file changed on both sides.
+pub(crate) fn merge_content(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    labels: &Labels,
+    options: &MergeOptions,
+) -> ContentMerge {
+    let base_lines = diff::lines(base);
+    let ours_lines = diff::lines(ours);
+    let theirs_lines = diff::lines(theirs);
+
+    let regions = if options.ignore_space_change {
+        let keys = |lines: &[&[u8]]| lines.iter().map(|l| normalize_space(l)).collect::<Vec<_>>();
+        merge_regions(
+            &keys(&base_lines),
+            &keys(&ours_lines),
+            &keys(&theirs_lines),
+            options.algorithm,
+        )
+    } else {
+        merge_regions(&base_lines, &ours_lines, &theirs_lines, options.algorithm)
+    };
+
+    let mut out = Vec::with_capacity(ours.len().max(theirs.len()));
+    let mut conflicts = 0;
+    for region in regions {
+        match region {
+            Region::Unchanged(start, end) | Region::Same(start, end) | Region::Ours(start, end) => {
+                ours_lines[start..end]
+                    .iter()
+                    .for_each(|l| out.extend_from_slice(l));
+            }
+            Region::Theirs(start, end) => {
+                theirs_lines[start..end]
+                    .iter()
+                    .for_each(|l| out.extend_from_slice(l));
+            }
+            Region::Conflict(_, (os, oe), _) if options.favor == Favor::Ours => {
+                ours_lines[os..oe]
+                    .iter()
+                    .for_each(|l| out.extend_from_slice(l));
+            }
+            Region::Conflict(_, _, (ts, te)) if options.favor == Favor::Theirs => {
+                theirs_lines[ts..te]
+                    .iter()
+                    .for_each(|l| out.extend_from_slice(l));
+            }
+            Region::Conflict((zs, ze), (os, oe), (ts, te)) => {
+                let ours = &ours_lines[os..oe];
+                let theirs = &theirs_lines[ts..te];
+                // Lines both sides agree on are moved out of the conflict, except in `diff3`
+                // style where the base would no longer line up with the sides.
+                let (prefix, suffix) = if options.style == ConflictStyle::Diff3 {
+                    (0, 0)
+                } else {
+                    let prefix = ours.iter().zip(theirs).take_while(|(a, b)| a == b).count();
+                    let suffix = ours[prefix..]
+                        .iter()
+                        .rev()
+                        .zip(theirs[prefix..].iter().rev())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    (prefix, suffix)
+                };
+                ours[..prefix].iter().for_each(|l| out.extend_from_slice(l));
+                conflicts += 1;
+                push_marker(&mut out, b'<', &labels.ours);
+                push_lines_terminated(&mut out, &ours[prefix..ours.len() - suffix]);
+                if options.style != ConflictStyle::Merge {
+                    push_marker(&mut out, b'|', &labels.base);
+                    push_lines_terminated(&mut out, &base_lines[zs..ze]);
+                }
+                push_marker(&mut out, b'=', "");
+                push_lines_terminated(&mut out, &theirs[prefix..theirs.len() - suffix]);
+                push_marker(&mut out, b'>', &labels.theirs);
+                ours[ours.len() - suffix..]
+                    .iter()
+                    .for_each(|l| out.extend_from_slice(l));
+            }
+        }
+    }
+
+    ContentMerge {
+        data: out,
+        conflicts,
+    }
+}
+
+/// A path that could not be merged cleanly, with the versions to record in index stages 1–3.
+#[derive(Debug, Clone)]
+pub(crate) struct Conflict {
+    /// The path of the conflicted file in the merged tree.
+    pub(crate) path: String,
+    /// The version of the merge base (stage 1).
+    pub(crate) base: Option<TreeEntry>,
+    /// Our version (stage 2).
+    pub(crate) ours: Option<TreeEntry>,
+    /// Their version (stage 3).
+    pub(crate) theirs: Option<TreeEntry>,
+    /// Human-readable description, e.g. `CONFLICT (content): Merge conflict in foo`.
+    pub(crate) message: String,
+}
+
+/// The result of merging two trees.
+pub(crate) struct TreeMerge {
+    /// The merged tree. Conflicted files hold their contents with conflict markers.
+    pub(crate) result: FlatTree,
+    /// Paths that could not be merged cleanly.
+    pub(crate) conflicts: Vec<Conflict>,
+    /// Files whose contents were merged cleanly.
+    pub(crate) auto_merged: Vec<String>,
+}
+
+/// Read the contents of a blob.
+fn read_blob(hash: &str) -> anyhow::Result<Vec<u8>> {
+    let object = Object::read(hash).with_context(|| format!("read blob {hash}"))?;
+    anyhow::ensure!(object.kind == Kind::Blob, "object {hash} is not a blob");
+    object.into_bytes()
+}
+
+/// Write `data` as a blob to the object store.
+fn write_blob(data: Vec<u8>) -> anyhow::Result<String> {
+    let hash = Object::from_bytes(Kind::Blob, data)
+        .write_to_objects()
+        .context("write merged blob")?;
+    Ok(hex::encode(hash))
+}
+
+/// Whether an entry holds file contents that can be merged line by line.
+fn is_regular(entry: &TreeEntry) -> bool {
+    entry.mode != MODE_SYMLINK && entry.mode != MODE_GITLINK && !entry.is_tree()
+}
+
+/// Percentage of `a` and `b` made up of common lines.
+fn similarity(a: &[u8], b: &[u8]) -> usize {
+    if a.is_empty() && b.is_empty() {
+        return 100;
+    }
+    let a_lines = diff::lines(a);
+    let b_lines = diff::lines(b);
+    let common: usize = diff::myers(&a_lines, &b_lines)
+        .iter()
+        .map(|m| {
+            a_lines[m.a..m.a + m.len]
+                .iter()
+                .map(|l| l.len())
+                .sum::<usize>()
+        })
+        .sum();
+    200 * common / (a.len() + b.len())
+}
+
+/// Find files of `base` that were renamed in `side`, as a map from old to new path.
+///
+/// Only additions that do not collide with a path in `other` are considered as rename targets.
+fn detect_renames(
+    base: &FlatTree,
+    side: &FlatTree,
+    other: &FlatTree,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut deleted: Vec<&String> = base
+        .iter()
+        .filter(|(path, entry)| !side.contains_key(*path) && is_regular(entry))
+        .map(|(path, _)| path)
+        .collect();
+    let mut added: Vec<&String> = side
+        .iter()
+        .filter(|(path, entry)| {
+            !base.contains_key(*path) && !other.contains_key(*path) && is_regular(entry)
+        })
+        .map(|(path, _)| path)
+        .collect();
+
+    let mut renames = BTreeMap::new();
+
+    // Exact renames first: same contents under a new name.
+    deleted.retain(|src| {
+        let hash = &base[*src].hash;
+        if let Some(i) = added.iter().position(|dst| &side[*dst].hash == hash) {
+            renames.insert(src.to_string(), added.remove(i).to_string());
+            false
+        } else {
+            true
+        }
+    });
+
+    if deleted.is_empty() || added.is_empty() || deleted.len() * added.len() > RENAME_LIMIT {
+        return Ok(renames);
+    }
+
+    let mut added_contents = Vec::with_capacity(added.len());
+    for dst in &added {
+        added_contents.push(read_blob(&side[*dst].hash)?);
+    }
+    let mut candidates = Vec::new();
+    for src in &deleted {
+        let src_contents = read_blob(&base[*src].hash)?;
+        for (i, dst_contents) in added_contents.iter().enumerate() {
+            let score = similarity(&src_contents, dst_contents);
+            if score >= RENAME_THRESHOLD {
+                candidates.push((score, src.to_string(), i));
+            }
+        }
+    }
+    // Best matches win; every source and destination is used at most once.
+    candidates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    let mut used = BTreeSet::new();
+    for (_, src, i) in candidates {
+        if renames.contains_key(&src) || !used.insert(i) {
+            continue;
+        }
+        renames.insert(src, added[i].to_string());
+    }
+    Ok(renames)
+}
+
+/// A path to merge, with the versions of the three sides.
+struct Unit {
+    path: String,
+    base: Option<TreeEntry>,
+    ours: Option<TreeEntry>,
+    theirs: Option<TreeEntry>,
+}
+
+/// Merges trees, accumulating results and conflicts.
+struct TreeMerger<'a> {
+    labels: &'a Labels,
+    options: &'a MergeOptions,
+    merge: TreeMerge,
+}
+
+impl TreeMerger<'_> {
+    fn conflict(&mut self, unit: &Unit, result: Option<TreeEntry>, message: String) {
+        if let Some(result) = result {
+            self.merge.result.insert(unit.path.clone(), result);
+        }
+        self.merge.conflicts.push(Conflict {
+            path: unit.path.clone(),
+            base: unit.base.clone(),
+            ours: unit.ours.clone(),
+            theirs: unit.theirs.clone(),
+            message,
+        });
+    }
+
+    /// Merge a single path.
+    fn merge_unit(&mut self, unit: Unit) -> anyhow::Result<()> {
+        let path = unit.path.clone();
+        let (base, ours, theirs) = (&unit.base, &unit.ours, &unit.theirs);
+
+        let resolved = if ours == theirs {
+            Some(ours.clone())
+        } else if ours == base {
+            Some(theirs.clone())
+        } else if theirs == base {
+            Some(ours.clone())
+        } else {
+            None
+        };
+        if let Some(resolved) = resolved {
+            if let Some(entry) = resolved {
+                self.merge.result.insert(path, entry);
+            }
+            return Ok(());
+        }
+
+        match (base, ours, theirs) {
+            (Some(_), None, Some(theirs)) => {
+                let message = format!(
+                    "CONFLICT (modify/delete): {path} deleted in {} and modified in {}. \
+                     Version {} of {path} left in tree.",
+                    self.labels.ours, self.labels.theirs, self.labels.theirs
+                );
+                let theirs = theirs.clone();
+                self.conflict(&unit, Some(theirs), message);
+            }
+            (Some(_), Some(ours), None) => {
+                let message = format!(
+                    "CONFLICT (modify/delete): {path} deleted in {} and modified in {}. \
+                     Version {} of {path} left in tree.",
+                    self.labels.theirs, self.labels.ours, self.labels.ours
+                );
+                let ours = ours.clone();
+                self.conflict(&unit, Some(ours), message);
+            }
+            (_, Some(ours), Some(theirs)) => {
+                let kind = if base.is_some() { "content" } else { "add/add" };
+                let mode = match base {
+                    Some(base) if ours.mode == base.mode => theirs.mode,
+                    Some(base) if theirs.mode == base.mode => ours.mode,
+                    _ if ours.mode == theirs.mode => ours.mode,
+                    _ => {
+                        let message =
+                            format!("CONFLICT (mode): Merge conflict in {path}, keeping our mode");
+                        let ours = ours.clone();
+                        self.conflict(&unit, Some(ours), message);
+                        return Ok(());
+                    }
+                };
+
+                if ours.hash == theirs.hash || base.as_ref().is_some_and(|b| b.hash == theirs.hash)
+                {
+                    let entry = TreeEntry {
+                        mode,
+                        hash: ours.hash.clone(),
+                    };
+                    self.merge.result.insert(path, entry);
+                    return Ok(());
+                }
+                if base.as_ref().is_some_and(|b| b.hash == ours.hash) {
+                    let entry = TreeEntry {
+                        mode,
+                        hash: theirs.hash.clone(),
+                    };
+                    self.merge.result.insert(path, entry);
+                    return Ok(());
+                }
+
+                if !is_regular(ours) || !is_regular(theirs) {
+                    let message = format!("CONFLICT ({kind}): Merge conflict in {path}");
+                    let ours = ours.clone();
+                    self.conflict(&unit, Some(ours), message);
+                    return Ok(());
+                }
+
+                let mut base_data = match base {
+                    Some(base) if is_regular(base) => read_blob(&base.hash)?,
+                    _ => Vec::new(),
+                };
+                let mut ours_data = read_blob(&ours.hash)?;
+                let mut theirs_data = read_blob(&theirs.hash)?;
+                if self.options.renormalize {
+                    base_data = renormalize(base_data);
+                    ours_data = renormalize(ours_data);
+                    theirs_data = renormalize(theirs_data);
+                }
+                if diff::is_binary(&base_data)
+                    || diff::is_binary(&ours_data)
+                    || diff::is_binary(&theirs_data)
+                {
+                    let winner = match self.options.favor {
+                        Favor::Ours => Some(ours),
+                        Favor::Theirs => Some(theirs),
+                        Favor::None => None,
+                    };
+                    if let Some(winner) = winner {
+                        let entry = TreeEntry {
+                            mode,
+                            hash: winner.hash.clone(),
+                        };
+                        self.merge.result.insert(path, entry);
+                        return Ok(());
+                    }
+                    let message = format!(
+                        "warning: Cannot merge binary files: {path} ({} vs. {})\n\
+                         CONFLICT ({kind}): Merge conflict in {path}",
+                        self.labels.ours, self.labels.theirs
+                    );
+                    let ours = ours.clone();
+                    self.conflict(&unit, Some(ours), message);
+                    return Ok(());
+                }
+
+                let merged = merge_content(
+                    &base_data,
+                    &ours_data,
+                    &theirs_data,
+                    self.labels,
+                    self.options,
+                );
+                let entry = TreeEntry {
+                    mode,
+                    hash: write_blob(merged.data)?,
+                };
+                if merged.conflicts > 0 {
+                    let message = format!("CONFLICT ({kind}): Merge conflict in {path}");
+                    self.conflict(&unit, Some(entry), message);
+                } else {
+                    self.merge.auto_merged.push(path.clone());
+                    self.merge.result.insert(path, entry);
+                }
+            }
+            (_, None, None) | (None, _, None) | (None, None, _) => {
+                unreachable!("one-sided changes are resolved above")
+            }
+        }
+        Ok(())
+    }
+
+    /// Move files out of the way of directories with the same name.
+    fn resolve_directory_file_conflicts(&mut self, ours: &FlatTree) {
+        let paths: Vec<String> = self.merge.result.keys().cloned().collect();
+        for path in paths {
+            let dir = format!("{path}/");
+            let has_children = self
+                .merge
+                .result
+                .range(dir.clone()..)
+                .next()
+                .is_some_and(|(p, _)| p.starts_with(&dir));
+            if !has_children {
+                continue;
+            }
+            let entry = self.merge.result.remove(&path).expect("path is in result");
+            let (label, from_ours) = if ours.get(&path) == Some(&entry) {
+                (&self.labels.ours, true)
+            } else {
+                (&self.labels.theirs, false)
+            };
+            let new_path = format!("{path}~{}", label.replace('/', "_"));
+            let message = format!(
+                "CONFLICT (file/directory): directory in the way of {path} from {label}; \
+                 moving it to {new_path} instead."
+            );
+            // An existing conflict at the path moves along with the file.
+            if let Some(conflict) = self.merge.conflicts.iter_mut().find(|c| c.path == path) {
+                conflict.path = new_path.clone();
+                conflict.message.push('\n');
+                conflict.message.push_str(&message);
+                self.merge.result.insert(new_path, entry);
+                continue;
+            }
+            let unit = Unit {
+                path: new_path,
+                base: None,
+                ours: from_ours.then(|| entry.clone()),
+                theirs: (!from_ours).then(|| entry.clone()),
+            };
+            self.conflict(&unit, Some(entry), message);
+        }
+    }
+}
+
+/// Merge the trees `ours` and `theirs` with the common ancestor `base`.
+pub(crate) fn merge_trees(
+    base: &FlatTree,
+    ours: &FlatTree,
+    theirs: &FlatTree,
+    labels: &Labels,
+    options: &MergeOptions,
+) -> anyhow::Result<TreeMerge> {
+    let ours_renames = detect_renames(base, ours, theirs).context("detect renames in ours")?;
+    let theirs_renames = detect_renames(base, theirs, ours).context("detect renames in theirs")?;
+
+    let mut merger = TreeMerger {
+        labels,
+        options,
+        merge: TreeMerge {
+            result: FlatTree::new(),
+            conflicts: Vec::new(),
+            auto_merged: Vec::new(),
+        },
+    };
+    let mut units = Vec::new();
+    let mut done_base = BTreeSet::new();
+    let mut done_ours = BTreeSet::new();
+    let mut done_theirs = BTreeSet::new();
+
+    for (src, ours_dst) in &ours_renames {
+        done_base.insert(src.clone());
+        done_ours.insert(ours_dst.clone());
+        match theirs_renames.get(src) {
+            Some(theirs_dst) if theirs_dst == ours_dst => {
+                done_theirs.insert(theirs_dst.clone());
+                units.push(Unit {
+                    path: ours_dst.clone(),
+                    base: base.get(src).cloned(),
+                    ours: ours.get(ours_dst).cloned(),
+                    theirs: theirs.get(theirs_dst).cloned(),
+                });
+            }
+            Some(theirs_dst) => {
+                done_theirs.insert(theirs_dst.clone());
+                let message = format!(
+                    "CONFLICT (rename/rename): {src} renamed to {ours_dst} in {} and to \
+                     {theirs_dst} in {}.",
+                    labels.ours, labels.theirs
+                );
+                let ours_unit = Unit {
+                    path: ours_dst.clone(),
+                    base: base.get(src).cloned(),
+                    ours: ours.get(ours_dst).cloned(),
+                    theirs: None,
+                };
+                merger.conflict(&ours_unit, ours_unit.ours.clone(), message.clone());
+                let theirs_unit = Unit {
+                    path: theirs_dst.clone(),
+                    base: base.get(src).cloned(),
+                    ours: None,
+                    theirs: theirs.get(theirs_dst).cloned(),
+                };
+                merger.conflict(&theirs_unit, theirs_unit.theirs.clone(), message);
+            }
+            None if theirs.contains_key(src) => {
+                done_theirs.insert(src.clone());
+                units.push(Unit {
+                    path: ours_dst.clone(),
+                    base: base.get(src).cloned(),
+                    ours: ours.get(ours_dst).cloned(),
+                    theirs: theirs.get(src).cloned(),
+                });
+            }
+            None => {
+                let unit = Unit {
+                    path: ours_dst.clone(),
+                    base: base.get(src).cloned(),
+                    ours: ours.get(ours_dst).cloned(),
+                    theirs: None,
+                };
+                let message = format!(
+                    "CONFLICT (rename/delete): {src} renamed to {ours_dst} in {}, but deleted \
+                     in {}.",
+                    labels.ours, labels.theirs
+                );
+                merger.conflict(&unit, unit.ours.clone(), message);
+            }
+        }
+    }
+    for (src, theirs_dst) in &theirs_renames {
+        if done_base.contains(src) {
+            continue;
+        }
+        done_base.insert(src.clone());
+        done_theirs.insert(theirs_dst.clone());
+        if ours.contains_key(src) {
+            done_ours.insert(src.clone());
+            units.push(Unit {
+                path: theirs_dst.clone(),
+                base: base.get(src).cloned(),
+                ours: ours.get(src).cloned(),
+                theirs: theirs.get(theirs_dst).cloned(),
+            });
+        } else {
+            let unit = Unit {
+                path: theirs_dst.clone(),
+                base: base.get(src).cloned(),
+                ours: None,
+                theirs: theirs.get(theirs_dst).cloned(),
+            };
+            let message = format!(
+                "CONFLICT (rename/delete): {src} renamed to {theirs_dst} in {}, but deleted \
+                 in {}.",
+                labels.theirs, labels.ours
+            );
+            merger.conflict(&unit, unit.theirs.clone(), message);
+        }
+    }
+
+    let paths: BTreeSet<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+    for path in paths {
+        let unit = Unit {
+            path: path.clone(),
+            base: base
+                .get(path)
+                .filter(|_| !done_base.contains(path))
+                .cloned(),
+            ours: ours
+                .get(path)
+                .filter(|_| !done_ours.contains(path))
+                .cloned(),
+            theirs: theirs
+                .get(path)
+                .filter(|_| !done_theirs.contains(path))
+                .cloned(),
+        };
+        if unit.base.is_none() && unit.ours.is_none() && unit.theirs.is_none() {
+            continue;
+        }
+        units.push(unit);
+    }
+
+    for unit in units {
+        merger.merge_unit(unit)?;
+    }
+    merger.resolve_directory_file_conflicts(ours);
+
+    Ok(merger.merge)
+}
@@ -1,17 +1,198 @@
 //! Git objects definitions.
+//!
+//! Objects are not rehashed when read unless `core.verifyObjects` is set, in which case reading an
+//! object to the end fails if its contents do not match its name. That setting is mini-git's own:
+//! Git has no equivalent and ignores it.
 use anyhow::Context;
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
-use sha1::{Digest, Sha1};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::ffi::CStr;
 use std::fmt;
 use std::io::prelude::*;
-use std::io::{self, BufReader};
-use std::path::Path;
+use std::io::{self, BufReader, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::OnceLock;
+
+use crate::config::Config;
+use crate::hash::{self, Hasher, ObjectId};
+use crate::pack;
+use crate::repository::git_path;
+use crate::shared;
+
+/// The path of the loose object `hash` in the object store.
+pub(crate) fn path(hash: &str) -> PathBuf {
+    git_path("objects").join(&hash[..2]).join(&hash[2..])
+}
+
+/// The hashes of all loose objects in the object store, sorted.
+pub(crate) fn loose_objects() -> anyhow::Result<Vec<String>> {
+    let mut hashes = Vec::new();
+    let dir = match std::fs::read_dir(git_path("objects")) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(hashes),
+        Err(e) => return Err(e).context("read .git/objects"),
+    };
+    for fan_out in dir {
+        let fan_out = fan_out.context("read .git/objects")?;
+        let prefix = fan_out.file_name().to_string_lossy().into_owned();
+        if prefix.len() != 2 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            continue;
+        }
+        for object in std::fs::read_dir(fan_out.path()).context("read object directory")? {
+            let rest = object.context("read object directory")?.file_name();
+            let hash = format!("{prefix}{}", rest.to_string_lossy());
+            if hash.len() == hash::algorithm().hex_len()
+                && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+            {
+                hashes.push(hash);
+            }
+        }
+    }
+    hashes.sort();
+    Ok(hashes)
+}
+
+/// Whether the object `hash` is in the object store, loose or packed.
+pub(crate) fn exists(hash: &str) -> anyhow::Result<bool> {
+    Ok(path(hash).exists() || pack::contains(hash)?)
+}
+
+/// Whether objects are rehashed when read, per `core.verifyObjects`. Unlike the other settings
+/// read here, this one is not Git's: Git never rehashes what it reads outside `fsck`, and its
+/// `transfer.fsckObjects` only checks objects received by fetches and pushes.
+fn verify_objects() -> anyhow::Result<bool> {
+    static VERIFY: OnceLock<bool> = OnceLock::new();
+    if let Some(verify) = VERIFY.get() {
+        return Ok(*verify);
+    }
+    let verify = Config::load()?
+        .get_bool("core.verifyObjects")?
+        .unwrap_or(false);
+    Ok(*VERIFY.get_or_init(|| verify))
+}
+
+/// The size past which blobs are streamed instead of read into memory, per
+/// `core.bigFileThreshold`: they are stored in packs undeltified and treated as binary by diffs.
+pub(crate) fn big_file_threshold() -> anyhow::Result<u64> {
+    static THRESHOLD: OnceLock<u64> = OnceLock::new();
+    if let Some(threshold) = THRESHOLD.get() {
+        return Ok(*threshold);
+    }
+    let threshold = Config::load()?
+        .get_int("core.bigFileThreshold")?
+        .map_or(Ok(512 << 20), u64::try_from)
+        .context("core.bigFileThreshold is negative")?;
+    Ok(*THRESHOLD.get_or_init(|| threshold))
+}
+
+/// Whether loose objects are flushed to disk before they are moved into place, per the
+/// components of `core.fsync` and the older `core.fsyncObjectFiles`. Git does not flush them by
+/// default.
+fn fsync_objects() -> anyhow::Result<bool> {
+    static FSYNC: OnceLock<bool> = OnceLock::new();
+    if let Some(fsync) = FSYNC.get() {
+        return Ok(*fsync);
+    }
+    let config = Config::load()?;
+    let mut fsync = config.get_bool("core.fsyncObjectFiles")?.unwrap_or(false);
+    // Removing a component only matters for those flushed by default, which loose objects are not.
+    for component in config
+        .get_string("core.fsync")
+        .unwrap_or_default()
+        .split(',')
+    {
+        let component = component.trim();
+        match component.strip_prefix('-').unwrap_or(component) {
+            "loose-object" | "objects" | "committed" | "added" | "all" => {
+                fsync |= !component.starts_with('-');
+            }
+            "" | "none" | "pack" | "pack-metadata" | "commit-graph" | "index" | "reference"
+            | "derived-metadata" => {}
+            _ => eprintln!("warning: ignoring unknown core.fsync component '{component}'"),
+        }
+    }
+    Ok(*FSYNC.get_or_init(|| fsync))
+}
+
+/// A temporary file in `.git/objects` that an object is written to before it is moved into
+/// place, so that readers never see a partly written object. It is removed if dropped before
+/// being stored, e.g. when writing fails.
+struct TempObject {
+    /// The path of the temporary file.
+    path: PathBuf,
+    /// The open temporary file.
+    file: std::fs::File,
+    /// Whether the file was moved into place.
+    stored: bool,
+}
+
+impl TempObject {
+    /// Create a temporary object file, with a name unique to the writer so that concurrent
+    /// writers do not clobber each other's objects.
+    fn create() -> anyhow::Result<TempObject> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = git_path("objects");
+        shared::create_dir_all(&dir)?;
+        let n = NEXT.fetch_add(1, atomic::Ordering::Relaxed);
+        let path = dir.join(format!("tmp_obj_{}_{n}", std::process::id()));
+        let file = std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("create temporary object file {}", path.display()))?;
+        Ok(TempObject {
+            path,
+            file,
+            stored: false,
+        })
+    }
+
+    /// Move the finished file to its place in the object store as the read-only object `hash`.
+    fn store(mut self, hash: &ObjectId) -> anyhow::Result<()> {
+        let hash = hash.to_string();
+        if fsync_objects()? {
+            self.file.sync_all().context("flush object file to disk")?;
+        }
+        let mut permissions = self.file.metadata()?.permissions();
+        permissions.set_readonly(true);
+        self.file.set_permissions(permissions)?;
+        shared::adjust(&self.path)?;
+        let path = path(&hash);
+        let dir = path
+            .parent()
+            .expect("object paths have a fan-out directory");
+        shared::create_dir_all(dir)?;
+        std::fs::rename(&self.path, &path)
+            .with_context(|| format!("move object to {}", path.display()))?;
+        self.stored = true;
+        Ok(())
+    }
+}
+
+impl Write for TempObject {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for TempObject {
+    fn drop(&mut self) {
+        if !self.stored {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
 
 /// Git object types
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum Kind {
     /// A blob is a file of arbitrary content.
     Blob,
@@ -20,6 +201,8 @@ pub(crate) enum Kind {
     /// A commit object is a set of metadata and file system changes associated with a particular
     /// snapshot of the project's source code.
     Commit,
+    /// An annotated tag names another object, usually a commit, with a message.
+    Tag,
 }
 
 impl fmt::Display for Kind {
@@ -28,6 +211,7 @@ impl fmt::Display for Kind {
             Kind::Blob => write!(f, "blob"),
             Kind::Tree => write!(f, "tree"),
             Kind::Commit => write!(f, "commit"),
+            Kind::Tag => write!(f, "tag"),
         }
     }
 }
@@ -43,6 +227,15 @@ pub(crate) struct Object<R> {
 }
 
 impl Object<()> {
+    /// Create a new in-memory object of the given kind.
+    pub(crate) fn from_bytes(kind: Kind, data: Vec<u8>) -> Object<Cursor<Vec<u8>>> {
+        Object {
+            kind,
+            expected_size: data.len() as u64,
+            reader: Cursor::new(data),
+        }
+    }
+
     /// Create a new object from a file (blob).
     pub(crate) fn blob_from_file(file: impl AsRef<Path>) -> anyhow::Result<Object<impl Read>> {
         let file = file.as_ref();
@@ -63,8 +256,19 @@ impl Object<()> {
     /// Read an object from the object store.
     pub(crate) fn read(hash: &str) -> anyhow::Result<Object<impl BufRead>> {
         // TODO: support shortest-unique object hashes
-        let f = std::fs::File::open(format!(".git/objects/{}/{}", &hash[..2], &hash[2..]))
-            .context("open in ./git/objects")?;
+        let path = path(hash);
+        let f = match std::fs::File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match pack::read(hash)? {
+                Some(object) => {
+                    let what = format!("packed object {hash}");
+                    let (kind, size) = (object.kind, object.expected_size);
+                    return Object::verifying(hash, what, kind, size, object.reader);
+                }
+                None => return Err(e).context("open in ./git/objects"),
+            },
+            Err(e) => return Err(e).context("open in ./git/objects"),
+        };
         let z = ZlibDecoder::new(f);
         let mut z = BufReader::new(z);
         let mut buf = Vec::new();
@@ -81,20 +285,44 @@ impl Object<()> {
             "blob" => Kind::Blob,
             "tree" => Kind::Tree,
             "commit" => Kind::Commit,
+            "tag" => Kind::Tag,
             _ => anyhow::bail!("unknown Git object kind: '{kind}'"),
         };
         let size = size
             .parse::<u64>()
             .context(".git/objects file header has invalid size: {size}")?;
-        let z = LimitReader {
+        let z: Box<dyn BufRead> = Box::new(LimitReader {
             reader: z,
             limit: size as usize,
-        };
+        });
+        let what = format!("loose object {hash} (stored in {})", path.display());
+        Object::verifying(hash, what, kind, size, z)
+    }
 
+    /// An object of `size` bytes read from `reader`, which is rehashed as it is read if
+    /// `core.verifyObjects` is set. `what` describes the object in errors.
+    fn verifying(
+        hash: &str,
+        what: String,
+        kind: Kind,
+        size: u64,
+        reader: Box<dyn BufRead>,
+    ) -> anyhow::Result<Object<VerifyReader<Box<dyn BufRead>>>> {
+        let hasher = verify_objects()?.then(|| {
+            let mut hasher = Hasher::for_repository();
+            hasher.update(format!("{kind} {size}\0").as_bytes());
+            hasher
+        });
+        let reader = VerifyReader {
+            reader,
+            hasher,
+            hash: hash.to_string(),
+            what,
+        };
         Ok(Object {
             kind,
             expected_size: size,
-            reader: z,
+            reader,
         })
     }
 }
@@ -104,44 +332,383 @@ where
     R: Read,
 {
     /// Write the object to a writer.
-    pub(crate) fn write(mut self, writer: impl Write) -> anyhow::Result<[u8; 20]> {
+    pub(crate) fn write(mut self, writer: impl Write) -> anyhow::Result<ObjectId> {
         let writer = ZlibEncoder::new(writer, Compression::default());
         let mut writer = HashWriter {
             writer,
-            hasher: Sha1::new(),
+            hasher: Hasher::for_repository(),
         };
         write!(writer, "{} {}\0", self.kind, self.expected_size)?;
         std::io::copy(&mut self.reader, &mut writer).context("stream file into blob")?;
         let _ = writer.writer.finish()?;
-        let hash = writer.hasher.finalize();
-        Ok(hash.into())
+        writer.hasher.finalize()
+    }
+
+    /// The id of the object, without compressing or storing it.
+    pub(crate) fn hash(mut self) -> anyhow::Result<ObjectId> {
+        let mut writer = HashWriter {
+            writer: io::sink(),
+            hasher: Hasher::for_repository(),
+        };
+        write!(writer, "{} {}\0", self.kind, self.expected_size)?;
+        std::io::copy(&mut self.reader, &mut writer).context("hash object contents")?;
+        writer.hasher.finalize()
+    }
+
+    /// Write the object to the `.git/objects` directory, through a temporary file there, unless
+    /// the object store already has it.
+    pub(crate) fn write_to_objects(self) -> anyhow::Result<ObjectId> {
+        self.write_to_store(false)
+    }
+
+    /// Write the object as a loose object, even if a pack already has it, e.g. because the pack
+    /// is about to be removed.
+    pub(crate) fn write_loose(self) -> anyhow::Result<ObjectId> {
+        self.write_to_store(true)
     }
 
-    /// Write the object to the `.git/objects` directory.
-    pub(crate) fn write_to_objects(self) -> anyhow::Result<[u8; 20]> {
-        let tmp = "tmp";
+    /// Write the object to a temporary file in `.git/objects` and move it into place unless the
+    /// object store already has it, as a loose object if `loose`.
+    fn write_to_store(self, loose: bool) -> anyhow::Result<ObjectId> {
+        let mut tmp = TempObject::create()?;
         let hash = self
-            .write(std::fs::File::create(tmp).context("construct temporary file for tree")?)
-            .context("stream tree object into file")?;
-
-        let hash_hex = hex::encode(hash);
-        std::fs::create_dir_all(format!(".git/objects/{}/", &hash_hex[..2]))
-            .context("create subdirectory of `.git/objects/`")?;
-        std::fs::rename(
-            tmp,
-            format!(".git/objects/{}/{}", &hash_hex[..2], &hash_hex[2..]),
-        )
-        .context("move tmp tree file into `.git/objects`")?;
+            .write(&mut tmp)
+            .context("stream object into temporary file")?;
+        let hex = hash.to_string();
+        let stored = if loose {
+            path(&hex).exists()
+        } else {
+            exists(&hex)?
+        };
+        // An unused temporary file is removed when dropped.
+        if !stored {
+            tmp.store(&hash)
+                .context("move temporary object file into `.git/objects`")?;
+        }
         Ok(hash)
     }
 }
 
-/// A writer that hashes the contents of a file using the SHA-1 algorithm.
+impl<R> Object<R>
+where
+    R: Read,
+{
+    /// Read the remaining contents of the object into memory.
+    pub(crate) fn into_bytes(mut self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.expected_size as usize);
+        self.reader
+            .read_to_end(&mut buf)
+            .context("read object contents")?;
+        anyhow::ensure!(
+            buf.len() as u64 == self.expected_size,
+            "object was not the expected size (expected: {}, actual: {})",
+            self.expected_size,
+            buf.len()
+        );
+        Ok(buf)
+    }
+}
+
+/// A parsed commit object.
+#[derive(Debug, Clone)]
+pub(crate) struct Commit {
+    /// Hex hash of the root tree of the commit.
+    pub(crate) tree: String,
+    /// Hex hashes of the parent commits, in order.
+    pub(crate) parents: Vec<String>,
+    /// The raw author line, e.g. `Name <email> 1700000000 +0000`.
+    pub(crate) author: String,
+    /// The raw committer line, e.g. `Name <email> 1700000000 +0000`.
+    pub(crate) committer: String,
+    /// The commit message, including its trailing newline.
+    pub(crate) message: String,
+}
+
+impl Commit {
+    /// Read and parse a commit object from the object store.
+    pub(crate) fn read(hash: &str) -> anyhow::Result<Commit> {
+        let object = Object::read(hash).with_context(|| format!("read commit {hash}"))?;
+        anyhow::ensure!(
+            object.kind == Kind::Commit,
+            "object {hash} is a {}, not a commit",
+            object.kind
+        );
+        let data = object.into_bytes()?;
+        Commit::parse(&data).with_context(|| format!("parse commit {hash}"))
+    }
+
+    /// Parse the contents of a commit object (without the object header).
+    pub(crate) fn parse(data: &[u8]) -> anyhow::Result<Commit> {
+        let data = std::str::from_utf8(data).context("commit is not valid UTF-8")?;
+        let (headers, message) = data.split_once("\n\n").unwrap_or((data, ""));
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        for line in headers.lines() {
+            // Continuation lines of multi-line headers (e.g. `gpgsig`) start with a space.
+            if line.starts_with(' ') {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "tree" => tree = Some(value.to_string()),
+                "author" => author = Some(value.to_string()),
+                "parent" => parents.push(value.to_string()),
+                "committer" => committer = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(Commit {
+            tree: tree.context("commit has no tree")?,
+            parents,
+            author: author.unwrap_or_default(),
+            committer: committer.context("commit has no committer")?,
+            message: message.to_string(),
+        })
+    }
+
+    /// The committer timestamp in seconds since the Unix epoch.
+    pub(crate) fn committer_time(&self) -> i64 {
+        signature_time(&self.committer)
+    }
+
+    /// The first line of the commit message.
+    pub(crate) fn summary(&self) -> &str {
+        self.message.lines().next().unwrap_or("")
+    }
+}
+
+/// A parsed annotated tag object.
+#[derive(Debug, Clone)]
+pub(crate) struct Tag {
+    /// Hex hash of the tagged object.
+    pub(crate) object: String,
+    /// The name of the tag, e.g. `v1.0`.
+    pub(crate) name: String,
+    /// The raw tagger line, e.g. `Name <email> 1700000000 +0000`, if any.
+    pub(crate) tagger: Option<String>,
+    /// The tag message, including its trailing newline.
+    pub(crate) message: String,
+}
+
+impl Tag {
+    /// Parse the contents of a tag object (without the object header).
+    pub(crate) fn parse(data: &[u8]) -> anyhow::Result<Tag> {
+        let data = std::str::from_utf8(data).context("tag is not valid UTF-8")?;
+        let (headers, message) = data.split_once("\n\n").unwrap_or((data, ""));
+        let mut object = None;
+        let mut name = String::new();
+        let mut tagger = None;
+        for line in headers.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "object" => object = Some(value.to_string()),
+                "tag" => name = value.to_string(),
+                "tagger" => tagger = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(Tag {
+            object: object.context("tag has no object")?,
+            name,
+            tagger,
+            message: message.to_string(),
+        })
+    }
+}
+
+/// Extract the timestamp from an identity line such as `Name <email> 1700000000 +0000`.
+pub(crate) fn signature_time(signature: &str) -> i64 {
+    signature
+        .rsplit_once('>')
+        .and_then(|(_, rest)| rest.split_whitespace().next())
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(0)
+}
+
+/// File mode of a subtree entry.
+pub(crate) const MODE_TREE: u32 = 0o040000;
+/// File mode of a regular, non-executable file.
+pub(crate) const MODE_FILE: u32 = 0o100644;
+/// File mode of an executable file.
+pub(crate) const MODE_EXECUTABLE: u32 = 0o100755;
+/// File mode of a symbolic link.
+pub(crate) const MODE_SYMLINK: u32 = 0o120000;
+/// File mode of a submodule commit.
+pub(crate) const MODE_GITLINK: u32 = 0o160000;
+
+/// A single entry of a tree object.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct TreeEntry {
+    /// The file mode, e.g. [`MODE_FILE`].
+    pub(crate) mode: u32,
+    /// Hex hash of the object the entry points to.
+    pub(crate) hash: String,
+}
+
+impl TreeEntry {
+    /// Whether the entry is a subtree.
+    pub(crate) fn is_tree(&self) -> bool {
+        self.mode == MODE_TREE
+    }
+}
+
+/// All blobs (and submodule commits) reachable from a tree, keyed by their `/`-separated path.
+pub(crate) type FlatTree = BTreeMap<String, TreeEntry>;
+
+/// A parsed tree object.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tree {
+    /// The entries of the tree in the order they are stored.
+    pub(crate) entries: Vec<(String, TreeEntry)>,
+}
+
+impl Tree {
+    /// Read and parse a tree object from the object store.
+    pub(crate) fn read(hash: &str) -> anyhow::Result<Tree> {
+        let object = Object::read(hash).with_context(|| format!("read tree {hash}"))?;
+        anyhow::ensure!(
+            object.kind == Kind::Tree,
+            "object {hash} is a {}, not a tree",
+            object.kind
+        );
+        let data = object.into_bytes()?;
+        Tree::parse(&data).with_context(|| format!("parse tree {hash}"))
+    }
+
+    /// Parse the contents of a tree object (without the object header).
+    pub(crate) fn parse(mut data: &[u8]) -> anyhow::Result<Tree> {
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            let nul = data
+                .iter()
+                .position(|&b| b == 0)
+                .context("tree entry is not NUL-terminated")?;
+            let mode_and_name =
+                std::str::from_utf8(&data[..nul]).context("tree entry name is not valid UTF-8")?;
+            let (mode, name) = mode_and_name
+                .split_once(' ')
+                .context("tree entry has no file name")?;
+            let mode = u32::from_str_radix(mode, 8).context("tree entry has invalid mode")?;
+            let size = hash::algorithm().size();
+            let hash = data
+                .get(nul + 1..nul + 1 + size)
+                .context("tree entry hash is truncated")?;
+            entries.push((
+                name.to_string(),
+                TreeEntry {
+                    mode,
+                    hash: hex::encode(hash),
+                },
+            ));
+            data = &data[nul + 1 + size..];
+        }
+        Ok(Tree { entries })
+    }
+
+    /// Serialize and write the tree to the `.git/objects` directory.
+    ///
+    /// Entries are sorted following Git's conventions first.
+    pub(crate) fn write(mut self) -> anyhow::Result<ObjectId> {
+        self.entries.sort_by(|a, b| {
+            tree_entry_cmp(a.0.as_bytes(), a.1.is_tree(), b.0.as_bytes(), b.1.is_tree())
+        });
+        let mut tree_object = Vec::new();
+        for (name, entry) in &self.entries {
+            tree_object.extend(format!("{:o} {name}\0", entry.mode).as_bytes());
+            tree_object.extend_from_slice(
+                ObjectId::from_hex(&entry.hash)
+                    .context("tree entry hash is not hex")?
+                    .as_bytes(),
+            );
+        }
+        Object::from_bytes(Kind::Tree, tree_object)
+            .write_to_objects()
+            .context("write tree object")
+    }
+
+    /// Read a tree and all its subtrees into a [`FlatTree`].
+    pub(crate) fn read_flat(hash: &str) -> anyhow::Result<FlatTree> {
+        let mut flat = FlatTree::new();
+        Tree::read_flat_into(hash, "", &mut flat)?;
+        Ok(flat)
+    }
+
+    fn read_flat_into(hash: &str, prefix: &str, flat: &mut FlatTree) -> anyhow::Result<()> {
+        for (name, entry) in Tree::read(hash)?.entries {
+            let path = format!("{prefix}{name}");
+            if entry.is_tree() {
+                Tree::read_flat_into(&entry.hash, &format!("{path}/"), flat)?;
+            } else {
+                flat.insert(path, entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a [`FlatTree`] as a hierarchy of tree objects, returning the root tree hash.
+    pub(crate) fn write_flat(flat: &FlatTree) -> anyhow::Result<ObjectId> {
+        let entries: Vec<(&str, &TreeEntry)> = flat
+            .iter()
+            .map(|(path, entry)| (path.as_str(), entry))
+            .collect();
+        Tree::write_flat_level(&entries)
+    }
+
+    fn write_flat_level(entries: &[(&str, &TreeEntry)]) -> anyhow::Result<ObjectId> {
+        let mut tree = Tree::default();
+        let mut subtrees: BTreeMap<&str, Vec<(&str, &TreeEntry)>> = BTreeMap::new();
+        for &(path, entry) in entries {
+            match path.split_once('/') {
+                Some((dir, rest)) => subtrees.entry(dir).or_default().push((rest, entry)),
+                None => tree.entries.push((path.to_string(), entry.clone())),
+            }
+        }
+        for (dir, children) in subtrees {
+            let hash = Tree::write_flat_level(&children)?;
+            tree.entries.push((
+                dir.to_string(),
+                TreeEntry {
+                    mode: MODE_TREE,
+                    hash: hash.to_string(),
+                },
+            ));
+        }
+        tree.write()
+    }
+}
+
+/// Compare two tree entry names following Git's conventions, where directories sort as if their
+/// name ended in `/`.
+///
+/// See <https://github.com/git/git/blob/64cbe5e2e8a7b0f92c780b210e602496bd5cad0f/tree.c#L101>
+pub(crate) fn tree_entry_cmp(a: &[u8], a_is_dir: bool, b: &[u8], b_is_dir: bool) -> Ordering {
+    let common_len = std::cmp::min(a.len(), b.len());
+    match a[..common_len].cmp(&b[..common_len]) {
+        Ordering::Equal => {}
+        o => return o,
+    }
+    if a.len() == b.len() {
+        return Ordering::Equal;
+    }
+    let c1 = a
+        .get(common_len)
+        .copied()
+        .or(if a_is_dir { Some(b'/') } else { None });
+    let c2 = b
+        .get(common_len)
+        .copied()
+        .or(if b_is_dir { Some(b'/') } else { None });
+    c1.cmp(&c2)
+}
+
+/// A writer that hashes the contents of a file using the repository's hash algorithm.
 struct HashWriter<W> {
     /// The underlying writer (e.g., `ZlibEncoder<File>`).
     writer: W,
     /// The hasher used to hash the contents of the file.
-    hasher: Sha1,
+    hasher: Hasher,
 }
 
 impl<W> Write for HashWriter<W>
@@ -167,6 +734,74 @@ struct LimitReader<R> {
     limit: usize,
 }
 
+/// A reader that hashes the contents of an object as they are read, and checks the hash against
+/// the name of the object when the end is reached.
+struct VerifyReader<R> {
+    /// Underlying reader (e.g., `LimitReader<BufReader<ZlibDecoder<File>>>`).
+    reader: R,
+    /// The hasher fed the object header and the contents read so far, `None` once verified or
+    /// if verification is disabled.
+    hasher: Option<Hasher>,
+    /// The hex hash the object is stored as.
+    hash: String,
+    /// A description of the object for errors, e.g. `loose object <hash> (stored in <path>)`.
+    what: String,
+}
+
+impl<R> VerifyReader<R> {
+    /// Check the hash of everything read against the name of the object.
+    fn verify(&mut self) -> io::Result<()> {
+        let Some(hasher) = self.hasher.take() else {
+            return Ok(());
+        };
+        let actual = hasher.finalize().map_err(io::Error::other)?;
+        if actual.to_string() != self.hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupt", self.what),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<R> Read for VerifyReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        match &mut self.hasher {
+            Some(hasher) if n > 0 => hasher.update(&buf[..n]),
+            Some(_) if !buf.is_empty() => self.verify()?,
+            _ => {}
+        }
+        Ok(n)
+    }
+}
+
+impl<R> BufRead for VerifyReader<R>
+where
+    R: BufRead,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.hasher.is_some() && self.reader.fill_buf()?.is_empty() {
+            self.verify()?;
+        }
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if let Some(hasher) = &mut self.hasher {
+            // The consumed bytes are still buffered, so this does not read.
+            if let Ok(buf) = self.reader.fill_buf() {
+                hasher.update(&buf[..amount.min(buf.len())]);
+            }
+        }
+        self.reader.consume(amount);
+    }
+}
+
 impl<R> Read for LimitReader<R>
 where
     R: Read,
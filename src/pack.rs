@@ -0,0 +1,975 @@
+//! Pack files, which store many objects in a single file, zlib-compressed and mostly as deltas
+//! against similar objects (see [`delta`]).
+//!
+//! Each `objects/pack/pack-<checksum>.pack` has a `.idx` file next to it listing its object ids
+//! in sorted order, with a fan-out table by first byte, the CRC-32 of each entry and its offset
+//! in the pack.
+//!
+//! A multi-pack index (see [`midx`]) can cover many packs, so that objects are looked up in it
+//! instead of in each of their indexes, and a pack can have reachability bitmaps (see
+//! [`bitmap`]).
+//!
+//! See: <https://git-scm.com/docs/pack-format>
+use anyhow::Context;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::hash::{self, Hasher, ObjectId};
+use crate::objects::{self, Kind, Object};
+use crate::repository::git_path;
+use crate::shared;
+use midx::MultiPackIndex;
+
+pub(crate) mod bitmap;
+pub(crate) mod delta;
+pub(crate) mod midx;
+
+/// The magic bytes pack files start with.
+const PACK_SIGNATURE: &[u8; 4] = b"PACK";
+
+/// The magic bytes version 2 index files start with.
+const IDX_SIGNATURE: &[u8; 4] = b"\xfftOc";
+
+/// The version of pack and index files written.
+const VERSION: u32 = 2;
+
+/// The size of the index file header and fan-out table.
+const IDX_HEADER_SIZE: usize = 8 + 256 * 4;
+
+/// The entry type of a delta against an earlier entry of the same pack.
+const OFS_DELTA: u8 = 6;
+
+/// The entry type of a delta against an object named by its id.
+const REF_DELTA: u8 = 7;
+
+/// The longest delta chain followed when reading, guarding against cycles.
+const MAX_CHAIN_LENGTH: usize = 10_000;
+
+/// The pack entry type of an object kind.
+fn type_code(kind: Kind) -> u8 {
+    match kind {
+        Kind::Commit => 1,
+        Kind::Tree => 2,
+        Kind::Blob => 3,
+        Kind::Tag => 4,
+    }
+}
+
+/// The object kind of a pack entry type, `None` for deltas and invalid types.
+fn kind_of(code: u8) -> Option<Kind> {
+    match code {
+        1 => Some(Kind::Commit),
+        2 => Some(Kind::Tree),
+        3 => Some(Kind::Blob),
+        4 => Some(Kind::Tag),
+        _ => None,
+    }
+}
+
+/// A pack file and its index.
+pub(crate) struct Pack {
+    /// The path of the `.pack` file.
+    pub(crate) path: PathBuf,
+    /// The contents of the `.idx` file.
+    idx: Vec<u8>,
+    /// The number of objects in the pack.
+    count: usize,
+}
+
+/// Where the base of a pack entry is.
+enum Base {
+    /// The entry is a whole object.
+    None,
+    /// The base is the entry at this offset of the pack.
+    Offset(u64),
+    /// The base is the object with this id.
+    Id(String),
+}
+
+/// A pack entry as stored: its type, base and inflated data.
+struct Entry {
+    /// The entry type, e.g. [`OFS_DELTA`].
+    code: u8,
+    /// The base of a delta.
+    base: Base,
+    /// The object contents, or the delta against the base.
+    data: Vec<u8>,
+}
+
+impl Pack {
+    /// Open the pack whose index file is `idx_path`.
+    pub(crate) fn open(idx_path: &Path) -> anyhow::Result<Pack> {
+        let idx = std::fs::read(idx_path)
+            .with_context(|| format!("read pack index {}", idx_path.display()))?;
+        let hash_size = hash::algorithm().size();
+        anyhow::ensure!(
+            idx.len() >= IDX_HEADER_SIZE + 2 * hash_size
+                && &idx[..4] == IDX_SIGNATURE
+                && be32(&idx[4..]) == VERSION,
+            "{} is not a version 2 pack index",
+            idx_path.display()
+        );
+        let count = be32(&idx[IDX_HEADER_SIZE - 4..]) as usize;
+        anyhow::ensure!(
+            idx.len() >= IDX_HEADER_SIZE + count * (hash_size + 8) + 2 * hash_size,
+            "pack index {} is truncated",
+            idx_path.display()
+        );
+        Ok(Pack {
+            path: idx_path.with_extension("pack"),
+            idx,
+            count,
+        })
+    }
+
+    /// The path of the `.idx` file.
+    pub(crate) fn idx_path(&self) -> PathBuf {
+        self.path.with_extension("idx")
+    }
+
+    /// The file name of the `.idx` file, which multi-pack indexes name packs by.
+    pub(crate) fn idx_name(&self) -> String {
+        let idx = self.idx_path();
+        idx.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// The id of the `i`th object in sorted order.
+    fn id(&self, i: usize) -> &[u8] {
+        let size = hash::algorithm().size();
+        let start = IDX_HEADER_SIZE + i * size;
+        &self.idx[start..start + size]
+    }
+
+    /// The CRC-32 of the `i`th object's entry.
+    fn crc(&self, i: usize) -> u32 {
+        let start = IDX_HEADER_SIZE + self.count * hash::algorithm().size();
+        be32(&self.idx[start + i * 4..])
+    }
+
+    /// The offset of the `i`th object's entry in the pack.
+    fn offset(&self, i: usize) -> u64 {
+        let start = IDX_HEADER_SIZE + self.count * (hash::algorithm().size() + 4);
+        let offset = be32(&self.idx[start + i * 4..]);
+        if offset & 0x8000_0000 == 0 {
+            return u64::from(offset);
+        }
+        // Offsets past 2 GiB are kept in a table of 8-byte offsets.
+        let large = start + self.count * 4 + (offset & 0x7fff_ffff) as usize * 8;
+        u64::from_be_bytes(self.idx[large..large + 8].try_into().expect("8 bytes"))
+    }
+
+    /// The hex ids of the objects in the pack, sorted.
+    pub(crate) fn hashes(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.count).map(|i| hex::encode(self.id(i)))
+    }
+
+    /// The position of the object `hash` in sorted order, if the pack has it.
+    fn find(&self, hash: &str) -> Option<usize> {
+        let id = ObjectId::from_hex(hash).ok()?;
+        let id = id.as_bytes();
+        let fan_out = |byte: usize| be32(&self.idx[8 + byte * 4..]) as usize;
+        let first = usize::from(id[0]);
+        let (mut low, mut high) = (
+            if first == 0 { 0 } else { fan_out(first - 1) },
+            fan_out(first),
+        );
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.id(mid).cmp(id) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    /// Whether the pack has the object `hash`.
+    pub(crate) fn contains(&self, hash: &str) -> bool {
+        self.find(hash).is_some()
+    }
+
+    /// Read the object `hash` from the pack, if it has it.
+    pub(crate) fn read(&self, hash: &str) -> anyhow::Result<Option<Object<Box<dyn BufRead>>>> {
+        let Some(i) = self.find(hash) else {
+            return Ok(None);
+        };
+        self.read_object(hash, self.offset(i)).map(Some)
+    }
+
+    /// Read the object `hash`, whose entry is at `offset`.
+    ///
+    /// A whole object is inflated as it is read, so that big blobs are never held in memory,
+    /// while a delta is applied to its base in memory.
+    fn read_object(&self, hash: &str, offset: u64) -> anyhow::Result<Object<Box<dyn BufRead>>> {
+        let context = || format!("read object {hash} from {}", self.path.display());
+        let mut file = BufReader::new(
+            File::open(&self.path).with_context(|| format!("open {}", self.path.display()))?,
+        );
+        let (code, size, base) = read_header(&mut file, offset).with_context(context)?;
+        if let (Base::None, Some(kind)) = (base, kind_of(code)) {
+            let reader = BufReader::new(flate2::bufread::ZlibDecoder::new(file).take(size));
+            return Ok(Object {
+                kind,
+                expected_size: size,
+                reader: Box::new(reader),
+            });
+        }
+        let (kind, data) = self.read_at(&mut file, offset).with_context(context)?;
+        Ok(Object {
+            kind,
+            expected_size: data.len() as u64,
+            reader: Box::new(std::io::Cursor::new(data)),
+        })
+    }
+
+    /// The kind of the object whose entry is at `offset`, following its delta bases without
+    /// inflating anything.
+    fn kind_at(&self, file: &mut BufReader<File>, mut offset: u64) -> anyhow::Result<Kind> {
+        for _ in 0..MAX_CHAIN_LENGTH {
+            let (code, _, base) = read_header(file, offset)?;
+            match base {
+                Base::None => {
+                    return kind_of(code).with_context(|| format!("invalid object type {code}"))
+                }
+                Base::Offset(base) => offset = base,
+                Base::Id(base) => match self.find(&base) {
+                    Some(i) => offset = self.offset(i),
+                    None => {
+                        return Ok(Object::read(&base)
+                            .with_context(|| format!("read delta base {base}"))?
+                            .kind)
+                    }
+                },
+            }
+        }
+        anyhow::bail!("delta chain is too long")
+    }
+
+    /// Read the object whose entry is at `offset`, applying any chain of deltas.
+    fn read_at(
+        &self,
+        file: &mut BufReader<File>,
+        mut offset: u64,
+    ) -> anyhow::Result<(Kind, Vec<u8>)> {
+        let mut deltas = Vec::new();
+        let (kind, mut data) = loop {
+            anyhow::ensure!(deltas.len() < MAX_CHAIN_LENGTH, "delta chain is too long");
+            let entry = read_entry(file, offset)?;
+            match entry.base {
+                Base::None => {
+                    let kind = kind_of(entry.code)
+                        .with_context(|| format!("invalid object type {}", entry.code))?;
+                    break (kind, entry.data);
+                }
+                Base::Offset(base) => {
+                    deltas.push(entry.data);
+                    offset = base;
+                }
+                Base::Id(base) => {
+                    deltas.push(entry.data);
+                    let base = match self.find(&base) {
+                        Some(i) => self.read_at(file, self.offset(i))?,
+                        None => {
+                            let object = Object::read(&base)
+                                .with_context(|| format!("read delta base {base}"))?;
+                            (object.kind, object.into_bytes()?)
+                        }
+                    };
+                    break base;
+                }
+            }
+        };
+        for delta in deltas.iter().rev() {
+            data = delta::apply(&data, delta)?;
+        }
+        Ok((kind, data))
+    }
+
+    /// Check the checksums of the pack and its index and the CRC-32 of every entry.
+    ///
+    /// The pack is read once from start to end, so that its size does not matter.
+    pub(crate) fn verify(&self) -> anyhow::Result<()> {
+        let size = hash::algorithm().size();
+        let file =
+            File::open(&self.path).with_context(|| format!("open {}", self.path.display()))?;
+        let len = file
+            .metadata()
+            .with_context(|| format!("read metadata of {}", self.path.display()))?
+            .len();
+        let mut reader = BufReader::new(file);
+        let mut header = [0; 12];
+        let valid = len >= 12 + size as u64
+            && reader.read_exact(&mut header).is_ok()
+            && &header[..4] == PACK_SIGNATURE;
+        anyhow::ensure!(valid, "{} is not a pack file", self.path.display());
+        let content_len = len - size as u64;
+        let mut hasher = Hasher::for_repository();
+        hasher.update(&header);
+
+        // Entries are checked in the order they are in the pack, and a bad CRC-32 is reported
+        // after a bad checksum, which it is a symptom of.
+        let mut offsets: Vec<(u64, usize)> = (0..self.count).map(|i| (self.offset(i), i)).collect();
+        offsets.sort_unstable();
+        let ends = offsets
+            .iter()
+            .skip(1)
+            .map(|&(offset, _)| offset)
+            .chain([content_len]);
+        let mut position = 12;
+        let mut bad_crc = None;
+        for (&(start, i), end) in offsets.iter().zip(ends) {
+            anyhow::ensure!(
+                position <= start && end <= content_len,
+                "offset {start} is beyond the end of the pack"
+            );
+            copy_exact(&mut reader, start - position, &mut hasher)
+                .with_context(|| format!("read {}", self.path.display()))?;
+            let mut crc = CrcWriter {
+                hasher: &mut hasher,
+                crc: Crc::new(),
+            };
+            copy_exact(&mut reader, end - start, &mut crc)
+                .with_context(|| format!("read {}", self.path.display()))?;
+            if crc.crc.sum() != self.crc(i) && bad_crc.is_none() {
+                bad_crc = Some(format!(
+                    "index CRC mismatch for object {} from {} at offset {start}",
+                    hex::encode(self.id(i)),
+                    self.path.display()
+                ));
+            }
+            position = end;
+        }
+        copy_exact(&mut reader, content_len - position, &mut hasher)
+            .with_context(|| format!("read {}", self.path.display()))?;
+        let mut trailer = vec![0; size];
+        reader
+            .read_exact(&mut trailer)
+            .with_context(|| format!("read {}", self.path.display()))?;
+        anyhow::ensure!(
+            hasher.finalize()?.as_bytes() == trailer,
+            "{} SHA1 checksum mismatch",
+            self.path.display()
+        );
+        let (idx_content, idx_trailer) = self.idx.split_at(self.idx.len() - size);
+        anyhow::ensure!(
+            Hasher::digest(idx_content)?.as_bytes() == idx_trailer,
+            "{} SHA1 checksum mismatch",
+            self.idx_path().display()
+        );
+        anyhow::ensure!(
+            idx_content[idx_content.len() - size..] == trailer
+                && be32(&header[8..]) as usize == self.count,
+            "packfile {} does not match index",
+            self.path.display()
+        );
+        match bad_crc {
+            Some(message) => anyhow::bail!(message),
+            None => Ok(()),
+        }
+    }
+
+    /// The id and kind of every object of the pack, in offset order, without reading their
+    /// contents.
+    pub(crate) fn kinds(&self) -> anyhow::Result<Vec<(String, Kind)>> {
+        let mut offsets: Vec<(u64, usize)> = (0..self.count).map(|i| (self.offset(i), i)).collect();
+        offsets.sort_unstable();
+        let mut file = BufReader::new(
+            File::open(&self.path).with_context(|| format!("open {}", self.path.display()))?,
+        );
+        offsets
+            .into_iter()
+            .map(|(offset, i)| {
+                let hash = hex::encode(self.id(i));
+                let kind = self
+                    .kind_at(&mut file, offset)
+                    .with_context(|| format!("read object {hash} from {}", self.path.display()))?;
+                Ok((hash, kind))
+            })
+            .collect()
+    }
+
+    /// The objects of the pack in offset order, each read as it is iterated over.
+    pub(crate) fn objects(
+        &self,
+    ) -> impl Iterator<Item = anyhow::Result<(String, Object<Box<dyn BufRead>>)>> + '_ {
+        let mut offsets: Vec<(u64, usize)> = (0..self.count).map(|i| (self.offset(i), i)).collect();
+        offsets.sort_unstable();
+        offsets.into_iter().map(|(offset, i)| {
+            let hash = hex::encode(self.id(i));
+            let object = self.read_object(&hash, offset)?;
+            Ok((hash, object))
+        })
+    }
+}
+
+/// A writer updating a hasher and a CRC-32 with everything written to it.
+struct CrcWriter<'a> {
+    /// The hasher of the whole file.
+    hasher: &'a mut Hasher,
+    /// The CRC-32 of what was written.
+    crc: Crc,
+}
+
+impl Write for CrcWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.update(buf);
+        self.crc.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Copy exactly `len` bytes from `reader` to `writer`.
+fn copy_exact(reader: &mut impl Read, len: u64, writer: &mut impl Write) -> anyhow::Result<()> {
+    let copied = std::io::copy(&mut reader.take(len), writer)?;
+    anyhow::ensure!(copied == len, "unexpected end of file");
+    Ok(())
+}
+
+/// Read the header of the entry at `offset` of a pack file: its type, the size of its inflated
+/// data and its base. The file is left at the start of the compressed data.
+fn read_header(file: &mut BufReader<File>, offset: u64) -> anyhow::Result<(u8, u64, Base)> {
+    file.seek(SeekFrom::Start(offset))
+        .context("seek to pack entry")?;
+    let mut byte = [0];
+    let mut next = |file: &mut BufReader<File>| -> anyhow::Result<u8> {
+        file.read_exact(&mut byte)
+            .context("read pack entry header")?;
+        Ok(byte[0])
+    };
+    let mut c = next(file)?;
+    let code = (c >> 4) & 7;
+    let mut size = u64::from(c & 0x0f);
+    let mut shift = 4;
+    while c & 0x80 != 0 {
+        anyhow::ensure!(shift < 64, "pack entry size is too large");
+        c = next(file)?;
+        size |= u64::from(c & 0x7f) << shift;
+        shift += 7;
+    }
+    let base = match code {
+        OFS_DELTA => {
+            c = next(file)?;
+            let mut distance = u64::from(c & 0x7f);
+            while c & 0x80 != 0 {
+                c = next(file)?;
+                distance = ((distance + 1) << 7) | u64::from(c & 0x7f);
+            }
+            let base = offset
+                .checked_sub(distance)
+                .context("delta base offset is out of bounds")?;
+            Base::Offset(base)
+        }
+        REF_DELTA => {
+            let mut id = vec![0; hash::algorithm().size()];
+            file.read_exact(&mut id).context("read delta base id")?;
+            Base::Id(hex::encode(id))
+        }
+        _ => Base::None,
+    };
+    Ok((code, size, base))
+}
+
+/// Read the entry at `offset` of a pack file.
+fn read_entry(file: &mut BufReader<File>, offset: u64) -> anyhow::Result<Entry> {
+    let (code, size, base) = read_header(file, offset)?;
+    let mut data = Vec::with_capacity(size as usize);
+    ZlibDecoder::new(file)
+        .take(size + 1)
+        .read_to_end(&mut data)
+        .context("inflate pack entry")?;
+    anyhow::ensure!(
+        data.len() as u64 == size,
+        "pack entry at offset {offset} has size {} instead of {size}",
+        data.len()
+    );
+    Ok(Entry { code, base, data })
+}
+
+/// Open the packs whose index is not called a name `skip` returns true for, most recently
+/// modified first.
+fn open_packs(skip: impl Fn(&str) -> bool) -> anyhow::Result<Vec<Pack>> {
+    let mut found = Vec::new();
+    if let Ok(dir) = std::fs::read_dir(git_path("objects/pack")) {
+        for entry in dir {
+            let path = entry.context("read .git/objects/pack")?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with("pack-")
+                && name.ends_with(".idx")
+                && !skip(&name)
+                && path.with_extension("pack").exists()
+            {
+                let modified = path
+                    .with_extension("pack")
+                    .metadata()
+                    .and_then(|meta| meta.modified())
+                    .ok();
+                found.push((Reverse(modified), Pack::open(&path)?));
+            }
+        }
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.path.cmp(&b.1.path)));
+    Ok(found.into_iter().map(|(_, pack)| pack).collect())
+}
+
+/// The packs of the repository, loaded once and shared until [`reload`].
+static PACKS: Mutex<Option<Arc<Vec<Pack>>>> = Mutex::new(None);
+
+/// The packs of the repository, most recently modified first.
+pub(crate) fn packs() -> anyhow::Result<Arc<Vec<Pack>>> {
+    let mut cached = PACKS.lock().expect("pack list lock is not poisoned");
+    if let Some(packs) = &*cached {
+        return Ok(Arc::clone(packs));
+    }
+    let packs = Arc::new(open_packs(|_| false)?);
+    *cached = Some(Arc::clone(&packs));
+    Ok(packs)
+}
+
+/// Where objects are looked up: the multi-pack index, and the packs it does not cover.
+struct Lookup {
+    /// The multi-pack index, if the repository has one.
+    midx: Option<MultiPackIndex>,
+    /// The packs the multi-pack index does not cover, most recently modified first.
+    others: Vec<Pack>,
+}
+
+/// Where objects are looked up, loaded once and shared until [`reload`].
+static LOOKUP: Mutex<Option<Arc<Lookup>>> = Mutex::new(None);
+
+/// Where objects are looked up.
+fn lookup() -> anyhow::Result<Arc<Lookup>> {
+    let mut cached = LOOKUP.lock().expect("pack lookup lock is not poisoned");
+    if let Some(lookup) = &*cached {
+        return Ok(Arc::clone(lookup));
+    }
+    let midx = midx::load()?;
+    let others = open_packs(|name| midx.as_ref().is_some_and(|midx| midx.covers(name)))?;
+    let lookup = Arc::new(Lookup { midx, others });
+    *cached = Some(Arc::clone(&lookup));
+    Ok(lookup)
+}
+
+/// Forget the loaded packs, so that packs written or deleted since are seen.
+pub(crate) fn reload() {
+    *PACKS.lock().expect("pack list lock is not poisoned") = None;
+    *LOOKUP.lock().expect("pack lookup lock is not poisoned") = None;
+}
+
+/// Read the object `hash` from whichever pack has it.
+pub(crate) fn read(hash: &str) -> anyhow::Result<Option<Object<Box<dyn BufRead>>>> {
+    let lookup = lookup()?;
+    if let Some((pack, offset)) = lookup
+        .midx
+        .as_ref()
+        .map(|midx| midx.locate(hash))
+        .transpose()?
+        .flatten()
+    {
+        return pack.read_object(hash, offset).map(Some);
+    }
+    for pack in &lookup.others {
+        if let Some(object) = pack.read(hash)? {
+            return Ok(Some(object));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether any pack has the object `hash`.
+pub(crate) fn contains(hash: &str) -> anyhow::Result<bool> {
+    let lookup = lookup()?;
+    Ok(lookup.midx.as_ref().is_some_and(|midx| midx.contains(hash))
+        || lookup.others.iter().any(|pack| pack.contains(hash)))
+}
+
+/// Remove the pack at `path`, its index and the other files named after it, except a `.keep`
+/// file.
+pub(crate) fn remove(path: &Path) -> anyhow::Result<()> {
+    for extension in ["pack", "idx", "rev", "bitmap"] {
+        let path = path.with_extension(extension);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("remove {}", path.display()));
+            }
+            _ => {}
+        }
+    }
+    reload();
+    Ok(())
+}
+
+/// An object to be written to a pack.
+pub(crate) struct PackObject {
+    /// The hex id of the object.
+    pub(crate) hash: String,
+    /// The type of the object.
+    pub(crate) kind: Kind,
+    /// The contents of the object, or `None` for a blob larger than `core.bigFileThreshold`,
+    /// which is streamed from the object store when written and never deltified.
+    pub(crate) data: Option<Vec<u8>>,
+    /// The size of the object.
+    pub(crate) size: u64,
+    /// The [`name_hash`] of the path the object was found at, which groups likely delta bases.
+    pub(crate) name_hash: u32,
+}
+
+impl PackObject {
+    /// The object `hash`, read from `object` and found at a path with the [`name_hash`]
+    /// `name_hash`. Its contents are kept in memory unless it is a big blob.
+    pub(crate) fn read(
+        hash: String,
+        object: Object<impl Read>,
+        name_hash: u32,
+    ) -> anyhow::Result<PackObject> {
+        let (kind, size) = (object.kind, object.expected_size);
+        let data = if kind == Kind::Blob && size > objects::big_file_threshold()? {
+            None
+        } else {
+            Some(object.into_bytes()?)
+        };
+        Ok(PackObject {
+            hash,
+            kind,
+            data,
+            size,
+            name_hash,
+        })
+    }
+}
+
+/// A writer of a pack file that keeps track of its checksum, the offset it is at and the CRC-32
+/// of the entry being written.
+struct PackWriter<W> {
+    /// The underlying writer, e.g. a `BufWriter<File>`.
+    writer: W,
+    /// The hasher of everything written, for the trailing checksum.
+    hasher: Hasher,
+    /// The CRC-32 of the current entry, reset before each.
+    crc: Crc,
+    /// How many bytes were written.
+    offset: u64,
+}
+
+impl<W: Write> Write for PackWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.crc.update(&buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A hash of a path that sorts files with the same name, and then the same extension, together.
+///
+/// See: <https://github.com/git/git/blob/v2.39.0/pack-objects.h#L191>
+pub(crate) fn name_hash(name: &str) -> u32 {
+    name.bytes()
+        .filter(|b| !b.is_ascii_whitespace())
+        .fold(0, |hash: u32, b| {
+            (hash >> 2).wrapping_add(u32::from(b) << 24)
+        })
+}
+
+/// Choose a delta base for each of `objects`, trying each object as a delta against the `window`
+/// objects before it when sorted by type, name and size, with delta chains at most `depth` long.
+///
+/// Returns the index of the base of each object and the delta against it, `None` for objects
+/// stored whole.
+fn find_deltas(
+    objects: &[PackObject],
+    window: usize,
+    depth: usize,
+) -> Vec<Option<(usize, Vec<u8>)>> {
+    let hash_size = hash::algorithm().size();
+    let mut order: Vec<usize> = (0..objects.len()).collect();
+    order.sort_by_key(|&i| {
+        let object = &objects[i];
+        (object.kind, object.name_hash, Reverse(object.size))
+    });
+    let mut bases: Vec<Option<(usize, Vec<u8>)>> = objects.iter().map(|_| None).collect();
+    let mut depths = vec![0; objects.len()];
+    for (position, &i) in order.iter().enumerate() {
+        let target = &objects[i];
+        // Big blobs are neither deltified nor delta bases.
+        let Some(target_data) = &target.data else {
+            continue;
+        };
+        let mut max_size = (target_data.len() / 2).saturating_sub(hash_size);
+        let mut best = None;
+        for &j in order[position.saturating_sub(window)..position]
+            .iter()
+            .rev()
+        {
+            let base = &objects[j];
+            let Some(base_data) = &base.data else {
+                continue;
+            };
+            if base.kind != target.kind || depths[j] >= depth {
+                continue;
+            }
+            if target_data.len().saturating_sub(base_data.len()) >= max_size {
+                continue;
+            }
+            if let Some(delta) = delta::create(base_data, target_data, max_size) {
+                max_size = delta.len().saturating_sub(1);
+                best = Some((j, delta));
+            }
+        }
+        if let Some((j, delta)) = best {
+            depths[i] = depths[j] + 1;
+            bases[i] = Some((j, delta));
+        }
+    }
+    bases
+}
+
+/// Write `objects` to a new pack, with deltas chosen by [`find_deltas`].
+///
+/// The pack is written to disk as it is built, and big blobs are streamed into it undeltified.
+///
+/// Returns the path of the new `.pack` file.
+pub(crate) fn write(
+    objects: &[PackObject],
+    window: usize,
+    depth: usize,
+) -> anyhow::Result<PathBuf> {
+    let bases = find_deltas(objects, window, depth);
+    let dir = git_path("objects/pack");
+    shared::create_dir_all(&dir)?;
+    let tmp = temp_path();
+    let written = File::create(&tmp)
+        .with_context(|| format!("create {}", tmp.display()))
+        .and_then(|file| write_entries(BufWriter::new(file), objects, &bases))
+        .with_context(|| format!("write {}", tmp.display()));
+    let (checksum, offsets, crcs) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+
+    let mut sorted: Vec<(ObjectId, usize)> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| Ok((ObjectId::from_hex(&object.hash)?, i)))
+        .collect::<anyhow::Result<_>>()?;
+    sorted.sort();
+    let mut idx = Vec::new();
+    idx.extend_from_slice(IDX_SIGNATURE);
+    idx.extend(VERSION.to_be_bytes());
+    for byte in 0..=255u8 {
+        let count = sorted.partition_point(|(id, _)| id.as_bytes()[0] <= byte);
+        idx.extend(u32::try_from(count)?.to_be_bytes());
+    }
+    for (id, _) in &sorted {
+        idx.extend_from_slice(id.as_bytes());
+    }
+    for &(_, i) in &sorted {
+        idx.extend(crcs[i].to_be_bytes());
+    }
+    let mut large = Vec::new();
+    for &(_, i) in &sorted {
+        let offset = offsets[i];
+        match u32::try_from(offset) {
+            Ok(small) if small & 0x8000_0000 == 0 => idx.extend(small.to_be_bytes()),
+            _ => {
+                idx.extend((0x8000_0000 | u32::try_from(large.len())?).to_be_bytes());
+                large.push(offset);
+            }
+        }
+    }
+    for offset in large {
+        idx.extend(offset.to_be_bytes());
+    }
+    idx.extend_from_slice(checksum.as_bytes());
+    let idx_checksum = Hasher::digest(&idx)?;
+    idx.extend_from_slice(idx_checksum.as_bytes());
+
+    let path = dir.join(format!("pack-{checksum}.pack"));
+    // Readers look packs up by their index, so the pack must be in place first.
+    move_into_place(&tmp, &path)?;
+    install(&path.with_extension("idx"), &idx)?;
+    reload();
+    Ok(path)
+}
+
+/// Write `objects` as a pack to `writer`, e.g. to send them to another repository, with deltas
+/// chosen by [`find_deltas`]. Big blobs are streamed from the object store undeltified.
+pub(crate) fn write_to(
+    writer: impl Write,
+    objects: &[PackObject],
+    window: usize,
+    depth: usize,
+) -> anyhow::Result<()> {
+    let bases = find_deltas(objects, window, depth);
+    write_entries(writer, objects, &bases)?;
+    Ok(())
+}
+
+/// Write the pack of `objects` to `writer`, as deltas against the bases chosen in `bases`,
+/// streaming big blobs from the object store.
+///
+/// Returns the checksum of the pack, and the offset and CRC-32 of the entry of each object.
+fn write_entries(
+    writer: impl Write,
+    objects: &[PackObject],
+    bases: &[Option<(usize, Vec<u8>)>],
+) -> anyhow::Result<(ObjectId, Vec<u64>, Vec<u32>)> {
+    let mut pack = PackWriter {
+        writer,
+        hasher: Hasher::for_repository(),
+        crc: Crc::new(),
+        offset: 0,
+    };
+    pack.write_all(PACK_SIGNATURE)?;
+    pack.write_all(&VERSION.to_be_bytes())?;
+    pack.write_all(&u32::try_from(objects.len())?.to_be_bytes())?;
+    let mut offsets: Vec<Option<u64>> = vec![None; objects.len()];
+    let mut crcs = vec![0; objects.len()];
+    for i in 0..objects.len() {
+        // Delta bases are written before the deltas pointing back at them.
+        let mut chain = vec![i];
+        while let Some((base, _)) = &bases[*chain.last().expect("chain is not empty")] {
+            if offsets[*base].is_some() {
+                break;
+            }
+            chain.push(*base);
+        }
+        for &k in chain.iter().rev() {
+            if offsets[k].is_some() {
+                continue;
+            }
+            let offset = pack.offset;
+            pack.crc.reset();
+            let mut header = Vec::new();
+            match &bases[k] {
+                Some((base, delta)) => {
+                    write_entry_header(&mut header, OFS_DELTA, delta.len() as u64);
+                    let base = offsets[*base].expect("delta bases are written first");
+                    write_offset(&mut header, offset - base);
+                    pack.write_all(&header)?;
+                    deflate(&mut pack, delta)?;
+                }
+                None => {
+                    let object = &objects[k];
+                    write_entry_header(&mut header, type_code(object.kind), object.size);
+                    pack.write_all(&header)?;
+                    match &object.data {
+                        Some(data) => deflate(&mut pack, data)?,
+                        None => stream(&mut pack, object)?,
+                    }
+                }
+            }
+            crcs[k] = pack.crc.sum();
+            offsets[k] = Some(offset);
+        }
+    }
+    let PackWriter {
+        mut writer, hasher, ..
+    } = pack;
+    let checksum = hasher.finalize()?;
+    writer.write_all(checksum.as_bytes())?;
+    writer.flush()?;
+    let offsets = offsets
+        .into_iter()
+        .map(|offset| offset.expect("every object is written"))
+        .collect();
+    Ok((checksum, offsets, crcs))
+}
+
+/// Append the big blob `object`, zlib-compressed as it is read from the object store.
+fn stream(pack: &mut impl Write, object: &PackObject) -> anyhow::Result<()> {
+    let mut reader = Object::read(&object.hash)
+        .with_context(|| format!("read object {}", object.hash))?
+        .reader;
+    let mut encoder = ZlibEncoder::new(pack, Compression::default());
+    let size = std::io::copy(&mut reader, &mut encoder)
+        .with_context(|| format!("compress object {}", object.hash))?;
+    encoder.finish().context("compress pack entry")?;
+    anyhow::ensure!(
+        size == object.size,
+        "object {} was not the expected size (expected: {}, actual: {size})",
+        object.hash,
+        object.size
+    );
+    Ok(())
+}
+
+/// A name for a temporary file in `.git/objects/pack`.
+fn temp_path() -> PathBuf {
+    git_path("objects/pack").join(format!("tmp_pack_{}", std::process::id()))
+}
+
+/// Write `data` to the read-only file `path` in `.git/objects/pack`, through a temporary file so
+/// that readers never see it partly written.
+fn install(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let tmp = temp_path();
+    std::fs::write(&tmp, data).with_context(|| format!("write {}", tmp.display()))?;
+    move_into_place(&tmp, path)
+}
+
+/// Make the finished temporary file `tmp` read-only and move it to `path`.
+fn move_into_place(tmp: &Path, path: &Path) -> anyhow::Result<()> {
+    let mut permissions = std::fs::metadata(tmp)?.permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(tmp, permissions)?;
+    shared::adjust(tmp)?;
+    std::fs::rename(tmp, path).with_context(|| format!("move pack to {}", path.display()))
+}
+
+/// Append the header of a pack entry: its type and the size of its inflated data.
+fn write_entry_header(pack: &mut Vec<u8>, code: u8, size: u64) {
+    let mut c = (code << 4) | (size & 0x0f) as u8;
+    let mut size = size >> 4;
+    while size > 0 {
+        pack.push(c | 0x80);
+        c = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    pack.push(c);
+}
+
+/// Append the distance back to the base of an offset delta, in Git's bijective base-128.
+fn write_offset(pack: &mut Vec<u8>, mut distance: u64) {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance > 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    pack.extend(bytes.iter().rev());
+}
+
+/// Append `data`, zlib-compressed.
+fn deflate(pack: &mut impl Write, data: &[u8]) -> anyhow::Result<()> {
+    let mut encoder = ZlibEncoder::new(pack, Compression::default());
+    encoder.write_all(data).context("compress pack entry")?;
+    encoder.finish().context("compress pack entry")?;
+    Ok(())
+}
+
+/// Read a big-endian 32-bit number from the start of `data`.
+fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().expect("4 bytes"))
+}
@@ -0,0 +1,550 @@
+//! Reachability bitmaps, which tell which objects of a pack some of its commits reach, so that
+//! finding every object reachable from a commit does not need to walk all of its history.
+//!
+//! `pack-<checksum>.bitmap` next to a pack that has every object its commits reach stores a bitmap
+//! of the objects of each type and, for selected commits, of the objects they reach. Bit `i`
+//! stands for the `i`th object of the pack by offset. Bitmaps are EWAH-compressed: runs of empty
+//! or full 64-bit words are stored as a count, the other words as they are.
+//!
+//! See: <https://git-scm.com/docs/bitmap-format>
+use anyhow::Context;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::{be32, Pack, PackObject};
+use crate::config::Config;
+use crate::hash::{self, Hasher};
+use crate::objects::{Commit, Kind, Object, Tag, Tree, MODE_GITLINK};
+
+/// The magic bytes bitmap files start with.
+const SIGNATURE: &[u8; 4] = b"BITM";
+
+/// The version of the file format.
+const VERSION: u16 = 1;
+
+/// Set in the options of files whose every commit's reachable objects are in the pack, which Git
+/// requires.
+const OPTION_FULL_DAG: u16 = 0x1;
+
+/// Set in the options of files that end with the name hash of each object.
+const OPTION_HASH_CACHE: u16 = 0x4;
+
+/// The largest running length of an EWAH marker word.
+const MAX_RUN: u64 = (1 << 32) - 1;
+
+/// The largest number of literal words after an EWAH marker word.
+const MAX_LITERALS: u64 = (1 << 31) - 1;
+
+/// Packs with fewer commits than this have a bitmap for each, and otherwise the least number of
+/// commits between two bitmaps past the first [`MUST_REGION`] commits.
+const MIN_COMMITS: usize = 100;
+
+/// The largest number of commits between two bitmaps.
+const MAX_COMMITS: usize = 5000;
+
+/// How many of the newest commits all get a bitmap.
+const MUST_REGION: usize = 100;
+
+/// How many of the newest commits are at most [`MIN_COMMITS`] apart from the next bitmap.
+const MIN_REGION: usize = 20000;
+
+/// A set of positions in a pack.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Bitmap {
+    /// The bits, the lowest of the first word standing for position 0.
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    /// Add `position` to the set.
+    fn set(&mut self, position: usize) {
+        let word = position / 64;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (position % 64);
+    }
+
+    /// Whether `position` is in the set.
+    fn get(&self, position: usize) -> bool {
+        self.words
+            .get(position / 64)
+            .is_some_and(|word| word & (1 << (position % 64)) != 0)
+    }
+
+    /// Add the positions of `other` to the set.
+    fn or(&mut self, other: &Bitmap) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// Toggle the positions of `other`.
+    fn xor(&mut self, other: &Bitmap) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word ^= other;
+        }
+    }
+
+    /// Remove the positions of `other` from the set.
+    pub(crate) fn and_not(&mut self, other: &Bitmap) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    /// The number of positions in both the set and `other`.
+    fn count_in(&self, other: &Bitmap) -> usize {
+        self.words
+            .iter()
+            .zip(&other.words)
+            .map(|(word, other)| (word & other).count_ones() as usize)
+            .sum()
+    }
+
+    /// The positions in the set, in increasing order.
+    fn positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+
+    /// Decode the EWAH-compressed bitmap at the start of `data`, returning it and its length.
+    fn decode(data: &[u8]) -> anyhow::Result<(Bitmap, usize)> {
+        anyhow::ensure!(data.len() >= 8, "bitmap is truncated");
+        let len = be32(&data[4..]) as usize;
+        let end = 8 + len * 8 + 4;
+        anyhow::ensure!(data.len() >= end, "bitmap is truncated");
+        let buffer: Vec<u64> = data[8..8 + len * 8]
+            .chunks_exact(8)
+            .map(|word| u64::from_be_bytes(word.try_into().expect("8 bytes")))
+            .collect();
+        let mut words = Vec::new();
+        let mut i = 0;
+        while i < buffer.len() {
+            let marker = buffer[i];
+            let run = (marker >> 1) & MAX_RUN;
+            let literals = (marker >> 33) as usize;
+            let fill = if marker & 1 == 0 { 0 } else { u64::MAX };
+            words.resize(words.len() + usize::try_from(run)?, fill);
+            let literals = buffer
+                .get(i + 1..i + 1 + literals)
+                .context("bitmap literal words are truncated")?;
+            words.extend_from_slice(literals);
+            i += 1 + literals.len();
+        }
+        Ok((Bitmap { words }, end))
+    }
+
+    /// Append the set, EWAH-compressed.
+    fn encode(&self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        // Trailing empty words are left out, but an empty set is stored as one empty word, like
+        // Git does.
+        let len = self
+            .words
+            .iter()
+            .rposition(|&word| word != 0)
+            .map_or(0, |last| last + 1);
+        let mut words = self.words[..len].to_vec();
+        if words.is_empty() {
+            words.push(0);
+        }
+        let clean = |word: u64| word == 0 || word == u64::MAX;
+        let mut buffer = Vec::new();
+        let mut last_marker;
+        let mut i = 0;
+        loop {
+            let fill = words.get(i).copied().unwrap_or(0);
+            let mut run = 0;
+            if clean(fill) {
+                while i < words.len() && words[i] == fill && run < MAX_RUN {
+                    i += 1;
+                    run += 1;
+                }
+            }
+            let start = i;
+            while i < words.len() && !clean(words[i]) && ((i - start) as u64) < MAX_LITERALS {
+                i += 1;
+            }
+            last_marker = buffer.len();
+            let literals = (i - start) as u64;
+            buffer.push(u64::from(fill == u64::MAX && run > 0) | run << 1 | literals << 33);
+            buffer.extend_from_slice(&words[start..i]);
+            if i >= words.len() {
+                break;
+            }
+        }
+        let bits = u32::try_from(words.len() * 64)?;
+        out.extend(bits.to_be_bytes());
+        out.extend(u32::try_from(buffer.len())?.to_be_bytes());
+        for word in buffer {
+            out.extend(word.to_be_bytes());
+        }
+        out.extend(u32::try_from(last_marker)?.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// The order of the objects of `pack` by offset, as the pack position of each object in index
+/// order and the index position of each object in pack order.
+fn pack_order(pack: &Pack) -> (Vec<usize>, Vec<usize>) {
+    let mut order: Vec<usize> = (0..pack.count).collect();
+    order.sort_by_key(|&i| pack.offset(i));
+    let mut positions = vec![0; pack.count];
+    for (position, &i) in order.iter().enumerate() {
+        positions[i] = position;
+    }
+    (positions, order)
+}
+
+/// The type bitmaps, in the order the file stores them.
+const KINDS: [Kind; 4] = [Kind::Commit, Kind::Tree, Kind::Blob, Kind::Tag];
+
+/// A pack and its reachability bitmaps.
+pub(crate) struct PackBitmap {
+    /// The pack.
+    pack: Pack,
+    /// The pack position of each object in index order.
+    positions: Vec<usize>,
+    /// The index position of each object in pack order.
+    order: Vec<usize>,
+    /// The objects of each type, in the order of [`KINDS`].
+    kinds: [Bitmap; 4],
+    /// The objects each selected commit reaches.
+    commits: HashMap<usize, Bitmap>,
+    /// The name hash of each object in index order, if the file has them.
+    name_hashes: Option<Vec<u32>>,
+}
+
+impl PackBitmap {
+    /// Open the bitmap of `pack`, at `path`.
+    fn open(pack: Pack, path: &Path) -> anyhow::Result<PackBitmap> {
+        let data = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        let size = hash::algorithm().size();
+        anyhow::ensure!(
+            data.len() >= 12 + 2 * size && &data[..4] == SIGNATURE,
+            "{} is not a bitmap file",
+            path.display()
+        );
+        let version = u16::from_be_bytes([data[4], data[5]]);
+        anyhow::ensure!(
+            version == VERSION,
+            "unsupported version '{version}' for bitmap index file"
+        );
+        let options = u16::from_be_bytes([data[6], data[7]]);
+        anyhow::ensure!(
+            options & OPTION_FULL_DAG != 0 && options & !(OPTION_FULL_DAG | OPTION_HASH_CACHE) == 0,
+            "unsupported options for bitmap index file"
+        );
+        let (content, checksum) = data.split_at(data.len() - size);
+        anyhow::ensure!(
+            Hasher::digest(content)?.as_bytes() == checksum,
+            "{} checksum mismatch",
+            path.display()
+        );
+        let pack_checksum = &pack.idx[pack.idx.len() - 2 * size..pack.idx.len() - size];
+        anyhow::ensure!(
+            &content[12..12 + size] == pack_checksum,
+            "checksum doesn't match in MIDX and bitmap"
+        );
+        let entries = be32(&content[8..]) as usize;
+        let mut at = 12 + size;
+        let mut kinds: [Bitmap; 4] = Default::default();
+        for kind in &mut kinds {
+            let (bitmap, len) = Bitmap::decode(&content[at..])?;
+            *kind = bitmap;
+            at += len;
+        }
+        let mut stored: Vec<(usize, Bitmap)> = Vec::with_capacity(entries);
+        for i in 0..entries {
+            let header = content
+                .get(at..at + 6)
+                .context("bitmap entries are truncated")?;
+            let index = be32(header) as usize;
+            let xor = usize::from(header[4]);
+            anyhow::ensure!(index < pack.count && xor <= i, "corrupted bitmap entry {i}");
+            let (mut bitmap, len) = Bitmap::decode(&content[at + 6..])?;
+            if xor > 0 {
+                bitmap.xor(&stored[i - xor].1);
+            }
+            stored.push((index, bitmap));
+            at += 6 + len;
+        }
+        let name_hashes = if options & OPTION_HASH_CACHE != 0 {
+            let cache = content
+                .get(at..at + 4 * pack.count)
+                .context("bitmap name hash cache is truncated")?;
+            Some(cache.chunks_exact(4).map(be32).collect())
+        } else {
+            None
+        };
+        let (positions, order) = pack_order(&pack);
+        let commits = stored
+            .into_iter()
+            .map(|(index, bitmap)| (positions[index], bitmap))
+            .collect();
+        Ok(PackBitmap {
+            pack,
+            positions,
+            order,
+            kinds,
+            commits,
+            name_hashes,
+        })
+    }
+
+    /// The pack position of the object `hash`, if the pack has it.
+    fn position(&self, hash: &str) -> Option<usize> {
+        self.pack.find(hash).map(|i| self.positions[i])
+    }
+
+    /// The hex id of the object at `position`.
+    fn hash(&self, position: usize) -> String {
+        hex::encode(self.pack.id(self.order[position]))
+    }
+
+    /// The type of the object at `position`.
+    fn kind(&self, position: usize) -> anyhow::Result<Kind> {
+        KINDS
+            .iter()
+            .zip(&self.kinds)
+            .find(|(_, bitmap)| bitmap.get(position))
+            .map(|(&kind, _)| kind)
+            .with_context(|| format!("object {} has no type bitmap", self.hash(position)))
+    }
+
+    /// The objects reachable from `roots`, walking from them to the nearest commits with a
+    /// bitmap. `None` if the walk reaches an object that is not in the pack.
+    pub(crate) fn reach(&self, roots: &[String]) -> anyhow::Result<Option<Bitmap>> {
+        let mut reached = Bitmap::default();
+        let mut pending: Vec<String> = roots.to_vec();
+        while let Some(hash) = pending.pop() {
+            let Some(position) = self.position(&hash) else {
+                return Ok(None);
+            };
+            if reached.get(position) {
+                continue;
+            }
+            if let Some(bitmap) = self.commits.get(&position) {
+                reached.or(bitmap);
+                continue;
+            }
+            reached.set(position);
+            match self.kind(position)? {
+                Kind::Commit => {
+                    let commit = Commit::read(&hash)?;
+                    pending.push(commit.tree);
+                    pending.extend(commit.parents);
+                }
+                Kind::Tree => pending.extend(
+                    Tree::read(&hash)?
+                        .entries
+                        .into_iter()
+                        .filter(|(_, entry)| entry.mode != MODE_GITLINK)
+                        .map(|(_, entry)| entry.hash),
+                ),
+                Kind::Tag => {
+                    let data = Object::read(&hash)?.into_bytes()?;
+                    pending.push(Tag::parse(&data)?.object);
+                }
+                Kind::Blob => {}
+            }
+        }
+        Ok(Some(reached))
+    }
+
+    /// The number of objects of `bitmap` of type `kind`.
+    pub(crate) fn count(&self, bitmap: &Bitmap, kind: Kind) -> usize {
+        let i = KINDS
+            .iter()
+            .position(|&k| k == kind)
+            .expect("every type has a bitmap");
+        bitmap.count_in(&self.kinds[i])
+    }
+
+    /// The hex ids of the objects of `bitmap` in pack order, each with the name hash of the path
+    /// it was found at when the pack was written, or 0 if the file does not have them.
+    pub(crate) fn named_hashes(&self, bitmap: &Bitmap) -> Vec<(String, u32)> {
+        bitmap
+            .positions()
+            .map(|position| {
+                let i = self.order[position];
+                let name_hash = self.name_hashes.as_ref().map_or(0, |hashes| hashes[i]);
+                (hex::encode(self.pack.id(i)), name_hash)
+            })
+            .collect()
+    }
+
+    /// The hex ids of the objects of `bitmap` of type `kind`, in pack order.
+    pub(crate) fn hashes(&self, bitmap: &Bitmap, kind: Kind) -> Vec<String> {
+        let i = KINDS
+            .iter()
+            .position(|&k| k == kind)
+            .expect("every type has a bitmap");
+        bitmap
+            .positions()
+            .filter(|&position| self.kinds[i].get(position))
+            .map(|position| self.hash(position))
+            .collect()
+    }
+}
+
+/// The bitmap of the most recently modified pack that has one, if `pack.useBitmaps` is not false.
+///
+/// A bitmap that cannot be used is ignored with a warning.
+pub(crate) fn load() -> anyhow::Result<Option<PackBitmap>> {
+    if !Config::load()?.get_bool("pack.useBitmaps")?.unwrap_or(true) {
+        return Ok(None);
+    }
+    for pack in super::packs()?.iter() {
+        let path = pack.path.with_extension("bitmap");
+        if !path.exists() {
+            continue;
+        }
+        match PackBitmap::open(Pack::open(&pack.idx_path())?, &path) {
+            Ok(bitmap) => return Ok(Some(bitmap)),
+            Err(e) => {
+                eprintln!("warning: ignoring bitmap {}: {e:#}", path.display());
+                return Ok(None);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// How many commits after the `i`th newest one the next commit with a bitmap is chosen among:
+/// none at first, then more and more as commits get older.
+///
+/// See: <https://github.com/git/git/blob/v2.39.0/pack-bitmap-write.c#L381>
+fn next_commit_index(i: usize) -> usize {
+    if i <= MUST_REGION {
+        0
+    } else if i <= MIN_REGION {
+        (i - MUST_REGION).min(MIN_COMMITS)
+    } else {
+        (i - MIN_REGION).clamp(MIN_COMMITS, MAX_COMMITS)
+    }
+}
+
+/// The pack positions of the commits among `commits`, each with its date and whether it is a
+/// merge, that get a bitmap: all of them if there are few, else a selection spreading out with
+/// age, preferring merges.
+fn select(mut commits: Vec<(i64, usize, bool)>) -> Vec<usize> {
+    if commits.len() < MIN_COMMITS {
+        return commits
+            .into_iter()
+            .map(|(_, position, _)| position)
+            .collect();
+    }
+    commits.sort_by_key(|&(date, _, _)| Reverse(date));
+    let mut selected = Vec::new();
+    let mut i = 0;
+    loop {
+        let next = next_commit_index(i);
+        if i + next >= commits.len() {
+            break;
+        }
+        // Like Git, the last merge in range wins.
+        let chosen = (0..=next).rev().find(|&j| commits[i + j].2).unwrap_or(next);
+        selected.push(commits[i + chosen].1);
+        i += next + 1;
+    }
+    selected
+}
+
+/// Write the bitmap of the new pack `pack`, which has `objects` and every object they reach.
+pub(crate) fn write(pack: &Pack, objects: &[PackObject]) -> anyhow::Result<()> {
+    let (positions, _) = pack_order(pack);
+    let position = |hash: &str| -> anyhow::Result<usize> {
+        let i = pack
+            .find(hash)
+            .with_context(|| format!("object {hash} is not in the pack"))?;
+        Ok(positions[i])
+    };
+    let mut kinds: [Bitmap; 4] = Default::default();
+    let mut children = vec![Vec::new(); pack.count];
+    let mut name_hashes = vec![0; pack.count];
+    let mut commits = Vec::new();
+    for object in objects {
+        let at = position(&object.hash)?;
+        let kind = KINDS
+            .iter()
+            .position(|&kind| kind == object.kind)
+            .expect("every type has a bitmap");
+        kinds[kind].set(at);
+        name_hashes[pack.find(&object.hash).expect("object is in the pack")] = object.name_hash;
+        // Only big blobs, which have no children, are not kept in memory.
+        let data = object.data.as_deref().unwrap_or_default();
+        children[at] = match object.kind {
+            Kind::Commit => {
+                let commit = Commit::parse(data)?;
+                commits.push((commit.committer_time(), at, commit.parents.len() > 1));
+                std::iter::once(&commit.tree)
+                    .chain(&commit.parents)
+                    .map(|hash| position(hash))
+                    .collect::<anyhow::Result<_>>()?
+            }
+            Kind::Tree => Tree::parse(data)?
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.mode != MODE_GITLINK)
+                .map(|(_, entry)| position(&entry.hash))
+                .collect::<anyhow::Result<_>>()?,
+            Kind::Tag => vec![position(&Tag::parse(data)?.object)?],
+            Kind::Blob => Vec::new(),
+        };
+    }
+
+    // Older commits first, so that newer ones can reuse the bitmaps of their ancestors.
+    let selected = select(commits);
+    let mut bitmaps: HashMap<usize, Bitmap> = HashMap::new();
+    for &commit in selected.iter().rev() {
+        let mut reached = Bitmap::default();
+        let mut pending = vec![commit];
+        while let Some(at) = pending.pop() {
+            if reached.get(at) {
+                continue;
+            }
+            if let Some(bitmap) = bitmaps.get(&at) {
+                reached.or(bitmap);
+                continue;
+            }
+            reached.set(at);
+            pending.extend(&children[at]);
+        }
+        bitmaps.insert(commit, reached);
+    }
+
+    let size = hash::algorithm().size();
+    let mut file = Vec::new();
+    file.extend_from_slice(SIGNATURE);
+    file.extend(VERSION.to_be_bytes());
+    file.extend((OPTION_FULL_DAG | OPTION_HASH_CACHE).to_be_bytes());
+    file.extend(u32::try_from(selected.len())?.to_be_bytes());
+    file.extend_from_slice(&pack.idx[pack.idx.len() - 2 * size..pack.idx.len() - size]);
+    for kind in &kinds {
+        kind.encode(&mut file)?;
+    }
+    let (_, order) = pack_order(pack);
+    for commit in &selected {
+        file.extend(u32::try_from(order[*commit])?.to_be_bytes());
+        // Neither XOR-ed with an earlier bitmap nor flagged.
+        file.extend([0, 0]);
+        bitmaps[commit].encode(&mut file)?;
+    }
+    for name_hash in name_hashes {
+        file.extend(name_hash.to_be_bytes());
+    }
+    let checksum = Hasher::digest(&file)?;
+    file.extend_from_slice(checksum.as_bytes());
+    super::install(&pack.path.with_extension("bitmap"), &file)
+}
@@ -0,0 +1,194 @@
+//! Deltas between objects, which describe an object as copies of ranges of a base object and
+//! inserted literal bytes.
+//!
+//! A delta starts with the sizes of the base and the result as varints, followed by
+//! instructions: a byte with the high bit set copies a range of the base, whose offset and size
+//! bytes follow as flagged by its low bits; any other nonzero byte inserts that many literal
+//! bytes.
+//!
+//! See: <https://git-scm.com/docs/pack-format#_deltified_representation>
+use std::collections::HashMap;
+
+/// The size of the base blocks matches are looked up by.
+const BLOCK_SIZE: usize = 16;
+
+/// The largest range a single copy instruction copies.
+const MAX_COPY_SIZE: usize = 0x10000;
+
+/// The most literal bytes a single insert instruction holds.
+const MAX_INSERT_SIZE: usize = 0x7f;
+
+/// How many earlier occurrences of a block are tried when looking for the longest match.
+const MAX_CANDIDATES: usize = 64;
+
+/// Apply `delta` to `base`.
+pub(crate) fn apply(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut delta = delta;
+    let base_size = read_size(&mut delta)?;
+    anyhow::ensure!(
+        base_size == base.len(),
+        "delta base size {base_size} does not match the base object size {}",
+        base.len()
+    );
+    let result_size = read_size(&mut delta)?;
+    let mut result = Vec::with_capacity(result_size);
+    while let Some((&op, rest)) = delta.split_first() {
+        delta = rest;
+        if op & 0x80 != 0 {
+            let mut offset = 0;
+            for i in 0..4 {
+                if op & (1 << i) != 0 {
+                    offset |= usize::from(next_byte(&mut delta)?) << (8 * i);
+                }
+            }
+            let mut size = 0;
+            for i in 0..3 {
+                if op & (0x10 << i) != 0 {
+                    size |= usize::from(next_byte(&mut delta)?) << (8 * i);
+                }
+            }
+            if size == 0 {
+                size = MAX_COPY_SIZE;
+            }
+            let range = base
+                .get(offset..offset + size)
+                .ok_or_else(|| anyhow::anyhow!("delta copies beyond the end of its base"))?;
+            result.extend_from_slice(range);
+        } else if op != 0 {
+            let size = usize::from(op);
+            anyhow::ensure!(delta.len() >= size, "delta insert is truncated");
+            result.extend_from_slice(&delta[..size]);
+            delta = &delta[size..];
+        } else {
+            anyhow::bail!("unexpected delta opcode 0");
+        }
+    }
+    anyhow::ensure!(
+        result.len() == result_size,
+        "delta result size {} does not match the expected {result_size}",
+        result.len()
+    );
+    Ok(result)
+}
+
+/// A delta turning `base` into `target`, or `None` if it would be larger than `max_size`.
+pub(crate) fn create(base: &[u8], target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let mut blocks: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (i, block) in base.chunks_exact(BLOCK_SIZE).enumerate() {
+        blocks.entry(block).or_default().push(i * BLOCK_SIZE);
+    }
+
+    let mut delta = Vec::new();
+    write_size(&mut delta, base.len());
+    write_size(&mut delta, target.len());
+    let mut insert = Vec::new();
+    let mut i = 0;
+    while i < target.len() {
+        let (mut offset, mut len) = (0, 0);
+        if let Some(candidates) = target
+            .get(i..i + BLOCK_SIZE)
+            .and_then(|block| blocks.get(block))
+        {
+            for &candidate in candidates.iter().rev().take(MAX_CANDIDATES) {
+                let matched = base[candidate..]
+                    .iter()
+                    .zip(&target[i..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if matched > len {
+                    (offset, len) = (candidate, matched);
+                }
+            }
+        }
+        if len < BLOCK_SIZE {
+            insert.push(target[i]);
+            if insert.len() == MAX_INSERT_SIZE {
+                flush_insert(&mut delta, &mut insert);
+            }
+            i += 1;
+        } else {
+            i += len;
+            // Take back literal bytes that the match extends over.
+            while offset > 0 && insert.last() == Some(&base[offset - 1]) {
+                insert.pop();
+                offset -= 1;
+                len += 1;
+            }
+            flush_insert(&mut delta, &mut insert);
+            let end = offset + len;
+            while offset < end {
+                let size = (end - offset).min(MAX_COPY_SIZE);
+                write_copy(&mut delta, offset, size);
+                offset += size;
+            }
+        }
+        if delta.len() > max_size {
+            return None;
+        }
+    }
+    flush_insert(&mut delta, &mut insert);
+    (delta.len() <= max_size).then_some(delta)
+}
+
+/// Read a size varint from the start of `data`.
+fn read_size(data: &mut &[u8]) -> anyhow::Result<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = next_byte(data)?;
+        size |= usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+        anyhow::ensure!(shift < usize::BITS, "delta size is too large");
+    }
+}
+
+/// Take the first byte of `data`.
+fn next_byte(data: &mut &[u8]) -> anyhow::Result<u8> {
+    let (&byte, rest) = data
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("delta is truncated"))?;
+    *data = rest;
+    Ok(byte)
+}
+
+/// Append `size` as a varint.
+fn write_size(delta: &mut Vec<u8>, mut size: usize) {
+    while size >= 0x80 {
+        delta.push(size as u8 | 0x80);
+        size >>= 7;
+    }
+    delta.push(size as u8);
+}
+
+/// Append an instruction inserting the pending literal bytes, if any.
+fn flush_insert(delta: &mut Vec<u8>, insert: &mut Vec<u8>) {
+    if !insert.is_empty() {
+        delta.push(insert.len() as u8);
+        delta.append(insert);
+    }
+}
+
+/// Append an instruction copying `size` bytes at `offset` of the base.
+fn write_copy(delta: &mut Vec<u8>, offset: usize, size: usize) {
+    let start = delta.len();
+    delta.push(0x80);
+    for i in 0..4 {
+        let byte = (offset >> (8 * i)) as u8;
+        if byte != 0 {
+            delta[start] |= 1 << i;
+            delta.push(byte);
+        }
+    }
+    // A size of 0x10000 is written as no size bytes at all.
+    let size = if size == MAX_COPY_SIZE { 0 } else { size };
+    for i in 0..3 {
+        let byte = (size >> (8 * i)) as u8;
+        if byte != 0 {
+            delta[start] |= 0x10 << i;
+            delta.push(byte);
+        }
+    }
+}
@@ -0,0 +1,608 @@
+//! Multi-pack indexes, which list the objects of many packs in a single sorted table, so that
+//! finding a packed object takes one binary search instead of one per pack.
+//!
+//! `objects/pack/multi-pack-index` names the packs it covers, sorted, in its `PNAM` chunk, and
+//! maps each object id to one of those packs and the object's offset in it. An object in several
+//! packs is listed once, for the preferred pack if it is one of them and else for the most
+//! recently modified, where the packs a previous index covered count as oldest.
+//!
+//! See: <https://git-scm.com/docs/gitformat-pack#_multi_pack_index_midx_files_have_the_following_format>
+use anyhow::Context;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+
+use super::{be32, Pack};
+use crate::config::Config;
+use crate::hash::{self, Hasher, ObjectId};
+use crate::lockfile::LockFile;
+use crate::repository::git_path;
+
+/// The magic bytes multi-pack-index files start with.
+const SIGNATURE: &[u8; 4] = b"MIDX";
+
+/// The version of the file format.
+const VERSION: u8 = 1;
+
+/// The size of the header: signature, version, hash version, chunk count, base file count and
+/// pack count.
+const HEADER_SIZE: usize = 12;
+
+/// The size of an entry of the table of contents: a chunk id and its offset.
+const TOC_ENTRY_SIZE: usize = 12;
+
+/// The NUL-terminated names of the packs' indexes, padded to a multiple of 4 bytes.
+const PACK_NAMES: [u8; 4] = *b"PNAM";
+/// The fan-out table of object ids by first byte.
+const OID_FANOUT: [u8; 4] = *b"OIDF";
+/// The sorted object ids.
+const OID_LOOKUP: [u8; 4] = *b"OIDL";
+/// The pack and offset of each object.
+const OBJECT_OFFSETS: [u8; 4] = *b"OOFF";
+/// Offsets of objects too far into their pack for `OOFF`.
+const LARGE_OFFSETS: [u8; 4] = *b"LOFF";
+
+/// Set on an offset in `OOFF` that is an index into `LOFF` instead.
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+/// The path of the multi-pack index.
+pub(crate) fn path() -> PathBuf {
+    git_path("objects/pack/multi-pack-index")
+}
+
+/// The multi-pack index of the repository.
+pub(crate) struct MultiPackIndex {
+    /// The contents of the file.
+    data: Vec<u8>,
+    /// The number of objects.
+    count: usize,
+    /// The start and end of each chunk.
+    chunks: HashMap<[u8; 4], (usize, usize)>,
+    /// The file names of the indexes of the packs, sorted.
+    names: Vec<String>,
+    /// The packs, opened when first needed.
+    packs: Vec<OnceLock<Pack>>,
+}
+
+impl MultiPackIndex {
+    /// Open the multi-pack index at `path`.
+    pub(crate) fn open(path: &Path) -> anyhow::Result<MultiPackIndex> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("read multi-pack-index {}", path.display()))?;
+        let size = hash::algorithm().size();
+        anyhow::ensure!(
+            data.len() >= HEADER_SIZE + TOC_ENTRY_SIZE + size && &data[..4] == SIGNATURE,
+            "{} is not a multi-pack-index file",
+            path.display()
+        );
+        anyhow::ensure!(
+            data[4] == VERSION,
+            "multi-pack-index version {} not recognized",
+            data[4]
+        );
+        anyhow::ensure!(
+            data[5] == hash::algorithm().version(),
+            "multi-pack-index hash version {} does not match version {}",
+            data[5],
+            hash::algorithm().version()
+        );
+        anyhow::ensure!(data[7] == 0, "multi-pack-index has base files");
+        let end = data.len() - size;
+        let mut chunks = HashMap::new();
+        for i in 0..usize::from(data[6]) {
+            let entry = HEADER_SIZE + i * TOC_ENTRY_SIZE;
+            let next = entry + TOC_ENTRY_SIZE;
+            anyhow::ensure!(
+                next + TOC_ENTRY_SIZE <= end,
+                "multi-pack-index chunk lookup table is truncated"
+            );
+            let id: [u8; 4] = data[entry..entry + 4].try_into().expect("4 bytes");
+            let start = usize::try_from(be64(&data[entry + 4..]))?;
+            let stop = usize::try_from(be64(&data[next + 4..]))?;
+            anyhow::ensure!(
+                start <= stop && stop <= end,
+                "multi-pack-index chunk {} is out of bounds",
+                String::from_utf8_lossy(&id)
+            );
+            chunks.insert(id, (start, stop));
+        }
+        for id in [PACK_NAMES, OID_FANOUT, OID_LOOKUP, OBJECT_OFFSETS] {
+            anyhow::ensure!(
+                chunks.contains_key(&id),
+                "multi-pack-index is missing the {} chunk",
+                String::from_utf8_lossy(&id)
+            );
+        }
+        let chunk = |id| &data[chunks[&id].0..chunks[&id].1];
+        let count = chunk(OID_LOOKUP).len() / size;
+        anyhow::ensure!(
+            chunk(OID_FANOUT).len() == 256 * 4 && chunk(OBJECT_OFFSETS).len() == count * 8,
+            "multi-pack-index {} has chunks of the wrong size",
+            path.display()
+        );
+        let pack_count = be32(&data[8..]) as usize;
+        let names: Vec<String> = chunk(PACK_NAMES)
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        anyhow::ensure!(
+            names.len() == pack_count,
+            "multi-pack-index pack-name chunk is too short"
+        );
+        Ok(MultiPackIndex {
+            packs: names.iter().map(|_| OnceLock::new()).collect(),
+            data,
+            count,
+            chunks,
+            names,
+        })
+    }
+
+    /// A chunk that every multi-pack index has.
+    fn chunk(&self, id: [u8; 4]) -> &[u8] {
+        let (start, stop) = self.chunks[&id];
+        &self.data[start..stop]
+    }
+
+    /// Whether the pack whose index is called `name` is covered.
+    pub(crate) fn covers(&self, name: &str) -> bool {
+        self.names
+            .binary_search_by(|n| n.as_str().cmp(name))
+            .is_ok()
+    }
+
+    /// The `id`th pack, opening it if needed.
+    fn pack(&self, id: usize) -> anyhow::Result<&Pack> {
+        let cell = self
+            .packs
+            .get(id)
+            .with_context(|| format!("bad pack-int-id: {id} ({} total packs)", self.packs.len()))?;
+        if let Some(pack) = cell.get() {
+            return Ok(pack);
+        }
+        let pack = Pack::open(&git_path("objects/pack").join(&self.names[id]))?;
+        Ok(cell.get_or_init(|| pack))
+    }
+
+    /// The id of the `i`th object in sorted order.
+    fn id(&self, i: usize) -> &[u8] {
+        let size = hash::algorithm().size();
+        &self.chunk(OID_LOOKUP)[i * size..(i + 1) * size]
+    }
+
+    /// The pack of the `i`th object and its offset there.
+    fn entry(&self, i: usize) -> anyhow::Result<(usize, u64)> {
+        let entry = &self.chunk(OBJECT_OFFSETS)[i * 8..];
+        let pack = be32(entry) as usize;
+        let offset = be32(&entry[4..]);
+        if offset & LARGE_OFFSET == 0 {
+            return Ok((pack, u64::from(offset)));
+        }
+        let index = (offset & !LARGE_OFFSET) as usize;
+        let large = self
+            .chunks
+            .get(&LARGE_OFFSETS)
+            .map(|&(start, stop)| &self.data[start..stop])
+            .and_then(|large| large.get(index * 8..index * 8 + 8))
+            .context("multi-pack-index large offset out of bounds")?;
+        Ok((pack, be64(large)))
+    }
+
+    /// The position of the object `hash` in sorted order, if a pack has it.
+    fn find(&self, hash: &str) -> Option<usize> {
+        let id = ObjectId::from_hex(hash).ok()?;
+        let id = id.as_bytes();
+        let fan_out = |byte: usize| be32(&self.chunk(OID_FANOUT)[byte * 4..]) as usize;
+        let first = usize::from(id[0]);
+        let mut low = if first == 0 { 0 } else { fan_out(first - 1) };
+        let mut high = fan_out(first).min(self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.id(mid).cmp(id) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    /// Whether a covered pack has the object `hash`.
+    pub(crate) fn contains(&self, hash: &str) -> bool {
+        self.find(hash).is_some()
+    }
+
+    /// The pack that has the object `hash` and its offset there, if one does.
+    pub(crate) fn locate(&self, hash: &str) -> anyhow::Result<Option<(&Pack, u64)>> {
+        let Some(i) = self.find(hash) else {
+            return Ok(None);
+        };
+        let (pack, offset) = self.entry(i)?;
+        Ok(Some((self.pack(pack)?, offset)))
+    }
+
+    /// The number of objects each pack is the one listed for.
+    fn referenced(&self) -> anyhow::Result<Vec<usize>> {
+        let mut counts = vec![0; self.names.len()];
+        for i in 0..self.count {
+            let (pack, _) = self.entry(i)?;
+            *counts
+                .get_mut(pack)
+                .with_context(|| format!("bad pack-int-id: {pack}"))? += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Whether the checksum at the end of the file matches its contents.
+    fn checksum_valid(&self) -> anyhow::Result<bool> {
+        let size = hash::algorithm().size();
+        let (content, checksum) = self.data.split_at(self.data.len() - size);
+        Ok(Hasher::digest(content)?.as_bytes() == checksum)
+    }
+
+    /// Check the multi-pack index against the packs, printing every problem found. Returns
+    /// whether there were none.
+    pub(crate) fn verify(&self) -> anyhow::Result<bool> {
+        let mut ok = true;
+        let mut report = |message: String| {
+            eprintln!("{message}");
+            ok = false;
+        };
+        if !self.checksum_valid()? {
+            report("incorrect checksum".to_string());
+        }
+        for id in 0..self.names.len() {
+            if self.pack(id).is_err() {
+                report(format!("failed to load pack in position {id}"));
+            }
+        }
+        let fan_out = self.chunk(OID_FANOUT);
+        for byte in 0..255 {
+            let (this, next) = (be32(&fan_out[byte * 4..]), be32(&fan_out[byte * 4 + 4..]));
+            if this > next {
+                report(format!(
+                    "oid fanout out of order: fanout[{byte}] = {this:x} > {next:x} = fanout[{}]",
+                    byte + 1
+                ));
+            }
+        }
+        if self.count == 0 {
+            report("the midx contains no oid".to_string());
+            return Ok(false);
+        }
+        for i in 1..self.count {
+            if self.id(i - 1) >= self.id(i) {
+                report(format!(
+                    "oid lookup out of order: oid[{}] = {} >= {} = oid[{i}]",
+                    i - 1,
+                    hex::encode(self.id(i - 1)),
+                    hex::encode(self.id(i))
+                ));
+            }
+        }
+        for i in 0..self.count {
+            let hash = hex::encode(self.id(i));
+            let Some((pack, offset)) = self
+                .entry(i)
+                .ok()
+                .and_then(|(pack, offset)| Some((self.pack(pack).ok()?, offset)))
+            else {
+                report(format!("failed to load pack entry for oid[{i}] = {hash}"));
+                continue;
+            };
+            // Like Git, an object missing from the pack is at offset 0.
+            let actual = pack.find(&hash).map_or(0, |j| pack.offset(j));
+            if actual != offset {
+                report(format!(
+                    "incorrect object offset for oid[{i}] = {hash}: {offset:x} != {actual:x}"
+                ));
+            }
+        }
+        Ok(ok)
+    }
+}
+
+/// The multi-pack index of the repository, if it has one, `core.multiPackIndex` is not false,
+/// and every pack it covers is still there.
+pub(crate) fn load() -> anyhow::Result<Option<MultiPackIndex>> {
+    let path = path();
+    if !path.exists()
+        || !Config::load()?
+            .get_bool("core.multiPackIndex")?
+            .unwrap_or(true)
+    {
+        return Ok(None);
+    }
+    let midx = MultiPackIndex::open(&path)?;
+    let dir = git_path("objects/pack");
+    let complete = midx.names.iter().all(|name| {
+        let idx = dir.join(name);
+        idx.exists() && idx.with_extension("pack").exists()
+    });
+    Ok(complete.then_some(midx))
+}
+
+/// The modification time of the pack at `path`, in seconds.
+fn mtime(path: &Path) -> anyhow::Result<u64> {
+    let modified = path
+        .metadata()
+        .and_then(|meta| meta.modified())
+        .with_context(|| format!("read the modification time of {}", path.display()))?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()))
+}
+
+/// Write a multi-pack index covering every pack of the repository.
+///
+/// An object in several packs is listed for `preferred`, the file name of a pack or its index,
+/// if it is one of them. Nothing is written if the existing index already covers every pack.
+pub(crate) fn write(preferred: Option<&str>) -> anyhow::Result<()> {
+    let existing = load()?.filter(|midx| {
+        let valid = midx.checksum_valid().unwrap_or(false);
+        if !valid {
+            eprintln!("warning: ignoring existing multi-pack-index; checksum mismatch");
+        }
+        valid
+    });
+    write_over(existing.as_ref(), &[], preferred)
+}
+
+/// Write a multi-pack index covering the packs `existing` covers except `dropped`, and the packs
+/// it does not cover.
+///
+/// Like Git, objects keep the pack `existing` lists them for unless a pack it does not cover, or
+/// the preferred one, has them too.
+fn write_over(
+    existing: Option<&MultiPackIndex>,
+    dropped: &[String],
+    preferred: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut others = super::open_packs(|name| existing.is_some_and(|midx| midx.covers(name)))?;
+    others.sort_by(|a, b| a.path.cmp(&b.path));
+    // The packs in the order they are numbered while selecting objects: covered ones first.
+    let mut packs: Vec<(String, &Pack)> = Vec::new();
+    let mut renumbered = HashMap::new();
+    if let Some(midx) = existing {
+        for (id, name) in midx.names.iter().enumerate() {
+            if !dropped.contains(name) {
+                renumbered.insert(id, packs.len());
+                packs.push((name.clone(), midx.pack(id)?));
+            }
+        }
+        if others.is_empty() && dropped.is_empty() {
+            return Ok(());
+        }
+    }
+    let covered = packs.len();
+    packs.extend(others.iter().map(|pack| (pack.idx_name(), pack)));
+    anyhow::ensure!(!packs.is_empty(), "no pack files to index.");
+    let preferred = preferred.and_then(|name| {
+        let idx = Path::new(name).with_extension("idx");
+        let found = packs.iter().position(|(n, _)| Path::new(n) == idx);
+        if found.is_none() {
+            eprintln!("warning: unknown preferred pack: '{name}'");
+        }
+        found
+    });
+
+    // Each object once: from the preferred pack, then the newest, counting the pack `existing`
+    // lists it for as oldest, then the first.
+    let mut entries = Vec::new();
+    if let Some(midx) = existing {
+        for i in 0..midx.count {
+            let (id, offset) = midx.entry(i)?;
+            match renumbered.get(&id) {
+                Some(&id) if Some(id) != preferred => {
+                    entries.push((midx.id(i), (Reverse(false), Reverse(0), id), offset));
+                }
+                _ => {}
+            }
+        }
+    }
+    for (id, (_, pack)) in packs.iter().enumerate() {
+        if id < covered && Some(id) != preferred {
+            continue;
+        }
+        let key = (
+            Reverse(Some(id) == preferred),
+            Reverse(mtime(&pack.path)?),
+            id,
+        );
+        for i in 0..pack.count {
+            entries.push((pack.id(i), key, pack.offset(i)));
+        }
+    }
+    entries.sort_unstable();
+    entries.dedup_by(|b, a| a.0 == b.0);
+
+    // Packs are numbered by name in the file.
+    let mut order: Vec<usize> = (0..packs.len()).collect();
+    order.sort_by(|&a, &b| packs[a].0.cmp(&packs[b].0));
+    let mut numbers = vec![0; packs.len()];
+    for (number, &id) in order.iter().enumerate() {
+        numbers[id] = number;
+    }
+    let packs: Vec<&(String, &Pack)> = order.iter().map(|&id| &packs[id]).collect();
+
+    let size = hash::algorithm().size();
+    let mut names = Vec::new();
+    for (name, _) in &packs {
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    names.resize(names.len().next_multiple_of(4), 0);
+    let mut fan_out = Vec::with_capacity(256 * 4);
+    for byte in 0..=255u8 {
+        let count = entries.partition_point(|(id, _, _)| id[0] <= byte);
+        fan_out.extend(u32::try_from(count)?.to_be_bytes());
+    }
+    let mut lookup = Vec::with_capacity(entries.len() * size);
+    let mut offsets = Vec::with_capacity(entries.len() * 8);
+    let mut large = Vec::new();
+    for &(id, (_, _, pack), offset) in &entries {
+        lookup.extend_from_slice(id);
+        offsets.extend(u32::try_from(numbers[pack])?.to_be_bytes());
+        match u32::try_from(offset) {
+            Ok(small) if small & LARGE_OFFSET == 0 => offsets.extend(small.to_be_bytes()),
+            _ => {
+                let index = u32::try_from(large.len() / 8)? | LARGE_OFFSET;
+                offsets.extend(index.to_be_bytes());
+                large.extend(offset.to_be_bytes());
+            }
+        }
+    }
+
+    let mut chunks: Vec<([u8; 4], Vec<u8>)> = vec![
+        (PACK_NAMES, names),
+        (OID_FANOUT, fan_out),
+        (OID_LOOKUP, lookup),
+        (OBJECT_OFFSETS, offsets),
+    ];
+    if !large.is_empty() {
+        chunks.push((LARGE_OFFSETS, large));
+    }
+    let mut file = Vec::new();
+    file.extend_from_slice(SIGNATURE);
+    file.extend([
+        VERSION,
+        hash::algorithm().version(),
+        u8::try_from(chunks.len())?,
+        0,
+    ]);
+    file.extend(u32::try_from(packs.len())?.to_be_bytes());
+    let mut offset = (HEADER_SIZE + (chunks.len() + 1) * TOC_ENTRY_SIZE) as u64;
+    for (id, chunk) in &chunks {
+        file.extend_from_slice(id);
+        file.extend(offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    file.extend([0; 4]);
+    file.extend(offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+        file.extend_from_slice(chunk);
+    }
+    let checksum = Hasher::digest(&file)?;
+    file.extend_from_slice(checksum.as_bytes());
+
+    let mut lock = LockFile::acquire(path())?;
+    lock.write_all(&file)?;
+    lock.commit()?;
+    super::reload();
+    Ok(())
+}
+
+/// Remove the multi-pack index, if there is one.
+pub(crate) fn remove() -> anyhow::Result<()> {
+    let path = path();
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("remove {}", path.display()))
+        }
+        _ => {
+            super::reload();
+            Ok(())
+        }
+    }
+}
+
+/// Delete the packs whose objects are all listed for other packs, except kept ones, and rewrite
+/// the multi-pack index without them.
+pub(crate) fn expire() -> anyhow::Result<()> {
+    let Some(midx) = load()? else {
+        return Ok(());
+    };
+    let dir = git_path("objects/pack");
+    let mut dropped = Vec::new();
+    for (id, count) in midx.referenced()?.into_iter().enumerate() {
+        let path = dir.join(&midx.names[id]).with_extension("pack");
+        if count > 0 || midx.pack(id).is_err() || path.with_extension("keep").exists() {
+            continue;
+        }
+        super::remove(&path)?;
+        dropped.push(midx.names[id].clone());
+    }
+    if dropped.is_empty() {
+        return Ok(());
+    }
+    write_over(Some(&midx), &dropped, None)
+}
+
+/// Write the objects listed for some of the covered packs to a new pack, and a multi-pack index
+/// that prefers it, which leaves those packs for [`expire`].
+///
+/// With a `batch_size`, these are the oldest packs whose size, scaled down to the objects listed
+/// for them, adds up to it, skipping those that alone reach it. Without, they are all the packs.
+/// Either way nothing happens unless there are at least two, and kept packs are left alone unless
+/// `repack.packKeptObjects` is set.
+pub(crate) fn repack(batch_size: u64, window: usize, depth: usize) -> anyhow::Result<()> {
+    let Some(midx) = load()? else {
+        return Ok(());
+    };
+    let pack_kept = Config::load()?
+        .get_bool("repack.packKeptObjects")?
+        .unwrap_or(false);
+    let referenced = midx.referenced()?;
+    let mut candidates = Vec::new();
+    for (id, &count) in referenced.iter().enumerate() {
+        let Ok(pack) = midx.pack(id) else {
+            continue;
+        };
+        if !pack_kept && pack.path.with_extension("keep").exists() {
+            continue;
+        }
+        candidates.push((mtime(&pack.path)?, id, pack, count));
+    }
+    let mut included = vec![false; referenced.len()];
+    if batch_size == 0 {
+        for &(_, id, _, _) in &candidates {
+            included[id] = true;
+        }
+    } else {
+        candidates.sort_by_key(|&(mtime, id, _, _)| (mtime, id));
+        let mut total = 0;
+        for &(_, id, pack, count) in &candidates {
+            if total >= batch_size {
+                break;
+            }
+            if pack.count == 0 {
+                continue;
+            }
+            let pack_size = pack
+                .path
+                .metadata()
+                .with_context(|| format!("read {}", pack.path.display()))?
+                .len();
+            let expected = pack_size * count as u64 / pack.count as u64;
+            if expected >= batch_size {
+                continue;
+            }
+            total += expected;
+            included[id] = true;
+        }
+    }
+    if included.iter().filter(|&&included| included).count() < 2 {
+        return Ok(());
+    }
+
+    let mut objects = Vec::new();
+    for i in 0..midx.count {
+        let (id, offset) = midx.entry(i)?;
+        if !included[id] {
+            continue;
+        }
+        let hash = hex::encode(midx.id(i));
+        let object = midx.pack(id)?.read_object(&hash, offset)?;
+        objects.push(super::PackObject::read(hash, object, 0)?);
+    }
+    super::write(&objects, window, depth)?;
+    write(None)
+}
+
+/// Read a big-endian 64-bit number from the start of `data`.
+fn be64(data: &[u8]) -> u64 {
+    u64::from_be_bytes(data[..8].try_into().expect("8 bytes"))
+}
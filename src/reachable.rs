@@ -0,0 +1,99 @@
+//! Reachability of objects from the references, their logs, `HEAD` and the index, which decides
+//! what repacking keeps and pruning removes.
+use anyhow::Context;
+use std::collections::HashSet;
+
+use crate::index::Index;
+use crate::objects::{self, Commit, Kind, Object, Tag, Tree, MODE_GITLINK};
+use crate::{hash, reflog, refs};
+
+/// An object reached by a walk.
+#[derive(Debug, Clone)]
+pub(crate) struct Reached {
+    /// The hex id of the object.
+    pub(crate) hash: String,
+    /// The path the object was first reached at, empty for commits, tags and root trees.
+    pub(crate) name: String,
+}
+
+/// The objects every walk starts from: what `HEAD` and the references point to, the values in
+/// their logs unless `reflogs` is false, and the blobs in the index.
+pub(crate) fn roots(reflogs: bool) -> anyhow::Result<Vec<String>> {
+    let mut roots: Vec<String> = refs::list()?.into_iter().map(|(_, hash)| hash).collect();
+    roots.extend(refs::resolve("HEAD")?);
+    if reflogs {
+        for name in reflog::names()? {
+            for entry in reflog::read(&name)? {
+                for hash in [entry.old, entry.new] {
+                    // Logs may mention objects that were pruned with `--no-reflogs`.
+                    if !hash::is_null(&hash) && objects::exists(&hash)? {
+                        roots.push(hash);
+                    }
+                }
+            }
+        }
+    }
+    for entry in Index::read()?.entries {
+        if entry.entry.mode != MODE_GITLINK {
+            roots.push(entry.entry.hash);
+        }
+    }
+    Ok(roots)
+}
+
+/// All objects reachable from `roots`, each once, in the order they are reached.
+///
+/// Fails if a reachable object is missing, unless `missing_ok`, in which case it is skipped.
+pub(crate) fn walk(roots: &[String], missing_ok: bool) -> anyhow::Result<Vec<Reached>> {
+    let mut seen = HashSet::new();
+    let mut reached = Vec::new();
+    let mut pending: Vec<(String, Option<Kind>, String)> = roots
+        .iter()
+        .rev()
+        .map(|hash| (hash.clone(), None, String::new()))
+        .collect();
+    while let Some((hash, kind, name)) = pending.pop() {
+        if seen.contains(&hash) {
+            continue;
+        }
+        if missing_ok && !objects::exists(&hash)? {
+            continue;
+        }
+        seen.insert(hash.clone());
+        let kind = match kind {
+            Some(kind) => kind,
+            None => {
+                Object::read(&hash)
+                    .with_context(|| format!("read object {hash}"))?
+                    .kind
+            }
+        };
+        match kind {
+            Kind::Commit => {
+                let commit = Commit::read(&hash)?;
+                pending.push((commit.tree, Some(Kind::Tree), String::new()));
+                for parent in commit.parents.into_iter().rev() {
+                    pending.push((parent, Some(Kind::Commit), String::new()));
+                }
+            }
+            Kind::Tree => {
+                for (entry_name, entry) in Tree::read(&hash)?.entries.into_iter().rev() {
+                    let kind = match entry.mode {
+                        MODE_GITLINK => continue,
+                        _ if entry.is_tree() => Kind::Tree,
+                        _ => Kind::Blob,
+                    };
+                    pending.push((entry.hash, Some(kind), entry_name));
+                }
+            }
+            Kind::Tag => {
+                let data = Object::read(&hash)?.into_bytes()?;
+                let tag = Tag::parse(&data).with_context(|| format!("parse tag {hash}"))?;
+                pending.push((tag.object, None, String::new()));
+            }
+            Kind::Blob => {}
+        }
+        reached.push(Reached { hash, name });
+    }
+    Ok(reached)
+}
@@ -0,0 +1,799 @@
+//! Replaying commits on top of a new base, as done by `rebase`.
+//!
+//! A rebase is driven by a todo list of instructions (`pick`, `squash`, `exec`, `merge`, ...)
+//! kept in `.git/rebase-merge/git-rebase-todo`, so that it can stop for conflicts or edits and be
+//! continued, skipped or aborted later. `HEAD` is detached while the rebase is in progress.
+//!
+//! See: <https://git-scm.com/docs/git-rebase#_interactive_mode>
+use anyhow::Context;
+use std::fmt::Write;
+
+use crate::commands::commit::{cleanup_message, unresolved_paths};
+use crate::commands::merge::{self as merge_command, merge_heads, short, MERGE_HEAD, MERGE_MSG};
+use crate::commands::merge_base::History;
+use crate::commands::{commit_tree, write_tree};
+use crate::editor;
+use crate::index::Index;
+use crate::merge::{MergeOptions, TreeMerge};
+use crate::objects::{Commit, FlatTree, Tree};
+use crate::repository::{self, git_path};
+use crate::sequencer::{self, Action};
+use crate::{refs, revision, shared, worktree};
+
+/// Directory holding the state of an in-progress rebase.
+pub(crate) const STATE_DIR: &str = "rebase-merge";
+/// The instructions still to be executed.
+const TODO: &str = "rebase-merge/git-rebase-todo";
+/// The instructions executed so far; the last one is the one the rebase stopped at.
+const DONE: &str = "rebase-merge/done";
+/// The branch being rebased, or `detached HEAD`.
+const HEAD_NAME: &str = "rebase-merge/head-name";
+/// The commit the rebased commits are replayed onto.
+const ONTO: &str = "rebase-merge/onto";
+/// The commit the branch pointed to before the rebase, restored by `--abort`.
+const ORIG_HEAD: &str = "rebase-merge/orig-head";
+/// Present if the todo list was edited by the user.
+const INTERACTIVE: &str = "rebase-merge/interactive";
+/// The commit whose instruction stopped the rebase.
+const STOPPED_SHA: &str = "rebase-merge/stopped-sha";
+/// The commit created by an `edit` instruction, which `--continue` amends with any changes.
+const AMEND: &str = "rebase-merge/amend";
+/// The `squash` and `fixup` instructions melded into the current commit so far.
+const CURRENT_FIXUPS: &str = "rebase-merge/current-fixups";
+/// Prefix of the references created by `label` instructions.
+const REWRITTEN_PREFIX: &str = "refs/rewritten/";
+
+/// Help appended to the todo list shown to the user.
+const TODO_HELP: &str = "\
+#
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# e, edit <commit> = use commit, but stop for amending
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\" but keep only the previous commit's log message
+# x, exec <command> = run command (the rest of the line) using shell
+# b, break = stop here (continue rebase later with 'git rebase --continue')
+# d, drop <commit> = remove commit
+# l, label <label> = label current HEAD with a name
+# t, reset <label> = reset HEAD to a label
+# m, merge [-C <commit> | -c <commit>] <label> [# <oneline>]
+# .       create a merge commit using the original merge commit's
+# .       message (or the oneline, if no original merge commit was
+# .       specified)
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+#
+# If you remove a line here THAT COMMIT WILL BE LOST.
+#
+# However, if you remove everything, the rebase will be aborted.
+#
+";
+
+/// A todo list command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// Use the commit.
+    Pick,
+    /// Use the commit, but edit its message.
+    Reword,
+    /// Use the commit, but stop for amending.
+    Edit,
+    /// Meld the commit into the previous one, combining their messages.
+    Squash,
+    /// Meld the commit into the previous one, keeping only the previous message.
+    Fixup,
+    /// Remove the commit.
+    Drop,
+    /// Run a shell command.
+    Exec,
+    /// Stop so that the rebase can be continued later.
+    Break,
+    /// Name the current `HEAD`.
+    Label,
+    /// Move `HEAD` to a label.
+    Reset,
+    /// Merge a label into `HEAD`.
+    Merge,
+}
+
+impl Command {
+    /// The name of the command in the todo list.
+    fn name(self) -> &'static str {
+        match self {
+            Command::Pick => "pick",
+            Command::Reword => "reword",
+            Command::Edit => "edit",
+            Command::Squash => "squash",
+            Command::Fixup => "fixup",
+            Command::Drop => "drop",
+            Command::Exec => "exec",
+            Command::Break => "break",
+            Command::Label => "label",
+            Command::Reset => "reset",
+            Command::Merge => "merge",
+        }
+    }
+
+    /// Parse a command name or its one-letter abbreviation.
+    fn parse(word: &str) -> Option<Command> {
+        Some(match word {
+            "p" | "pick" => Command::Pick,
+            "r" | "reword" => Command::Reword,
+            "e" | "edit" => Command::Edit,
+            "s" | "squash" => Command::Squash,
+            "f" | "fixup" => Command::Fixup,
+            "d" | "drop" => Command::Drop,
+            "x" | "exec" => Command::Exec,
+            "b" | "break" => Command::Break,
+            "l" | "label" => Command::Label,
+            "t" | "reset" => Command::Reset,
+            "m" | "merge" => Command::Merge,
+            _ => return None,
+        })
+    }
+
+    /// Whether the command melds a commit into the previous one.
+    fn is_fixup(self) -> bool {
+        matches!(self, Command::Squash | Command::Fixup)
+    }
+}
+
+/// A line of the todo list.
+#[derive(Debug, Clone)]
+pub(crate) struct Instruction {
+    pub(crate) command: Command,
+    /// The commit to use, or the original merge commit whose message `merge -C` reuses.
+    pub(crate) commit: Option<String>,
+    /// The label of `label`, `reset` and `merge`, or the shell command of `exec`.
+    pub(crate) arg: String,
+}
+
+impl Instruction {
+    /// An instruction using `commit`.
+    pub(crate) fn new(command: Command, commit: &str) -> Instruction {
+        Instruction {
+            command,
+            commit: Some(commit.to_string()),
+            arg: String::new(),
+        }
+    }
+
+    /// Parse a todo list line, returning `None` for blank lines and comments.
+    fn parse(line: &str) -> anyhow::Result<Option<Instruction>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let command =
+            Command::parse(word).with_context(|| format!("invalid command '{word}' in {line}"))?;
+        let rest = rest.trim();
+        let first = |rest: &str| rest.split_whitespace().next().unwrap_or("").to_string();
+        let mut instruction = Instruction {
+            command,
+            commit: None,
+            arg: String::new(),
+        };
+        match command {
+            Command::Exec => instruction.arg = rest.to_string(),
+            Command::Break => {}
+            Command::Label | Command::Reset => instruction.arg = first(rest),
+            Command::Merge => {
+                let rest = rest.split_once('#').map_or(rest, |(rest, _)| rest);
+                let mut words = rest.split_whitespace();
+                let mut word = words.next();
+                if let Some("-C" | "-c") = word {
+                    let commit = words.next().context("missing commit after -C")?;
+                    instruction.commit = Some(revision::resolve(commit)?);
+                    word = words.next();
+                }
+                instruction.arg = word.context("missing label to merge")?.to_string();
+            }
+            _ => {
+                let commit = first(rest);
+                anyhow::ensure!(!commit.is_empty(), "missing commit in {line}");
+                instruction.commit = Some(
+                    revision::resolve(&commit).with_context(|| format!("invalid line: {line}"))?,
+                );
+            }
+        }
+        anyhow::ensure!(
+            !matches!(command, Command::Exec | Command::Label | Command::Reset)
+                || !instruction.arg.is_empty(),
+            "missing argument in {line}"
+        );
+        Ok(Some(instruction))
+    }
+
+    /// Format the instruction as a todo list line.
+    fn line(&self) -> anyhow::Result<String> {
+        let name = self.command.name();
+        Ok(match (self.command, &self.commit) {
+            (Command::Merge, Some(commit)) => format!(
+                "{name} -C {} {} # {}",
+                short(commit),
+                self.arg,
+                Commit::read(commit)?.summary()
+            ),
+            (_, Some(commit)) => {
+                format!(
+                    "{name} {} {}",
+                    short(commit),
+                    Commit::read(commit)?.summary()
+                )
+            }
+            (Command::Break, None) => name.to_string(),
+            (_, None) => format!("{name} {}", self.arg),
+        })
+    }
+}
+
+/// Read the todo list file `name` in the Git directory.
+fn read_instructions(name: &str) -> anyhow::Result<Vec<Instruction>> {
+    let path = git_path(name);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+    let mut instructions = Vec::new();
+    for line in contents.lines() {
+        instructions.extend(Instruction::parse(line)?);
+    }
+    Ok(instructions)
+}
+
+/// Write the todo list.
+fn write_todo(todo: &[Instruction]) -> anyhow::Result<()> {
+    let mut contents = String::new();
+    for instruction in todo {
+        writeln!(contents, "{}", instruction.line()?)?;
+    }
+    std::fs::write(git_path(TODO), contents).context("write .git/rebase-merge/git-rebase-todo")
+}
+
+/// Record an instruction as executed.
+fn append_done(instruction: &Instruction) -> anyhow::Result<()> {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(git_path(DONE))
+        .context("open .git/rebase-merge/done")?;
+    writeln!(file, "{}", instruction.line()?).context("write .git/rebase-merge/done")
+}
+
+/// Read the single-line state file `name` in the Git directory.
+fn read_state(name: &str) -> anyhow::Result<Option<String>> {
+    let path = git_path(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents =
+        std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+    Ok(Some(contents.trim().to_string()))
+}
+
+/// Write the single-line state file `name` in the Git directory.
+fn write_state(name: &str, value: &str) -> anyhow::Result<()> {
+    let path = git_path(name);
+    std::fs::write(&path, format!("{value}\n")).with_context(|| format!("write {}", path.display()))
+}
+
+/// Remove the state file `name` in the Git directory if it exists.
+fn remove_state(name: &str) -> anyhow::Result<()> {
+    let path = git_path(name);
+    if path.exists() {
+        std::fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The commit `HEAD` points to.
+fn head() -> anyhow::Result<String> {
+    refs::resolve("HEAD")?.context("HEAD does not point to a commit")
+}
+
+/// The tree of `commit` as a flat map.
+fn tree_of(commit: &str) -> anyhow::Result<FlatTree> {
+    Tree::read_flat(&Commit::read(commit)?.tree)
+}
+
+/// Detach `HEAD` at `commit`, updating the working tree and the index and recording `reason` in
+/// the log of `HEAD`.
+fn checkout(commit: &str, reason: &str) -> anyhow::Result<()> {
+    let from = tree_of(&head()?)?;
+    let to = tree_of(commit)?;
+    let overwritten = worktree::overwritten_paths(&from, &to)?;
+    if !overwritten.is_empty() {
+        anyhow::bail!(
+            "Your local changes to the following files would be overwritten by checkout:\n\t{}",
+            overwritten.join("\n\t")
+        );
+    }
+    worktree::checkout(&from, &to)?;
+    Index::from_tree(&to).write()?;
+    refs::update("HEAD", commit, reason)
+}
+
+/// Update the working tree and the index to the result of `merge` of `HEAD` with something.
+fn apply_merge(merge: &TreeMerge) -> anyhow::Result<()> {
+    let ours = tree_of(&head()?)?;
+    let overwritten = worktree::overwritten_paths(&ours, &merge.result)?;
+    if !overwritten.is_empty() {
+        anyhow::bail!(
+            "Your local changes to the following files would be overwritten by merge:\n\t{}",
+            overwritten.join("\n\t")
+        );
+    }
+    for path in &merge.auto_merged {
+        println!("Auto-merging {path}");
+    }
+    worktree::checkout(&ours, &merge.result)?;
+    Index::from_merge(&merge.result, &merge.conflicts).write()
+}
+
+/// The reason recorded in the log of `HEAD` for a commit made by `action`, e.g. `pick`.
+fn reflog_message(action: &str, message: &str) -> String {
+    let subject = message.lines().next().unwrap_or_default();
+    format!("rebase ({action}): {subject}")
+}
+
+/// Create a commit made by `action` and detach `HEAD` at it.
+fn commit(
+    tree: &str,
+    parents: &[&str],
+    message: &str,
+    author: Option<&str>,
+    action: &str,
+) -> anyhow::Result<String> {
+    let hash = commit_tree::write_commit_with_author(message.trim_end(), tree, parents, author)
+        .context("create commit")?;
+    let hash = hex::encode(hash);
+    refs::update("HEAD", &hash, &reflog_message(action, message))?;
+    Ok(hash)
+}
+
+/// Replace the commit at `HEAD` with one for `tree` and `message`.
+fn amend(tree: &str, message: &str, action: &str) -> anyhow::Result<String> {
+    let head = Commit::read(&head()?)?;
+    let parents: Vec<&str> = head.parents.iter().map(String::as_str).collect();
+    commit(tree, &parents, message, Some(&head.author), action)
+}
+
+/// Let the user edit a commit message, returning the cleaned up result.
+fn edit_message(message: &str, header: &str) -> anyhow::Result<String> {
+    let path = git_path("COMMIT_EDITMSG");
+    std::fs::write(
+        &path,
+        format!(
+            "{header}{}\n\n\
+             # Please enter the commit message for your changes. Lines starting\n\
+             # with '#' will be ignored, and an empty message aborts the commit.\n",
+            message.trim_end()
+        ),
+    )
+    .context("write .git/COMMIT_EDITMSG")?;
+    editor::launch(&editor::editor(), &path)?;
+    let message = cleanup_message(&std::fs::read_to_string(&path).context("read COMMIT_EDITMSG")?);
+    anyhow::ensure!(
+        !message.is_empty(),
+        "Aborting commit due to empty commit message."
+    );
+    Ok(message)
+}
+
+/// The message of the commit melding `commit` into `HEAD`.
+fn fixup_message(command: Command, commit: &Commit) -> anyhow::Result<String> {
+    let head = Commit::read(&head()?)?;
+    Ok(match command {
+        Command::Squash => {
+            // The subject of a `squash!` commit only names its target and is left out.
+            let message = commit.message.trim_end();
+            let message = match message.strip_prefix("squash! ") {
+                Some(rest) => rest
+                    .split_once('\n')
+                    .map_or("", |(_, body)| body.trim_start()),
+                None => message,
+            };
+            if message.is_empty() {
+                head.message.trim_end().to_string()
+            } else {
+                format!("{}\n\n{message}", head.message.trim_end())
+            }
+        }
+        _ => head.message.trim_end().to_string(),
+    })
+}
+
+/// Finish melding commits into `HEAD` if `next` does not continue the chain of fixups, letting
+/// the user edit the combined message if any of them was a `squash`.
+fn finish_fixups(next: Option<&Instruction>) -> anyhow::Result<()> {
+    if next.is_some_and(|i| i.command.is_fixup()) {
+        return Ok(());
+    }
+    let Some(fixups) = read_state(CURRENT_FIXUPS)? else {
+        return Ok(());
+    };
+    if fixups.lines().any(|line| line.starts_with("squash")) {
+        let head = Commit::read(&head()?)?;
+        let header = format!(
+            "# This is a combination of {} commits.\n",
+            fixups.lines().count() + 1
+        );
+        let message = edit_message(&head.message, &header)?;
+        amend(&head.tree, &message, "squash")?;
+    }
+    remove_state(CURRENT_FIXUPS)
+}
+
+/// Print how to go on after stopping at a conflict and exit with a failure status.
+fn stop_at_conflict(merge: &TreeMerge, hash: &str, commit: &Commit) -> ! {
+    for conflict in &merge.conflicts {
+        println!("{}", conflict.message);
+    }
+    let subject = commit.summary();
+    eprintln!("error: could not apply {}... {subject}", short(hash));
+    eprintln!("hint: Resolve all conflicts manually, remove the conflict markers, then run");
+    eprintln!("hint: \"git rebase --continue\".");
+    eprintln!("hint: You can instead skip this commit: run \"git rebase --skip\".");
+    eprintln!(
+        "hint: To abort and get back to the state before \"git rebase\", run \"git rebase --abort\"."
+    );
+    eprintln!("Could not apply {}... {subject}", short(hash));
+    std::process::exit(1);
+}
+
+/// Record the state needed to continue after stopping at a conflict.
+fn save_conflict(merge: &TreeMerge, hash: &str, message: &str) -> anyhow::Result<()> {
+    write_state(STOPPED_SHA, hash)?;
+    let mut merge_msg = format!("{}\n\n# Conflicts:\n", message.trim_end());
+    for conflict in &merge.conflicts {
+        writeln!(merge_msg, "#\t{}", conflict.path)?;
+    }
+    std::fs::write(git_path(MERGE_MSG), merge_msg).context("write .git/MERGE_MSG")
+}
+
+/// Execute `pick`, `reword`, `edit`, `squash` and `fixup`.
+fn pick(instruction: &Instruction, next: Option<&Instruction>) -> anyhow::Result<()> {
+    let command = instruction.command;
+    let hash = instruction.commit.as_deref().context("missing commit")?;
+    let commit = Commit::read(hash)?;
+    let head = head()?;
+
+    // Reuse the commit itself if it would be replayed unchanged onto its own parent.
+    if matches!(command, Command::Pick | Command::Edit) && commit.parents == [head.clone()] {
+        checkout(hash, &reflog_message(command.name(), &commit.message))?;
+    } else {
+        let options = sequencer::Options::default();
+        let merge = sequencer::apply(Action::Pick, hash, &commit, &tree_of(&head)?, &options)?;
+        apply_merge(&merge)?;
+        let message = if command.is_fixup() {
+            fixup_message(command, &commit)?
+        } else {
+            commit.message.clone()
+        };
+        if !merge.conflicts.is_empty() {
+            save_conflict(&merge, hash, &message)?;
+            stop_at_conflict(&merge, hash, &commit);
+        }
+        let tree = hex::encode(Tree::write_flat(&merge.result)?);
+        match command {
+            Command::Squash | Command::Fixup => {
+                amend(&tree, &message, command.name())?;
+                let fixups = read_state(CURRENT_FIXUPS)?.unwrap_or_default();
+                write_state(
+                    CURRENT_FIXUPS,
+                    format!("{fixups}\n{} {hash}", command.name()).trim(),
+                )?;
+                finish_fixups(next)?;
+            }
+            Command::Reword => {
+                let message = edit_message(&message, "")?;
+                let author = Some(commit.author.as_str());
+                self::commit(&tree, &[&head], &message, author, command.name())?;
+            }
+            _ => {
+                let author = Some(commit.author.as_str());
+                self::commit(&tree, &[&head], &message, author, command.name())?;
+            }
+        }
+    }
+
+    if command == Command::Edit {
+        stop_for_edit(hash, &commit)?;
+    }
+    Ok(())
+}
+
+/// Stop after an `edit` instruction so that the user can amend the commit.
+fn stop_for_edit(hash: &str, commit: &Commit) -> anyhow::Result<()> {
+    write_state(STOPPED_SHA, hash)?;
+    write_state(AMEND, &head()?)?;
+    println!("Stopped at {}...  {}", short(hash), commit.summary());
+    println!("You can amend the commit now by changing the working tree.");
+    println!();
+    println!("Once you are satisfied with your changes, run");
+    println!();
+    println!("  git rebase --continue");
+    std::process::exit(0);
+}
+
+/// Execute `merge`.
+fn merge(instruction: &Instruction) -> anyhow::Result<()> {
+    let head = head()?;
+    let label = &instruction.arg;
+    let theirs = resolve_label(label)?;
+    let original = instruction
+        .commit
+        .as_deref()
+        .map(Commit::read)
+        .transpose()?;
+
+    // Reuse the original merge commit if both of its parents are unchanged.
+    if let (Some(hash), Some(commit)) = (&instruction.commit, &original) {
+        if commit.parents == [head.clone(), theirs.clone()] {
+            return checkout(hash, &reflog_message("merge", &commit.message));
+        }
+    }
+
+    let mut history = History::default();
+    let options = MergeOptions::new(&[])?;
+    let merge =
+        merge_command::merge_commits(&mut history, &head, &theirs, "HEAD", label, &options)?;
+    apply_merge(&merge)?;
+    let message = match &original {
+        Some(commit) => commit.message.clone(),
+        None => format!("Merge branch '{label}'"),
+    };
+    if !merge.conflicts.is_empty() {
+        std::fs::write(git_path(MERGE_HEAD), format!("{theirs}\n"))
+            .context("write .git/MERGE_HEAD")?;
+        let stopped = instruction.commit.clone().unwrap_or_else(|| theirs.clone());
+        save_conflict(&merge, &stopped, &message)?;
+        for conflict in &merge.conflicts {
+            println!("{}", conflict.message);
+        }
+        eprintln!("error: could not merge {label}");
+        eprintln!("hint: Resolve all conflicts manually, remove the conflict markers, then run");
+        eprintln!("hint: \"git rebase --continue\".");
+        std::process::exit(1);
+    }
+    let tree = hex::encode(Tree::write_flat(&merge.result)?);
+    let author = original.as_ref().map(|c| c.author.as_str());
+    commit(&tree, &[&head, &theirs], &message, author, "merge")?;
+    Ok(())
+}
+
+/// The commit a label (or any revision) refers to.
+fn resolve_label(label: &str) -> anyhow::Result<String> {
+    match refs::resolve(&format!("{REWRITTEN_PREFIX}{label}"))? {
+        Some(hash) => Ok(hash),
+        None => revision::resolve(label).with_context(|| format!("could not resolve '{label}'")),
+    }
+}
+
+/// Execute a single instruction; `next` is the instruction after it.
+fn execute(instruction: &Instruction, next: Option<&Instruction>) -> anyhow::Result<()> {
+    match instruction.command {
+        Command::Pick | Command::Reword | Command::Edit | Command::Squash | Command::Fixup => {
+            pick(instruction, next)
+        }
+        Command::Drop => Ok(()),
+        Command::Exec => {
+            println!("Executing: {}", instruction.arg);
+            let status = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(&instruction.arg)
+                .status()
+                .context("run exec command")?;
+            if !status.success() {
+                eprintln!("warning: execution failed: {}", instruction.arg);
+                eprintln!("You can fix the problem, and then run");
+                eprintln!();
+                eprintln!("  git rebase --continue");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Break => std::process::exit(0),
+        Command::Label => refs::write(&format!("{REWRITTEN_PREFIX}{}", instruction.arg), &head()?),
+        Command::Reset => checkout(
+            &resolve_label(&instruction.arg)?,
+            &format!("rebase (reset): {}", instruction.arg),
+        ),
+        Command::Merge => merge(instruction),
+    }
+}
+
+/// Execute the todo list until it is empty or an instruction stops the rebase.
+fn run() -> anyhow::Result<()> {
+    let mut todo = read_instructions(TODO)?;
+    while !todo.is_empty() {
+        let instruction = todo.remove(0);
+        write_todo(&todo)?;
+        append_done(&instruction)?;
+        execute(&instruction, todo.first())?;
+    }
+    finish()
+}
+
+/// Point the rebased branch at the result and clean up the state.
+fn finish() -> anyhow::Result<()> {
+    let head = head()?;
+    let head_name = read_state(HEAD_NAME)?.context("read .git/rebase-merge/head-name")?;
+    if head_name != "detached HEAD" {
+        let onto = read_state(ONTO)?.context("read .git/rebase-merge/onto")?;
+        refs::update(
+            &head_name,
+            &head,
+            &format!("rebase (finish): {head_name} onto {onto}"),
+        )?;
+        refs::set_head(
+            &head_name,
+            &format!("rebase (finish): returning to {head_name}"),
+        )?;
+    }
+    cleanup()?;
+    println!("Successfully rebased and updated {head_name}.");
+    Ok(())
+}
+
+/// Remove the state of the rebase.
+fn cleanup() -> anyhow::Result<()> {
+    std::fs::remove_dir_all(git_path(STATE_DIR)).context("remove .git/rebase-merge")?;
+    let mut transaction = refs::Transaction::default();
+    for (name, _) in refs::list()? {
+        if name.starts_with(REWRITTEN_PREFIX) {
+            transaction.delete(&name, None);
+        }
+    }
+    transaction.commit()
+}
+
+/// Start rebasing the branch `head_name` (at `orig_head`) onto `onto` (given as `onto_name`) with
+/// `todo`, letting the user edit the todo list first if `interactive`.
+pub(crate) fn start(
+    head_name: &str,
+    orig_head: &str,
+    onto: &str,
+    onto_name: &str,
+    todo: &[Instruction],
+    interactive: bool,
+) -> anyhow::Result<()> {
+    shared::create_dir_all(&git_path(STATE_DIR))?;
+    write_state(HEAD_NAME, head_name)?;
+    write_state(ONTO, onto)?;
+    write_state(ORIG_HEAD, orig_head)?;
+    write_todo(todo)?;
+
+    if interactive {
+        write_state(INTERACTIVE, "")?;
+        let mut contents = std::fs::read_to_string(git_path(TODO)).context("read todo list")?;
+        write!(
+            contents,
+            "\n# Rebase {}..{} onto {} ({} command{})\n{TODO_HELP}",
+            short(onto),
+            short(orig_head),
+            short(onto),
+            todo.len(),
+            if todo.len() == 1 { "" } else { "s" }
+        )?;
+        std::fs::write(git_path(TODO), contents).context("write todo list")?;
+        if let Err(err) = editor::launch(&editor::sequence_editor(), &git_path(TODO)) {
+            cleanup()?;
+            return Err(err);
+        }
+        if read_instructions(TODO)?.is_empty() {
+            cleanup()?;
+            anyhow::bail!("nothing to do");
+        }
+        // Normalize the edited list, dropping the comments.
+        write_todo(&read_instructions(TODO)?)?;
+    }
+
+    refs::write("ORIG_HEAD", orig_head)?;
+    checkout(onto, &format!("rebase (start): checkout {onto_name}"))?;
+    run()
+}
+
+/// Conclude the instruction the rebase stopped at with the resolved working tree, then
+/// continue with the rest of the todo list.
+pub(crate) fn resume() -> anyhow::Result<()> {
+    anyhow::ensure!(git_path(STATE_DIR).exists(), "No rebase in progress?");
+    let unresolved = unresolved_paths()?;
+    if !unresolved.is_empty() {
+        anyhow::bail!(
+            "Committing is not possible because you have unmerged files:\n\t{}",
+            unresolved.join("\n\t")
+        );
+    }
+
+    let head = head()?;
+    let head_commit = Commit::read(&head)?;
+    let tree = write_tree::write_tree_for(repository::work_tree()?)
+        .context("write tree")?
+        .map(hex::encode)
+        .unwrap_or_default();
+    let stopped = read_state(STOPPED_SHA)?;
+    let done = read_instructions(DONE)?;
+    let last = done.last();
+
+    if !tree.is_empty() && tree != head_commit.tree {
+        if read_state(AMEND)?.as_deref() == Some(head.as_str()) {
+            amend(&tree, &head_commit.message, "continue")?;
+        } else if let (Some(hash), Some(last)) = (&stopped, last) {
+            let commit = Commit::read(hash)?;
+            let message = match merge_command::read_state_message()? {
+                Some(message) => cleanup_message(&message),
+                None => commit.message.clone(),
+            };
+            match last.command {
+                Command::Squash | Command::Fixup => {
+                    amend(&tree, &message, "continue")?;
+                    let fixups = read_state(CURRENT_FIXUPS)?.unwrap_or_default();
+                    write_state(
+                        CURRENT_FIXUPS,
+                        format!("{fixups}\n{} {hash}", last.command.name()).trim(),
+                    )?;
+                }
+                Command::Merge => {
+                    let mut parents = vec![head.as_str()];
+                    let merge_heads = merge_heads()?;
+                    parents.extend(merge_heads.iter().map(String::as_str));
+                    let author = last.commit.as_ref().map(|_| commit.author.as_str());
+                    self::commit(&tree, &parents, &message, author, "continue")?;
+                }
+                Command::Reword => {
+                    let message = edit_message(&message, "")?;
+                    let author = Some(commit.author.as_str());
+                    self::commit(&tree, &[&head], &message, author, "continue")?;
+                }
+                _ => {
+                    let author = Some(commit.author.as_str());
+                    self::commit(&tree, &[&head], &message, author, "continue")?;
+                }
+            }
+            Index::from_tree(&Tree::read_flat(&tree)?).write()?;
+        }
+    }
+    if last.is_some_and(|i| i.command.is_fixup()) {
+        finish_fixups(read_instructions(TODO)?.first())?;
+    }
+    remove_state(STOPPED_SHA)?;
+    remove_state(AMEND)?;
+    merge_command::clear_state()?;
+    run()
+}
+
+/// Discard the changes of the instruction the rebase stopped at and continue with the rest of
+/// the todo list.
+pub(crate) fn skip() -> anyhow::Result<()> {
+    anyhow::ensure!(git_path(STATE_DIR).exists(), "No rebase in progress?");
+    worktree::reset_hard(&tree_of(&head()?)?)?;
+    remove_state(STOPPED_SHA)?;
+    remove_state(AMEND)?;
+    merge_command::clear_state()?;
+    run()
+}
+
+/// Stop the rebase and restore the branch, the index and the working tree to how they were
+/// before it started.
+pub(crate) fn abort() -> anyhow::Result<()> {
+    anyhow::ensure!(git_path(STATE_DIR).exists(), "No rebase in progress?");
+    let orig_head = read_state(ORIG_HEAD)?.context("read .git/rebase-merge/orig-head")?;
+    let head_name = read_state(HEAD_NAME)?.context("read .git/rebase-merge/head-name")?;
+    worktree::reset_hard(&tree_of(&orig_head)?)?;
+    if head_name == "detached HEAD" {
+        refs::update("HEAD", &orig_head, "rebase (abort): updating HEAD")?;
+    } else {
+        refs::write(&head_name, &orig_head)?;
+        refs::set_head(
+            &head_name,
+            &format!("rebase (abort): returning to {head_name}"),
+        )?;
+    }
+    merge_command::clear_state()?;
+    cleanup()
+}
@@ -0,0 +1,123 @@
+//! Reference logs, recording the values a reference had over time.
+//!
+//! See: <https://git-scm.com/docs/git-reflog>
+use anyhow::Context;
+use std::fmt;
+
+use crate::commands::commit_tree;
+use crate::{config, hash, refs};
+
+/// A single reference log entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    /// The value of the reference before the update.
+    pub(crate) old: String,
+    /// The value of the reference after the update.
+    pub(crate) new: String,
+    /// Who updated the reference and when, e.g. `Name <email> 1700000000 +0000`.
+    pub(crate) identity: String,
+    /// Why the reference was updated.
+    pub(crate) message: String,
+}
+
+impl Entry {
+    /// An entry recording an update from `old` (`None` for a new reference) to `new` by the
+    /// current user, now.
+    pub(crate) fn new(old: Option<&str>, new: &str, message: &str) -> anyhow::Result<Entry> {
+        Ok(Entry {
+            old: old.unwrap_or(hash::null_hex()).to_string(),
+            new: new.to_string(),
+            identity: commit_tree::identity()?,
+            message: message.to_string(),
+        })
+    }
+
+    /// When the reference was updated, in seconds since the Unix epoch.
+    pub(crate) fn timestamp(&self) -> i64 {
+        self.identity
+            .rsplit(' ')
+            .nth(1)
+            .and_then(|timestamp| timestamp.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Parse a line of a reference log file.
+    pub(crate) fn parse(line: &str) -> anyhow::Result<Entry> {
+        let (head, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut parts = head.splitn(3, ' ');
+        let mut next = || parts.next().context("reflog entry is truncated");
+        Ok(Entry {
+            old: next()?.to_string(),
+            new: next()?.to_string(),
+            identity: next()?.to_string(),
+            message: message.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Entry {
+    /// Format the entry as a line of a reference log file, without the trailing newline.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.old, self.new, self.identity)?;
+        // Like Git, an entry without a message has no tab either.
+        if !self.message.is_empty() {
+            write!(f, "\t{}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the reference `name` has a log.
+pub(crate) fn exists(name: &str) -> bool {
+    refs::store().is_ok_and(|store| store.log_exists(name))
+}
+
+/// The names of all references that have a log, sorted.
+pub(crate) fn names() -> anyhow::Result<Vec<String>> {
+    refs::store()?.log_names()
+}
+
+/// Read the log of the reference `name`, oldest entry first.
+pub(crate) fn read(name: &str) -> anyhow::Result<Vec<Entry>> {
+    refs::store()?.read_log(name)
+}
+
+/// Replace the log of the reference `name`.
+pub(crate) fn write(name: &str, entries: &[Entry]) -> anyhow::Result<()> {
+    refs::store()?.write_log(name, entries)
+}
+
+/// Record that the reference `name` was updated from `old` to `new`.
+pub(crate) fn append(
+    name: &str,
+    old: Option<&str>,
+    new: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    refs::store()?.append_log(name, &Entry::new(old, new, message)?)
+}
+
+/// Whether updates of the reference `name` are logged: references that already have a log, and
+/// per `core.logAllRefUpdates` (true by default) `HEAD`, branches, remote-tracking branches and
+/// notes, or all references if it is `always`.
+pub(crate) fn should_log(name: &str) -> anyhow::Result<bool> {
+    if exists(name) {
+        return Ok(true);
+    }
+    let key = "core.logAllRefUpdates";
+    let enabled = match config::get(key)?.as_deref() {
+        Some("always") => return Ok(true),
+        Some(value) => config::parse_bool(key, Some(value))?,
+        None => true,
+    };
+    Ok(enabled
+        && (name == "HEAD"
+            || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix))))
+}
+
+/// Remove the log of the reference `name`, if any.
+pub(crate) fn delete(name: &str) -> anyhow::Result<()> {
+    refs::store()?.delete_log(name)
+}
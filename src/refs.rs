@@ -0,0 +1,442 @@
+//! Git references.
+//!
+//! References and their logs live in a [`RefStore`], chosen per repository by
+//! `extensions.refStorage`: loose files and `packed-refs` (see [`files`]), or a stack of
+//! reftables (see [`reftable`]). Updates go through a [`Transaction`], which the store applies
+//! atomically after locking every reference it touches and checking their current values.
+//!
+//! See: <https://git-scm.com/book/en/v2/Git-Internals-Git-References>
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use crate::config::{self, Config};
+use crate::hash;
+use crate::objects::{Kind, Object, Tag};
+use crate::reflog;
+use crate::repository::git_path;
+
+pub(crate) mod files;
+pub(crate) mod reftable;
+
+/// Maximum number of symbolic references followed before giving up.
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// How a repository stores its references.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Format {
+    /// Loose files under `.git/refs` and `.git/packed-refs`, with logs under `.git/logs`.
+    Files,
+    /// Binary tables under `.git/reftable`, holding both references and their logs.
+    Reftable,
+}
+
+impl Format {
+    /// The format of the repository, per `extensions.refStorage` in `.git/config`.
+    pub(crate) fn of_repository() -> anyhow::Result<Format> {
+        let config = Config::from_file(&git_path("config"))?;
+        match config.get_string("extensions.refStorage") {
+            None | Some("files") => Ok(Format::Files),
+            Some("reftable") => Ok(Format::Reftable),
+            Some(other) => anyhow::bail!("unknown ref storage format '{other}'"),
+        }
+    }
+
+    /// Set up an empty store of this format in a new repository, with `HEAD` pointing to the
+    /// branch `head`, e.g. `refs/heads/main`.
+    pub(crate) fn init(self, head: &str) -> anyhow::Result<()> {
+        check_name(head)?;
+        match self {
+            Format::Files => files::init(head),
+            Format::Reftable => {
+                let path = git_path("config");
+                config::set(&path, "core.repositoryFormatVersion", "1", false)?;
+                config::set(&path, "extensions.refStorage", "reftable", false)?;
+                reftable::init(head)
+            }
+        }
+    }
+}
+
+/// A way of storing references and their logs.
+pub(crate) trait RefStore {
+    /// Read the raw value of the reference `name`: an object hash, or `ref: <target>` for a
+    /// symbolic reference. Returns `None` if the reference does not exist.
+    fn read(&self, name: &str) -> anyhow::Result<Option<String>>;
+
+    /// The raw values of all references under `refs/`, sorted by name.
+    fn refs(&self) -> anyhow::Result<Vec<(String, String)>>;
+
+    /// Lock the references `updates` touch, check them against their current values with
+    /// [`check`], and apply them together with their log entries from [`log_entries`].
+    fn commit(&self, updates: &[RefUpdate]) -> anyhow::Result<()>;
+
+    /// Compact the stored references. Loose files only pack tags unless `all`, and keep the
+    /// packed loose files unless `prune`.
+    fn pack(&self, all: bool, prune: bool) -> anyhow::Result<()>;
+
+    /// Whether the reference `name` has a log.
+    fn log_exists(&self, name: &str) -> bool;
+
+    /// The names of all references that have a log, sorted.
+    fn log_names(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Read the log of the reference `name`, oldest entry first.
+    fn read_log(&self, name: &str) -> anyhow::Result<Vec<reflog::Entry>>;
+
+    /// Replace the log of the reference `name`.
+    fn write_log(&self, name: &str, entries: &[reflog::Entry]) -> anyhow::Result<()>;
+
+    /// Add `entry` to the log of the reference `name`.
+    fn append_log(&self, name: &str, entry: &reflog::Entry) -> anyhow::Result<()>;
+
+    /// Remove the log of the reference `name`, if any.
+    fn delete_log(&self, name: &str) -> anyhow::Result<()>;
+}
+
+/// The reference store of the repository.
+pub(crate) fn store() -> anyhow::Result<Box<dyn RefStore>> {
+    static FORMAT: OnceLock<Format> = OnceLock::new();
+    let format = match FORMAT.get() {
+        Some(format) => *format,
+        None => {
+            let format = Format::of_repository()?;
+            *FORMAT.get_or_init(|| format)
+        }
+    };
+    Ok(match format {
+        Format::Files => Box::new(files::FilesStore),
+        Format::Reftable => Box::new(reftable::ReftableStore),
+    })
+}
+
+/// Check that `name` is a valid reference name, e.g. `HEAD` or `refs/heads/main`.
+///
+/// See: <https://git-scm.com/docs/git-check-ref-format>
+pub(crate) fn check_name(name: &str) -> anyhow::Result<()> {
+    let pseudo = name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_');
+    let valid = (pseudo && !name.is_empty() || name.starts_with("refs/"))
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && name != "@"
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && name
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.') && !part.ends_with(".lock"));
+    anyhow::ensure!(valid, "refusing to update ref with bad name '{name}'");
+    Ok(())
+}
+
+/// Read the raw value of a reference, e.g. `HEAD` or `refs/heads/main`: either an object hash or
+/// `ref: <target>` for a symbolic reference.
+///
+/// Returns `None` if the reference does not exist.
+pub(crate) fn read_raw(name: &str) -> anyhow::Result<Option<String>> {
+    store()?.read(name)
+}
+
+/// Resolve a reference to the object hash it points to, following symbolic references.
+///
+/// Returns `None` if the reference (or the target of a symbolic reference) does not exist.
+pub(crate) fn resolve(name: &str) -> anyhow::Result<Option<String>> {
+    resolve_in(&*store()?, name)
+}
+
+/// Resolve the reference `name` in `store`, like [`resolve`].
+fn resolve_in(store: &dyn RefStore, name: &str) -> anyhow::Result<Option<String>> {
+    let target = target_name_in(store, name)?;
+    match store.read(&target)? {
+        Some(contents) if !contents.starts_with("ref: ") => Ok(Some(contents)),
+        _ => Ok(None),
+    }
+}
+
+/// The reference a chain of symbolic references starting at `name` ends in, which need not
+/// exist, e.g. `refs/heads/main` for `HEAD`.
+pub(crate) fn target_name(name: &str) -> anyhow::Result<String> {
+    target_name_in(&*store()?, name)
+}
+
+/// Follow the symbolic references starting at `name` in `store`, like [`target_name`].
+fn target_name_in(store: &dyn RefStore, name: &str) -> anyhow::Result<String> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match store
+            .read(&name)?
+            .as_deref()
+            .and_then(|c| c.strip_prefix("ref: "))
+        {
+            Some(target) => name = target.trim().to_string(),
+            None => return Ok(name),
+        }
+    }
+    anyhow::bail!("reference {name} is nested too deeply");
+}
+
+/// The branch `HEAD` points to, e.g. `refs/heads/main`, through any nested symbolic references,
+/// or `None` if `HEAD` is detached. The branch does not exist yet if it is unborn.
+pub(crate) fn head_target() -> anyhow::Result<Option<String>> {
+    head_target_in(&*store()?)
+}
+
+/// The branch `HEAD` points to in `store`, like [`head_target`].
+fn head_target_in(store: &dyn RefStore) -> anyhow::Result<Option<String>> {
+    store.read("HEAD")?.context("HEAD does not exist")?;
+    let target = target_name_in(store, "HEAD")?;
+    Ok((target != "HEAD").then_some(target))
+}
+
+/// The object an annotated tag `hash` ultimately points to, or `None` if `hash` is not a tag.
+pub(crate) fn peel(hash: &str) -> anyhow::Result<Option<String>> {
+    let mut hash = hash.to_string();
+    let mut peeled = None;
+    loop {
+        let object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+        if object.kind != Kind::Tag {
+            return Ok(peeled);
+        }
+        let data = object.into_bytes()?;
+        hash = Tag::parse(&data)
+            .with_context(|| format!("parse tag {hash}"))?
+            .object;
+        peeled = Some(hash.clone());
+    }
+}
+
+/// All references under `refs/` and the object hashes they point to, sorted by name.
+pub(crate) fn list() -> anyhow::Result<Vec<(String, String)>> {
+    let store = store()?;
+    let raw: BTreeMap<String, String> = store.refs()?.into_iter().collect();
+    let mut refs = Vec::with_capacity(raw.len());
+    for (name, value) in &raw {
+        let hash = match value.strip_prefix("ref: ") {
+            // Symbolic references usually point to another listed reference.
+            Some(target) => match raw.get(target.trim()) {
+                Some(value) if !value.starts_with("ref: ") => Some(value.clone()),
+                _ => resolve_in(&*store, name)?,
+            },
+            None => Some(value.clone()),
+        };
+        if let Some(hash) = hash {
+            refs.push((name.clone(), hash));
+        }
+    }
+    Ok(refs)
+}
+
+/// What a transaction does to a reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Change {
+    /// Point the reference at an object.
+    Set(String),
+    /// Make the reference a symbolic reference to another one.
+    Symbolic(String),
+    /// Delete the reference and its log.
+    Delete,
+    /// Only check the current value of the reference.
+    Verify,
+}
+
+/// A single update in a [`Transaction`].
+#[derive(Debug, Clone)]
+pub(crate) struct RefUpdate {
+    /// The full name of the reference.
+    pub(crate) name: String,
+    /// What to do with the reference.
+    pub(crate) change: Change,
+    /// The value the reference must have beforehand, the null hash if it must not exist.
+    pub(crate) old: Option<String>,
+    /// Why the reference is updated, recorded in the reference logs.
+    pub(crate) message: Option<String>,
+}
+
+/// Check `updates` against the current values of their references in `store`, which must have
+/// locked them, and return those values.
+pub(crate) fn check(
+    store: &dyn RefStore,
+    updates: &[RefUpdate],
+) -> anyhow::Result<Vec<Option<String>>> {
+    let mut values = Vec::with_capacity(updates.len());
+    for update in updates {
+        let current = resolve_in(store, &update.name)?;
+        match (update.old.as_deref(), current.as_deref()) {
+            (None, _) => {}
+            (Some(old), None) if hash::is_null(old) => {}
+            (Some(old), Some(_)) if hash::is_null(old) => anyhow::bail!(
+                "cannot lock ref '{}': reference already exists",
+                update.name
+            ),
+            (Some(_), None) => anyhow::bail!(
+                "cannot lock ref '{}': unable to resolve reference '{}'",
+                update.name,
+                update.name
+            ),
+            (Some(old), Some(current)) if old != current => anyhow::bail!(
+                "cannot lock ref '{}': is at {current} but expected {old}",
+                update.name
+            ),
+            _ => {}
+        }
+        values.push(current);
+    }
+    Ok(values)
+}
+
+/// The log entries recording `updates` of references whose `current` values [`check`] returned:
+/// one for each logged reference that is given a new value, and one for `HEAD` when the branch
+/// it points to is.
+pub(crate) fn log_entries(
+    store: &dyn RefStore,
+    updates: &[RefUpdate],
+    current: &[Option<String>],
+) -> anyhow::Result<Vec<(String, reflog::Entry)>> {
+    let head = head_target_in(store).ok().flatten();
+    let mut entries = Vec::new();
+    for (update, current) in updates.iter().zip(current) {
+        let Some(message) = &update.message else {
+            continue;
+        };
+        let new = match &update.change {
+            Change::Set(new) => new.clone(),
+            Change::Symbolic(target) => match resolve_in(store, target)? {
+                Some(new) => new,
+                None => continue,
+            },
+            Change::Delete | Change::Verify => continue,
+        };
+        let entry = reflog::Entry::new(current.as_deref(), &new, message)?;
+        if matches!(update.change, Change::Set(_))
+            && update.name != "HEAD"
+            && head.as_deref() == Some(&update.name)
+            && reflog::should_log("HEAD")?
+        {
+            entries.push(("HEAD".to_string(), entry.clone()));
+        }
+        if reflog::should_log(&update.name)? {
+            entries.push((update.name.clone(), entry));
+        }
+    }
+    Ok(entries)
+}
+
+/// Updates of several references that are applied together or not at all.
+#[derive(Debug, Default)]
+pub(crate) struct Transaction {
+    updates: Vec<RefUpdate>,
+}
+
+impl Transaction {
+    /// Point the reference `name` at the object `new`. If `old` is given, the reference must
+    /// currently point to it, or not exist if it is the null hash. If `message` is given, the
+    /// update is recorded in the reference logs.
+    pub(crate) fn update(
+        &mut self,
+        name: &str,
+        new: &str,
+        old: Option<&str>,
+        message: Option<&str>,
+    ) {
+        self.push(name, Change::Set(new.to_string()), old, message);
+    }
+
+    /// Make `name` a symbolic reference to `target`, recording `message` in the log of `name`
+    /// if given.
+    pub(crate) fn update_symbolic(&mut self, name: &str, target: &str, message: Option<&str>) {
+        self.push(name, Change::Symbolic(target.to_string()), None, message);
+    }
+
+    /// Delete the reference `name`, which must currently point to `old` if given.
+    pub(crate) fn delete(&mut self, name: &str, old: Option<&str>) {
+        self.push(name, Change::Delete, old, None);
+    }
+
+    /// Check that the reference `name` points to `old`, or does not exist if it is
+    /// the null hash, without changing it.
+    pub(crate) fn verify(&mut self, name: &str, old: &str) {
+        self.push(name, Change::Verify, Some(old), None);
+    }
+
+    fn push(&mut self, name: &str, change: Change, old: Option<&str>, message: Option<&str>) {
+        self.updates.push(RefUpdate {
+            name: name.to_string(),
+            change,
+            old: old.map(str::to_string),
+            message: message.map(str::to_string),
+        });
+    }
+
+    /// Lock all references, check their current values, and apply the updates. If any check
+    /// fails, no reference is changed.
+    pub(crate) fn commit(self) -> anyhow::Result<()> {
+        let mut updates = self.updates;
+        updates.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(pair) = updates.windows(2).find(|pair| pair[0].name == pair[1].name) {
+            anyhow::bail!("multiple updates for ref '{}' not allowed", pair[0].name);
+        }
+        for update in &updates {
+            check_name(&update.name)?;
+            if let Change::Symbolic(target) = &update.change {
+                check_name(target)?;
+            }
+        }
+        store()?.commit(&updates)
+    }
+}
+
+/// Point the reference `name` at the object `hash`, without recording it in the reference logs.
+pub(crate) fn write(name: &str, hash: &str) -> anyhow::Result<()> {
+    let mut transaction = Transaction::default();
+    transaction.update(name, hash, None, None);
+    transaction.commit()
+}
+
+/// Delete the reference `name` and its log, if it exists.
+pub(crate) fn delete(name: &str) -> anyhow::Result<()> {
+    let mut transaction = Transaction::default();
+    transaction.delete(name, None);
+    transaction.commit()
+}
+
+/// Point the reference `name` at the object `hash` and record why in its reference log, and in
+/// the log of `HEAD` if `HEAD` points to `name`.
+pub(crate) fn update(name: &str, hash: &str, message: &str) -> anyhow::Result<()> {
+    let mut transaction = Transaction::default();
+    transaction.update(name, hash, None, Some(message));
+    transaction.commit()
+}
+
+/// Point the current branch (or `HEAD` itself, if detached) at the commit `hash`, recording
+/// `message` in the reference logs.
+pub(crate) fn update_head(hash: &str, message: &str) -> anyhow::Result<()> {
+    match head_target()? {
+        Some(branch) => update(&branch, hash, message),
+        None => update("HEAD", hash, message),
+    }
+}
+
+/// Make `name` a symbolic reference to `target`, e.g. `refs/heads/main`, recording `message` in
+/// the log of `name` if given.
+pub(crate) fn write_symbolic(
+    name: &str,
+    target: &str,
+    message: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut transaction = Transaction::default();
+    transaction.update_symbolic(name, target, message);
+    transaction.commit()
+}
+
+/// Point `HEAD` at the branch `target`, e.g. `refs/heads/main`, recording `message` in the log
+/// of `HEAD`.
+pub(crate) fn set_head(target: &str, message: &str) -> anyhow::Result<()> {
+    write_symbolic("HEAD", target, Some(message))
+}
+
+/// Compact the references of the repository, see [`RefStore::pack`].
+pub(crate) fn pack(all: bool, prune: bool) -> anyhow::Result<()> {
+    store()?.pack(all, prune)
+}
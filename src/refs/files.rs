@@ -0,0 +1,351 @@
+//! The files reference store: loose references under `.git`, e.g. `.git/refs/heads/main`, and
+//! packed ones in `.git/packed-refs`, where a loose file takes precedence. Each reference is
+//! locked with a `<ref>.lock` file while it is updated, and its log is kept in
+//! `.git/logs/<ref>`.
+//!
+//! See: <https://git-scm.com/docs/gitrepository-layout>
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::{Change, RefStore, RefUpdate};
+use crate::lockfile::LockFile;
+use crate::reflog;
+use crate::repository::{git_dir, git_path};
+use crate::shared;
+
+/// The file holding packed references.
+const PACKED_REFS: &str = "packed-refs";
+
+/// The header of the packed references file Git writes, listing the traits of its contents.
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted \n";
+
+/// References that belong to a single worktree, and so are never packed.
+const PER_WORKTREE_PREFIXES: [&str; 3] = ["refs/bisect/", "refs/rewritten/", "refs/worktree/"];
+
+/// The path of the loose reference `name`.
+fn path(name: &str) -> PathBuf {
+    git_path(name)
+}
+
+/// The path of the log of the reference `name`.
+fn log_path(name: &str) -> PathBuf {
+    git_path("logs").join(name)
+}
+
+/// Set up the files store of a new repository, with `HEAD` pointing to `head`.
+pub(crate) fn init(head: &str) -> anyhow::Result<()> {
+    for dir in ["refs/heads", "refs/tags"] {
+        std::fs::create_dir_all(git_path(dir)).with_context(|| format!("create .git/{dir}"))?;
+    }
+    std::fs::write(git_path("HEAD"), format!("ref: {head}\n")).context("write .git/HEAD")
+}
+
+/// A reference in the packed references file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PackedRef {
+    /// The full name, e.g. `refs/tags/v1.0`.
+    name: String,
+    /// The object the reference points to.
+    hash: String,
+    /// The object an annotated tag ultimately points to, if `hash` is one.
+    peeled: Option<String>,
+}
+
+/// Read the packed references, sorted by name.
+fn read_packed() -> anyhow::Result<Vec<PackedRef>> {
+    let contents = match std::fs::read_to_string(git_path(PACKED_REFS)) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error).context("read .git/packed-refs"),
+    };
+    let mut refs: Vec<PackedRef> = Vec::new();
+    for line in contents.lines() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if let Some(peeled) = line.strip_prefix('^') {
+            let last = refs
+                .last_mut()
+                .context("peeled line without a reference in .git/packed-refs")?;
+            last.peeled = Some(peeled.to_string());
+            continue;
+        }
+        let (hash, name) = line
+            .split_once(' ')
+            .with_context(|| format!("unexpected line in .git/packed-refs: {line}"))?;
+        refs.push(PackedRef {
+            name: name.to_string(),
+            hash: hash.to_string(),
+            peeled: None,
+        });
+    }
+    refs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(refs)
+}
+
+/// Replace the packed references with `refs`, sorted by name, through the `lock` on them.
+fn write_packed(mut lock: LockFile, refs: &BTreeMap<String, PackedRef>) -> anyhow::Result<()> {
+    let mut contents = String::from(PACKED_REFS_HEADER);
+    for packed in refs.values() {
+        contents.push_str(&format!("{} {}\n", packed.hash, packed.name));
+        if let Some(peeled) = &packed.peeled {
+            contents.push_str(&format!("^{peeled}\n"));
+        }
+    }
+    lock.write_all(contents.as_bytes())?;
+    lock.commit()
+}
+
+/// Read the raw contents of a loose reference file, e.g. `HEAD` or `refs/heads/main`.
+fn read_loose(name: &str) -> anyhow::Result<Option<String>> {
+    let path = path(name);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let contents =
+        std::fs::read_to_string(&path).with_context(|| format!("read reference {name}"))?;
+    Ok(Some(contents.trim().to_string()))
+}
+
+/// The names of all files under `dir`, relative to `base`, sorted.
+fn walk(dir: &Path, base: &Path) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("read {}", dir.display()))?
+                .path();
+            if path.is_dir() {
+                stack.push(path);
+            } else if let Ok(name) = path.strip_prefix(base) {
+                names.push(name.to_string_lossy().into_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// The names of all loose references under `refs/`, sorted.
+fn loose_names() -> anyhow::Result<Vec<String>> {
+    let mut names = walk(&path("refs"), git_dir())?;
+    names.retain(|name| !name.ends_with(".lock"));
+    Ok(names)
+}
+
+/// Remove the empty directories above the loose reference `name`, keeping the top-level ones
+/// like `.git/refs/heads`.
+fn prune_directories(name: &str) {
+    let mut dir = path(name);
+    let refs = path("refs");
+    while dir.pop()
+        && dir
+            .strip_prefix(&refs)
+            .is_ok_and(|rel| rel.components().count() > 1)
+    {
+        if std::fs::remove_dir(&dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// References stored as loose files and in `.git/packed-refs`.
+pub(crate) struct FilesStore;
+
+impl RefStore for FilesStore {
+    fn read(&self, name: &str) -> anyhow::Result<Option<String>> {
+        if let Some(contents) = read_loose(name)? {
+            return Ok(Some(contents));
+        }
+        Ok(read_packed()?
+            .into_iter()
+            .find(|packed| packed.name == name)
+            .map(|packed| packed.hash))
+    }
+
+    fn refs(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let mut refs: BTreeMap<String, String> = read_packed()?
+            .into_iter()
+            .map(|packed| (packed.name, packed.hash))
+            .collect();
+        for name in loose_names()? {
+            if let Some(contents) = read_loose(&name)? {
+                refs.insert(name, contents);
+            }
+        }
+        Ok(refs.into_iter().collect())
+    }
+
+    fn commit(&self, updates: &[RefUpdate]) -> anyhow::Result<()> {
+        // Lock every reference before checking any value, so that none can change in between.
+        let mut locks = Vec::with_capacity(updates.len());
+        for update in updates {
+            locks.push(
+                LockFile::acquire(path(&update.name))
+                    .with_context(|| format!("cannot lock ref '{}'", update.name))?,
+            );
+        }
+        let current = super::check(self, updates)?;
+        let logs = super::log_entries(self, updates, &current)?;
+        for (lock, update) in locks.iter_mut().zip(updates) {
+            match &update.change {
+                Change::Set(new) => lock.write_all(format!("{new}\n").as_bytes())?,
+                Change::Symbolic(target) => {
+                    lock.write_all(format!("ref: {target}\n").as_bytes())?
+                }
+                Change::Delete | Change::Verify => {}
+            }
+        }
+
+        // Remove deleted references from the packed references before their loose files, so
+        // that an interrupted deletion never brings back an older packed value.
+        let deleted: Vec<&str> = updates
+            .iter()
+            .filter(|update| update.change == Change::Delete)
+            .map(|update| update.name.as_str())
+            .collect();
+        if read_packed()?
+            .iter()
+            .any(|packed| deleted.contains(&packed.name.as_str()))
+        {
+            let lock = LockFile::acquire(git_path(PACKED_REFS))?;
+            let remaining = read_packed()?
+                .into_iter()
+                .filter(|packed| !deleted.contains(&packed.name.as_str()))
+                .map(|packed| (packed.name.clone(), packed))
+                .collect();
+            write_packed(lock, &remaining)?;
+        }
+
+        for (lock, update) in locks.into_iter().zip(updates) {
+            match &update.change {
+                Change::Set(_) | Change::Symbolic(_) => lock.commit()?,
+                Change::Delete => {
+                    let path = path(&update.name);
+                    if path.is_file() {
+                        std::fs::remove_file(&path)
+                            .with_context(|| format!("delete reference {}", update.name))?;
+                    }
+                    drop(lock);
+                    prune_directories(&update.name);
+                    self.delete_log(&update.name)?;
+                }
+                Change::Verify => drop(lock),
+            }
+        }
+        for (name, entry) in logs {
+            self.append_log(&name, &entry)?;
+        }
+        Ok(())
+    }
+
+    fn pack(&self, all: bool, prune: bool) -> anyhow::Result<()> {
+        let lock = LockFile::acquire(git_path(PACKED_REFS))?;
+        let mut packed: BTreeMap<String, PackedRef> = read_packed()?
+            .into_iter()
+            .map(|packed| (packed.name.clone(), packed))
+            .collect();
+        let mut loose = Vec::new();
+        for name in loose_names()? {
+            if !all && !name.starts_with("refs/tags/")
+                || PER_WORKTREE_PREFIXES.iter().any(|p| name.starts_with(p))
+            {
+                continue;
+            }
+            let Some(hash) = read_loose(&name)? else {
+                continue;
+            };
+            // Symbolic references cannot be packed.
+            if hash.starts_with("ref: ") {
+                continue;
+            }
+            let peeled = super::peel(&hash)?;
+            packed.insert(
+                name.clone(),
+                PackedRef {
+                    name: name.clone(),
+                    hash: hash.clone(),
+                    peeled,
+                },
+            );
+            loose.push((name, hash));
+        }
+        write_packed(lock, &packed)?;
+
+        if prune {
+            for (name, hash) in loose {
+                // Leave references that were locked or updated in the meantime alone.
+                let Ok(lock) = LockFile::acquire(path(&name)) else {
+                    continue;
+                };
+                if read_loose(&name)?.as_deref() == Some(hash.as_str()) {
+                    std::fs::remove_file(path(&name))
+                        .with_context(|| format!("delete reference {name}"))?;
+                }
+                drop(lock);
+                prune_directories(&name);
+            }
+        }
+        Ok(())
+    }
+
+    fn log_exists(&self, name: &str) -> bool {
+        log_path(name).is_file()
+    }
+
+    fn log_names(&self) -> anyhow::Result<Vec<String>> {
+        let logs = git_path("logs");
+        walk(&logs, &logs)
+    }
+
+    fn read_log(&self, name: &str) -> anyhow::Result<Vec<reflog::Entry>> {
+        let path = log_path(name);
+        if !path.is_file() {
+            return Ok(Vec::new());
+        }
+        let contents =
+            std::fs::read_to_string(&path).with_context(|| format!("read reflog of {name}"))?;
+        contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(reflog::Entry::parse)
+            .collect()
+    }
+
+    fn write_log(&self, name: &str, entries: &[reflog::Entry]) -> anyhow::Result<()> {
+        let contents: String = entries.iter().map(|entry| format!("{entry}\n")).collect();
+        std::fs::write(log_path(name), contents).with_context(|| format!("write reflog of {name}"))
+    }
+
+    fn append_log(&self, name: &str, entry: &reflog::Entry) -> anyhow::Result<()> {
+        let path = log_path(name);
+        if let Some(parent) = path.parent() {
+            shared::create_dir_all(parent)
+                .with_context(|| format!("create directory for reflog of {name}"))?;
+        }
+        let created = !path.exists();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("open reflog of {name}"))?;
+        if created {
+            shared::adjust(&path)?;
+        }
+        writeln!(file, "{entry}").with_context(|| format!("append to reflog of {name}"))
+    }
+
+    fn delete_log(&self, name: &str) -> anyhow::Result<()> {
+        let path = log_path(name);
+        if path.is_file() {
+            std::fs::remove_file(&path).with_context(|| format!("remove reflog of {name}"))?;
+        }
+        Ok(())
+    }
+}
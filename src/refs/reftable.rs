@@ -0,0 +1,1480 @@
+//! The reftable reference store: a stack of immutable binary tables under `.git/reftable`,
+//! listed oldest first in `tables.list`. Each table holds sorted, prefix-compressed reference
+//! records and zlib-compressed log records; records in newer tables override those in older
+//! ones. Every update adds a table while `tables.list.lock` is held, after which the newest
+//! tables are merged until each table is at least twice the size of the one above it.
+//!
+//! See: <https://git-scm.com/docs/reftable>
+use anyhow::Context;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc, Decompress, FlushDecompress, Status};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Change, RefStore, RefUpdate};
+use crate::hash::{self, Algorithm};
+use crate::lockfile::LockFile;
+use crate::reflog;
+use crate::repository::git_path;
+use crate::shared;
+
+/// The directory holding the tables.
+const DIR: &str = "reftable";
+
+/// The file listing the tables of the stack, oldest first.
+const TABLES_LIST: &str = "reftable/tables.list";
+
+/// The magic bytes every table starts with.
+const MAGIC: &[u8; 4] = b"REFT";
+
+/// The size that reference blocks are padded to.
+const BLOCK_SIZE: usize = 4096;
+
+/// How many records follow each restart point, whose key is stored in full.
+const RESTART_INTERVAL: usize = 16;
+
+/// The size of the file footer beyond the header it repeats: five positions and a checksum.
+const FOOTER_EXTRA_SIZE: usize = 44;
+
+/// How many reference blocks a table needs for an index of them to be written.
+const MIN_INDEXED_BLOCKS: usize = 4;
+
+/// How many times larger than the table above it each table in the stack is kept.
+const COMPACTION_FACTOR: u64 = 2;
+
+/// How many times reading the stack is retried when a concurrent compaction removes a table.
+const READ_ATTEMPTS: usize = 5;
+
+/// Block types.
+const REF_BLOCK: u8 = b'r';
+const LOG_BLOCK: u8 = b'g';
+const INDEX_BLOCK: u8 = b'i';
+
+/// The version of the format: 1 for SHA-1 object hashes, and 2 for SHA-256 ones, whose header
+/// names the hash algorithm.
+fn version() -> u8 {
+    match hash::algorithm() {
+        Algorithm::Sha1 => 1,
+        Algorithm::Sha256 => 2,
+    }
+}
+
+/// The size of the file header.
+fn header_size() -> usize {
+    match version() {
+        1 => 24,
+        _ => 28,
+    }
+}
+
+/// The size of the file footer, which repeats the header.
+fn footer_size() -> usize {
+    header_size() + FOOTER_EXTRA_SIZE
+}
+
+/// Value types of reference records.
+const REF_DELETION: u8 = 0;
+const REF_HASH: u8 = 1;
+const REF_PEELED: u8 = 2;
+const REF_SYMBOLIC: u8 = 3;
+
+/// Value types of log records.
+const LOG_DELETION: u8 = 0;
+const LOG_UPDATE: u8 = 1;
+
+/// Set up the reftable store of a new repository, with `HEAD` pointing to `head`.
+pub(crate) fn init(head: &str) -> anyhow::Result<()> {
+    std::fs::create_dir_all(git_path(DIR))
+        .with_context(|| format!("create {}", git_path(DIR).display()))?;
+    std::fs::write(git_path(TABLES_LIST), "").context("write .git/reftable/tables.list")?;
+    // Like Git, leave files behind that make tools unaware of reftables reject the repository
+    // rather than see it as empty.
+    std::fs::create_dir_all(git_path("refs")).context("create .git/refs")?;
+    std::fs::write(
+        git_path("refs/heads"),
+        "this repository uses the reftable format\n",
+    )
+    .context("write .git/refs/heads")?;
+    std::fs::write(git_path("HEAD"), "ref: refs/heads/.invalid\n").context("write .git/HEAD")?;
+    ReftableStore.commit(&[RefUpdate {
+        name: "HEAD".to_string(),
+        change: Change::Symbolic(head.to_string()),
+        old: None,
+        message: None,
+    }])
+}
+
+/// Append `value` as a variable-length integer: 7 bits per byte, most significant first, with
+/// the high bit set on all bytes but the last, and one subtracted from all but the last group.
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    let mut buf = [0; 10];
+    let mut i = buf.len() - 1;
+    buf[i] = (value & 0x7f) as u8;
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        i -= 1;
+        buf[i] = 0x80 | (value & 0x7f) as u8;
+        value >>= 7;
+    }
+    out.extend_from_slice(&buf[i..]);
+}
+
+/// Read a variable-length integer written by [`put_varint`] at `pos`, advancing it.
+fn get_varint(data: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+    let mut next = || {
+        let byte = *data.get(*pos).context("reftable record is truncated")?;
+        *pos += 1;
+        anyhow::Ok(byte)
+    };
+    let mut byte = next()?;
+    let mut value = u64::from(byte & 0x7f);
+    while byte & 0x80 != 0 {
+        byte = next()?;
+        value = ((value + 1) << 7) | u64::from(byte & 0x7f);
+    }
+    Ok(value)
+}
+
+/// Read `len` bytes at `pos`, advancing it.
+fn get_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> anyhow::Result<&'a [u8]> {
+    let bytes = data
+        .get(*pos..*pos + len)
+        .context("reftable record is truncated")?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/// Read a length-prefixed string at `pos`, advancing it.
+fn get_string(data: &[u8], pos: &mut usize) -> anyhow::Result<String> {
+    let len = usize::try_from(get_varint(data, pos)?)?;
+    Ok(String::from_utf8_lossy(get_bytes(data, pos, len)?).into_owned())
+}
+
+/// Append a length-prefixed string.
+fn put_string(out: &mut Vec<u8>, value: &str) {
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Read an object hash at `pos` as hex, advancing it.
+fn get_hash(data: &[u8], pos: &mut usize) -> anyhow::Result<String> {
+    Ok(hex::encode(get_bytes(data, pos, hash::algorithm().size())?))
+}
+
+/// Append the hex object hash `hash`.
+fn put_hash(out: &mut Vec<u8>, hash: &str) -> anyhow::Result<()> {
+    let bytes = hex::decode(hash).with_context(|| format!("invalid object hash {hash}"))?;
+    anyhow::ensure!(
+        bytes.len() == hash::algorithm().size(),
+        "invalid object hash {hash}"
+    );
+    out.extend_from_slice(&bytes);
+    Ok(())
+}
+
+/// Read a big-endian 24-bit integer.
+fn get_u24(data: &[u8]) -> usize {
+    (usize::from(data[0]) << 16) | (usize::from(data[1]) << 8) | usize::from(data[2])
+}
+
+/// Append a big-endian 24-bit integer.
+fn put_u24(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+}
+
+/// Read a big-endian 64-bit integer at `offset`.
+fn get_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+/// The value of a reference record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RefValue {
+    /// The reference was deleted.
+    Deletion,
+    /// The reference points to an object.
+    Hash(String),
+    /// The reference points to an annotated tag, and the object that tag ultimately points to.
+    Peeled(String, String),
+    /// The reference is a symbolic reference to another one.
+    Symbolic(String),
+}
+
+/// A reference record: the value of a reference as of an update.
+#[derive(Debug, Clone)]
+struct RefRecord {
+    name: String,
+    update_index: u64,
+    value: RefValue,
+}
+
+impl RefRecord {
+    /// The raw value of the reference as [`RefStore::read`] returns it, `None` if deleted.
+    fn raw(&self) -> Option<String> {
+        match &self.value {
+            RefValue::Deletion => None,
+            RefValue::Hash(hash) | RefValue::Peeled(hash, _) => Some(hash.clone()),
+            RefValue::Symbolic(target) => Some(format!("ref: {target}")),
+        }
+    }
+
+    /// Encode the value type and value of the record in a table whose update indices start at
+    /// `min_update_index`.
+    fn encode(&self, min_update_index: u64) -> anyhow::Result<(u8, Vec<u8>)> {
+        let mut value = Vec::new();
+        put_varint(&mut value, self.update_index - min_update_index);
+        let kind = match &self.value {
+            RefValue::Deletion => REF_DELETION,
+            RefValue::Hash(hash) => {
+                put_hash(&mut value, hash)?;
+                REF_HASH
+            }
+            RefValue::Peeled(hash, peeled) => {
+                put_hash(&mut value, hash)?;
+                put_hash(&mut value, peeled)?;
+                REF_PEELED
+            }
+            RefValue::Symbolic(target) => {
+                put_string(&mut value, target);
+                REF_SYMBOLIC
+            }
+        };
+        Ok((kind, value))
+    }
+
+    /// Decode a record with the key `name` and value type `kind` whose value starts at `pos`.
+    fn decode(
+        name: &[u8],
+        kind: u8,
+        data: &[u8],
+        pos: &mut usize,
+        min_update_index: u64,
+    ) -> anyhow::Result<RefRecord> {
+        let update_index = min_update_index + get_varint(data, pos)?;
+        let value = match kind {
+            REF_DELETION => RefValue::Deletion,
+            REF_HASH => RefValue::Hash(get_hash(data, pos)?),
+            REF_PEELED => RefValue::Peeled(get_hash(data, pos)?, get_hash(data, pos)?),
+            REF_SYMBOLIC => RefValue::Symbolic(get_string(data, pos)?),
+            _ => anyhow::bail!("unknown reftable value type {kind}"),
+        };
+        Ok(RefRecord {
+            name: String::from_utf8_lossy(name).into_owned(),
+            update_index,
+            value,
+        })
+    }
+}
+
+/// A log record: a reference log entry, or the deletion of one.
+#[derive(Debug, Clone)]
+struct LogRecord {
+    name: String,
+    update_index: u64,
+    entry: Option<reflog::Entry>,
+}
+
+impl LogRecord {
+    /// The key of the record: the reference name, then the update index reversed so that the
+    /// most recent entries come first.
+    fn key(&self) -> Vec<u8> {
+        let mut key = self.name.as_bytes().to_vec();
+        key.push(0);
+        key.extend_from_slice(&(u64::MAX - self.update_index).to_be_bytes());
+        key
+    }
+
+    /// Encode the value type and value of the record.
+    fn encode(&self) -> anyhow::Result<(u8, Vec<u8>)> {
+        let Some(entry) = &self.entry else {
+            return Ok((LOG_DELETION, Vec::new()));
+        };
+        let (name, email, time, offset) = split_identity(&entry.identity)?;
+        let mut value = Vec::new();
+        put_hash(&mut value, &entry.old)?;
+        put_hash(&mut value, &entry.new)?;
+        put_string(&mut value, name);
+        put_string(&mut value, email);
+        put_varint(&mut value, time);
+        value.extend_from_slice(&offset.to_be_bytes());
+        put_string(&mut value, &entry.message);
+        Ok((LOG_UPDATE, value))
+    }
+
+    /// Decode a record with the key `key` and value type `kind` whose value starts at `pos`.
+    fn decode(key: &[u8], kind: u8, data: &[u8], pos: &mut usize) -> anyhow::Result<LogRecord> {
+        let split = key
+            .len()
+            .checked_sub(9)
+            .filter(|&split| key[split] == 0)
+            .context("invalid reftable log key")?;
+        let reversed = u64::from_be_bytes(key[split + 1..].try_into().expect("8 bytes"));
+        let entry = match kind {
+            LOG_DELETION => None,
+            LOG_UPDATE => {
+                let old = get_hash(data, pos)?;
+                let new = get_hash(data, pos)?;
+                let name = get_string(data, pos)?;
+                let email = get_string(data, pos)?;
+                let time = get_varint(data, pos)?;
+                let offset = i16::from_be_bytes(get_bytes(data, pos, 2)?.try_into()?);
+                let message = get_string(data, pos)?;
+                let sign = if offset < 0 { '-' } else { '+' };
+                let minutes = offset.unsigned_abs();
+                Some(reflog::Entry {
+                    old,
+                    new,
+                    identity: format!(
+                        "{name} <{email}> {time} {sign}{:02}{:02}",
+                        minutes / 60,
+                        minutes % 60
+                    ),
+                    message,
+                })
+            }
+            _ => anyhow::bail!("unknown reftable log value type {kind}"),
+        };
+        Ok(LogRecord {
+            name: String::from_utf8_lossy(&key[..split]).into_owned(),
+            update_index: u64::MAX - reversed,
+            entry,
+        })
+    }
+}
+
+/// Split an identity such as `Name <email> 1700000000 +0100` into its name, email, timestamp
+/// and time zone offset in minutes.
+fn split_identity(identity: &str) -> anyhow::Result<(&str, &str, u64, i16)> {
+    let invalid = || format!("invalid identity in reflog entry: {identity}");
+    let (who, when) = identity.rsplit_once('>').with_context(invalid)?;
+    let (name, email) = who.split_once('<').with_context(invalid)?;
+    let mut when = when.split_whitespace();
+    let time = when
+        .next()
+        .and_then(|t| t.parse().ok())
+        .with_context(invalid)?;
+    let zone = when.next().unwrap_or("+0000");
+    let (sign, digits) = zone.split_at(1);
+    let hours: i16 = digits
+        .get(..2)
+        .and_then(|h| h.parse().ok())
+        .with_context(invalid)?;
+    let minutes: i16 = digits
+        .get(2..)
+        .and_then(|m| m.parse().ok())
+        .with_context(invalid)?;
+    let offset = hours * 60 + minutes;
+    Ok((
+        name.trim_end(),
+        email,
+        time,
+        if sign == "-" { -offset } else { offset },
+    ))
+}
+
+/// Writes the records of a block, storing each key as the length of the prefix it shares with
+/// the previous key and the remaining suffix, except at restart points.
+struct BlockWriter {
+    /// The block type.
+    kind: u8,
+    /// The size of the file header preceding the block header in the first block of a table.
+    header_offset: usize,
+    /// The size the block must not exceed, unless it holds a single record.
+    limit: usize,
+    /// The encoded records.
+    records: Vec<u8>,
+    /// The offsets of the restart points from the start of the block.
+    restarts: Vec<usize>,
+    /// The key of the last record.
+    last_key: Vec<u8>,
+    /// The number of records.
+    count: usize,
+}
+
+impl BlockWriter {
+    fn new(kind: u8, header_offset: usize, limit: usize) -> BlockWriter {
+        BlockWriter {
+            kind,
+            header_offset,
+            limit,
+            records: Vec::new(),
+            restarts: Vec::new(),
+            last_key: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// The size of the block with `extra` more bytes of records and `restarts` restart points.
+    fn size(&self, extra: usize, restarts: usize) -> usize {
+        self.header_offset + 4 + self.records.len() + extra + 3 * restarts + 2
+    }
+
+    /// Add a record, or return false if the block has no room left for it.
+    fn add(&mut self, key: &[u8], kind: u8, value: &[u8]) -> bool {
+        let restart = self.count % RESTART_INTERVAL == 0;
+        let prefix = if restart {
+            0
+        } else {
+            key.iter()
+                .zip(&self.last_key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        let mut record = Vec::new();
+        put_varint(&mut record, prefix as u64);
+        put_varint(
+            &mut record,
+            (((key.len() - prefix) as u64) << 3) | u64::from(kind),
+        );
+        record.extend_from_slice(&key[prefix..]);
+        record.extend_from_slice(value);
+
+        let restarts = self.restarts.len() + usize::from(restart);
+        if self.count > 0 && self.size(record.len(), restarts) > self.limit {
+            return false;
+        }
+        if restart {
+            self.restarts.push(self.size(0, 0) - 2);
+        }
+        self.records.extend_from_slice(&record);
+        self.last_key = key.to_vec();
+        self.count += 1;
+        true
+    }
+
+    /// The block from its header, which follows any file header, to its restart table.
+    fn finish(&self) -> Vec<u8> {
+        let mut block = vec![self.kind];
+        put_u24(&mut block, self.size(0, self.restarts.len()));
+        block.extend_from_slice(&self.records);
+        for &restart in &self.restarts {
+            put_u24(&mut block, restart);
+        }
+        block.extend_from_slice(&(self.restarts.len() as u16).to_be_bytes());
+        block
+    }
+}
+
+/// The type of the block at file offset `offset`, whose data starts `data`.
+fn block_type(data: &[u8], offset: u64) -> u8 {
+    let header_offset = if offset == 0 { header_size() } else { 0 };
+    data.get(header_offset).copied().unwrap_or_default()
+}
+
+/// Decode the records of the block at the start of `block`, whose header follows
+/// `header_offset` bytes of file header, with `decode` given each key, value type and the
+/// position of its value. Returns the records and the length of the block.
+fn decode_block<T>(
+    block: &[u8],
+    header_offset: usize,
+    kind: u8,
+    mut decode: impl FnMut(&[u8], u8, &[u8], &mut usize) -> anyhow::Result<T>,
+) -> anyhow::Result<(Vec<T>, usize)> {
+    let header = block
+        .get(header_offset..header_offset + 4)
+        .context("reftable block is truncated")?;
+    anyhow::ensure!(
+        header[0] == kind,
+        "expected reftable block of type '{}', found '{}'",
+        kind as char,
+        header[0] as char
+    );
+    let len = get_u24(&header[1..]);
+    let block = block.get(..len).context("reftable block is truncated")?;
+    let restarts = usize::from(u16::from_be_bytes([block[len - 2], block[len - 1]]));
+    let end = len - 2 - 3 * restarts;
+
+    let mut records = Vec::new();
+    let mut key = Vec::new();
+    let mut pos = header_offset + 4;
+    while pos < end {
+        let prefix = usize::try_from(get_varint(block, &mut pos)?)?;
+        let suffix_and_type = get_varint(block, &mut pos)?;
+        let suffix = get_bytes(block, &mut pos, usize::try_from(suffix_and_type >> 3)?)?;
+        anyhow::ensure!(prefix <= key.len(), "invalid reftable key prefix");
+        key.truncate(prefix);
+        key.extend_from_slice(suffix);
+        records.push(decode(&key, (suffix_and_type & 7) as u8, block, &mut pos)?);
+    }
+    Ok((records, len))
+}
+
+/// Encode a table with update indices from `min` to `max` holding `refs` and `logs`.
+fn write_table(
+    min: u64,
+    max: u64,
+    refs: &[RefRecord],
+    logs: &[LogRecord],
+) -> anyhow::Result<Vec<u8>> {
+    let mut header = MAGIC.to_vec();
+    header.push(version());
+    put_u24(&mut header, BLOCK_SIZE);
+    header.extend_from_slice(&min.to_be_bytes());
+    header.extend_from_slice(&max.to_be_bytes());
+    if version() == 2 {
+        header.extend_from_slice(b"s256");
+    }
+    let mut out = header.clone();
+
+    // Reference blocks, padded to the block size; the first one includes the file header.
+    let mut refs: Vec<&RefRecord> = refs.iter().collect();
+    refs.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut index = Vec::new();
+    let mut block = BlockWriter::new(REF_BLOCK, header_size(), BLOCK_SIZE);
+    let mut start = 0;
+    for record in refs {
+        let (kind, value) = record.encode(min)?;
+        if !block.add(record.name.as_bytes(), kind, &value) {
+            out.extend_from_slice(&block.finish());
+            pad_block(&mut out, start);
+            index.push((block.last_key.clone(), start));
+            start = out.len();
+            block = BlockWriter::new(REF_BLOCK, 0, BLOCK_SIZE);
+            anyhow::ensure!(block.add(record.name.as_bytes(), kind, &value));
+        }
+    }
+    if block.count > 0 {
+        out.extend_from_slice(&block.finish());
+        index.push((block.last_key.clone(), start));
+    }
+
+    // An index of the reference blocks by their last key, for tables with many blocks. Index
+    // blocks are no larger than reference blocks, so an index that does not fit in one is
+    // itself indexed, level by level, until a single root block is left.
+    let mut ref_index_position = 0;
+    if index.len() >= MIN_INDEXED_BLOCKS {
+        loop {
+            pad_block(&mut out, start);
+            start = out.len();
+            let mut level = Vec::new();
+            let mut block = BlockWriter::new(INDEX_BLOCK, 0, BLOCK_SIZE);
+            for (key, position) in &index {
+                let mut value = Vec::new();
+                put_varint(&mut value, *position as u64);
+                if !block.add(key, 0, &value) {
+                    out.extend_from_slice(&block.finish());
+                    pad_block(&mut out, start);
+                    level.push((block.last_key.clone(), start));
+                    start = out.len();
+                    block = BlockWriter::new(INDEX_BLOCK, 0, BLOCK_SIZE);
+                    anyhow::ensure!(block.add(key, 0, &value));
+                }
+            }
+            out.extend_from_slice(&block.finish());
+            level.push((block.last_key.clone(), start));
+            if level.len() == 1 {
+                ref_index_position = start as u64;
+                break;
+            }
+            index = level;
+        }
+    }
+
+    // Log blocks, whose records follow the block header compressed.
+    let mut log_position = 0;
+    let mut logs: Vec<(Vec<u8>, &LogRecord)> = logs.iter().map(|log| (log.key(), log)).collect();
+    logs.sort_by(|a, b| a.0.cmp(&b.0));
+    if !logs.is_empty() {
+        log_position = out.len() as u64;
+        let mut block = BlockWriter::new(LOG_BLOCK, 0, BLOCK_SIZE);
+        for (key, record) in &logs {
+            let (kind, value) = record.encode()?;
+            if !block.add(key, kind, &value) {
+                write_log_block(&mut out, &block)?;
+                block = BlockWriter::new(LOG_BLOCK, 0, BLOCK_SIZE);
+                anyhow::ensure!(block.add(key, kind, &value));
+            }
+        }
+        write_log_block(&mut out, &block)?;
+    }
+
+    let mut footer = header;
+    footer.extend_from_slice(&ref_index_position.to_be_bytes());
+    // No object blocks, which map object hashes to the references pointing to them.
+    footer.extend_from_slice(&0u64.to_be_bytes());
+    footer.extend_from_slice(&0u64.to_be_bytes());
+    footer.extend_from_slice(&log_position.to_be_bytes());
+    // No log index.
+    footer.extend_from_slice(&0u64.to_be_bytes());
+    let mut crc = Crc::new();
+    crc.update(&footer);
+    footer.extend_from_slice(&crc.sum().to_be_bytes());
+    out.extend_from_slice(&footer);
+    Ok(out)
+}
+
+/// Pad the block starting at `start` at the end of `out` to the block size.
+fn pad_block(out: &mut Vec<u8>, start: usize) {
+    if out.len() < start + BLOCK_SIZE {
+        out.resize(start + BLOCK_SIZE, 0);
+    }
+}
+
+/// Append the log block `block` to `out`, compressing it after its header.
+fn write_log_block(out: &mut Vec<u8>, block: &BlockWriter) -> anyhow::Result<()> {
+    let block = block.finish();
+    out.extend_from_slice(&block[..4]);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&block[4..])
+        .context("compress log block")?;
+    out.extend_from_slice(&encoder.finish().context("compress log block")?);
+    Ok(())
+}
+
+/// A table of the stack.
+struct Table {
+    /// The file name of the table in `.git/reftable`.
+    name: String,
+    file: File,
+    /// The size of the file.
+    size: u64,
+    /// The range of update indices of the records in the table.
+    min_update_index: u64,
+    max_update_index: u64,
+    /// The size reference blocks are padded to.
+    block_size: u64,
+    /// Where the index of the reference blocks starts, 0 if there is none.
+    ref_index_position: u64,
+    /// Where the log blocks start, 0 if there are none.
+    log_position: u64,
+}
+
+impl Table {
+    /// Open the table `name` and read its header and footer.
+    fn open(name: &str) -> anyhow::Result<Table> {
+        let path = git_path(DIR).join(name);
+        let file = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+        Table::from_file(name, file)
+    }
+
+    /// Read the header and footer of the table `name` from `file`.
+    fn from_file(name: &str, file: File) -> anyhow::Result<Table> {
+        let size = file.metadata()?.len();
+        let mut table = Table {
+            name: name.to_string(),
+            file,
+            size,
+            min_update_index: 0,
+            max_update_index: 0,
+            block_size: 0,
+            ref_index_position: 0,
+            log_position: 0,
+        };
+        let (header_size, footer_size, version) = (header_size(), footer_size(), version());
+        anyhow::ensure!(
+            size >= (header_size + footer_size) as u64,
+            "reftable {name} is truncated"
+        );
+        let footer = table.read_range(size - footer_size as u64, footer_size)?;
+        anyhow::ensure!(
+            &footer[..4] == MAGIC && footer[4] == version,
+            "{name} is not a version {version} reftable"
+        );
+        let mut crc = Crc::new();
+        crc.update(&footer[..footer_size - 4]);
+        anyhow::ensure!(
+            crc.sum().to_be_bytes() == footer[footer_size - 4..],
+            "reftable {name} has a corrupt footer"
+        );
+        anyhow::ensure!(
+            table.read_range(0, header_size)? == footer[..header_size],
+            "reftable {name} has a corrupt header"
+        );
+        anyhow::ensure!(
+            version == 1 || &footer[24..28] == b"s256",
+            "reftable {name} does not use SHA-256"
+        );
+        table.block_size = get_u24(&footer[5..]) as u64;
+        table.min_update_index = get_u64(&footer, 8);
+        table.max_update_index = get_u64(&footer, 16);
+        table.ref_index_position = get_u64(&footer, header_size);
+        table.log_position = get_u64(&footer, header_size + 24);
+        Ok(table)
+    }
+
+    /// Read `len` bytes at `offset`.
+    fn read_range(&self, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut file = &self.file;
+        let mut data = vec![0; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)
+            .with_context(|| format!("read reftable {}", self.name))?;
+        Ok(data)
+    }
+
+    /// Where the reference blocks end.
+    fn ref_end(&self) -> u64 {
+        [self.ref_index_position, self.log_position]
+            .into_iter()
+            .find(|&position| position != 0)
+            .unwrap_or(self.size - footer_size() as u64)
+    }
+
+    /// Where the log blocks end.
+    fn log_end(&self) -> u64 {
+        self.size - footer_size() as u64
+    }
+
+    /// Decode the reference block at `offset` in `data`, which starts at file offset `base`,
+    /// returning its records and the offset of the next block.
+    fn decode_ref_block(
+        &self,
+        data: &[u8],
+        base: u64,
+        offset: u64,
+    ) -> anyhow::Result<(Vec<RefRecord>, u64)> {
+        let header_offset = if offset == 0 { header_size() } else { 0 };
+        let start = usize::try_from(offset - base)?;
+        let (records, len) = decode_block(
+            &data[start..],
+            header_offset,
+            REF_BLOCK,
+            |key, kind, block, pos| RefRecord::decode(key, kind, block, pos, self.min_update_index),
+        )?;
+        Ok((records, offset + (len as u64).max(self.block_size)))
+    }
+
+    /// All reference records, sorted by name.
+    fn refs(&self) -> anyhow::Result<Vec<RefRecord>> {
+        let end = self.ref_end();
+        if end <= header_size() as u64 {
+            return Ok(Vec::new());
+        }
+        let data = self.read_range(0, usize::try_from(end)?)?;
+        let mut refs = Vec::new();
+        let mut offset = 0;
+        // The reference blocks are followed by any index blocks below the root one.
+        while offset < end && block_type(&data[usize::try_from(offset)?..], offset) == REF_BLOCK {
+            let (records, next) = self.decode_ref_block(&data, 0, offset)?;
+            refs.extend(records);
+            offset = next;
+        }
+        Ok(refs)
+    }
+
+    /// The reference record for `name`, if the table has one.
+    fn find_ref(&self, name: &str) -> anyhow::Result<Option<RefRecord>> {
+        let end = self.ref_end();
+        if end <= header_size() as u64 {
+            return Ok(None);
+        }
+        // With an index, only the first block whose last key is not before `name` can hold it,
+        // found by descending from the root index block through any lower levels.
+        let mut offset = 0;
+        if self.ref_index_position != 0 {
+            offset = self.ref_index_position;
+            loop {
+                let data = self.read_block(offset)?;
+                if block_type(&data, offset) != INDEX_BLOCK {
+                    break;
+                }
+                let (index, _) = decode_block(&data, 0, INDEX_BLOCK, |key, _, block, pos| {
+                    Ok((key.to_vec(), get_varint(block, pos)?))
+                })?;
+                match index
+                    .iter()
+                    .find(|(key, _)| key.as_slice() >= name.as_bytes())
+                {
+                    // Blocks are always written before the index blocks pointing to them.
+                    Some((_, position)) if *position < offset => offset = *position,
+                    Some(_) => anyhow::bail!("reftable {} has a corrupt index", self.name),
+                    None => return Ok(None),
+                }
+            }
+        }
+        while offset < end {
+            let data = self.read_block(offset)?;
+            if block_type(&data, offset) != REF_BLOCK {
+                break;
+            }
+            let (records, next) = self.decode_ref_block(&data, offset, offset)?;
+            if let Some(record) = records.iter().find(|record| record.name == name) {
+                return Ok(Some(record.clone()));
+            }
+            if records
+                .last()
+                .is_some_and(|record| record.name.as_str() > name)
+            {
+                break;
+            }
+            offset = next;
+        }
+        Ok(None)
+    }
+
+    /// Read the reference or index block at `offset`.
+    fn read_block(&self, offset: u64) -> anyhow::Result<Vec<u8>> {
+        let header_offset = if offset == 0 { header_size() } else { 0 };
+        let end = self.log_end();
+        anyhow::ensure!(
+            offset + header_offset as u64 + 4 <= end,
+            "reftable {} has a block beyond its end",
+            self.name
+        );
+        let len = self
+            .block_size
+            .min(end - offset)
+            .max(header_offset as u64 + 4);
+        let mut data = self.read_range(offset, usize::try_from(len)?)?;
+        let block_len = get_u24(&data[header_offset + 1..]) as u64;
+        if block_len > len {
+            data = self.read_range(offset, usize::try_from(block_len)?)?;
+        }
+        Ok(data)
+    }
+
+    /// All log records, sorted by key.
+    fn logs(&self) -> anyhow::Result<Vec<LogRecord>> {
+        if self.log_position == 0 {
+            return Ok(Vec::new());
+        }
+        let data = self.read_range(
+            self.log_position,
+            usize::try_from(self.log_end() - self.log_position)?,
+        )?;
+        let mut logs = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let header = data
+                .get(pos..pos + 4)
+                .context("reftable log block is truncated")?;
+            let len = get_u24(&header[1..]);
+            let mut block = header.to_vec();
+            block.reserve(len.saturating_sub(4));
+            let mut inflate = Decompress::new(true);
+            let status = inflate
+                .decompress_vec(&data[pos + 4..], &mut block, FlushDecompress::Finish)
+                .with_context(|| format!("decompress log block of reftable {}", self.name))?;
+            anyhow::ensure!(
+                status == Status::StreamEnd && block.len() == len,
+                "log block of reftable {} is corrupt",
+                self.name
+            );
+            let (records, _) = decode_block(&block, 0, LOG_BLOCK, |key, kind, block, pos| {
+                LogRecord::decode(key, kind, block, pos)
+            })?;
+            logs.extend(records);
+            pos += 4 + usize::try_from(inflate.total_in())?;
+        }
+        Ok(logs)
+    }
+}
+
+/// The stack of tables, oldest first.
+struct Stack {
+    tables: Vec<Table>,
+}
+
+impl Stack {
+    /// Open the tables listed in `tables.list`.
+    fn read() -> anyhow::Result<Stack> {
+        let mut attempt = 0;
+        loop {
+            let list = std::fs::read_to_string(git_path(TABLES_LIST))
+                .context("read .git/reftable/tables.list")?;
+            match list.lines().map(Table::open).collect() {
+                Ok(tables) => return Ok(Stack { tables }),
+                // A concurrent compaction may have replaced tables since the list was read.
+                Err(_) if attempt + 1 < READ_ATTEMPTS => attempt += 1,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// The update index the next table starts at.
+    fn next_update_index(&self) -> u64 {
+        self.tables
+            .last()
+            .map_or(1, |table| table.max_update_index + 1)
+    }
+
+    /// The most recent record of the reference `name`.
+    fn find_ref(&self, name: &str) -> anyhow::Result<Option<RefRecord>> {
+        for table in self.tables.iter().rev() {
+            if let Some(record) = table.find_ref(name)? {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The most recent records of all references in the tables from `start` on, by name.
+    fn refs(&self, start: usize) -> anyhow::Result<BTreeMap<String, RefRecord>> {
+        let mut refs = BTreeMap::new();
+        for table in &self.tables[start..] {
+            for record in table.refs()? {
+                refs.insert(record.name.clone(), record);
+            }
+        }
+        Ok(refs)
+    }
+
+    /// The most recent log records in the tables from `start` on, by reference name and update
+    /// index.
+    fn logs(&self, start: usize) -> anyhow::Result<BTreeMap<(String, u64), LogRecord>> {
+        let mut logs = BTreeMap::new();
+        for table in &self.tables[start..] {
+            for record in table.logs()? {
+                logs.insert((record.name.clone(), record.update_index), record);
+            }
+        }
+        Ok(logs)
+    }
+
+    /// The current log entries of the reference `name`, oldest first, with their update indices.
+    fn log(&self, name: &str) -> anyhow::Result<Vec<(u64, reflog::Entry)>> {
+        let mut entries = BTreeMap::new();
+        for table in &self.tables {
+            for record in table.logs()? {
+                if record.name == name {
+                    entries.insert(record.update_index, record.entry);
+                }
+            }
+        }
+        Ok(entries
+            .into_iter()
+            .filter_map(|(index, entry)| Some((index, entry?)))
+            .collect())
+    }
+}
+
+/// A unique file name for a table with update indices from `min` to `max`.
+fn table_name(min: u64, max: u64) -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let suffix = time.subsec_nanos() ^ std::process::id().rotate_left(16) ^ time.as_secs() as u32;
+    format!("0x{min:012x}-0x{max:012x}-{suffix:08x}.ref")
+}
+
+/// Write a new table file, returning its name.
+fn create_table(
+    min: u64,
+    max: u64,
+    refs: &[RefRecord],
+    logs: &[LogRecord],
+) -> anyhow::Result<String> {
+    let data = write_table(min, max, refs, logs)?;
+    loop {
+        let name = table_name(min, max);
+        let path = git_path(DIR).join(&name);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                file.write_all(&data)
+                    .and_then(|()| file.sync_all())
+                    .with_context(|| format!("write {}", path.display()))?;
+                shared::adjust(&path)?;
+                return Ok(name);
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error).with_context(|| format!("create {}", path.display())),
+        }
+    }
+}
+
+/// Merge the tables of `stack` from `start` on into a new table, returning its name. Deletions
+/// are dropped when merging from the bottom of the stack, as there is nothing left to delete.
+fn merge(stack: &Stack, start: usize) -> anyhow::Result<String> {
+    let bottom = start == 0;
+    let refs: Vec<RefRecord> = stack
+        .refs(start)?
+        .into_values()
+        .filter(|record| !bottom || record.value != RefValue::Deletion)
+        .collect();
+    let logs: Vec<LogRecord> = stack
+        .logs(start)?
+        .into_values()
+        .filter(|record| !bottom || record.entry.is_some())
+        .collect();
+    let min = stack.tables[start].min_update_index;
+    let max = stack
+        .tables
+        .last()
+        .map_or(min, |table| table.max_update_index);
+    create_table(min, max, &refs, &logs)
+}
+
+/// Add a table with update indices from `min` to `max` holding `refs` and `logs` to the stack
+/// locked by `lock`, then merge the newest tables while the one below the newest is less than
+/// [`COMPACTION_FACTOR`] times its size, or all tables if `compact_all`.
+fn add_table(
+    mut lock: LockFile,
+    min: u64,
+    max: u64,
+    refs: &[RefRecord],
+    logs: &[LogRecord],
+    compact_all: bool,
+) -> anyhow::Result<()> {
+    let mut stack = Stack::read()?;
+    let mut obsolete = Vec::new();
+    if !refs.is_empty() || !logs.is_empty() {
+        let name = create_table(min, max, refs, logs)?;
+        stack.tables.push(Table::open(&name)?);
+    }
+    if compact_all {
+        if !stack.tables.is_empty() {
+            let name = merge(&stack, 0)?;
+            obsolete.extend(stack.tables.drain(..).map(|table| table.name));
+            stack.tables.push(Table::open(&name)?);
+        }
+    } else {
+        while let [.., below, newest] = stack.tables.as_slice() {
+            if below.size >= COMPACTION_FACTOR * newest.size {
+                break;
+            }
+            let start = stack.tables.len() - 2;
+            let name = merge(&stack, start)?;
+            obsolete.extend(stack.tables.drain(start..).map(|table| table.name));
+            stack.tables.push(Table::open(&name)?);
+        }
+    }
+
+    let list: String = stack
+        .tables
+        .iter()
+        .map(|table| format!("{}\n", table.name))
+        .collect();
+    lock.write_all(list.as_bytes())?;
+    lock.commit()?;
+    for name in obsolete {
+        let _ = std::fs::remove_file(git_path(DIR).join(name));
+    }
+    Ok(())
+}
+
+/// Lock the stack for an update.
+fn lock() -> anyhow::Result<LockFile> {
+    LockFile::acquire(git_path(TABLES_LIST)).context("cannot lock the reftable stack")
+}
+
+/// References and their logs stored in reftables.
+pub(crate) struct ReftableStore;
+
+impl ReftableStore {
+    /// Replace the log of `name` with `entries` in a new table: delete its current entries and
+    /// add `entries` with new update indices.
+    fn replace_log(&self, name: &str, entries: &[reflog::Entry]) -> anyhow::Result<()> {
+        let lock = lock()?;
+        let stack = Stack::read()?;
+        let min = stack.next_update_index();
+        let mut logs: Vec<LogRecord> = stack
+            .log(name)?
+            .into_iter()
+            .map(|(update_index, _)| LogRecord {
+                name: name.to_string(),
+                update_index,
+                entry: None,
+            })
+            .collect();
+        logs.extend(
+            entries
+                .iter()
+                .zip(min..)
+                .map(|(entry, update_index)| LogRecord {
+                    name: name.to_string(),
+                    update_index,
+                    entry: Some(entry.clone()),
+                }),
+        );
+        let max = min + (entries.len() as u64).max(1) - 1;
+        drop(stack);
+        add_table(lock, min, max, &[], &logs, false)
+    }
+}
+
+impl RefStore for ReftableStore {
+    fn read(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(Stack::read()?
+            .find_ref(name)?
+            .and_then(|record| record.raw()))
+    }
+
+    fn refs(&self) -> anyhow::Result<Vec<(String, String)>> {
+        Ok(Stack::read()?
+            .refs(0)?
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("refs/"))
+            .filter_map(|(name, record)| Some((name, record.raw()?)))
+            .collect())
+    }
+
+    fn commit(&self, updates: &[RefUpdate]) -> anyhow::Result<()> {
+        // One lock covers every reference.
+        let lock = lock()?;
+        let current = super::check(self, updates)?;
+        let entries = super::log_entries(self, updates, &current)?;
+
+        let stack = Stack::read()?;
+        let update_index = stack.next_update_index();
+        let mut refs = Vec::new();
+        let mut logs = BTreeMap::new();
+        for update in updates {
+            let value = match &update.change {
+                Change::Set(hash) => match super::peel(hash)? {
+                    Some(peeled) => RefValue::Peeled(hash.clone(), peeled),
+                    None => RefValue::Hash(hash.clone()),
+                },
+                Change::Symbolic(target) => RefValue::Symbolic(target.clone()),
+                Change::Delete => {
+                    for (index, _) in stack.log(&update.name)? {
+                        logs.insert(
+                            (update.name.clone(), index),
+                            LogRecord {
+                                name: update.name.clone(),
+                                update_index: index,
+                                entry: None,
+                            },
+                        );
+                    }
+                    RefValue::Deletion
+                }
+                Change::Verify => continue,
+            };
+            refs.push(RefRecord {
+                name: update.name.clone(),
+                update_index,
+                value,
+            });
+        }
+        for (name, entry) in entries {
+            logs.insert(
+                (name.clone(), update_index),
+                LogRecord {
+                    name,
+                    update_index,
+                    entry: Some(entry),
+                },
+            );
+        }
+        if refs.is_empty() && logs.is_empty() {
+            return Ok(());
+        }
+        let logs: Vec<LogRecord> = logs.into_values().collect();
+        drop(stack);
+        add_table(lock, update_index, update_index, &refs, &logs, false)
+    }
+
+    fn pack(&self, _all: bool, _prune: bool) -> anyhow::Result<()> {
+        add_table(lock()?, 0, 0, &[], &[], true)
+    }
+
+    fn log_exists(&self, name: &str) -> bool {
+        self.read_log(name).is_ok_and(|entries| !entries.is_empty())
+    }
+
+    fn log_names(&self) -> anyhow::Result<Vec<String>> {
+        let mut names: Vec<String> = Stack::read()?
+            .logs(0)?
+            .into_values()
+            .filter(|record| record.entry.is_some())
+            .map(|record| record.name)
+            .collect();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn read_log(&self, name: &str) -> anyhow::Result<Vec<reflog::Entry>> {
+        Ok(Stack::read()?
+            .log(name)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    fn write_log(&self, name: &str, entries: &[reflog::Entry]) -> anyhow::Result<()> {
+        self.replace_log(name, entries)
+    }
+
+    fn append_log(&self, name: &str, entry: &reflog::Entry) -> anyhow::Result<()> {
+        let lock = lock()?;
+        let update_index = Stack::read()?.next_update_index();
+        let log = LogRecord {
+            name: name.to_string(),
+            update_index,
+            entry: Some(entry.clone()),
+        };
+        add_table(lock, update_index, update_index, &[], &[log], false)
+    }
+
+    fn delete_log(&self, name: &str) -> anyhow::Result<()> {
+        self.replace_log(name, &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A table laid out byte by byte from the format specification: a ref block holding only
+    /// `HEAD` as a symbolic reference to `refs/heads/main`, at update index 1, and no index or
+    /// log blocks.
+    const SPEC_TABLE: &[u8] = &[
+        // Header: magic, version 1, block size 4096, update indices 1 to 1.
+        b'R', b'E', b'F', b'T', 0x01, 0x00, 0x10, 0x00, //
+        0, 0, 0, 0, 0, 0, 0, 1, //
+        0, 0, 0, 0, 0, 0, 0, 1, //
+        // Ref block of 56 bytes including the file header.
+        b'r', 0x00, 0x00, 0x38, //
+        // No prefix, a 4-byte suffix of value type 3, update index delta 0, a 15-byte target.
+        0x00, 0x23, b'H', b'E', b'A', b'D', 0x00, 0x0f, //
+        b'r', b'e', b'f', b's', b'/', b'h', b'e', b'a', b'd', b's', b'/', b'm', b'a', b'i', b'n',
+        // One restart point, at offset 28.
+        0x00, 0x00, 0x1c, 0x00, 0x01, //
+        // Footer: the header again, then the ref index, object, object index, log and log index
+        // positions, all zero, and the CRC-32 of all that.
+        b'R', b'E', b'F', b'T', 0x01, 0x00, 0x10, 0x00, //
+        0, 0, 0, 0, 0, 0, 0, 1, //
+        0, 0, 0, 0, 0, 0, 0, 1, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0xb6, 0xbf, 0xf7, 0x8a,
+    ];
+
+    /// Open `data` as the table `name`, through a temporary file.
+    fn open(name: &str, data: &[u8]) -> anyhow::Result<Table> {
+        let path = std::env::temp_dir().join(format!("mini-git-{}-{name}", std::process::id()));
+        std::fs::write(&path, data)?;
+        let file = File::open(&path)?;
+        std::fs::remove_file(&path)?;
+        Table::from_file(name, file)
+    }
+
+    /// A made-up object id.
+    fn hash(i: usize) -> String {
+        format!("{i:040x}")
+    }
+
+    fn ref_record(name: &str, update_index: u64, value: RefValue) -> RefRecord {
+        RefRecord {
+            name: name.to_string(),
+            update_index,
+            value,
+        }
+    }
+
+    /// The parts of reference records to compare.
+    fn ref_parts(refs: &[RefRecord]) -> Vec<(&str, u64, &RefValue)> {
+        refs.iter()
+            .map(|record| (record.name.as_str(), record.update_index, &record.value))
+            .collect()
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        // The encoding shared with the offsets of deltas in pack files.
+        for (value, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x00]),
+            (16511, &[0xff, 0x7f]),
+            (16512, &[0x80, 0x80, 0x00]),
+        ] {
+            let mut out = Vec::new();
+            put_varint(&mut out, value);
+            assert_eq!(out, encoded, "encoding of {value}");
+        }
+        for value in [0, 1, 300, 1 << 32, u64::MAX - 1, u64::MAX] {
+            let mut out = Vec::new();
+            put_varint(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(get_varint(&out, &mut pos).unwrap(), value);
+            assert_eq!(pos, out.len());
+        }
+        let mut pos = 0;
+        assert!(get_varint(&[0x80], &mut pos).is_err());
+    }
+
+    #[test]
+    fn record_block_round_trip() {
+        let keys: Vec<String> = (0..40)
+            .map(|i| format!("refs/heads/topic/{i:03}"))
+            .collect();
+        let mut block = BlockWriter::new(REF_BLOCK, 0, BLOCK_SIZE);
+        for (i, key) in keys.iter().enumerate() {
+            assert!(block.add(key.as_bytes(), REF_HASH, hash(i).as_bytes()));
+        }
+        let data = block.finish();
+        // A restart point every 16 records, and only the restart keys stored in full.
+        assert_eq!(
+            u16::from_be_bytes([data[data.len() - 2], data[data.len() - 1]]),
+            3
+        );
+        let full: usize = keys.iter().map(String::len).sum();
+        assert!(data.len() < full + 40 * 40);
+
+        let (records, len) = decode_block(&data, 0, REF_BLOCK, |key, kind, block, pos| {
+            let value = get_bytes(block, pos, 40)?;
+            Ok((key.to_vec(), kind, value.to_vec()))
+        })
+        .unwrap();
+        assert_eq!(len, data.len());
+        let expected: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key.as_bytes().to_vec(), REF_HASH, hash(i).into_bytes()))
+            .collect();
+        assert_eq!(records, expected);
+        assert!(decode_block(&data, 0, LOG_BLOCK, |_, _, _, _| Ok(())).is_err());
+    }
+
+    #[test]
+    fn block_size_limit() {
+        let mut block = BlockWriter::new(REF_BLOCK, 0, 64);
+        assert!(block.add(b"refs/heads/a", REF_HASH, &[0; 40]));
+        assert!(!block.add(b"refs/heads/b", REF_HASH, &[0; 40]));
+        // A record larger than the limit still fits in a block of its own.
+        let mut block = BlockWriter::new(REF_BLOCK, 0, 64);
+        assert!(block.add(b"refs/heads/a", REF_HASH, &[0; 100]));
+    }
+
+    #[test]
+    fn ref_table_round_trip() {
+        let refs = vec![
+            ref_record("HEAD", 3, RefValue::Symbolic("refs/heads/main".to_string())),
+            ref_record("refs/heads/gone", 4, RefValue::Deletion),
+            ref_record("refs/heads/main", 4, RefValue::Hash(hash(1))),
+            ref_record("refs/tags/v1", 5, RefValue::Peeled(hash(2), hash(1))),
+        ];
+        let data = write_table(3, 5, &refs, &[]).unwrap();
+        let table = open("refs", &data).unwrap();
+        assert_eq!((table.min_update_index, table.max_update_index), (3, 5));
+        assert_eq!(table.block_size, BLOCK_SIZE as u64);
+        assert_eq!(ref_parts(&table.refs().unwrap()), ref_parts(&refs));
+        for record in &refs {
+            let found = table.find_ref(&record.name).unwrap().unwrap();
+            assert_eq!(ref_parts(&[found]), ref_parts(std::slice::from_ref(record)));
+        }
+        for missing in ["A", "refs/heads/m", "refs/tags/v2"] {
+            assert!(table.find_ref(missing).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn header_and_footer_checks() {
+        let refs = [ref_record("refs/heads/main", 1, RefValue::Hash(hash(1)))];
+        let data = write_table(1, 1, &refs, &[]).unwrap();
+        assert!(open("good", &data).is_ok());
+
+        // A changed footer no longer matches its CRC.
+        let mut bad = data.clone();
+        let footer = bad.len() - footer_size();
+        bad[footer + 10] ^= 1;
+        let error = open("footer", &bad).err().unwrap().to_string();
+        assert!(error.contains("corrupt footer"), "{error}");
+
+        // A changed header no longer matches the footer.
+        let mut bad = data.clone();
+        bad[10] ^= 1;
+        let error = open("header", &bad).err().unwrap().to_string();
+        assert!(error.contains("corrupt header"), "{error}");
+
+        assert!(open("truncated", &data[..footer_size()]).is_err());
+    }
+
+    #[test]
+    fn log_table_round_trip() {
+        let mut logs: Vec<LogRecord> = (1..=300)
+            .map(|i| LogRecord {
+                name: format!("refs/heads/b{}", i % 7),
+                update_index: i,
+                entry: Some(reflog::Entry {
+                    old: hash(i as usize),
+                    new: hash(i as usize + 1),
+                    identity: format!("A U Thor <author@example.com> {} -0130", 1_700_000_000 + i),
+                    message: format!("commit: change number {i} with a long enough message"),
+                }),
+            })
+            .collect();
+        logs.push(LogRecord {
+            name: "refs/heads/b0".to_string(),
+            update_index: 301,
+            entry: None,
+        });
+        let data = write_table(1, 301, &[], &logs).unwrap();
+        let table = open("logs", &data).unwrap();
+        assert!(table.log_position != 0);
+
+        let read = table.logs().unwrap();
+        logs.sort_by_key(LogRecord::key);
+        let parts = |logs: &[LogRecord]| -> Vec<_> {
+            logs.iter()
+                .map(|log| (log.name.clone(), log.update_index, log.entry.clone()))
+                .collect()
+        };
+        assert_eq!(parts(&read), parts(&logs));
+        // The records are split over several compressed blocks.
+        let first_len = get_u24(&data[usize::try_from(table.log_position).unwrap() + 1..]);
+        assert!(first_len <= BLOCK_SIZE);
+        assert!(data.len() - (table.log_position as usize) > BLOCK_SIZE / 4);
+    }
+
+    #[test]
+    fn multi_level_index() {
+        // Long names keep both the reference blocks and the index blocks to a few records each.
+        let padding = "x".repeat(200);
+        let refs: Vec<RefRecord> = (0..8000)
+            .map(|i| {
+                let name = format!("refs/heads/{i:05}/{padding}");
+                ref_record(&name, 1, RefValue::Hash(hash(i)))
+            })
+            .collect();
+        let data = write_table(1, 1, &refs, &[]).unwrap();
+        let table = open("index", &data).unwrap();
+        assert!(table.ref_index_position != 0);
+
+        // Every index block fits in the block size, and the root is at least two levels above
+        // the reference blocks.
+        let mut levels = 0;
+        let mut blocks = vec![table.ref_index_position];
+        while let Some(&offset) = blocks.first() {
+            if block_type(&table.read_block(offset).unwrap(), offset) != INDEX_BLOCK {
+                break;
+            }
+            levels += 1;
+            let mut children = Vec::new();
+            for offset in blocks {
+                let block = table.read_block(offset).unwrap();
+                let (index, len) = decode_block(&block, 0, INDEX_BLOCK, |_, _, block, pos| {
+                    get_varint(block, pos)
+                })
+                .unwrap();
+                assert!(len <= BLOCK_SIZE);
+                children.extend(index);
+            }
+            blocks = children;
+        }
+        assert!(levels >= 3, "{levels} index levels");
+
+        assert_eq!(table.refs().unwrap().len(), refs.len());
+        for record in refs.iter().step_by(97).chain(refs.last()) {
+            let found = table.find_ref(&record.name).unwrap().unwrap();
+            assert_eq!(found.value, record.value);
+        }
+        for missing in ["refs/heads/0", "refs/heads/00042/y", "refs/heads/99999"] {
+            assert!(table.find_ref(missing).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn spec_table() {
+        let head = ref_record("HEAD", 1, RefValue::Symbolic("refs/heads/main".to_string()));
+        let table = open("spec", SPEC_TABLE).unwrap();
+        assert_eq!(
+            ref_parts(&table.refs().unwrap()),
+            ref_parts(std::slice::from_ref(&head))
+        );
+        assert!(table.logs().unwrap().is_empty());
+        assert_eq!(write_table(1, 1, &[head], &[]).unwrap(), SPEC_TABLE);
+    }
+}
@@ -0,0 +1,233 @@
+//! The repository a command runs in: its Git directory and working tree.
+//!
+//! Like Git, the Git directory is taken from `GIT_DIR` (or `--git-dir`) if set, and otherwise
+//! found by walking up from the current directory to the first one containing a `.git`
+//! directory or `.git` file, or that is a bare repository itself. The walk stops at
+//! `GIT_CEILING_DIRECTORIES` and, unless `GIT_DISCOVERY_ACROSS_FILESYSTEM` is set, at filesystem
+//! boundaries. Once found, the process changes to the top of the working tree, so paths in the
+//! working tree are relative to it, and paths in the Git directory come from [`git_path`].
+//!
+//! See: <https://git-scm.com/docs/git#_the_git_repository>
+use anyhow::Context;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::config::{self, Config};
+use crate::hash::Algorithm;
+
+/// The repository of the process, set by [`Repository::enter`].
+static REPOSITORY: OnceLock<Repository> = OnceLock::new();
+
+/// A Git directory and the working tree it belongs to.
+#[derive(Debug)]
+pub(crate) struct Repository {
+    /// The Git directory, e.g. `/home/me/project/.git`.
+    pub(crate) git_dir: PathBuf,
+    /// The top of the working tree, `None` for a bare repository.
+    pub(crate) work_tree: Option<PathBuf>,
+    /// The directory the command was run from, relative to the top of the working tree.
+    pub(crate) prefix: PathBuf,
+    /// The hash algorithm naming objects, set by [`Repository::enter`].
+    pub(crate) algorithm: Algorithm,
+}
+
+impl Repository {
+    /// The repository with the Git directory `git_dir` and the working tree `work_tree`, for a
+    /// command run from `cwd`.
+    pub(crate) fn new(git_dir: PathBuf, work_tree: Option<PathBuf>, cwd: &Path) -> Repository {
+        let prefix = work_tree
+            .as_deref()
+            .and_then(|work_tree| cwd.strip_prefix(work_tree).ok())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        Repository {
+            git_dir,
+            work_tree,
+            prefix,
+            algorithm: Algorithm::Sha1,
+        }
+    }
+
+    /// Find the repository of the current directory, or `None` if it is not in one.
+    pub(crate) fn discover() -> anyhow::Result<Option<Repository>> {
+        let cwd = std::env::current_dir().context("get current directory")?;
+        let work_tree = std::env::var_os("GIT_WORK_TREE").map(|path| cwd.join(path));
+
+        if let Some(git_dir) = std::env::var_os("GIT_DIR") {
+            let git_dir = cwd.join(git_dir);
+            anyhow::ensure!(
+                is_git_dir(&git_dir),
+                "not a git repository: '{}'",
+                git_dir.display()
+            );
+            // Without a working tree given, it is the current directory unless the repository is
+            // bare.
+            let work_tree = match work_tree {
+                Some(work_tree) => Some(work_tree),
+                None if is_bare(&git_dir)? => None,
+                None => Some(cwd.clone()),
+            };
+            return Ok(Some(Repository::new(git_dir, work_tree, &cwd)));
+        }
+
+        let ceilings = ceiling_directories();
+        let across_filesystems = match std::env::var("GIT_DISCOVERY_ACROSS_FILESYSTEM") {
+            Ok(value) => config::parse_bool("GIT_DISCOVERY_ACROSS_FILESYSTEM", Some(&value))?,
+            Err(_) => false,
+        };
+        let device = std::fs::metadata(&cwd)
+            .with_context(|| format!("read metadata of {}", cwd.display()))?
+            .dev();
+        let mut dir = cwd.as_path();
+        loop {
+            if let Some(git_dir) = read_dot_git(&dir.join(".git"))? {
+                let work_tree = work_tree.unwrap_or_else(|| dir.to_path_buf());
+                return Ok(Some(Repository::new(git_dir, Some(work_tree), &cwd)));
+            }
+            if is_git_dir(dir) {
+                return Ok(Some(Repository::new(dir.to_path_buf(), work_tree, &cwd)));
+            }
+            let Some(parent) = dir.parent() else {
+                return Ok(None);
+            };
+            if ceilings.iter().any(|ceiling| ceiling == parent) {
+                return Ok(None);
+            }
+            if !across_filesystems
+                && std::fs::metadata(parent).map(|m| m.dev()).ok() != Some(device)
+            {
+                anyhow::bail!(
+                    "not a git repository (or any parent up to mount point {})\n\
+                     Stopping at filesystem boundary (GIT_DISCOVERY_ACROSS_FILESYSTEM not set).",
+                    dir.display()
+                );
+            }
+            dir = parent;
+        }
+    }
+
+    /// Make this the repository of the process and change to the top of its working tree.
+    pub(crate) fn enter(mut self) -> anyhow::Result<()> {
+        self.algorithm = Algorithm::of_repository(&self.git_dir)?;
+        if let Some(work_tree) = &self.work_tree {
+            std::env::set_current_dir(work_tree)
+                .with_context(|| format!("cannot chdir to '{}'", work_tree.display()))?;
+        }
+        REPOSITORY
+            .set(self)
+            .map_err(|_| anyhow::anyhow!("the repository is already set up"))
+    }
+}
+
+/// Whether `path` looks like a Git directory: it has a `HEAD`, an object store and references.
+fn is_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
+}
+
+/// Whether the repository in `git_dir` is bare, see `core.bare`.
+fn is_bare(git_dir: &Path) -> anyhow::Result<bool> {
+    Ok(Config::from_file(&git_dir.join("config"))?
+        .get_bool("core.bare")?
+        .unwrap_or(false))
+}
+
+/// The Git directory `dot_git` stands for: itself if it is one, or the one a `.git` file points
+/// to with a `gitdir: <path>` line. Returns `None` if there is no such file or directory.
+fn read_dot_git(dot_git: &Path) -> anyhow::Result<Option<PathBuf>> {
+    if dot_git.is_dir() {
+        return Ok(is_git_dir(dot_git).then(|| dot_git.to_path_buf()));
+    }
+    if !dot_git.is_file() {
+        return Ok(None);
+    }
+    let contents =
+        std::fs::read_to_string(dot_git).with_context(|| format!("read {}", dot_git.display()))?;
+    let target = contents
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("gitdir: "))
+        .with_context(|| format!("invalid gitfile format: {}", dot_git.display()))?;
+    // A relative path is relative to the directory of the `.git` file.
+    let git_dir = dot_git
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(target.trim());
+    anyhow::ensure!(
+        is_git_dir(&git_dir),
+        "not a git repository: {}",
+        git_dir.display()
+    );
+    Ok(Some(git_dir))
+}
+
+/// The directories from `GIT_CEILING_DIRECTORIES` that discovery must not walk up into.
+fn ceiling_directories() -> Vec<PathBuf> {
+    let Some(value) = std::env::var_os("GIT_CEILING_DIRECTORIES") else {
+        return Vec::new();
+    };
+    std::env::split_paths(&value)
+        .filter(|path| path.is_absolute())
+        .map(|path| std::fs::canonicalize(&path).unwrap_or(path))
+        .collect()
+}
+
+/// The repository of the process, if the command runs in one.
+pub(crate) fn get() -> Option<&'static Repository> {
+    REPOSITORY.get()
+}
+
+/// The Git directory of the repository.
+pub(crate) fn git_dir() -> &'static Path {
+    &get()
+        .expect("the repository is set up before commands access it")
+        .git_dir
+}
+
+/// The path of `name` in the Git directory, e.g. `/home/me/project/.git/index` for `index`.
+pub(crate) fn git_path(name: impl AsRef<Path>) -> PathBuf {
+    git_dir().join(name)
+}
+
+/// The top of the working tree, which the process has changed to.
+pub(crate) fn work_tree() -> anyhow::Result<&'static Path> {
+    get()
+        .and_then(|repository| repository.work_tree.as_deref())
+        .context("this operation must be run in a work tree")
+}
+
+/// The path `path` given on the command line, relative to the directory the command was run
+/// from, as a path relative to the top of the working tree.
+pub(crate) fn prefixed(path: &Path) -> PathBuf {
+    match get() {
+        Some(repository) => repository.prefix.join(path),
+        None => path.to_path_buf(),
+    }
+}
+
+/// The pathspec `spec` given on the command line as a path relative to the top of the working
+/// tree, e.g. `src/main.rs` for `main.rs` run from `src`.
+pub(crate) fn pathspec(spec: &str) -> anyhow::Result<String> {
+    let mut path = prefixed(Path::new(spec));
+    if let Some(work_tree) = get().and_then(|repository| repository.work_tree.as_deref()) {
+        if let Ok(inside) = path.strip_prefix(work_tree) {
+            path = inside.to_path_buf();
+        }
+    }
+    let mut parts: Vec<String> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            Component::ParentDir => {
+                parts
+                    .pop()
+                    .with_context(|| format!("{spec}: '{spec}' is outside repository"))?;
+            }
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => {
+                anyhow::bail!("{spec}: '{spec}' is outside repository")
+            }
+        }
+    }
+    Ok(parts.join("/"))
+}
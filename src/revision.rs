@@ -0,0 +1,219 @@
+//! Revision parsing.
+//!
+//! See: <https://git-scm.com/docs/gitrevisions>
+use anyhow::Context;
+
+use crate::objects::Commit;
+use crate::repository::git_path;
+use crate::{hash, pack, reflog, refs};
+
+/// Resolve a revision such as `HEAD~2`, `main^2` or an (abbreviated) object hash into a full
+/// object hash.
+pub(crate) fn resolve(spec: &str) -> anyhow::Result<String> {
+    let split = spec.find(['^', '~']).unwrap_or(spec.len());
+    let (base, mut suffix) = spec.split_at(split);
+    let mut hash = resolve_base(base)?;
+
+    while !suffix.is_empty() {
+        let op = suffix.as_bytes()[0];
+        let digits = suffix[1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(suffix.len(), |i| i + 1);
+        let n = if digits > 1 {
+            suffix[1..digits]
+                .parse::<usize>()
+                .with_context(|| format!("invalid revision suffix in {spec}"))?
+        } else {
+            1
+        };
+        suffix = &suffix[digits..];
+        match op {
+            b'^' if n == 0 => {}
+            b'^' => {
+                let commit = Commit::read(&hash)?;
+                hash = commit
+                    .parents
+                    .get(n - 1)
+                    .with_context(|| format!("{spec}: commit {hash} has no parent {n}"))?
+                    .clone();
+            }
+            b'~' => {
+                for _ in 0..n {
+                    let commit = Commit::read(&hash)?;
+                    hash = commit
+                        .parents
+                        .first()
+                        .with_context(|| format!("{spec}: commit {hash} has no parent"))?
+                        .clone();
+                }
+            }
+            _ => anyhow::bail!("invalid revision suffix in {spec}"),
+        }
+    }
+
+    Ok(hash)
+}
+
+/// Commits selected by revision arguments such as `A..B`, `^A` and `B`.
+#[derive(Debug, Default)]
+pub(crate) struct Selection {
+    /// Commits whose history is selected, in the order given.
+    pub(crate) include: Vec<String>,
+    /// Commits whose history is excluded.
+    pub(crate) exclude: Vec<String>,
+    /// Whether any range or exclusion was given, i.e. whether the history needs to be walked
+    /// rather than taking the included commits as they are.
+    pub(crate) walk: bool,
+}
+
+/// Resolve revision arguments that may select ranges of commits.
+pub(crate) fn resolve_selection(specs: &[String]) -> anyhow::Result<Selection> {
+    let mut selection = Selection::default();
+    for spec in specs {
+        if let Some((from, to)) = spec.split_once("..") {
+            selection.exclude.push(resolve(from)?);
+            selection.include.push(resolve(to)?);
+            selection.walk = true;
+        } else if let Some(excluded) = spec.strip_prefix('^') {
+            selection.exclude.push(resolve(excluded)?);
+            selection.walk = true;
+        } else {
+            selection.include.push(resolve(spec)?);
+        }
+    }
+    Ok(selection)
+}
+
+/// The rules Git uses to look up a short name such as `main`, as the prefix and suffix around it
+/// that make up the full reference name, in order; see `git help revisions`.
+const REF_RULES: [(&str, &str); 6] = [
+    ("", ""),
+    ("refs/", ""),
+    ("refs/tags/", ""),
+    ("refs/heads/", ""),
+    ("refs/remotes/", ""),
+    ("refs/remotes/", "/HEAD"),
+];
+
+/// The references a short name such as `main` may refer to, in the order Git looks them up.
+fn ref_candidates(name: &str) -> [String; 6] {
+    REF_RULES.map(|(prefix, suffix)| format!("{prefix}{name}{suffix}"))
+}
+
+/// The shortest name that unambiguously refers to the reference `name`, e.g. `main` for
+/// `refs/heads/main`, or `heads/main` if there is also a tag `main`.
+///
+/// A short name is ambiguous if a rule looked up before its own finds a reference or, if
+/// `strict`, any other rule does.
+pub(crate) fn shorten_ref(name: &str, strict: bool) -> anyhow::Result<String> {
+    // Like Git, never shorten to the whole name or through the `<remote>/HEAD` rule.
+    'rules: for (i, (prefix, suffix)) in REF_RULES.iter().enumerate().skip(1).rev() {
+        let Some(short) = name
+            .strip_prefix(prefix)
+            .filter(|short| suffix.is_empty() && !short.is_empty())
+        else {
+            continue;
+        };
+        let others = if strict { REF_RULES.len() } else { i };
+        for (j, (prefix, suffix)) in REF_RULES[..others].iter().enumerate() {
+            if j != i && refs::resolve(&format!("{prefix}{short}{suffix}"))?.is_some() {
+                continue 'rules;
+            }
+        }
+        return Ok(short.to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// The full name of the reference a short name such as `main` refers to, e.g. `refs/heads/main`;
+/// references that no longer exist but still have a log are found too.
+pub(crate) fn expand_ref(name: &str) -> anyhow::Result<Option<String>> {
+    for candidate in ref_candidates(name) {
+        if refs::resolve(&candidate)?.is_some() || reflog::exists(&candidate) {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+/// Resolve `<ref>@{<n>}`, the value `ref` had `n` updates ago according to its log; the current
+/// branch if `ref` is empty.
+fn resolve_reflog(name: &str, n: &str) -> anyhow::Result<String> {
+    let n: usize = n
+        .parse()
+        .with_context(|| format!("unsupported reflog selector '@{{{n}}}'"))?;
+    let full = if name.is_empty() {
+        refs::head_target()?.unwrap_or_else(|| "HEAD".to_string())
+    } else {
+        expand_ref(name)?.with_context(|| format!("unknown revision: '{name}'"))?
+    };
+    let entries = reflog::read(&full)?;
+    if n == 0 && entries.is_empty() {
+        return refs::resolve(&full)?.with_context(|| format!("unknown revision: '{name}'"));
+    }
+    let display = if name.is_empty() { full.as_str() } else { name };
+    let entry = entries
+        .len()
+        .checked_sub(n + 1)
+        .map(|i| &entries[i])
+        .with_context(|| format!("log for '{display}' only has {} entries", entries.len()))?;
+    Ok(entry.new.clone())
+}
+
+/// Resolve the part of a revision before any `^`/`~` suffixes.
+fn resolve_base(base: &str) -> anyhow::Result<String> {
+    if let Some((name, selector)) = base.split_once("@{") {
+        if let Some(n) = selector.strip_suffix('}') {
+            return resolve_reflog(name, n);
+        }
+    }
+    let base = if base.is_empty() || base == "@" {
+        "HEAD"
+    } else {
+        base
+    };
+
+    for candidate in ref_candidates(base) {
+        if let Some(hash) = refs::resolve(&candidate)? {
+            return Ok(hash);
+        }
+    }
+
+    if base.len() >= 4
+        && base.len() <= hash::algorithm().hex_len()
+        && base.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return expand_abbreviated(&base.to_ascii_lowercase());
+    }
+
+    anyhow::bail!("unknown revision: '{base}'")
+}
+
+/// Expand an abbreviated object hash to the unique full hash it refers to.
+fn expand_abbreviated(prefix: &str) -> anyhow::Result<String> {
+    let dir = git_path("objects").join(&prefix[..2]);
+    let mut matches = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("bad directory entry in {}", dir.display()))?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.starts_with(&prefix[2..]) {
+                matches.push(format!("{}{name}", &prefix[..2]));
+            }
+        }
+    }
+    for pack in pack::packs()?.iter() {
+        matches.extend(pack.hashes().filter(|hash| hash.starts_with(prefix)));
+    }
+    matches.sort();
+    matches.dedup();
+    match matches.len() {
+        0 => anyhow::bail!("unknown revision: '{prefix}'"),
+        1 => Ok(matches.pop().expect("exactly one match")),
+        _ => anyhow::bail!("short object ID {prefix} is ambiguous"),
+    }
+}
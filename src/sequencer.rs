@@ -0,0 +1,529 @@
+//! Applying a series of existing commits on top of `HEAD`, as done by `cherry-pick` and
+//! `revert`.
+//!
+//! The commits still to be applied are kept in `.git/sequencer/todo` so that a series stopped
+//! by a conflict can be continued, skipped or aborted later.
+//!
+//! See: <https://git-scm.com/docs/git-cherry-pick#_sequencer_subcommands>
+use anyhow::Context;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::commands::commit::{cleanup_message, unresolved_paths};
+use crate::commands::merge::{short, MERGE_MSG};
+use crate::commands::merge_base::History;
+use crate::commands::{commit_tree, merge as merge_command, write_tree};
+use crate::config::Config;
+use crate::index::Index;
+use crate::merge::{self, Labels, MergeOptions, TreeMerge};
+use crate::objects::{Commit, FlatTree, Tree};
+use crate::repository::{self, git_path};
+use crate::{refs, revision, shared, worktree};
+
+/// The commit being cherry-picked while a cherry-pick is stopped by a conflict.
+pub(crate) const CHERRY_PICK_HEAD: &str = "CHERRY_PICK_HEAD";
+/// The commit being reverted while a revert is stopped by a conflict.
+pub(crate) const REVERT_HEAD: &str = "REVERT_HEAD";
+
+/// Directory holding the state of an in-progress series of picks or reverts.
+const SEQUENCER_DIR: &str = "sequencer";
+/// The commits still to be applied, one `<action> <hash> <subject>` line per commit.
+const TODO: &str = "sequencer/todo";
+/// The commit `HEAD` pointed to before the series started, restored by `--abort`.
+const ORIG_HEAD: &str = "sequencer/head";
+/// The options of the series, in the configuration file format.
+const OPTS: &str = "sequencer/opts";
+
+/// What to do with a commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Apply the changes introduced by the commit.
+    Pick,
+    /// Apply the inverse of the changes introduced by the commit.
+    Revert,
+}
+
+impl Action {
+    /// The command implementing this action.
+    fn command(self) -> &'static str {
+        match self {
+            Action::Pick => "cherry-pick",
+            Action::Revert => "revert",
+        }
+    }
+
+    /// The name of this action in the todo file.
+    fn name(self) -> &'static str {
+        match self {
+            Action::Pick => "pick",
+            Action::Revert => "revert",
+        }
+    }
+
+    /// The file recording the commit being applied while stopped by a conflict.
+    fn head_file(self) -> PathBuf {
+        git_path(match self {
+            Action::Pick => CHERRY_PICK_HEAD,
+            Action::Revert => REVERT_HEAD,
+        })
+    }
+}
+
+/// Options of a series of picks or reverts.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Options {
+    /// Append a `(cherry picked from commit …)` line to the messages of picked commits.
+    pub(crate) record_origin: bool,
+    /// Apply the changes to the working tree and index without committing them.
+    pub(crate) no_commit: bool,
+    /// The parent (starting at 1) of merge commits to compute their changes against.
+    pub(crate) mainline: Option<usize>,
+    /// `-X` options passed through to the merge.
+    pub(crate) strategy_options: Vec<String>,
+}
+
+impl Options {
+    /// Read the options of the series in progress.
+    fn read() -> anyhow::Result<Options> {
+        let config = Config::from_file(&git_path(OPTS))?;
+        Ok(Options {
+            record_origin: config.get_bool("options.record-origin")?.unwrap_or(false),
+            no_commit: config.get_bool("options.no-commit")?.unwrap_or(false),
+            mainline: config
+                .get_int("options.mainline")?
+                .map(|mainline| usize::try_from(mainline).context("parse mainline"))
+                .transpose()?,
+            strategy_options: config
+                .get_all("options.strategy-option")
+                .into_iter()
+                .filter_map(|entry| entry.value.clone())
+                .collect(),
+        })
+    }
+
+    /// Save the options of the series in progress.
+    fn write(&self) -> anyhow::Result<()> {
+        let mut contents = String::from("[options]\n");
+        if self.record_origin {
+            contents.push_str("\trecord-origin = true\n");
+        }
+        if self.no_commit {
+            contents.push_str("\tno-commit = true\n");
+        }
+        if let Some(mainline) = self.mainline {
+            writeln!(contents, "\tmainline = {mainline}")?;
+        }
+        for option in &self.strategy_options {
+            writeln!(contents, "\tstrategy-option = {option}")?;
+        }
+        std::fs::write(git_path(OPTS), contents).context("write .git/sequencer/opts")
+    }
+}
+
+/// A commit to apply.
+#[derive(Debug, Clone)]
+struct Item {
+    action: Action,
+    hash: String,
+}
+
+/// Read the commits still to be applied.
+fn read_todo() -> anyhow::Result<Vec<Item>> {
+    let contents = std::fs::read_to_string(git_path(TODO)).context("read .git/sequencer/todo")?;
+    let mut todo = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let action = match words.next() {
+            Some("pick" | "p") => Action::Pick,
+            Some("revert") => Action::Revert,
+            _ => anyhow::bail!("invalid line in .git/sequencer/todo: {line}"),
+        };
+        let hash = revision::resolve(words.next().context("missing commit in todo line")?)?;
+        todo.push(Item { action, hash });
+    }
+    Ok(todo)
+}
+
+/// Save the commits still to be applied.
+fn write_todo(todo: &[Item]) -> anyhow::Result<()> {
+    let mut contents = String::new();
+    for item in todo {
+        let commit = Commit::read(&item.hash)?;
+        writeln!(
+            contents,
+            "{} {} {}",
+            item.action.name(),
+            short(&item.hash),
+            commit.summary()
+        )?;
+    }
+    std::fs::write(git_path(TODO), contents).context("write .git/sequencer/todo")
+}
+
+/// Whether a series of picks or reverts is in progress.
+fn in_progress() -> bool {
+    git_path(SEQUENCER_DIR).exists()
+        || git_path(CHERRY_PICK_HEAD).exists()
+        || git_path(REVERT_HEAD).exists()
+}
+
+/// The message of the commit created by applying `commit` (`hash`).
+fn message(action: Action, hash: &str, commit: &Commit, options: &Options) -> String {
+    match action {
+        Action::Pick => {
+            let mut message = commit.message.trim_end().to_string();
+            if options.record_origin {
+                // Trailers are kept together in the last paragraph of the message.
+                let last = message.rsplit("\n\n").next().unwrap_or("");
+                let has_trailers = message.contains("\n\n")
+                    && last.lines().all(|line| {
+                        line.starts_with("(cherry picked from commit ")
+                            || line.split_once(": ").is_some_and(|(key, _)| {
+                                !key.is_empty() && !key.contains(char::is_whitespace)
+                            })
+                    });
+                message.push_str(if has_trailers { "\n" } else { "\n\n" });
+                write!(message, "(cherry picked from commit {hash})").unwrap();
+            }
+            message
+        }
+        Action::Revert => {
+            let mut message = format!(
+                "Revert \"{}\"\n\nThis reverts commit {hash}",
+                commit.summary()
+            );
+            match (options.mainline, commit.parents.len()) {
+                (Some(mainline), n) if n > 1 => write!(
+                    message,
+                    ", reversing\nchanges made to {}.",
+                    commit.parents[mainline - 1]
+                )
+                .unwrap(),
+                _ => message.push('.'),
+            }
+            message
+        }
+    }
+}
+
+/// The parent of `commit` (`hash`) whose changes are applied, or `None` for a root commit.
+pub(crate) fn parent(
+    hash: &str,
+    commit: &Commit,
+    options: &Options,
+) -> anyhow::Result<Option<String>> {
+    match (commit.parents.len(), options.mainline) {
+        (0, None) => Ok(None),
+        (1, None) => Ok(Some(commit.parents[0].clone())),
+        (_, None) => anyhow::bail!("commit {hash} is a merge but no -m option was given."),
+        (0 | 1, Some(_)) => {
+            anyhow::bail!("mainline was specified but commit {hash} is not a merge.")
+        }
+        (_, Some(mainline)) => Ok(Some(
+            commit
+                .parents
+                .get(mainline.wrapping_sub(1))
+                .with_context(|| format!("commit {hash} does not have parent {mainline}"))?
+                .clone(),
+        )),
+    }
+}
+
+/// Merge the changes introduced (or, when reverting, undone) by `commit` (`hash`) into `ours`.
+pub(crate) fn apply(
+    action: Action,
+    hash: &str,
+    commit: &Commit,
+    ours: &FlatTree,
+    options: &Options,
+) -> anyhow::Result<TreeMerge> {
+    let parent = parent(hash, commit, options)?;
+    let parent_tree = match &parent {
+        Some(parent) => Tree::read_flat(&Commit::read(parent)?.tree)?,
+        None => FlatTree::new(),
+    };
+    let commit_tree = Tree::read_flat(&commit.tree)?;
+
+    let label = format!("{} ({})", short(hash), commit.summary());
+    let parent_label = format!("parent of {label}");
+    let (base, theirs, labels) = match action {
+        Action::Pick => (
+            parent_tree,
+            commit_tree,
+            Labels {
+                base: parent_label,
+                ours: "HEAD".to_string(),
+                theirs: label,
+            },
+        ),
+        Action::Revert => (
+            commit_tree,
+            parent_tree,
+            Labels {
+                base: label,
+                ours: "HEAD".to_string(),
+                theirs: parent_label,
+            },
+        ),
+    };
+    let merge_options = MergeOptions::new(&options.strategy_options)?;
+    merge::merge_trees(&base, ours, &theirs, &labels, &merge_options)
+}
+
+/// Print how to go on after the series stopped at a conflict and exit with a failure status.
+fn stop(action: Action, hash: &str, commit: &Commit) -> ! {
+    let verb = match action {
+        Action::Pick => "apply",
+        Action::Revert => "revert",
+    };
+    let command = action.command();
+    eprintln!(
+        "error: could not {verb} {}... {}",
+        short(hash),
+        commit.summary()
+    );
+    eprintln!("hint: After resolving the conflicts, remove the conflict markers and run");
+    eprintln!("hint: \"git {command} --continue\".");
+    eprintln!("hint: You can instead skip this commit with \"git {command} --skip\".");
+    eprintln!("hint: To abort and get back to the state before \"git {command}\",");
+    eprintln!("hint: run \"git {command} --abort\".");
+    std::process::exit(1);
+}
+
+/// Commit `tree` on top of `head` with the message of the commit being applied.
+fn commit(
+    action: Action,
+    hash: &str,
+    commit: &Commit,
+    head: &str,
+    tree: &str,
+    message: &str,
+) -> anyhow::Result<String> {
+    // Picked commits keep their original author; reverts are authored anew.
+    let author = match action {
+        Action::Pick => Some(commit.author.as_str()),
+        Action::Revert => None,
+    };
+    let commit_hash =
+        commit_tree::write_commit_with_author(message.trim_end(), tree, &[head], author)
+            .with_context(|| format!("commit {} of {hash}", action.command()))?;
+    let commit_hash = hex::encode(commit_hash);
+    let subject = message.lines().next().unwrap_or_default();
+    refs::update_head(&commit_hash, &format!("{}: {subject}", action.command()))?;
+
+    let branch = match refs::head_target()? {
+        Some(target) => target
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&target)
+            .to_string(),
+        None => "detached HEAD".to_string(),
+    };
+    let summary = message.lines().next().unwrap_or("");
+    println!("[{branch} {}] {summary}", short(&commit_hash));
+    Ok(commit_hash)
+}
+
+/// Apply the commits of `todo` one after another, stopping at the first conflict.
+fn run(mut todo: Vec<Item>, options: &Options) -> anyhow::Result<()> {
+    let head = refs::resolve("HEAD")?.context("HEAD does not point to a commit")?;
+    let mut ours = Tree::read_flat(&Commit::read(&head)?.tree)?;
+    let mut head = head;
+
+    while let Some(item) = todo.first() {
+        write_todo(&todo)?;
+        let Item { action, hash } = item.clone();
+        let commit = Commit::read(&hash)?;
+        let merge = apply(action, &hash, &commit, &ours, options)?;
+
+        let overwritten = worktree::overwritten_paths(&ours, &merge.result)?;
+        if !overwritten.is_empty() {
+            anyhow::bail!(
+                "Your local changes to the following files would be overwritten by {}:\n\t{}",
+                action.command(),
+                overwritten.join("\n\t")
+            );
+        }
+        for path in &merge.auto_merged {
+            println!("Auto-merging {path}");
+        }
+        worktree::checkout(&ours, &merge.result)?;
+        Index::from_merge(&merge.result, &merge.conflicts).write()?;
+
+        let message = message(action, &hash, &commit, options);
+        if !merge.conflicts.is_empty() {
+            let mut merge_msg = format!("{message}\n\n# Conflicts:\n");
+            for conflict in &merge.conflicts {
+                println!("{}", conflict.message);
+                writeln!(merge_msg, "#\t{}", conflict.path)?;
+            }
+            std::fs::write(git_path(MERGE_MSG), merge_msg).context("write .git/MERGE_MSG")?;
+            if !options.no_commit {
+                write_head_file(action, &hash)?;
+            }
+            stop(action, &hash, &commit);
+        }
+
+        if options.no_commit {
+            std::fs::write(git_path(MERGE_MSG), format!("{message}\n"))
+                .context("write .git/MERGE_MSG")?;
+        } else {
+            if merge.result == ours {
+                write_head_file(action, &hash)?;
+                let command = action.command();
+                eprintln!(
+                    "The previous {command} is now empty, possibly due to conflict resolution."
+                );
+                eprintln!("Otherwise, please use 'git {command} --skip'");
+                std::process::exit(1);
+            }
+            let tree = hex::encode(Tree::write_flat(&merge.result)?);
+            head = self::commit(action, &hash, &commit, &head, &tree, &message)?;
+        }
+        ours = merge.result;
+        todo.remove(0);
+    }
+
+    std::fs::remove_dir_all(git_path(SEQUENCER_DIR)).context("remove .git/sequencer")?;
+    Ok(())
+}
+
+/// Start applying `commits` (which may include ranges like `A..B`) with `action`.
+pub(crate) fn start(action: Action, commits: &[String], options: Options) -> anyhow::Result<()> {
+    if in_progress() {
+        anyhow::bail!(
+            "a cherry-pick or revert is already in progress\n\
+             hint: try \"git {} (--continue | --skip | --abort)\"",
+            action.command()
+        );
+    }
+    let head =
+        refs::resolve("HEAD")?.context("cannot cherry-pick or revert onto an unborn branch")?;
+
+    let selection = revision::resolve_selection(commits)?;
+    let hashes = if selection.walk {
+        let mut hashes = History::default().walk(&selection.include, &selection.exclude)?;
+        // Picked ranges are applied oldest first; reverted ranges newest first.
+        if action == Action::Pick {
+            hashes.reverse();
+        }
+        hashes
+    } else {
+        selection.include
+    };
+    anyhow::ensure!(!hashes.is_empty(), "empty commit set passed");
+    for hash in &hashes {
+        parent(hash, &Commit::read(hash)?, &options)?;
+    }
+    let todo: Vec<Item> = hashes
+        .into_iter()
+        .map(|hash| Item { action, hash })
+        .collect();
+
+    shared::create_dir_all(&git_path(SEQUENCER_DIR))?;
+    std::fs::write(git_path(ORIG_HEAD), format!("{head}\n"))
+        .context("write .git/sequencer/head")?;
+    options.write()?;
+    run(todo, &options)
+}
+
+/// Record the commit the series stopped at.
+fn write_head_file(action: Action, hash: &str) -> anyhow::Result<()> {
+    let path = action.head_file();
+    std::fs::write(&path, format!("{hash}\n")).with_context(|| format!("write {}", path.display()))
+}
+
+/// The commit recorded by a series stopped by a conflict, if any.
+fn stopped_at() -> anyhow::Result<Option<(Action, String)>> {
+    for action in [Action::Pick, Action::Revert] {
+        let path = action.head_file();
+        if path.exists() {
+            let hash = std::fs::read_to_string(&path)
+                .with_context(|| format!("read {}", path.display()))?;
+            return Ok(Some((action, hash.trim().to_string())));
+        }
+    }
+    Ok(None)
+}
+
+/// Commit the resolution of a conflict and continue with the remaining commits.
+pub(crate) fn resume() -> anyhow::Result<()> {
+    anyhow::ensure!(in_progress(), "no cherry-pick or revert in progress");
+    let options = Options::read()?;
+
+    if let Some((action, hash)) = stopped_at()? {
+        let unresolved = unresolved_paths()?;
+        if !unresolved.is_empty() {
+            anyhow::bail!(
+                "Committing is not possible because you have unmerged files:\n\t{}",
+                unresolved.join("\n\t")
+            );
+        }
+        let head = refs::resolve("HEAD")?.context("HEAD does not point to a commit")?;
+        let tree = write_tree::write_tree_for(repository::work_tree()?)
+            .context("write tree")?
+            .map(hex::encode)
+            .context("nothing to commit")?;
+        if tree == Commit::read(&head)?.tree {
+            anyhow::bail!(
+                "The previous {} is now empty, possibly due to conflict resolution.\n\
+                 hint: use 'git {} --skip' to skip it",
+                action.command(),
+                action.command()
+            );
+        }
+        let message = match merge_command::read_state_message()? {
+            Some(message) => cleanup_message(&message),
+            None => self::message(action, &hash, &Commit::read(&hash)?, &options),
+        };
+        self::commit(action, &hash, &Commit::read(&hash)?, &head, &tree, &message)?;
+        Index::from_tree(&Tree::read_flat(&tree)?).write()?;
+        merge_command::clear_state()?;
+    }
+
+    if !git_path(SEQUENCER_DIR).exists() {
+        return Ok(());
+    }
+    let mut todo = read_todo()?;
+    if !todo.is_empty() {
+        todo.remove(0);
+    }
+    run(todo, &options)
+}
+
+/// Skip the commit the series stopped at and continue with the remaining commits.
+pub(crate) fn skip() -> anyhow::Result<()> {
+    anyhow::ensure!(in_progress(), "no cherry-pick or revert in progress");
+    let head = refs::resolve("HEAD")?.context("HEAD does not point to a commit")?;
+    worktree::reset_hard(&Tree::read_flat(&Commit::read(&head)?.tree)?)?;
+    merge_command::clear_state()?;
+
+    if !git_path(SEQUENCER_DIR).exists() {
+        return Ok(());
+    }
+    let mut todo = read_todo()?;
+    if !todo.is_empty() {
+        todo.remove(0);
+    }
+    run(todo, &Options::read()?)
+}
+
+/// Stop the series and restore `HEAD`, the index and the working tree to how they were before
+/// it started.
+pub(crate) fn abort() -> anyhow::Result<()> {
+    anyhow::ensure!(in_progress(), "no cherry-pick or revert in progress");
+    let orig_head = match refs::read_raw("sequencer/head")? {
+        Some(hash) => hash,
+        None => refs::resolve("HEAD")?.context("HEAD does not point to a commit")?,
+    };
+    worktree::reset_hard(&Tree::read_flat(&Commit::read(&orig_head)?.tree)?)?;
+    refs::update_head(&orig_head, &format!("reset: moving to {orig_head}"))?;
+    merge_command::clear_state()?;
+    if git_path(SEQUENCER_DIR).exists() {
+        std::fs::remove_dir_all(git_path(SEQUENCER_DIR)).context("remove .git/sequencer")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! Permissions of shared repositories, which several users may write to.
+//!
+//! With `core.sharedRepository` set, every file and directory created in the Git directory gets
+//! the configured permissions, so that the other users can update it too.
+//!
+//! See: <https://git-scm.com/docs/git-config#Documentation/git-config.txt-coresharedRepository>
+use anyhow::Context;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::config::Config;
+
+/// How the files of a shared repository may be accessed, per `core.sharedRepository`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Shared {
+    /// Permissions follow the umask.
+    Umask,
+    /// The group may write, `0660`.
+    Group,
+    /// The group may write and everybody may read, `0664`.
+    All,
+    /// Exactly these permissions, e.g. `0640`.
+    Mode(u32),
+}
+
+impl Shared {
+    /// Parse a `--shared` or `core.sharedRepository` value.
+    pub(crate) fn parse(value: &str) -> anyhow::Result<Shared> {
+        Ok(match value {
+            "umask" | "false" | "no" | "off" => Shared::Umask,
+            "group" | "true" | "yes" | "on" | "1" => Shared::Group,
+            "all" | "world" | "everybody" | "2" => Shared::All,
+            _ => {
+                let mode = u32::from_str_radix(value, 8)
+                    .ok()
+                    .filter(|mode| mode & !0o777 == 0)
+                    .with_context(|| format!("invalid shared permissions: '{value}'"))?;
+                anyhow::ensure!(
+                    mode & 0o600 == 0o600,
+                    "problem with core.sharedRepository filemode value (0{mode:o}).\n\
+                     The owner of files must always have read and write permissions."
+                );
+                Shared::Mode(mode)
+            }
+        })
+    }
+
+    /// The value recorded in `core.sharedRepository`, or `None` for [`Shared::Umask`].
+    pub(crate) fn config_value(self) -> Option<String> {
+        match self {
+            Shared::Umask => None,
+            Shared::Group => Some("1".to_string()),
+            Shared::All => Some("2".to_string()),
+            Shared::Mode(mode) => Some(format!("0{mode:o}")),
+        }
+    }
+
+    /// The permissions of a file or directory that currently has `mode`.
+    fn adjust(self, mode: u32, is_dir: bool) -> u32 {
+        let mut tweak = match self {
+            Shared::Umask => return mode,
+            Shared::Group => 0o660,
+            Shared::All => 0o664,
+            Shared::Mode(mode) => mode,
+        };
+        if mode & 0o200 == 0 {
+            tweak &= !0o222;
+        }
+        // Whoever may read a directory or executable may also search or run it.
+        if mode & 0o100 != 0 {
+            tweak |= (tweak & 0o444) >> 2;
+        }
+        let mut mode = match self {
+            Shared::Mode(_) => (mode & !0o777) | tweak,
+            _ => mode | tweak,
+        };
+        // New files in directories belong to the group of the directory, not of their creator.
+        if is_dir {
+            mode |= 0o2000;
+        }
+        mode
+    }
+}
+
+/// Apply the permissions of `shared` to `path` and everything below it.
+pub(crate) fn adjust_recursively(path: &Path, shared: Shared) -> anyhow::Result<()> {
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("read metadata of {}", path.display()))?;
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    let mode = meta.permissions().mode();
+    let adjusted = shared.adjust(mode, meta.is_dir());
+    if adjusted != mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(adjusted))
+            .with_context(|| format!("change permissions of {}", path.display()))?;
+    }
+    if meta.is_dir() {
+        for entry in
+            std::fs::read_dir(path).with_context(|| format!("read directory {}", path.display()))?
+        {
+            adjust_recursively(&entry?.path(), shared)?;
+        }
+    }
+    Ok(())
+}
+
+/// How the repository is shared, per `core.sharedRepository`, read once.
+fn configured() -> anyhow::Result<Shared> {
+    static SHARED: OnceLock<Shared> = OnceLock::new();
+    if let Some(shared) = SHARED.get() {
+        return Ok(*shared);
+    }
+    let shared = match Config::load()?.get_string("core.sharedRepository") {
+        Some(value) => Shared::parse(&value.to_ascii_lowercase())?,
+        None => Shared::Umask,
+    };
+    Ok(*SHARED.get_or_init(|| shared))
+}
+
+/// Apply the permissions of `core.sharedRepository` to the file or directory `path`, which was
+/// just created in the Git directory.
+pub(crate) fn adjust(path: &Path) -> anyhow::Result<()> {
+    let shared = configured()?;
+    if shared == Shared::Umask {
+        return Ok(());
+    }
+    let meta =
+        std::fs::metadata(path).with_context(|| format!("read metadata of {}", path.display()))?;
+    let mode = meta.permissions().mode();
+    let adjusted = shared.adjust(mode, meta.is_dir());
+    if adjusted != mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(adjusted))
+            .with_context(|| format!("change permissions of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Create the directory `path` and its missing parents in the Git directory, applying the
+/// permissions of `core.sharedRepository` to those created.
+pub(crate) fn create_dir_all(path: &Path) -> anyhow::Result<()> {
+    let missing: Vec<&Path> = path.ancestors().take_while(|dir| !dir.exists()).collect();
+    std::fs::create_dir_all(path).with_context(|| format!("create {}", path.display()))?;
+    for dir in missing.into_iter().rev() {
+        adjust(dir)?;
+    }
+    Ok(())
+}
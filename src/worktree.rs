@@ -0,0 +1,281 @@
+//! Reading and updating files in the working tree.
+use anyhow::Context;
+use std::io::Cursor;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::index::Index;
+use crate::objects::{
+    FlatTree, Kind, Object, TreeEntry, MODE_EXECUTABLE, MODE_FILE, MODE_GITLINK, MODE_SYMLINK,
+};
+
+/// Hash the working tree file at `path` as it would be stored, without writing it.
+///
+/// Returns `None` if there is no file at `path`.
+pub(crate) fn hash_file(path: &str) -> anyhow::Result<Option<TreeEntry>> {
+    file_entry(path, false)
+}
+
+/// Write the working tree file at `path` to the object store as a blob.
+///
+/// Returns `None` if there is no file at `path`.
+pub(crate) fn store_file(path: &str) -> anyhow::Result<Option<TreeEntry>> {
+    file_entry(path, true)
+}
+
+/// The blob a symbolic link at `path` is stored as: the path it points to, not the contents of
+/// what it points to.
+pub(crate) fn symlink_blob(path: impl AsRef<Path>) -> anyhow::Result<Object<Cursor<Vec<u8>>>> {
+    let path = path.as_ref();
+    let target =
+        std::fs::read_link(path).with_context(|| format!("read symlink {}", path.display()))?;
+    Ok(Object::from_bytes(
+        Kind::Blob,
+        target.into_os_string().into_encoded_bytes(),
+    ))
+}
+
+fn file_entry(path: &str, store: bool) -> anyhow::Result<Option<TreeEntry>> {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return Ok(None);
+    };
+    let (mode, hash) = if meta.is_symlink() {
+        let object = symlink_blob(path)?;
+        let hash = if store {
+            object.write_to_objects()?
+        } else {
+            object.hash()?
+        };
+        (MODE_SYMLINK, hash)
+    } else if meta.is_file() {
+        let mode = if meta.permissions().mode() & 0o111 != 0 {
+            MODE_EXECUTABLE
+        } else {
+            MODE_FILE
+        };
+        let object = Object::blob_from_file(path)?;
+        let hash = if store {
+            object.write_to_objects()?
+        } else {
+            object.hash()?
+        };
+        (mode, hash)
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(TreeEntry {
+        mode,
+        hash: hex::encode(hash),
+    }))
+}
+
+/// Whether `path` is selected by `pathspecs`: equal to one of them or inside one of them as a
+/// directory. An empty list selects every path.
+pub(crate) fn matches_pathspec(path: &str, pathspecs: &[String]) -> bool {
+    pathspecs.is_empty()
+        || pathspecs.iter().any(|spec| {
+            let spec = spec.trim_end_matches('/');
+            spec.is_empty()
+                || spec == "."
+                || path == spec
+                || path
+                    .strip_prefix(spec)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+}
+
+/// Files in the working tree that are not in `tracked`, sorted by path.
+pub(crate) fn untracked_paths(tracked: &FlatTree) -> anyhow::Result<Vec<String>> {
+    untracked_paths_in(Path::new("."), tracked)
+}
+
+/// Files under the directory `root` that are not in `tracked`, sorted by path.
+fn untracked_paths_in(root: &Path, tracked: &FlatTree) -> anyhow::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("read {}", dir.display()))? {
+            let entry = entry.with_context(|| format!("read entry of {}", dir.display()))?;
+            if entry.file_name() == ".git" {
+                continue;
+            }
+            let path = entry.path();
+            let name = path
+                .strip_prefix(".")
+                .unwrap_or(&path)
+                .to_str()
+                .context("path is not valid UTF-8")?
+                .to_string();
+            let file_type = entry.file_type().context("file type")?;
+            if file_type.is_dir() && !tracked.contains_key(&name) {
+                dirs.push(path);
+            } else if !tracked.contains_key(&name) {
+                paths.push(name);
+            }
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Whether the working tree file at `path` matches `entry`, or is absent if `entry` is `None`.
+pub(crate) fn matches(path: &str, entry: Option<&TreeEntry>) -> anyhow::Result<bool> {
+    if entry.is_some_and(|e| e.mode == MODE_GITLINK) {
+        // Submodules are not checked out.
+        return Ok(true);
+    }
+    Ok(hash_file(path)?.as_ref() == entry)
+}
+
+/// Write the blob `entry` to `path` in the working tree, replacing whatever is there.
+pub(crate) fn write_entry(path: &str, entry: &TreeEntry) -> anyhow::Result<()> {
+    if entry.mode == MODE_GITLINK {
+        std::fs::create_dir_all(path).with_context(|| format!("create submodule dir {path}"))?;
+        return Ok(());
+    }
+    if let Some(parent) = Path::new(path).parent() {
+        // A file may be in the way of the parent directory.
+        for ancestor in parent.ancestors() {
+            if ancestor.is_file() || ancestor.is_symlink() {
+                std::fs::remove_file(ancestor)
+                    .with_context(|| format!("remove {}", ancestor.display()))?;
+            }
+        }
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create directory {}", parent.display()))?;
+    }
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.is_dir() {
+            // Only a directory left empty is replaced: `overwritten_paths` reports untracked files
+            // in the way rather than have them deleted.
+            std::fs::remove_dir(path).with_context(|| format!("remove directory {path}"))?;
+        } else {
+            std::fs::remove_file(path).with_context(|| format!("remove {path}"))?;
+        }
+    }
+
+    let mut object = Object::read(&entry.hash).with_context(|| format!("read blob for {path}"))?;
+    if entry.mode == MODE_SYMLINK {
+        let data = object.into_bytes()?;
+        let target = std::ffi::OsStr::new(
+            std::str::from_utf8(&data).context("symlink target is not valid UTF-8")?,
+        );
+        std::os::unix::fs::symlink(target, path)
+            .with_context(|| format!("create symlink {path}"))?;
+    } else {
+        // Blobs are streamed to the file, so that big ones are never held in memory.
+        let mut file = std::fs::File::create(path).with_context(|| format!("write {path}"))?;
+        let size = std::io::copy(&mut object.reader, &mut file)
+            .with_context(|| format!("write {path}"))?;
+        anyhow::ensure!(
+            size == object.expected_size,
+            "blob {} was not the expected size (expected: {}, actual: {size})",
+            entry.hash,
+            object.expected_size
+        );
+        let mode = if entry.mode == MODE_EXECUTABLE {
+            0o755
+        } else {
+            0o644
+        };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("set permissions of {path}"))?;
+    }
+    Ok(())
+}
+
+/// Remove `path` from the working tree, along with any directories left empty.
+pub(crate) fn remove_entry(path: &str) -> anyhow::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => std::fs::remove_file(path).with_context(|| format!("remove {path}"))?,
+        Err(_) => {}
+    }
+    let mut dir = Path::new(path).parent();
+    while let Some(d) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        if std::fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+    Ok(())
+}
+
+/// Paths whose working tree contents would be lost by switching from `from` to `to`, because they
+/// have local modifications or are untracked files in the way, either at the path of a file of
+/// `to`, inside a directory there or in place of one of its parent directories.
+pub(crate) fn overwritten_paths(from: &FlatTree, to: &FlatTree) -> anyhow::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for path in from
+        .keys()
+        .chain(to.keys().filter(|p| !from.contains_key(*p)))
+    {
+        let old = from.get(path);
+        let new = to.get(path);
+        if old == new {
+            continue;
+        }
+        if !matches(path, old)? && !matches(path, new)? {
+            paths.push(path.clone());
+        } else if new.is_some_and(|entry| entry.mode != MODE_GITLINK) {
+            if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.is_dir()) {
+                paths.extend(untracked_paths_in(Path::new(path), from)?);
+            }
+            for ancestor in Path::new(path).ancestors().skip(1) {
+                let Some(name) = ancestor.to_str().filter(|name| !name.is_empty()) else {
+                    continue;
+                };
+                let in_the_way =
+                    std::fs::symlink_metadata(ancestor).is_ok_and(|meta| !meta.is_dir());
+                if in_the_way && !from.contains_key(name) {
+                    paths.push(name.to_string());
+                }
+            }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// Paths of `tree` whose working tree files have been modified or deleted.
+pub(crate) fn modified_paths(tree: &FlatTree) -> anyhow::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for (path, entry) in tree {
+        if !matches(path, Some(entry))? {
+            paths.push(path.clone());
+        }
+    }
+    Ok(paths)
+}
+
+/// Update the working tree from the tree `from` to the tree `to`.
+pub(crate) fn checkout(from: &FlatTree, to: &FlatTree) -> anyhow::Result<()> {
+    for path in from.keys() {
+        if !to.contains_key(path) {
+            remove_entry(path)?;
+        }
+    }
+    for (path, entry) in to {
+        if from.get(path) != Some(entry) {
+            write_entry(path, entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Make the working tree and the index match `tree`, discarding local changes to tracked files
+/// and removing files that are only in the index.
+pub(crate) fn reset_hard(tree: &FlatTree) -> anyhow::Result<()> {
+    for entry in Index::read()?.entries {
+        if !tree.contains_key(&entry.path) {
+            remove_entry(&entry.path)?;
+        }
+    }
+    for (path, entry) in tree {
+        if !matches(path, Some(entry))? {
+            write_entry(path, entry)?;
+        }
+    }
+    Index::from_tree(tree).write()
+}
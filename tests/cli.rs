@@ -0,0 +1,118 @@
+//! End-to-end tests running the `mini-git` binary in throwaway repositories.
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A directory under the system's temporary directory, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "mini-git-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run `mini-git` with `args` in `dir`.
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mini-git"))
+        .args(args)
+        .current_dir(dir)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap()
+}
+
+/// Run `mini-git` with `args` in `dir`, and return its standard output if it succeeds.
+fn ok(dir: &Path, args: &[&str]) -> String {
+    let output = run(dir, args);
+    assert!(
+        output.status.success(),
+        "mini-git {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// A new repository with an identity to commit as.
+fn repository() -> TempDir {
+    let dir = TempDir::new();
+    ok(dir.path(), &["init", "-q"]);
+    ok(dir.path(), &["config", "user.name", "Test"]);
+    ok(dir.path(), &["config", "user.email", "test@example.com"]);
+    dir
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_are_committed_as_their_target() {
+    let repo = repository();
+    let dir = repo.path();
+    std::fs::write(dir.join("a"), "content\n").unwrap();
+    std::fs::create_dir(dir.join("d")).unwrap();
+    std::fs::write(dir.join("d/f"), "file\n").unwrap();
+    std::os::unix::fs::symlink("a", dir.join("link")).unwrap();
+    std::os::unix::fs::symlink("d", dir.join("dir-link")).unwrap();
+    ok(dir, &["commit", "-m", "links"]);
+
+    let tree = ok(dir, &["write-tree"]);
+    let tree = ok(dir, &["ls-tree", tree.trim()]);
+    for (name, target) in [("link", "a"), ("dir-link", "d")] {
+        let expected = repo.path().join(".git").join("expected");
+        std::fs::write(&expected, target).unwrap();
+        let hash = ok(dir, &["hash-object", expected.to_str().unwrap()]);
+        let entry = format!("120000 blob {}\t{name}", hash.trim());
+        assert!(tree.contains(&entry), "{entry} not in {tree}");
+    }
+
+    // Nothing changed since the commit.
+    let status = ok(dir, &["stash", "push"]);
+    assert_eq!(status.trim(), "No local changes to save");
+
+    std::fs::write(dir.join("a"), "changed\n").unwrap();
+    ok(dir, &["stash", "push"]);
+    assert_eq!(
+        std::fs::read_link(dir.join("link")).unwrap(),
+        Path::new("a")
+    );
+    assert_eq!(std::fs::read_to_string(dir.join("a")).unwrap(), "content\n");
+    ok(dir, &["stash", "pop"]);
+    assert_eq!(
+        std::fs::read_link(dir.join("link")).unwrap(),
+        Path::new("a")
+    );
+    assert_eq!(
+        std::fs::read_link(dir.join("dir-link")).unwrap(),
+        Path::new("d")
+    );
+    assert_eq!(std::fs::read_to_string(dir.join("a")).unwrap(), "changed\n");
+}
+
+#[test]
+fn hash_object_without_write_works_outside_a_repository() {
+    let dir = TempDir::new();
+    std::fs::write(dir.path().join("file"), "hello\n").unwrap();
+    let hash = ok(dir.path(), &["hash-object", "file"]);
+    assert_eq!(hash.trim(), "ce013625030ba8dba906f756967f9e9ca394464a");
+
+    let output = run(dir.path(), &["hash-object", "-w", "file"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not a git repository"));
+}